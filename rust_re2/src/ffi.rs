@@ -22,6 +22,91 @@ pub struct UemacsEvent {
     pub consumed: bool,
 }
 
+/// Payload of `re2:search:start`
+///
+/// Event payloads are only valid for the duration of the emit call;
+/// consumers must copy any strings they want to keep.
+#[repr(C)]
+pub struct SearchStartEvent {
+    pub pattern: *const c_char,
+    pub directory: *const c_char,
+}
+
+/// Payload of `re2:search:progress`, emitted at most a few times a second
+#[repr(C)]
+pub struct SearchProgressEvent {
+    pub files_searched: usize,
+    pub matches: usize,
+}
+
+/// Payload of `re2:search:done`, emitted after every search - directory,
+/// buffer or region - whether or not anyone subscribes
+///
+/// For C consumers the layout is:
+///
+/// ```c
+/// struct re2_search_done {
+///     size_t matches;
+///     size_t files_searched;      /* buffers, for buffer searches */
+///     uint64_t elapsed_ms;
+///     const char *results_buffer;
+///     bool cancelled;             /* C99 _Bool */
+/// };
+/// ```
+///
+/// A search that failed (a bad pattern, say) reports all zeros.
+#[repr(C)]
+pub struct SearchDoneEvent {
+    pub matches: usize,
+    pub files_searched: usize,
+    pub elapsed_ms: u64,
+    /// Buffer holding the results, e.g. "*re2-results*"
+    pub results_buffer: *const c_char,
+    /// The search was cancelled; counts cover only what finished
+    pub cancelled: bool,
+}
+
+/// Payload of `input:mouse` (matches struct input_key_event)
+///
+/// Key and mouse input share the struct; `input:key` handlers only read
+/// the leading key code.
+#[repr(C)]
+pub struct InputKeyEvent {
+    pub key: c_int,
+    pub modifiers: c_int,
+    /// Mouse action: 0 press, 1 release, 2 drag, 3 move
+    pub code: c_int,
+    /// SGR 1006 button: 0 left, 1 middle, 2 right, 64 and up the wheel
+    pub mouse_button: u8,
+    /// Screen column and row of the pointer
+    pub mouse_x: u16,
+    pub mouse_y: u16,
+}
+
+/// Lexer state carried between lines (matches uemacs_lexer_state_t)
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct LexerState {
+    pub mode: c_int,
+    pub nest_depth: c_int,
+    pub string_delim: c_char,
+    pub state_hash: u32,
+}
+
+/// Syntax lexer callback (matches uemacs_syntax_lex_fn)
+///
+/// Arguments are the language, buffer, line number, line text and its
+/// length, the previous line's state and the token list to append to.
+pub type SyntaxLexFn = extern "C" fn(
+    *const c_void,
+    *mut c_void,
+    c_int,
+    *const c_char,
+    c_int,
+    LexerState,
+    *mut c_void,
+) -> LexerState;
+
 /// Event handler callback (matches uemacs_event_fn)
 pub type EventFn = extern "C" fn(*mut UemacsEvent, *mut c_void) -> bool;
 
@@ -0,0 +1,46 @@
+//! Scratch directories for tests
+//!
+//! Every test that needs files on disk gets them through fixture_dir, so
+//! each module's tests name, build and clean up their directories alike.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory under the system temp dir, removed when dropped
+///
+/// Removal happens on unwind too, so a failing test leaves nothing behind.
+pub struct Fixture(PathBuf);
+
+impl Deref for Fixture {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Fixture {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Create a fresh fixture directory holding `files` (relative path,
+/// contents); `name` keeps concurrently running tests apart
+pub fn fixture_dir(name: &str, files: &[(&str, &str)]) -> Fixture {
+    let dir = std::env::temp_dir().join(format!("rust_re2_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for (rel, contents) in files {
+        let path = dir.join(rel);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    Fixture(dir)
+}
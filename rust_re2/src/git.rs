@@ -0,0 +1,230 @@
+//! Files and lines git reports as changed
+//!
+//! re2-search-dirty searches only what `git status` (or `git diff` against
+//! a base) lists, rather than walking the tree. Paths come back absolute,
+//! limited to the directory asked about, and only for files that still
+//! exist: deletions are dropped and renames give their new name.
+//!
+//! re2-search-changed narrows further, to the lines `git diff -U0` shows
+//! as added or modified.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::search::LineRanges;
+
+/// Run git in `dir`, returning its stdout
+///
+/// Errors carry git's own message, or say git couldn't be started.
+fn git(dir: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Cannot run git: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr.trim().trim_start_matches("fatal: ").to_string());
+    }
+    Ok(output.stdout)
+}
+
+/// Top of the work tree holding `dir`
+pub fn toplevel(dir: &Path) -> Result<PathBuf, String> {
+    let out = git(dir, &["rev-parse", "--show-toplevel"])
+        .map_err(|_| format!("Not in a git repository: {}", dir.display()))?;
+    Ok(PathBuf::from(String::from_utf8_lossy(&out).trim_end()))
+}
+
+/// What a changed-files search compares against, for the results header
+pub fn describe_base(base: Option<&str>) -> String {
+    match base {
+        Some(base) => format!("git diff {}", base),
+        None => "git status vs HEAD".to_string(),
+    }
+}
+
+/// Files under `dir` that changed: uncommitted and untracked ones when
+/// `base` is None (`git status`), else those differing from `base`
+/// (`git diff --name-only <base>`)
+pub fn changed_files(dir: &Path, base: Option<&str>) -> Result<Vec<PathBuf>, String> {
+    let top = toplevel(dir)?;
+    let names = match base {
+        None => {
+            let args = ["status", "--porcelain", "-z", "--untracked-files=all", "--", "."];
+            parse_status(&git(dir, &args)?)
+        }
+        Some(base) => parse_names(&git(dir, &["diff", "--name-only", "-z", base, "--", "."])?),
+    };
+
+    let mut files: Vec<PathBuf> = names
+        .into_iter()
+        .map(|name| top.join(name))
+        .filter(|file| file.is_file())
+        .collect();
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Paths from `git status --porcelain -z`, relative to the work tree top
+///
+/// Each entry is `XY path`; a rename or copy (`R`/`C` in either column)
+/// is followed by a second field holding the old path, which is skipped.
+/// Deleted entries are kept here and dropped once they prove missing.
+fn parse_status(out: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut fields = out.split(|&b| b == 0).filter(|f| !f.is_empty());
+    while let Some(entry) = fields.next() {
+        let Some((status, path)) = entry.split_at_checked(3) else {
+            continue;
+        };
+        if status[..2].iter().any(|&c| c == b'R' || c == b'C') {
+            fields.next();
+        }
+        names.push(String::from_utf8_lossy(path).into_owned());
+    }
+    names
+}
+
+/// Lines added or modified since `base`, per file under `dir`
+///
+/// Files with only deleted lines, and files since deleted, are left out.
+pub fn changed_lines(dir: &Path, base: &str) -> Result<LineRanges, String> {
+    let top = toplevel(dir)?;
+    let diff = git(dir, &["diff", "-U0", "--no-color", "--no-ext-diff", base, "--", "."])?;
+    Ok(parse_hunks(&String::from_utf8_lossy(&diff))
+        .into_iter()
+        .map(|(name, ranges)| (top.join(name), ranges))
+        .filter(|(file, _)| file.is_file())
+        .collect())
+}
+
+/// New-side line ranges from `git diff -U0` output, keyed by the path
+/// after `+++ b/`
+///
+/// A hunk header `@@ -a,b +c,d @@` covers lines c to c+d-1 of the new
+/// file; d is 1 when left out and 0 for a pure deletion, which adds no
+/// range. A `+++` line only names a file straight after the `---` one,
+/// since an added line reading `++ x` looks the same inside a hunk.
+fn parse_hunks(diff: &str) -> BTreeMap<String, Vec<(u64, u64)>> {
+    let mut files: BTreeMap<String, Vec<(u64, u64)>> = BTreeMap::new();
+    let mut current: Option<String> = None;
+    let mut after_old_name = false;
+
+    for line in diff.lines() {
+        let old_name = std::mem::replace(&mut after_old_name, line.starts_with("--- "));
+        if let Some(path) = line.strip_prefix("+++ ").filter(|_| old_name) {
+            // Unusual names come quoted; /dev/null is a deleted file
+            let path = path.trim_matches('"');
+            current = path.strip_prefix("b/").map(str::to_string);
+            continue;
+        }
+        let (Some(file), Some(hunk)) = (&current, line.strip_prefix("@@ ")) else {
+            continue;
+        };
+        let Some(new) = hunk.split_whitespace().find_map(|part| part.strip_prefix('+')) else {
+            continue;
+        };
+        let (start, count) = match new.split_once(',') {
+            Some((start, count)) => (start.parse::<u64>(), count.parse::<u64>()),
+            None => (new.parse::<u64>(), Ok(1)),
+        };
+        if let (Ok(start), Ok(count @ 1..)) = (start, count) {
+            files.entry(file.clone()).or_default().push((start, start + count - 1));
+        }
+    }
+
+    files
+}
+
+/// Paths from a NUL-separated `git diff --name-only -z`
+fn parse_names(out: &[u8]) -> Vec<String> {
+    out.split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::fixture_dir;
+
+    #[test]
+    fn test_changed_lines_from_hunks() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+index 1111111..2222222 100644
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -3 +3 @@ fn main() {
+-    old();
++    new();
+@@ -10,0 +11,2 @@ fn main() {
++    added();
++++ looks like a header
+@@ -20,2 +21,0 @@
+-    gone();
+-    gone();
+diff --git a/old.rs b/old.rs
+deleted file mode 100644
+--- a/old.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-x
+";
+        let files = parse_hunks(diff);
+        assert_eq!(files.len(), 1);
+        let ranges = &files["src/a.rs"];
+        assert_eq!(ranges, &vec![(3, 3), (11, 12)]);
+    }
+
+    #[test]
+    fn test_changed_files_follow_renames_and_skip_deletions() {
+        assert_eq!(
+            parse_status(b" M src/a.rs\0R  new.rs\0old.rs\0 D gone.rs\0?? notes.txt\0"),
+            vec!["src/a.rs", "new.rs", "gone.rs", "notes.txt"]
+        );
+
+        let dir = fixture_dir("git", &[]);
+        let run = |args: &[&str]| {
+            Command::new("git")
+                .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+                .args(args)
+                .current_dir(&*dir)
+                .output()
+                .is_ok_and(|o| o.status.success())
+        };
+        if !run(&["init", "-q"]) {
+            return; // git not installed
+        }
+        for name in ["same.rs", "edited.rs", "old.rs", "gone.rs"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        assert!(run(&["add", "."]));
+        assert!(run(&["commit", "-qm", "base"]));
+
+        std::fs::write(dir.join("edited.rs"), "changed").unwrap();
+        std::fs::write(dir.join("untracked.rs"), "new").unwrap();
+        std::fs::remove_file(dir.join("gone.rs")).unwrap();
+        assert!(run(&["mv", "old.rs", "renamed.rs"]));
+
+        let names = |files: Vec<PathBuf>| -> Vec<String> {
+            files.iter().map(|f| f.file_name().unwrap().to_string_lossy().into_owned()).collect()
+        };
+        let dirty = changed_files(&dir, None).unwrap();
+        assert_eq!(names(dirty), vec!["edited.rs", "renamed.rs", "untracked.rs"]);
+        let vs_head = changed_files(&dir, Some("HEAD")).unwrap();
+        assert_eq!(names(vs_head), vec!["edited.rs", "renamed.rs"]);
+        assert!(changed_files(&dir, Some("no-such-ref")).is_err());
+        let lines = changed_lines(&dir, "HEAD").unwrap();
+        // A rename without edits changes no lines
+        assert_eq!(names(lines.keys().cloned().collect()), vec!["edited.rs"]);
+        assert_eq!(lines[&dir.join("edited.rs")], vec![(1, 1)]);
+
+        let err = changed_files(&std::env::temp_dir(), None).unwrap_err();
+        assert!(err.starts_with("Not in a git repository"), "{}", err);
+    }
+}
@@ -0,0 +1,88 @@
+//! Search pattern history
+//!
+//! A bounded ring of recently searched patterns, newest first. Every
+//! search command records into the same ring so re2-history can offer
+//! them back regardless of which command ran them.
+
+use std::collections::VecDeque;
+
+/// Default number of patterns kept
+pub const DEFAULT_CAPACITY: usize = 50;
+
+/// Bounded pattern history, newest first
+pub struct History {
+    entries: VecDeque<String>,
+    capacity: usize,
+}
+
+impl History {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Change the capacity, dropping the oldest entries if it shrank
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.truncate(capacity);
+    }
+
+    /// Record a pattern; empty patterns and repeats of the newest are ignored
+    pub fn push(&mut self, pattern: &str) {
+        if pattern.is_empty() || self.capacity == 0 {
+            return;
+        }
+        if self.entries.front().map(String::as_str) == Some(pattern) {
+            return;
+        }
+        self.entries.push_front(pattern.to_string());
+        self.entries.truncate(self.capacity);
+    }
+
+    /// Most recently searched pattern
+    pub fn latest(&self) -> Option<&str> {
+        self.entries.front().map(String::as_str)
+    }
+
+    /// Patterns from newest to oldest
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_collapses_consecutive_duplicates() {
+        let mut h = History::new(DEFAULT_CAPACITY);
+        h.push("foo");
+        h.push("foo");
+        h.push("bar");
+        h.push("foo");
+        h.push("");
+
+        let entries: Vec<&str> = h.iter().collect();
+        assert_eq!(entries, vec!["foo", "bar", "foo"]);
+        assert_eq!(h.latest(), Some("foo"));
+    }
+
+    #[test]
+    fn test_capacity_drops_oldest() {
+        let mut h = History::new(2);
+        h.push("a");
+        h.push("b");
+        h.push("c");
+        assert_eq!(h.iter().collect::<Vec<_>>(), vec!["c", "b"]);
+
+        h.set_capacity(1);
+        assert_eq!(h.iter().collect::<Vec<_>>(), vec!["c"]);
+    }
+}
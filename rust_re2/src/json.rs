@@ -0,0 +1,247 @@
+//! ripgrep-compatible JSON export
+//!
+//! re2-export-json writes a search's matches as newline-delimited JSON in
+//! the shape of `rg --json`: a `begin` message before each file's matches,
+//! a `match` message per matched line, an `end` message after them with
+//! that file's stats, and one closing `summary`:
+//!
+//! ```text
+//! {"type":"begin","data":{"path":{"text":"src/a.rs"}}}
+//! {"type":"match","data":{"path":{"text":"src/a.rs"},"lines":{"text":"let x = 1;\n"},
+//!  "line_number":3,"absolute_offset":42,"submatches":[{"match":{"text":"x"},"start":4,"end":5}]}}
+//! {"type":"end","data":{"path":{"text":"src/a.rs"},"binary_offset":null,"stats":{...}}}
+//! {"type":"summary","data":{"elapsed_total":{...},"stats":{...}}}
+//! ```
+//!
+//! Line text is written with a `\n` terminator as ripgrep does, whatever
+//! the file used. Offsets count the searched text, so for decompressed or
+//! transcoded files they are not offsets into the file on disk. Bytes
+//! searched aren't tracked and are always 0.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::search::{self, Engine, Match, SearchResult};
+
+/// One line of output
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+pub enum Message {
+    Begin(Begin),
+    Match(MatchData),
+    End(End),
+    Summary(Summary),
+}
+
+/// Text as ripgrep writes it; files are searched as UTF-8, so never the
+/// base64 `bytes` form
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Data {
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Begin {
+    pub path: Data,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatchData {
+    pub path: Data,
+    pub lines: Data,
+    pub line_number: u64,
+    pub absolute_offset: u64,
+    pub submatches: Vec<SubMatch>,
+}
+
+/// One occurrence within a matched line, as byte offsets into `lines`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubMatch {
+    #[serde(rename = "match")]
+    pub matched: Data,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct End {
+    pub path: Data,
+    pub binary_offset: Option<u64>,
+    pub stats: Stats,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub elapsed_total: Duration,
+    pub stats: Stats,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub elapsed: Duration,
+    pub searches: u64,
+    pub searches_with_match: u64,
+    pub bytes_searched: u64,
+    pub bytes_printed: u64,
+    pub matched_lines: u64,
+    pub matches: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Duration {
+    pub secs: u64,
+    pub nanos: u32,
+    pub human: String,
+}
+
+impl Duration {
+    fn from_ms(ms: u64) -> Duration {
+        let d = std::time::Duration::from_millis(ms);
+        Duration {
+            secs: d.as_secs(),
+            nanos: d.subsec_nanos(),
+            human: format!("{:.6}s", d.as_secs_f64()),
+        }
+    }
+}
+
+/// Serialize a search result as ripgrep JSON lines
+///
+/// Each occurrence on a line is found with `engine` when given; without
+/// one only the first, as recorded in the match, is listed. Paths under
+/// `relative_to` are written relative to it.
+pub fn format_json(
+    result: &SearchResult,
+    engine: Option<&Engine>,
+    relative_to: Option<&Path>,
+) -> Result<String, String> {
+    let mut output = String::new();
+    let mut write = |message: &Message| -> Result<u64, String> {
+        let line = serde_json::to_string(message).map_err(|e| format!("JSON export failed: {}", e))?;
+        output.push_str(&line);
+        output.push('\n');
+        Ok(line.len() as u64 + 1)
+    };
+
+    let mut total = Stats {
+        elapsed: Duration::from_ms(result.stats.elapsed_ms),
+        searches: result.stats.files_searched as u64,
+        searches_with_match: result.stats.files_matched as u64,
+        ..Stats::default()
+    };
+    for group in result.matches.chunk_by(|a, b| a.file == b.file) {
+        let file = &group[0].file;
+        let path = relative_to
+            .and_then(|root| file.strip_prefix(root).ok())
+            .unwrap_or(file)
+            .to_string_lossy()
+            .into_owned();
+        let text = |s: &str| Data { text: s.to_string() };
+
+        let mut stats = Stats {
+            searches: 1,
+            searches_with_match: 1,
+            ..Stats::default()
+        };
+        stats.bytes_printed += write(&Message::Begin(Begin { path: text(&path) }))?;
+        for m in group {
+            let data = match_data(m, engine, text(&path));
+            stats.matched_lines += 1;
+            stats.matches += data.submatches.len() as u64;
+            stats.bytes_printed += write(&Message::Match(data))?;
+        }
+
+        total.matched_lines += stats.matched_lines;
+        total.matches += stats.matches;
+        total.bytes_printed += stats.bytes_printed;
+        total.bytes_printed += write(&Message::End(End {
+            path: text(&path),
+            binary_offset: None,
+            stats,
+        }))?;
+    }
+
+    write(&Message::Summary(Summary {
+        elapsed_total: Duration::from_ms(result.stats.elapsed_ms),
+        stats: total,
+    }))?;
+    Ok(output)
+}
+
+/// A `match` message for one matched line
+fn match_data(m: &Match, engine: Option<&Engine>, path: Data) -> MatchData {
+    let spans = match engine {
+        _ if m.inverted => Vec::new(),
+        Some(engine) => search::match_spans(engine, &m.text),
+        None if m.end_column > m.column => vec![(m.column, m.end_column)],
+        None => Vec::new(),
+    };
+    let submatches = spans
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let matched = m.text.get(start..end)?;
+            Some(SubMatch {
+                matched: Data { text: matched.to_string() },
+                start,
+                end,
+            })
+        })
+        .collect();
+
+    MatchData {
+        path,
+        lines: Data { text: format!("{}\n", m.text) },
+        line_number: m.line_number,
+        absolute_offset: m.offset,
+        submatches,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::SearchOptions;
+
+    #[test]
+    fn test_json_round_trip() {
+        let text = "fn a() { a(); }\n\"quoted\\\" \t a\nnone\n";
+        let opts = SearchOptions::default();
+        let result = search::search_buffer(r"\ba\b", "/p/x.rs", text.as_bytes(), &opts).unwrap();
+        let engine = search::build_engine(r"\ba\b", &opts).unwrap();
+        let output = format_json(&result, Some(&engine), Some(Path::new("/p"))).unwrap();
+
+        let messages: Vec<Message> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(messages.len(), 5);
+        assert!(matches!(&messages[0], Message::Begin(b) if b.path.text == "x.rs"));
+
+        let Message::Match(second) = &messages[2] else {
+            panic!("expected a match: {:?}", messages[2]);
+        };
+        assert_eq!(second.line_number, 2);
+        assert_eq!(second.absolute_offset, 16);
+        assert_eq!(second.lines.text, "\"quoted\\\" \t a\n");
+        assert_eq!(&text[16 + second.submatches[0].start..][..1], "a");
+
+        let Message::Summary(summary) = &messages[4] else {
+            panic!("expected the summary: {:?}", messages[4]);
+        };
+        assert_eq!(summary.stats.matched_lines, result.matches.len() as u64);
+        // Both calls on the first line count
+        assert_eq!(summary.stats.matches, 3);
+        let printed: usize = output.lines().take(4).map(|l| l.len() + 1).sum();
+        assert_eq!(summary.stats.bytes_printed as usize, printed);
+
+        // Without a matcher only the recorded first span is listed
+        let output = format_json(&result, None, None).unwrap();
+        let Message::Match(first) = serde_json::from_str(output.lines().nth(1).unwrap()).unwrap() else {
+            panic!("expected a match");
+        };
+        assert_eq!(first.path.text, "/p/x.rs");
+        assert_eq!(first.submatches.len(), 1);
+        assert_eq!((first.submatches[0].start, first.submatches[0].end), (3, 4));
+    }
+}
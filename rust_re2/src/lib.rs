@@ -6,30 +6,297 @@
 //!
 //! Commands provided:
 //! - re2: Search for pattern in current directory
-//! - re2-word: Search for word under cursor
+//! - re2-new: Search like re2 into a new numbered results buffer
+//! - re2-results-list: List the results buffers and their searches
+//! - re2-literal: Search for a fixed string (no regex metacharacters)
+//! - re2-search-any: Search for lines matching any of several space-separated patterns
+//! - re2-word: Search for whole-word occurrences of the word under cursor
+//! - re2-whole-word: Prompt for a pattern and match it as whole words (-w)
+//! - re2-word-type: Search word under cursor in files of the buffer's type
+//! - re2-def: Find the likely definition of the word under cursor
+//! - re2-dir: Prompt for a directory, then search it
+//! - re2-tracked: Search only files git tracks
+//! - re2-search-dirty: Search only files git reports as changed (or changed since a ref)
+//! - re2-search-changed: Search only lines added or modified since a git ref
+//! - re2-unrestricted: Search hidden and ignored files too (-uu)
+//! - re2-invert: List lines NOT matching, in files of a required glob or type
+//! - re2-type: Search files of the given types (e.g. "rust,toml")
+//! - re2-buffer: Search the current buffer (including unsaved edits)
+//! - re2-buffers: Search every open buffer
+//! - re2-region: Search the region between mark and point (or the buffer)
+//! - re2-files: List files (like `rg --files`), optionally filtered
+//! - re2-count: Count matches per file (like `rg -c`)
+//! - re2-count-total: Count matches everywhere, showing just the total
+//! - re2-todo: List TODO/FIXME/HACK/XXX markers in the project, by kind
+//! - re2-exists: Say whether a pattern matches anywhere, stopping at the first hit
+//! - re2-export-results: Write the last search's matches to a file as path:line:col:text
+//! - re2-export-json: Write the last search's matches as ripgrep JSON lines
+//! - re2-multiline: Search with patterns that may span lines
+//! - re2-live: Live grep - results update as you type the pattern
+//! - re2-preview: Toggle previewing the selected result in the other window
+//! - re2-jump-back: Return to where you were before the last jump or search
+//! - re2-next-match / re2-prev-match: Step through the last results from any buffer
+//! - re2-history: List recent patterns; Enter re-runs one
+//! - re2-refine: Narrow the results to those matching another pattern
+//! - re2-refine-reset: Undo every re2-refine on the current results
+//! - re2-replace-preview: Show what replacing a pattern would change
+//! - re2-replace-apply: Write the previewed replacements to disk
+//! - re2-replace-interactive: Confirm each replacement in turn
+//! - re2-save-search: Save the last search under a name
+//! - re2-run-saved: Run a saved search from the project root
+//! - re2-again: Repeat the last search (prefix argument: edit the pattern first)
+//! - re2-cancel: Cancel the running search (ESC and C-g also work)
 //! - re2-case: Toggle case insensitive mode
 //! - re2-smart: Toggle smart case mode
 //! - re2-word-boundary: Toggle whole word matching
 //! - re2-hidden: Toggle hidden files
 //! - re2-gitignore: Toggle .gitignore respect
+//! - re2-mmap: Toggle memory-mapped file reading
+//! - re2-grouping: Toggle grouped (heading per file) results
+//! - re2-threads: Set the search thread count for this session
+//! - re2-max-filesize: Skip files larger than a size (e.g. 2M) this session
+//! - re2-settings: List every config key with its current value
+//! - re2-reload-config: Reread the config without restarting the editor
 //!
-//! Press Enter in results buffer to jump to file:line.
+//! In the results buffer: Enter or a mouse click jumps to file:line, o
+//! opens it in the other window, n/p show the next/previous result in the
+//! other window, g searches again, C/W re-run it with case matching cycled
+//! or whole words toggled, and q returns to the buffer the search started
+//! from.
 
 mod ffi;
+#[cfg(test)]
+mod fixture;
+mod git;
+mod history;
+mod json;
+mod project;
+mod replace;
 mod search;
+mod state;
 
-use ffi::{CmdFn, EventFn, GetFunctionFn, UemacsApi, UemacsEvent, UemacsExtension};
-use search::SearchOptions;
+use ffi::{
+    CmdFn, EventFn, GetFunctionFn, InputKeyEvent, LexerState, SearchDoneEvent, SearchProgressEvent,
+    SearchStartEvent, SyntaxLexFn, UemacsApi, UemacsEvent, UemacsExtension,
+};
+use history::History;
+use search::{Highlight, Layout, LineHighlight, SearchOptions, SortOrder, Style};
+use state::{SavedSearch, State};
+use std::collections::BTreeMap;
 use std::ffi::{c_char, c_int, c_void, CStr, CString};
-use std::sync::atomic::{AtomicPtr, Ordering};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Results buffer name unless config `results_buffer` overrides it
+const DEFAULT_RESULTS_BUFFER: &str = "*re2-results*";
+
+/// Configured results buffer name; re2-new numbers buffers after it
+static RESULTS_BASE: Mutex<String> = Mutex::new(String::new());
+
+/// Numbered buffer re2-new is writing to, for the length of its search
+static RESULTS_TARGET: Mutex<Option<String>> = Mutex::new(None);
+
+/// The last directory or buffer search and the refine filters applied
+struct Refine {
+    full: search::SearchResult,
+    filters: Vec<String>,
+}
+
+/// Base for re2-refine; replaced by every new search
+static REFINE: Mutex<Option<Refine>> = Mutex::new(None);
+
+/// A directory search shown in a results buffer, so g, Enter and
+/// re2-refine there act on that buffer's own search
+struct ResultsSearch {
+    buffer: String,
+    pattern: String,
+    dir: String,
+    opts: SearchOptions,
+    layout: Layout,
+    refine: Refine,
+}
+
+/// Directory searches by the results buffer showing them, oldest first
+static RESULTS_SEARCHES: Mutex<Vec<ResultsSearch>> = Mutex::new(Vec::new());
+
+/// Results buffers kept unless config `results_buffers` says otherwise;
+/// older ones are cleared
+const DEFAULT_RESULTS_BUFFERS: usize = 5;
+
+/// Buffer that was current when results were last shown, for q
+static ORIGIN_BUFFER: Mutex<Option<String>> = Mutex::new(None);
+
+/// A place re2-jump-back can return to
+#[derive(Clone, Debug, PartialEq)]
+struct Jump {
+    buffer: String,
+    line: i32,
+    column: i32,
+}
+
+/// Most places re2-jump-back remembers; the oldest go first
+const JUMP_STACK_DEPTH: usize = 50;
+
+/// A listed match: where it points and the results line showing it
+#[derive(Clone)]
+struct MatchStep {
+    target: JumpTarget,
+    /// Line listing it in the results buffer
+    results_line: i32,
+}
+
+/// The matches a results buffer lists, in order, and the one
+/// re2-next-match / re2-prev-match visited last
+///
+/// Enter, o and v look the line under point up here rather than parsing
+/// its text back; lines written while a search streams in aren't listed
+/// until it finishes, so those still parse.
+struct Listing {
+    buffer: String,
+    steps: Vec<MatchStep>,
+    index: Option<usize>,
+}
+
+/// Listings by results buffer, replaced whenever one lists matches
+static LISTINGS: Mutex<Vec<Listing>> = Mutex::new(Vec::new());
+
+/// Results buffer re2-next-match steps through: the one listed or
+/// visited last
+static STEPPING: Mutex<Option<String>> = Mutex::new(None);
+
+/// Where the cursor was before each search and each jump to a result,
+/// newest last
+static JUMP_STACK: Mutex<Vec<Jump>> = Mutex::new(Vec::new());
+
+/// Number re2-new gives its next buffer
+static NEXT_RESULTS_NUMBER: AtomicUsize = AtomicUsize::new(2);
+
+/// Pattern history buffer name
+const RE2_HISTORY_BUFFER: &str = "*re2-history*";
+
+/// Saved searches listing buffer name
+const RE2_SAVED_BUFFER: &str = "*re2-saved*";
+
+/// Buffer listing files that couldn't be searched
+const RE2_ERRORS_BUFFER: &str = "*re2-errors*";
+
+/// Buffer showing a replace preview
+const RE2_REPLACE_BUFFER: &str = "*re2-replace*";
+
+/// Buffer re2-settings lists the current options in
+const RE2_SETTINGS_BUFFER: &str = "*re2-settings*";
+
+/// Buffer re2-results-list lists the results buffers in
+const RE2_RESULTS_LIST_BUFFER: &str = "*re2-results-list*";
+
+/// File re2-export-results writes to, in the search directory, unless
+/// another is given
+const EXPORT_FILE: &str = "re2-results.txt";
+
+/// File re2-export-json writes to, likewise
+const EXPORT_JSON_FILE: &str = "re2-results.json";
+
+/// Buffer re2-export-json writes to with a prefix argument
+const RE2_JSON_BUFFER: &str = "*re2-json*";
 
-/// Results buffer name
-const RE2_RESULTS_BUFFER: &str = "*re2-results*";
+/// Buffer v shows a result's surrounding lines in
+const RE2_PREVIEW_BUFFER: &str = "*re2-preview*";
+
+/// Edits from the last re2-replace-preview, waiting for re2-replace-apply
+static REPLACE_PLAN: Mutex<Option<Vec<replace::LineEdit>>> = Mutex::new(None);
+
+/// File extension to ripgrep type name, for re2-word-type
+const EXTENSION_TYPES: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cc", "cpp"),
+    ("cpp", "cpp"),
+    ("cxx", "cpp"),
+    ("hpp", "cpp"),
+    ("go", "go"),
+    ("py", "py"),
+    ("js", "js"),
+    ("jsx", "js"),
+    ("ts", "ts"),
+    ("tsx", "ts"),
+    ("java", "java"),
+    ("rb", "ruby"),
+    ("sh", "sh"),
+    ("zig", "zig"),
+    ("hs", "haskell"),
+    ("adb", "ada"),
+    ("ads", "ada"),
+    ("cr", "crystal"),
+    ("pas", "pascal"),
+    ("lua", "lua"),
+    ("toml", "toml"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("json", "json"),
+    ("md", "markdown"),
+];
 
 /// Event name for key input
 static INPUT_KEY_EVENT: &[u8; 10] = b"input:key\0";
 
+/// Event name for mouse input (published when mouse reporting is on)
+static INPUT_MOUSE_EVENT: &[u8; 12] = b"input:mouse\0";
+
+/// Format string for printf-style API calls, so `%` in text is never
+/// interpreted
+static PRINTF_STR: &[u8; 3] = b"%s\0";
+
+/// Event name for buffer switches
+static BUFFER_SWITCH_EVENT: &[u8; 14] = b"buffer:switch\0";
+
+/// Events published for other extensions (payloads in ffi.rs)
+static SEARCH_START_EVENT: &[u8; 17] = b"re2:search:start\0";
+static SEARCH_PROGRESS_EVENT: &[u8; 20] = b"re2:search:progress\0";
+static SEARCH_DONE_EVENT: &[u8; 16] = b"re2:search:done\0";
+
+/// Event other extensions send to run a search; the payload is a
+/// NUL-terminated pattern
+static SEARCH_REQUEST_EVENT: &[u8; 19] = b"re2:search:request\0";
+
+/// Longest re2:search:request pattern read, NUL included, when the
+/// event carries no size
+const MAX_REQUEST_PATTERN: usize = 4096;
+
+/// Minimum time between re2:search:progress events
+const PROGRESS_EVENT_MS: u128 = 250;
+
+/// Files the running search has finished, for progress messages
+static SEARCH_FILES_SEARCHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Set while a directory search is running
+static SEARCH_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Asks the running search to stop; cleared when a search starts
+static SEARCH_CANCEL: AtomicBool = AtomicBool::new(false);
+
+/// Set when the user (not a buffer switch) cancelled, so it gets reported
+static CANCEL_BY_USER: AtomicBool = AtomicBool::new(false);
+
+/// The running search is on its own thread; cleared once its results
+/// are collected
+static SEARCH_DETACHED: AtomicBool = AtomicBool::new(false);
+
+/// What a detached search leaves for the editor thread to publish and
+/// show; collect_detached_search picks it up
+static DETACHED_MAILBOX: Mutex<Mailbox> = Mutex::new(Mailbox {
+    pattern: String::new(),
+    progress: None,
+    finished: None,
+});
+
+/// When collect_detached_search last reported progress
+static DETACHED_PROGRESS_AT: Mutex<Option<std::time::Instant>> = Mutex::new(None);
+
+/// The latest detached search thread, joined before the next one starts
+/// and at cleanup
+static SEARCH_THREAD: Mutex<Option<std::thread::JoinHandle<()>>> = Mutex::new(None);
+
 /// Extension name for config lookups
 static EXT_NAME: &[u8; 9] = b"rust_re2\0";
 
@@ -39,6 +306,153 @@ static GET_FUNCTION: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
 /// Last search pattern (for repeat searches)
 static LAST_PATTERN: Mutex<Option<String>> = Mutex::new(None);
 
+/// Recent patterns from every search command, newest first
+static HISTORY: Mutex<History> = Mutex::new(History::new(history::DEFAULT_CAPACITY));
+
+/// Options the last search ran with, for re2-save-search and re2-again
+static LAST_OPTIONS: Mutex<Option<SearchOptions>> = Mutex::new(None);
+
+/// Directory the last directory search ran in, for resolving relative
+/// paths in its results
+static LAST_DIR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Where the last search looked, so re2-again repeats it there
+static LAST_TARGET: Mutex<Option<SearchTarget>> = Mutex::new(None);
+
+/// Directory last chosen at the re2-dir prompt, offered as its default
+static CHOSEN_DIR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Persisted state (saved searches; history is copied in on save)
+static SAVED_STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// What the lines in the results buffer refer to
+enum ResultsKind {
+    /// `file:line:col: text` matches from a directory search
+    Files,
+    /// `buffer:line:col: text` matches from in-buffer searches
+    Buffers,
+    /// Bare relative paths from re2-files, rooted at the given directory
+    FileList(String),
+    /// `file: count` lines from re2-count
+    Counts,
+}
+
+/// What a search ran over
+#[derive(Clone)]
+enum SearchTarget {
+    /// Files under a directory
+    Dir(String),
+    /// One buffer, by name
+    Buffer(String),
+    /// Lines `first..=last` of one buffer
+    Region { buffer: String, first: u64, last: u64 },
+    /// Every open buffer
+    Buffers,
+}
+
+/// Kind of the results currently shown in the results buffer
+static RESULTS_KIND: Mutex<ResultsKind> = Mutex::new(ResultsKind::Files);
+
+/// Layout of the output in the results buffer (None = the default);
+/// changing group_by_file or format_template must not change how
+/// existing results are parsed
+static RESULTS_LAYOUT: Mutex<Option<Layout>> = Mutex::new(None);
+
+/// Lexer that colors the results buffers
+static RESULTS_LEXER: &[u8; 12] = b"re2-results\0";
+
+/// Buffer-name patterns the lexer was registered for, kept alive here
+/// in case the editor holds on to them
+static RESULTS_LEXER_PATTERNS: Mutex<Option<LexerPatterns>> = Mutex::new(None);
+
+/// NULL-terminated pattern list handed to syntax_register_lexer
+struct LexerPatterns {
+    _glob: CString,
+    ptrs: [*const c_char; 2],
+}
+
+impl LexerPatterns {
+    fn new(glob: CString) -> Self {
+        let ptrs = [glob.as_ptr(), std::ptr::null()];
+        LexerPatterns { _glob: glob, ptrs }
+    }
+}
+
+// Safety: the pointers refer to the owned string, which is never written
+unsafe impl Send for LexerPatterns {}
+
+/// Faces for results highlighting (UEMACS_FACE_* in extension_api.h)
+const FACE_DEFAULT: c_int = 0;
+const FACE_KEYWORD: c_int = 1;
+const FACE_NUMBER: c_int = 4;
+const FACE_FUNCTION: c_int = 6;
+
+/// syntax_add_token, copied out of the API so the lexer never takes the
+/// API lock (it runs inside update_display, which holds it); null when
+/// the editor has no syntax API and highlighting is off
+static SYNTAX_ADD_TOKEN: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Highlight spans keyed by rendered results line, refilled whenever the
+/// results buffer is rewritten
+static RESULT_HIGHLIGHTS: Mutex<BTreeMap<String, LineHighlight>> = Mutex::new(BTreeMap::new());
+
+/// Live-grep session started by re2-live
+struct LiveState {
+    pattern: String,
+    /// Buffer to return to when the session is aborted
+    previous_buffer: Option<String>,
+    search_dir: String,
+    opts: SearchOptions,
+    /// When the last key arrived, for debouncing
+    last_key: Option<std::time::Instant>,
+    /// Pattern changed but the search was skipped by the debounce
+    stale: bool,
+}
+
+/// Active live-grep session, if any
+static LIVE: Mutex<Option<LiveState>> = Mutex::new(None);
+
+/// Shortest pattern live grep will search for
+const LIVE_MIN_CHARS: usize = 3;
+
+/// Keys arriving faster than this skip the search until typing pauses
+const LIVE_DEBOUNCE_MS: u128 = 150;
+
+/// Preview the result under point in the other window while moving
+static PREVIEW_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Follow mode (F): show each result's surrounding lines in *re2-preview*
+/// while moving, rather than opening the file
+static AUTO_PREVIEW: AtomicBool = AtomicBool::new(false);
+
+/// Debounce bookkeeping for preview mode
+struct PreviewState {
+    last_move: Option<std::time::Instant>,
+    /// A move was skipped by the debounce and still needs previewing
+    pending: bool,
+    /// Location currently shown, so repeats don't reopen the file
+    shown: Option<String>,
+}
+
+static PREVIEW: Mutex<PreviewState> = Mutex::new(PreviewState {
+    last_move: None,
+    pending: false,
+    shown: None,
+});
+
+/// Moves arriving faster than this don't open files until movement pauses
+const PREVIEW_DEBOUNCE_MS: u128 = 120;
+
+/// Lines shown above and below a match by v
+const PREVIEW_CONTEXT_LINES: u64 = 5;
+
+/// Bytes of a file read looking for a previewed line, so a match deep in
+/// a huge file doesn't load all of it
+const PREVIEW_SCAN_BYTES: u64 = 16 << 20;
+
+/// Screen rows probed when looking for the other window
+const MAX_WINDOW_ROWS: c_int = 500;
+
 /// Current search options (loaded from config, can be toggled at runtime)
 static SEARCH_OPTIONS: Mutex<Option<SearchOptions>> = Mutex::new(None);
 
@@ -78,23 +492,48 @@ type BufferInsertFn = unsafe extern "C" fn(*const c_char, usize) -> c_int;
 type BufferCreateFn = unsafe extern "C" fn(*const c_char) -> *mut c_void;
 type BufferSwitchFn = unsafe extern "C" fn(*mut c_void) -> c_int;
 type BufferClearFn = unsafe extern "C" fn(*mut c_void) -> c_int;
+type BufferContentsFn = unsafe extern "C" fn(*mut c_void, *mut usize) -> *mut c_char;
+type BufferModifiedFn = unsafe extern "C" fn(*mut c_void) -> bool;
+type BufferSetUnmodifiedFn = unsafe extern "C" fn(*mut c_void);
+type FindBufferFn = unsafe extern "C" fn(*const c_char) -> *mut c_void;
+type BufferFirstFn = unsafe extern "C" fn() -> *mut c_void;
+type BufferNextFn = unsafe extern "C" fn(*mut c_void) -> *mut c_void;
+type GetPointFn = unsafe extern "C" fn(*mut c_int, *mut c_int);
+type GetMarkFn = unsafe extern "C" fn(*mut c_int, *mut c_int);
 type SetPointFn = unsafe extern "C" fn(c_int, c_int);
 type GetWordAtPointFn = unsafe extern "C" fn() -> *mut c_char;
 type GetCurrentLineFn = unsafe extern "C" fn() -> *mut c_char;
-type MessageFn = unsafe extern "C" fn(*const c_char);
+type GetLineAtFn = unsafe extern "C" fn(*mut c_void, c_int) -> *mut c_char;
+// message and the log functions are printf-style; user text (patterns,
+// file names) must only ever be passed as an argument to PRINTF_STR
+type MessageFn = unsafe extern "C" fn(*const c_char, ...);
 type PromptFn = unsafe extern "C" fn(*const c_char, *mut c_char, usize) -> c_int;
 type UpdateDisplayFn = unsafe extern "C" fn();
+type EmitFn = unsafe extern "C" fn(*const c_char, *mut c_void) -> bool;
 type FindFileLineFn = unsafe extern "C" fn(*const c_char, c_int) -> c_int;
 type FreeFn = unsafe extern "C" fn(*mut c_void);
-type LogInfoFn = unsafe extern "C" fn(*const c_char);
+type LogInfoFn = unsafe extern "C" fn(*const c_char, ...);
+type LogWarnFn = unsafe extern "C" fn(*const c_char, ...);
+type LogErrorFn = unsafe extern "C" fn(*const c_char, ...);
+type PromptYnFn = unsafe extern "C" fn(*const c_char) -> c_int;
+type CurrentWindowFn = unsafe extern "C" fn() -> *mut c_void;
+type WindowAtRowFn = unsafe extern "C" fn(c_int) -> *mut c_void;
+type WindowSwitchFn = unsafe extern "C" fn(*mut c_void) -> c_int;
+type ScreenToBufferPosFn = unsafe extern "C" fn(*mut c_void, c_int, c_int, *mut c_int, *mut c_int) -> c_int;
+type SyntaxRegisterLexerFn =
+    unsafe extern "C" fn(*const c_char, *const *const c_char, SyntaxLexFn, *mut c_void) -> c_int;
+type SyntaxUnregisterLexerFn = unsafe extern "C" fn(*const c_char) -> c_int;
+type SyntaxAddTokenFn = unsafe extern "C" fn(*mut c_void, c_int, c_int) -> c_int;
 
 // ============================================================================
 // Stored function pointers (looked up via get_function during init)
 // ============================================================================
 
+#[derive(Default)]
 struct Api {
     on: Option<OnFn>,
     off: Option<OffFn>,
+    emit: Option<EmitFn>,
     config_int: Option<ConfigIntFn>,
     config_bool: Option<ConfigBoolFn>,
     config_string: Option<ConfigStringFn>,
@@ -107,15 +546,33 @@ struct Api {
     buffer_create: Option<BufferCreateFn>,
     buffer_switch: Option<BufferSwitchFn>,
     buffer_clear: Option<BufferClearFn>,
+    buffer_contents: Option<BufferContentsFn>,
+    buffer_modified: Option<BufferModifiedFn>,
+    buffer_set_unmodified: Option<BufferSetUnmodifiedFn>,
+    find_buffer: Option<FindBufferFn>,
+    buffer_first: Option<BufferFirstFn>,
+    buffer_next: Option<BufferNextFn>,
+    get_point: Option<GetPointFn>,
+    get_mark: Option<GetMarkFn>,
     set_point: Option<SetPointFn>,
     get_word_at_point: Option<GetWordAtPointFn>,
     get_current_line: Option<GetCurrentLineFn>,
+    get_line_at: Option<GetLineAtFn>,
     message: Option<MessageFn>,
     prompt: Option<PromptFn>,
+    prompt_yn: Option<PromptYnFn>,
     update_display: Option<UpdateDisplayFn>,
     find_file_line: Option<FindFileLineFn>,
     free: Option<FreeFn>,
     log_info: Option<LogInfoFn>,
+    log_warn: Option<LogWarnFn>,
+    log_error: Option<LogErrorFn>,
+    current_window: Option<CurrentWindowFn>,
+    window_at_row: Option<WindowAtRowFn>,
+    window_switch: Option<WindowSwitchFn>,
+    screen_to_buffer_pos: Option<ScreenToBufferPosFn>,
+    syntax_register_lexer: Option<SyntaxRegisterLexerFn>,
+    syntax_unregister_lexer: Option<SyntaxUnregisterLexerFn>,
 }
 
 static API: Mutex<Option<Api>> = Mutex::new(None);
@@ -138,137 +595,423 @@ unsafe fn lookup(name: &[u8]) -> Option<unsafe extern "C" fn()> {
 
 /// Initialize the extension
 extern "C" fn re2_init(api_ptr: *mut UemacsApi) -> c_int {
-    // Get get_function from the API struct
-    let get_fn = unsafe {
-        if api_ptr.is_null() {
-            eprintln!("rust_re2: NULL API pointer");
-            return -1;
-        }
-        match (*api_ptr).get_function {
-            Some(f) => f,
-            None => {
-                eprintln!("rust_re2: Requires μEmacs with get_function() support");
+    guarded("re2_init", -1, || {
+        // Get get_function from the API struct
+        let get_fn = unsafe {
+            if api_ptr.is_null() {
+                eprintln!("rust_re2: NULL API pointer");
                 return -1;
             }
-        }
-    };
-
-    // Store get_function for later lookups
-    GET_FUNCTION.store(get_fn as *mut (), Ordering::SeqCst);
-
-    // Look up all API functions by name
-    unsafe {
-        let api = Api {
-            on: lookup(b"on\0").map(|f| std::mem::transmute(f)),
-            off: lookup(b"off\0").map(|f| std::mem::transmute(f)),
-            config_int: lookup(b"config_int\0").map(|f| std::mem::transmute(f)),
-            config_bool: lookup(b"config_bool\0").map(|f| std::mem::transmute(f)),
-            config_string: lookup(b"config_string\0").map(|f| std::mem::transmute(f)),
-            register_command: lookup(b"register_command\0").map(|f| std::mem::transmute(f)),
-            unregister_command: lookup(b"unregister_command\0").map(|f| std::mem::transmute(f)),
-            current_buffer: lookup(b"current_buffer\0").map(|f| std::mem::transmute(f)),
-            buffer_filename: lookup(b"buffer_filename\0").map(|f| std::mem::transmute(f)),
-            buffer_name: lookup(b"buffer_name\0").map(|f| std::mem::transmute(f)),
-            buffer_insert: lookup(b"buffer_insert\0").map(|f| std::mem::transmute(f)),
-            buffer_create: lookup(b"buffer_create\0").map(|f| std::mem::transmute(f)),
-            buffer_switch: lookup(b"buffer_switch\0").map(|f| std::mem::transmute(f)),
-            buffer_clear: lookup(b"buffer_clear\0").map(|f| std::mem::transmute(f)),
-            set_point: lookup(b"set_point\0").map(|f| std::mem::transmute(f)),
-            get_word_at_point: lookup(b"get_word_at_point\0").map(|f| std::mem::transmute(f)),
-            get_current_line: lookup(b"get_current_line\0").map(|f| std::mem::transmute(f)),
-            message: lookup(b"message\0").map(|f| std::mem::transmute(f)),
-            prompt: lookup(b"prompt\0").map(|f| std::mem::transmute(f)),
-            update_display: lookup(b"update_display\0").map(|f| std::mem::transmute(f)),
-            find_file_line: lookup(b"find_file_line\0").map(|f| std::mem::transmute(f)),
-            free: lookup(b"free\0").map(|f| std::mem::transmute(f)),
-            log_info: lookup(b"log_info\0").map(|f| std::mem::transmute(f)),
+            // api_version is the only field at a fixed offset in every
+            // editor, so check it before trusting the rest of the layout.
+            // Newer editors keep get_function working, so only older ones
+            // are refused; nothing is looked up yet, so log_error falls
+            // back to stderr.
+            let version = (*api_ptr).api_version;
+            if version < UEMACS_API_VERSION {
+                log_error(&format!(
+                    "rust_re2: editor API version {} is older than the {} this extension was built for",
+                    version, UEMACS_API_VERSION
+                ));
+                return -1;
+            }
+            match (*api_ptr).get_function {
+                Some(f) => f,
+                None => {
+                    eprintln!("rust_re2: Requires μEmacs with get_function() support");
+                    return -1;
+                }
+            }
         };
 
-        // Verify critical functions
-        if api.register_command.is_none() {
-            eprintln!("rust_re2: Failed to look up register_command");
-            return -1;
-        }
+        // Store get_function for later lookups
+        GET_FUNCTION.store(get_fn as *mut (), Ordering::SeqCst);
 
-        // Store API
-        *API.lock().unwrap() = Some(api);
-    }
+        // Look up all API functions by name
+        unsafe {
+            let api = Api {
+                on: lookup(b"on\0").map(|f| std::mem::transmute(f)),
+                off: lookup(b"off\0").map(|f| std::mem::transmute(f)),
+                emit: lookup(b"emit\0").map(|f| std::mem::transmute(f)),
+                config_int: lookup(b"config_int\0").map(|f| std::mem::transmute(f)),
+                config_bool: lookup(b"config_bool\0").map(|f| std::mem::transmute(f)),
+                config_string: lookup(b"config_string\0").map(|f| std::mem::transmute(f)),
+                register_command: lookup(b"register_command\0").map(|f| std::mem::transmute(f)),
+                unregister_command: lookup(b"unregister_command\0").map(|f| std::mem::transmute(f)),
+                current_buffer: lookup(b"current_buffer\0").map(|f| std::mem::transmute(f)),
+                buffer_filename: lookup(b"buffer_filename\0").map(|f| std::mem::transmute(f)),
+                buffer_name: lookup(b"buffer_name\0").map(|f| std::mem::transmute(f)),
+                buffer_insert: lookup(b"buffer_insert\0").map(|f| std::mem::transmute(f)),
+                buffer_create: lookup(b"buffer_create\0").map(|f| std::mem::transmute(f)),
+                buffer_switch: lookup(b"buffer_switch\0").map(|f| std::mem::transmute(f)),
+                buffer_clear: lookup(b"buffer_clear\0").map(|f| std::mem::transmute(f)),
+                buffer_contents: lookup(b"buffer_contents\0").map(|f| std::mem::transmute(f)),
+                buffer_modified: lookup(b"buffer_modified\0").map(|f| std::mem::transmute(f)),
+                buffer_set_unmodified: lookup(b"buffer_set_unmodified\0")
+                    .map(|f| std::mem::transmute(f)),
+                find_buffer: lookup(b"find_buffer\0").map(|f| std::mem::transmute(f)),
+                buffer_first: lookup(b"buffer_first\0").map(|f| std::mem::transmute(f)),
+                buffer_next: lookup(b"buffer_next\0").map(|f| std::mem::transmute(f)),
+                get_point: lookup(b"get_point\0").map(|f| std::mem::transmute(f)),
+                get_mark: lookup(b"get_mark\0").map(|f| std::mem::transmute(f)),
+                set_point: lookup(b"set_point\0").map(|f| std::mem::transmute(f)),
+                get_word_at_point: lookup(b"get_word_at_point\0").map(|f| std::mem::transmute(f)),
+                get_current_line: lookup(b"get_current_line\0").map(|f| std::mem::transmute(f)),
+                get_line_at: lookup(b"get_line_at\0").map(|f| std::mem::transmute(f)),
+                message: lookup(b"message\0").map(|f| std::mem::transmute(f)),
+                prompt: lookup(b"prompt\0").map(|f| std::mem::transmute(f)),
+                prompt_yn: lookup(b"prompt_yn\0").map(|f| std::mem::transmute(f)),
+                // Already the lookup's own fn type, but cast like the rest
+                // so the entry keeps following UpdateDisplayFn
+                #[allow(clippy::useless_transmute)]
+                update_display: lookup(b"update_display\0").map(|f| std::mem::transmute(f)),
+                find_file_line: lookup(b"find_file_line\0").map(|f| std::mem::transmute(f)),
+                free: lookup(b"free\0").map(|f| std::mem::transmute(f)),
+                log_info: lookup(b"log_info\0").map(|f| std::mem::transmute(f)),
+                log_warn: lookup(b"log_warn\0").map(|f| std::mem::transmute(f)),
+                log_error: lookup(b"log_error\0").map(|f| std::mem::transmute(f)),
+                current_window: lookup(b"current_window\0").map(|f| std::mem::transmute(f)),
+                window_at_row: lookup(b"window_at_row\0").map(|f| std::mem::transmute(f)),
+                window_switch: lookup(b"window_switch\0").map(|f| std::mem::transmute(f)),
+                screen_to_buffer_pos: lookup(b"screen_to_buffer_pos\0").map(|f| std::mem::transmute(f)),
+                syntax_register_lexer: lookup(b"syntax_register_lexer\0").map(|f| std::mem::transmute(f)),
+                syntax_unregister_lexer: lookup(b"syntax_unregister_lexer\0").map(|f| std::mem::transmute(f)),
+            };
 
-    // Load config into search options
-    {
-        let mut guard = SEARCH_OPTIONS.lock().unwrap();
-        *guard = Some(load_config());
-    }
-
-    // Register commands
-    with_api(|api| unsafe {
-        if let Some(register) = api.register_command {
-            let cmd_search = CString::new("re2").unwrap();
-            let cmd_word = CString::new("re2-word").unwrap();
-            let cmd_case = CString::new("re2-case").unwrap();
-            let cmd_smart = CString::new("re2-smart").unwrap();
-            let cmd_word_boundary = CString::new("re2-word-boundary").unwrap();
-            let cmd_hidden = CString::new("re2-hidden").unwrap();
-            let cmd_gitignore = CString::new("re2-gitignore").unwrap();
-
-            register(cmd_search.as_ptr(), cmd_re2_search);
-            register(cmd_word.as_ptr(), cmd_re2_search_word);
-            register(cmd_case.as_ptr(), cmd_re2_toggle_case);
-            register(cmd_smart.as_ptr(), cmd_re2_toggle_smart);
-            register(cmd_word_boundary.as_ptr(), cmd_re2_toggle_word_boundary);
-            register(cmd_hidden.as_ptr(), cmd_re2_toggle_hidden);
-            register(cmd_gitignore.as_ptr(), cmd_re2_toggle_gitignore);
-        }
-
-        // Register key event handler
-        if let Some(on) = api.on {
-            on(
-                INPUT_KEY_EVENT.as_ptr() as *const c_char,
-                re2_key_event_handler,
-                std::ptr::null_mut(),
-                0,
-            );
+            // Verify critical functions
+            if api.register_command.is_none() {
+                eprintln!("rust_re2: Failed to look up register_command");
+                return -1;
+            }
+
+            // Store API
+            *lock(&API) = Some(api);
         }
 
-        // Log that we loaded
-        if let Some(log_info) = api.log_info {
-            let msg = CString::new("rust_re2: Loaded (v4.0, ABI-stable)").unwrap();
-            log_info(msg.as_ptr());
+        apply_config();
+        {
+            let loaded = state::load(&get_search_options());
+            let mut history = lock(&HISTORY);
+            for pattern in loaded.history.iter().rev() {
+                history.push(pattern);
+            }
+            *lock(&SAVED_STATE) = Some(loaded);
         }
-    });
 
-    0 // Success
+        // Register commands
+        with_api(|api| unsafe {
+            if let Some(register) = api.register_command {
+                let cmd_search = CString::new("re2").unwrap();
+                let cmd_new = CString::new("re2-new").unwrap();
+                let cmd_results_list = CString::new("re2-results-list").unwrap();
+                let cmd_literal = CString::new("re2-literal").unwrap();
+                let cmd_any = CString::new("re2-search-any").unwrap();
+                let cmd_word = CString::new("re2-word").unwrap();
+                let cmd_whole_word = CString::new("re2-whole-word").unwrap();
+                let cmd_word_type = CString::new("re2-word-type").unwrap();
+                let cmd_def = CString::new("re2-def").unwrap();
+                let cmd_dir = CString::new("re2-dir").unwrap();
+                let cmd_tracked = CString::new("re2-tracked").unwrap();
+                let cmd_dirty = CString::new("re2-search-dirty").unwrap();
+                let cmd_changed = CString::new("re2-search-changed").unwrap();
+                let cmd_unrestricted = CString::new("re2-unrestricted").unwrap();
+                let cmd_invert = CString::new("re2-invert").unwrap();
+                let cmd_type = CString::new("re2-type").unwrap();
+                let cmd_buffer = CString::new("re2-buffer").unwrap();
+                let cmd_buffers = CString::new("re2-buffers").unwrap();
+                let cmd_region = CString::new("re2-region").unwrap();
+                let cmd_files = CString::new("re2-files").unwrap();
+                let cmd_count = CString::new("re2-count").unwrap();
+                let cmd_count_total = CString::new("re2-count-total").unwrap();
+                let cmd_todo = CString::new("re2-todo").unwrap();
+                let cmd_exists = CString::new("re2-exists").unwrap();
+                let cmd_export = CString::new("re2-export-results").unwrap();
+                let cmd_export_json = CString::new("re2-export-json").unwrap();
+                let cmd_multiline = CString::new("re2-multiline").unwrap();
+                let cmd_live = CString::new("re2-live").unwrap();
+                let cmd_preview = CString::new("re2-preview").unwrap();
+                let cmd_jump_back = CString::new("re2-jump-back").unwrap();
+                let cmd_next_match = CString::new("re2-next-match").unwrap();
+                let cmd_prev_match = CString::new("re2-prev-match").unwrap();
+                let cmd_history = CString::new("re2-history").unwrap();
+                let cmd_refine = CString::new("re2-refine").unwrap();
+                let cmd_refine_reset = CString::new("re2-refine-reset").unwrap();
+                let cmd_replace_preview = CString::new("re2-replace-preview").unwrap();
+                let cmd_replace_apply = CString::new("re2-replace-apply").unwrap();
+                let cmd_replace_interactive = CString::new("re2-replace-interactive").unwrap();
+                let cmd_save_search = CString::new("re2-save-search").unwrap();
+                let cmd_run_saved = CString::new("re2-run-saved").unwrap();
+                let cmd_again = CString::new("re2-again").unwrap();
+                let cmd_cancel = CString::new("re2-cancel").unwrap();
+                let cmd_case = CString::new("re2-case").unwrap();
+                let cmd_smart = CString::new("re2-smart").unwrap();
+                let cmd_word_boundary = CString::new("re2-word-boundary").unwrap();
+                let cmd_hidden = CString::new("re2-hidden").unwrap();
+                let cmd_gitignore = CString::new("re2-gitignore").unwrap();
+                let cmd_mmap = CString::new("re2-mmap").unwrap();
+                let cmd_grouping = CString::new("re2-grouping").unwrap();
+                let cmd_threads = CString::new("re2-threads").unwrap();
+                let cmd_max_filesize = CString::new("re2-max-filesize").unwrap();
+                let cmd_settings = CString::new("re2-settings").unwrap();
+                let cmd_reload_config = CString::new("re2-reload-config").unwrap();
+
+                register(cmd_search.as_ptr(), cmd_re2_search);
+                register(cmd_new.as_ptr(), cmd_re2_search_new);
+                register(cmd_results_list.as_ptr(), cmd_re2_results_list);
+                register(cmd_literal.as_ptr(), cmd_re2_search_literal);
+                register(cmd_any.as_ptr(), cmd_re2_search_any);
+                register(cmd_word.as_ptr(), cmd_re2_search_word);
+                register(cmd_whole_word.as_ptr(), cmd_re2_search_whole_word);
+                register(cmd_word_type.as_ptr(), cmd_re2_search_word_type);
+                register(cmd_def.as_ptr(), cmd_re2_def);
+                register(cmd_dir.as_ptr(), cmd_re2_search_dir);
+                register(cmd_tracked.as_ptr(), cmd_re2_search_tracked);
+                register(cmd_dirty.as_ptr(), cmd_re2_search_dirty);
+                register(cmd_changed.as_ptr(), cmd_re2_search_changed);
+                register(cmd_unrestricted.as_ptr(), cmd_re2_search_unrestricted);
+                register(cmd_invert.as_ptr(), cmd_re2_search_invert);
+                register(cmd_type.as_ptr(), cmd_re2_search_type);
+                register(cmd_buffer.as_ptr(), cmd_re2_search_buffer);
+                register(cmd_buffers.as_ptr(), cmd_re2_search_buffers);
+                register(cmd_region.as_ptr(), cmd_re2_search_region);
+                register(cmd_files.as_ptr(), cmd_re2_files);
+                register(cmd_count.as_ptr(), cmd_re2_count);
+                register(cmd_count_total.as_ptr(), cmd_re2_count_total);
+                register(cmd_todo.as_ptr(), cmd_re2_todo);
+                register(cmd_exists.as_ptr(), cmd_re2_exists);
+                register(cmd_export.as_ptr(), cmd_re2_export_results);
+                register(cmd_export_json.as_ptr(), cmd_re2_export_json);
+                register(cmd_multiline.as_ptr(), cmd_re2_search_multiline);
+                register(cmd_live.as_ptr(), cmd_re2_live);
+                register(cmd_preview.as_ptr(), cmd_re2_toggle_preview);
+                register(cmd_jump_back.as_ptr(), cmd_re2_jump_back);
+                register(cmd_next_match.as_ptr(), cmd_re2_next_match);
+                register(cmd_prev_match.as_ptr(), cmd_re2_prev_match);
+                register(cmd_history.as_ptr(), cmd_re2_history);
+                register(cmd_refine.as_ptr(), cmd_re2_refine);
+                register(cmd_refine_reset.as_ptr(), cmd_re2_refine_reset);
+                register(cmd_replace_preview.as_ptr(), cmd_re2_replace_preview);
+                register(cmd_replace_apply.as_ptr(), cmd_re2_replace_apply);
+                register(cmd_replace_interactive.as_ptr(), cmd_re2_replace_interactive);
+                register(cmd_save_search.as_ptr(), cmd_re2_save_search);
+                register(cmd_run_saved.as_ptr(), cmd_re2_run_saved);
+                register(cmd_again.as_ptr(), cmd_re2_again);
+                register(cmd_cancel.as_ptr(), cmd_re2_cancel);
+                register(cmd_case.as_ptr(), cmd_re2_toggle_case);
+                register(cmd_smart.as_ptr(), cmd_re2_toggle_smart);
+                register(cmd_word_boundary.as_ptr(), cmd_re2_toggle_word_boundary);
+                register(cmd_hidden.as_ptr(), cmd_re2_toggle_hidden);
+                register(cmd_gitignore.as_ptr(), cmd_re2_toggle_gitignore);
+                register(cmd_mmap.as_ptr(), cmd_re2_toggle_mmap);
+                register(cmd_grouping.as_ptr(), cmd_re2_toggle_grouping);
+                register(cmd_threads.as_ptr(), cmd_re2_set_threads);
+                register(cmd_max_filesize.as_ptr(), cmd_re2_set_max_filesize);
+                register(cmd_settings.as_ptr(), cmd_re2_settings);
+                register(cmd_reload_config.as_ptr(), cmd_re2_reload_config);
+            }
+
+            // Register key event handler
+            if let Some(on) = api.on {
+                on(
+                    INPUT_KEY_EVENT.as_ptr() as *const c_char,
+                    re2_key_event_handler,
+                    std::ptr::null_mut(),
+                    0,
+                );
+                on(
+                    BUFFER_SWITCH_EVENT.as_ptr() as *const c_char,
+                    re2_buffer_switch_handler,
+                    std::ptr::null_mut(),
+                    0,
+                );
+                on(
+                    INPUT_MOUSE_EVENT.as_ptr() as *const c_char,
+                    re2_mouse_event_handler,
+                    std::ptr::null_mut(),
+                    0,
+                );
+                on(
+                    SEARCH_REQUEST_EVENT.as_ptr() as *const c_char,
+                    re2_search_request_handler,
+                    std::ptr::null_mut(),
+                    0,
+                );
+            }
+
+            // Color the results buffer when the editor has a syntax API
+            let add_token = lookup(b"syntax_add_token\0");
+            let glob = format!("*{}*", results_base().trim_matches('*'));
+            if let (Some(register), Some(add_token), Ok(glob)) =
+                (api.syntax_register_lexer, add_token, CString::new(glob))
+            {
+                let mut patterns = lock(&RESULTS_LEXER_PATTERNS);
+                let patterns = patterns.insert(LexerPatterns::new(glob));
+                let registered = register(
+                    RESULTS_LEXER.as_ptr() as *const c_char,
+                    patterns.ptrs.as_ptr(),
+                    re2_results_lexer,
+                    std::ptr::null_mut(),
+                );
+                if registered >= 0 {
+                    SYNTAX_ADD_TOKEN.store(add_token as *mut (), Ordering::SeqCst);
+                }
+            }
+
+            // Log that we loaded
+            if let Some(log_info) = api.log_info {
+                let msg = CString::new("rust_re2: Loaded (v4.0, ABI-stable)").unwrap();
+                log_info(PRINTF_STR.as_ptr() as *const c_char, msg.as_ptr());
+            }
+        });
+
+        0 // Success
+    })
 }
 
 /// Cleanup the extension
 extern "C" fn re2_cleanup() {
-    with_api(|api| unsafe {
-        // Unregister key event handler
-        if let Some(off) = api.off {
-            off(
-                INPUT_KEY_EVENT.as_ptr() as *const c_char,
-                re2_key_event_handler,
-            );
-        }
+    guarded("re2_cleanup", (), || {
+        // A detached search must be gone before the library is unmapped
+        stop_search_thread();
+
+        with_api(|api| unsafe {
+            // Unregister key event handler
+            if let Some(off) = api.off {
+                off(
+                    INPUT_KEY_EVENT.as_ptr() as *const c_char,
+                    re2_key_event_handler,
+                );
+                off(
+                    BUFFER_SWITCH_EVENT.as_ptr() as *const c_char,
+                    re2_buffer_switch_handler,
+                );
+                off(
+                    INPUT_MOUSE_EVENT.as_ptr() as *const c_char,
+                    re2_mouse_event_handler,
+                );
+                off(
+                    SEARCH_REQUEST_EVENT.as_ptr() as *const c_char,
+                    re2_search_request_handler,
+                );
+            }
+
+            if let Some(unregister_lexer) = api.syntax_unregister_lexer {
+                if !SYNTAX_ADD_TOKEN.swap(std::ptr::null_mut(), Ordering::SeqCst).is_null() {
+                    unregister_lexer(RESULTS_LEXER.as_ptr() as *const c_char);
+                }
+            }
 
-        if let Some(unregister) = api.unregister_command {
-            let cmd_search = CString::new("re2").unwrap();
-            let cmd_word = CString::new("re2-word").unwrap();
-            let cmd_case = CString::new("re2-case").unwrap();
-            let cmd_smart = CString::new("re2-smart").unwrap();
-            let cmd_word_boundary = CString::new("re2-word-boundary").unwrap();
-            let cmd_hidden = CString::new("re2-hidden").unwrap();
-            let cmd_gitignore = CString::new("re2-gitignore").unwrap();
+            if let Some(unregister) = api.unregister_command {
+                let cmd_search = CString::new("re2").unwrap();
+                let cmd_new = CString::new("re2-new").unwrap();
+                let cmd_results_list = CString::new("re2-results-list").unwrap();
+                let cmd_literal = CString::new("re2-literal").unwrap();
+                let cmd_any = CString::new("re2-search-any").unwrap();
+                let cmd_word = CString::new("re2-word").unwrap();
+                let cmd_whole_word = CString::new("re2-whole-word").unwrap();
+                let cmd_word_type = CString::new("re2-word-type").unwrap();
+                let cmd_def = CString::new("re2-def").unwrap();
+                let cmd_dir = CString::new("re2-dir").unwrap();
+                let cmd_tracked = CString::new("re2-tracked").unwrap();
+                let cmd_dirty = CString::new("re2-search-dirty").unwrap();
+                let cmd_changed = CString::new("re2-search-changed").unwrap();
+                let cmd_unrestricted = CString::new("re2-unrestricted").unwrap();
+                let cmd_invert = CString::new("re2-invert").unwrap();
+                let cmd_type = CString::new("re2-type").unwrap();
+                let cmd_buffer = CString::new("re2-buffer").unwrap();
+                let cmd_buffers = CString::new("re2-buffers").unwrap();
+                let cmd_region = CString::new("re2-region").unwrap();
+                let cmd_files = CString::new("re2-files").unwrap();
+                let cmd_count = CString::new("re2-count").unwrap();
+                let cmd_count_total = CString::new("re2-count-total").unwrap();
+                let cmd_todo = CString::new("re2-todo").unwrap();
+                let cmd_exists = CString::new("re2-exists").unwrap();
+                let cmd_export = CString::new("re2-export-results").unwrap();
+                let cmd_export_json = CString::new("re2-export-json").unwrap();
+                let cmd_multiline = CString::new("re2-multiline").unwrap();
+                let cmd_live = CString::new("re2-live").unwrap();
+                let cmd_preview = CString::new("re2-preview").unwrap();
+                let cmd_jump_back = CString::new("re2-jump-back").unwrap();
+                let cmd_next_match = CString::new("re2-next-match").unwrap();
+                let cmd_prev_match = CString::new("re2-prev-match").unwrap();
+                let cmd_history = CString::new("re2-history").unwrap();
+                let cmd_refine = CString::new("re2-refine").unwrap();
+                let cmd_refine_reset = CString::new("re2-refine-reset").unwrap();
+                let cmd_replace_preview = CString::new("re2-replace-preview").unwrap();
+                let cmd_replace_apply = CString::new("re2-replace-apply").unwrap();
+                let cmd_replace_interactive = CString::new("re2-replace-interactive").unwrap();
+                let cmd_save_search = CString::new("re2-save-search").unwrap();
+                let cmd_run_saved = CString::new("re2-run-saved").unwrap();
+                let cmd_again = CString::new("re2-again").unwrap();
+                let cmd_cancel = CString::new("re2-cancel").unwrap();
+                let cmd_case = CString::new("re2-case").unwrap();
+                let cmd_smart = CString::new("re2-smart").unwrap();
+                let cmd_word_boundary = CString::new("re2-word-boundary").unwrap();
+                let cmd_hidden = CString::new("re2-hidden").unwrap();
+                let cmd_gitignore = CString::new("re2-gitignore").unwrap();
+                let cmd_mmap = CString::new("re2-mmap").unwrap();
+                let cmd_grouping = CString::new("re2-grouping").unwrap();
+                let cmd_threads = CString::new("re2-threads").unwrap();
+                let cmd_max_filesize = CString::new("re2-max-filesize").unwrap();
+                let cmd_settings = CString::new("re2-settings").unwrap();
+                let cmd_reload_config = CString::new("re2-reload-config").unwrap();
 
-            unregister(cmd_search.as_ptr());
-            unregister(cmd_word.as_ptr());
-            unregister(cmd_case.as_ptr());
-            unregister(cmd_smart.as_ptr());
-            unregister(cmd_word_boundary.as_ptr());
-            unregister(cmd_hidden.as_ptr());
-            unregister(cmd_gitignore.as_ptr());
-        }
-    });
+                unregister(cmd_search.as_ptr());
+                unregister(cmd_new.as_ptr());
+                unregister(cmd_results_list.as_ptr());
+                unregister(cmd_literal.as_ptr());
+                unregister(cmd_any.as_ptr());
+                unregister(cmd_word.as_ptr());
+                unregister(cmd_whole_word.as_ptr());
+                unregister(cmd_word_type.as_ptr());
+                unregister(cmd_def.as_ptr());
+                unregister(cmd_dir.as_ptr());
+                unregister(cmd_tracked.as_ptr());
+                unregister(cmd_dirty.as_ptr());
+                unregister(cmd_changed.as_ptr());
+                unregister(cmd_unrestricted.as_ptr());
+                unregister(cmd_invert.as_ptr());
+                unregister(cmd_type.as_ptr());
+                unregister(cmd_buffer.as_ptr());
+                unregister(cmd_buffers.as_ptr());
+                unregister(cmd_region.as_ptr());
+                unregister(cmd_files.as_ptr());
+                unregister(cmd_count.as_ptr());
+                unregister(cmd_count_total.as_ptr());
+                unregister(cmd_todo.as_ptr());
+                unregister(cmd_exists.as_ptr());
+                unregister(cmd_export.as_ptr());
+                unregister(cmd_export_json.as_ptr());
+                unregister(cmd_multiline.as_ptr());
+                unregister(cmd_live.as_ptr());
+                unregister(cmd_preview.as_ptr());
+                unregister(cmd_jump_back.as_ptr());
+                unregister(cmd_next_match.as_ptr());
+                unregister(cmd_prev_match.as_ptr());
+                unregister(cmd_history.as_ptr());
+                unregister(cmd_refine.as_ptr());
+                unregister(cmd_refine_reset.as_ptr());
+                unregister(cmd_replace_preview.as_ptr());
+                unregister(cmd_replace_apply.as_ptr());
+                unregister(cmd_replace_interactive.as_ptr());
+                unregister(cmd_save_search.as_ptr());
+                unregister(cmd_run_saved.as_ptr());
+                unregister(cmd_again.as_ptr());
+                unregister(cmd_cancel.as_ptr());
+                unregister(cmd_case.as_ptr());
+                unregister(cmd_smart.as_ptr());
+                unregister(cmd_word_boundary.as_ptr());
+                unregister(cmd_hidden.as_ptr());
+                unregister(cmd_gitignore.as_ptr());
+                unregister(cmd_mmap.as_ptr());
+                unregister(cmd_grouping.as_ptr());
+                unregister(cmd_threads.as_ptr());
+                unregister(cmd_max_filesize.as_ptr());
+                unregister(cmd_settings.as_ptr());
+                unregister(cmd_reload_config.as_ptr());
+            }
+        });
+    })
 }
 
 /// Execute a closure with the API, if available
@@ -276,11 +1019,38 @@ fn with_api<F, R>(f: F) -> Option<R>
 where
     F: FnOnce(&Api) -> R,
 {
-    let guard = API.lock().ok()?;
+    let guard = lock(&API);
     let api = guard.as_ref()?;
     Some(f(api))
 }
 
+/// Lock a global, recovering the data if an earlier panic poisoned it
+///
+/// Panics are caught at the FFI boundary, so a poisoned lock only means
+/// one callback failed part way; the data is still usable.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Run the body of an `extern "C"` callback, turning a panic into `fallback`
+///
+/// Unwinding across the FFI boundary into the editor is undefined
+/// behavior, so every callback body runs through here.
+fn guarded<R>(name: &str, fallback: R, body: impl FnOnce() -> R) -> R {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)) {
+        Ok(r) => r,
+        Err(payload) => {
+            let reason = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            log_error(&format!("rust_re2: panic in {}: {}", name, reason));
+            fallback
+        }
+    }
+}
+
 /// Read a boolean config value
 fn config_bool(key: &str, default: bool) -> bool {
     with_api(|api| unsafe {
@@ -353,43 +1123,191 @@ fn load_config() -> SearchOptions {
         case_insensitive: config_bool("case_insensitive", false),
         smart_case: config_bool("smart_case", true),
         word_boundary: config_bool("word_boundary", false),
-        context_before: config_int("context_before", 0) as usize,
-        context_after: config_int("context_after", 0) as usize,
+        // `context` sets both sides; the -B/-A style keys override it
+        context_before: config_int("context_before", config_int("context", 0)).max(0) as usize,
+        context_after: config_int("context_after", config_int("context", 0)).max(0) as usize,
         invert_match: false,
         hidden: config_bool("hidden", false),
         follow_symlinks: config_bool("follow_symlinks", false),
         git_ignore: config_bool("git_ignore", true),
+        no_ignore: config_bool("no_ignore", false),
+        git_tracked_only: config_bool("git_tracked_only", false),
+        path_list: None,
         max_depth: {
             let d = config_int("max_depth", 0);
             if d > 0 { Some(d as usize) } else { None }
         },
-        threads: config_int("threads", 0) as usize,
+        threads: {
+            let (threads, clamped) = search::clamp_threads(config_int("threads", 0).max(0) as usize);
+            if clamped {
+                log_warn(&format!("rust_re2: threads clamped to {}", threads));
+            }
+            threads
+        },
         file_types: parse_csv(&config_string("file_types", "")),
-        glob_include: parse_csv(&config_string("glob_include", "")),
-        glob_exclude: parse_csv(&config_string("glob_exclude", "")),
-        max_filesize: {
-            let s = config_int("max_filesize", 0);
-            if s > 0 { Some(s as u64) } else { None }
+        globs: search::ordered_globs(
+            &parse_csv(&config_string("glob_include", "")),
+            // `excludes` is accepted as another name for it
+            &parse_csv(&config_string("glob_exclude", &config_string("excludes", ""))),
+        ),
+        max_filesize: match search::parse_size(&config_string("max_filesize", "0")) {
+            Ok(size) => size,
+            Err(e) => {
+                log_warn(&format!("rust_re2: max_filesize: {}", e));
+                None
+            }
         },
         mmap: config_bool("mmap", true),
+        search_compressed: config_bool("search_compressed", false),
+        lossy_utf8: config_bool("lossy_utf8", true),
+        encoding: search::parse_encoding(&config_string("encoding", "auto")).unwrap_or(None),
         fixed_strings: config_bool("fixed_strings", false),
+        pcre2: config_bool("pcre2", false),
         multiline: config_bool("multiline", false),
+        multiline_dotall: config_bool("multiline_dotall", false),
+        group_by_file: config_bool("group_by_file", false),
+        format_template: {
+            let template = config_string("format_template", search::DEFAULT_TEMPLATE);
+            match search::Template::parse(&template) {
+                Ok(_) => template,
+                Err(e) => {
+                    log_warn(&format!("{} - using {}", e, search::DEFAULT_TEMPLATE));
+                    search::DEFAULT_TEMPLATE.to_string()
+                }
+            }
+        },
+        sort: SortOrder::parse(&config_string("sort", "path")).unwrap_or(SortOrder::Path),
         max_count: {
             let c = config_int("max_count", 0);
             if c > 0 { Some(c as u64) } else { None }
         },
+        max_results: {
+            // max_total_matches is the older name for the same cap
+            let fallback = config_int("max_total_matches", search::DEFAULT_MAX_RESULTS as i32);
+            let c = config_int("max_results", fallback);
+            if c > 0 { Some(c as usize) } else { None }
+        },
+        count_only: false,
+        max_line_len: {
+            let n = config_int("max_line_len", search::DEFAULT_MAX_LINE_LEN as i32);
+            if n > 0 { Some(n as usize) } else { None }
+        },
+        timeout_ms: {
+            let ms = config_int("timeout_ms", 0);
+            if ms > 0 { Some(ms as u64) } else { None }
+        },
+    }
+}
+
+/// Read the config into the search options and the other settings
+///
+/// Runs at init and for re2-reload-config; missing keys keep their
+/// built-in defaults.
+fn apply_config() {
+    *lock(&SEARCH_OPTIONS) = Some(load_config());
+    PREVIEW_MODE.store(config_bool("preview", false), Ordering::SeqCst);
+    AUTO_PREVIEW.store(config_bool("auto_preview", false), Ordering::SeqCst);
+    *lock(&RESULTS_BASE) = config_string("results_buffer", DEFAULT_RESULTS_BUFFER);
+    let history_size = config_int("history_size", history::DEFAULT_CAPACITY as i32);
+    lock(&HISTORY).set_capacity(history_size.max(0) as usize);
+}
+
+/// re2-settings listing: `key = value` per config key, with what it does
+fn format_settings(opts: &SearchOptions) -> String {
+    let list = |v: &[String]| format!("\"{}\"", v.join(","));
+    let limit = |n: Option<usize>| n.map_or("0".to_string(), |n| n.to_string());
+    let settings = [
+        ("case_insensitive", opts.case_insensitive.to_string(), "Always ignore case"),
+        ("smart_case", opts.smart_case.to_string(), "Ignore case unless the pattern has uppercase"),
+        ("word_boundary", opts.word_boundary.to_string(), "Match whole words only"),
+        (
+            "context",
+            if opts.context_before == opts.context_after {
+                opts.context_before.to_string()
+            } else {
+                "-".to_string()
+            },
+            "Lines shown around each match (sets both below)",
+        ),
+        ("context_before", opts.context_before.to_string(), "Lines shown before each match"),
+        ("context_after", opts.context_after.to_string(), "Lines shown after each match"),
+        ("hidden", opts.hidden.to_string(), "Search hidden files and directories"),
+        ("follow_symlinks", opts.follow_symlinks.to_string(), "Follow symbolic links"),
+        ("git_ignore", opts.git_ignore.to_string(), "Respect .gitignore"),
+        ("no_ignore", opts.no_ignore.to_string(), "Disregard every ignore file"),
+        ("git_tracked_only", opts.git_tracked_only.to_string(), "Search only files git tracks"),
+        ("max_depth", limit(opts.max_depth), "Directory depth limit (0 = unlimited)"),
+        ("threads", opts.threads.to_string(), "Search threads (0 = auto-detect)"),
+        ("file_types", list(&opts.file_types), "File types searched"),
+        ("glob_include", list(&search::globs_of(&opts.globs, true)), "Files to include"),
+        (
+            "glob_exclude",
+            list(&search::globs_of(&opts.globs, false)),
+            "Files to exclude (or key excludes)",
+        ),
+        (
+            "max_filesize",
+            opts.max_filesize.map_or("0".to_string(), search::format_size),
+            "Skip larger files (0 = no limit)",
+        ),
+        (
+            "max_count",
+            opts.max_count.map_or("0".to_string(), |n| n.to_string()),
+            "Matches per file (0 = unlimited)",
+        ),
+        ("max_results", limit(opts.max_results), "Matches per search (0 = unlimited)"),
+        ("max_line_len", limit(opts.max_line_len), "Characters shown per result line"),
+        (
+            "timeout_ms",
+            opts.timeout_ms.map_or("0".to_string(), |ms| ms.to_string()),
+            "Stop searches after this long (0 = no limit)",
+        ),
+        ("mmap", opts.mmap.to_string(), "Memory-map large files"),
+        ("search_compressed", opts.search_compressed.to_string(), "Search compressed files"),
+        ("lossy_utf8", opts.lossy_utf8.to_string(), "Search files that aren't valid UTF-8"),
+        (
+            "encoding",
+            opts.encoding.map_or("auto", |e| e.name()).to_string(),
+            "Encoding of files without a BOM",
+        ),
+        ("fixed_strings", opts.fixed_strings.to_string(), "Take patterns literally"),
+        ("pcre2", opts.pcre2.to_string(), "Use PCRE2"),
+        ("multiline", opts.multiline.to_string(), "Let matches span lines"),
+        ("multiline_dotall", opts.multiline_dotall.to_string(), "Let . match newlines in multiline"),
+        ("group_by_file", opts.group_by_file.to_string(), "Heading per file in results"),
+        (
+            "format_template",
+            format!("\"{}\"", opts.format_template),
+            "Flat result line: {path} {line} {col} {text}",
+        ),
+        (
+            "sort",
+            match opts.sort {
+                SortOrder::Path => "path",
+                SortOrder::Modified => "modified",
+            }
+            .to_string(),
+            "Result order",
+        ),
+    ];
+
+    let mut out =
+        String::from("[extension.rust_re2] - current values; re2-reload-config rereads the config\n\n");
+    for (key, value, about) in settings {
+        out.push_str(&format!("{:<18} = {:<12} # {}\n", key, value, about));
     }
+    out
 }
 
 /// Get current search options (thread-safe)
 fn get_search_options() -> SearchOptions {
-    let guard = SEARCH_OPTIONS.lock().unwrap();
+    let guard = lock(&SEARCH_OPTIONS);
     guard.clone().unwrap_or_default()
 }
 
 /// Update search options (thread-safe)
 fn update_search_options<F: FnOnce(&mut SearchOptions)>(f: F) {
-    let mut guard = SEARCH_OPTIONS.lock().unwrap();
+    let mut guard = lock(&SEARCH_OPTIONS);
     if let Some(ref mut opts) = *guard {
         f(opts);
     }
@@ -400,12 +1318,59 @@ fn message(msg: &str) {
     with_api(|api| unsafe {
         if let Some(message_fn) = api.message {
             if let Ok(cmsg) = CString::new(msg) {
-                message_fn(cmsg.as_ptr());
+                message_fn(PRINTF_STR.as_ptr() as *const c_char, cmsg.as_ptr());
+            }
+        }
+    });
+}
+
+/// Write a warning to the editor log
+fn log_warn(msg: &str) {
+    with_api(|api| unsafe {
+        if let Some(log_fn) = api.log_warn {
+            if let Ok(cmsg) = CString::new(msg) {
+                log_fn(PRINTF_STR.as_ptr() as *const c_char, cmsg.as_ptr());
             }
         }
     });
 }
 
+/// Publish an event on the editor's event bus
+///
+/// The API lock is released before emitting so handlers may call back in.
+fn emit<T>(event: &[u8], payload: &mut T) -> bool {
+    let Some(emit_fn) = with_api(|api| api.emit).flatten() else {
+        return false;
+    };
+    unsafe { emit_fn(event.as_ptr() as *const c_char, payload as *mut T as *mut c_void) }
+}
+
+/// True (and `last` reset) if at least `interval_ms` passed since `last`
+fn throttle_due(last: &mut Option<std::time::Instant>, interval_ms: u128) -> bool {
+    let now = std::time::Instant::now();
+    let due = last
+        .map(|t| now.duration_since(t).as_millis() >= interval_ms)
+        .unwrap_or(true);
+    if due {
+        *last = Some(now);
+    }
+    due
+}
+
+/// Log an error to the editor log, or stderr if the API is unavailable
+fn log_error(msg: &str) {
+    let logged = with_api(|api| unsafe {
+        let log_fn = api.log_error?;
+        let cmsg = CString::new(msg).ok()?;
+        log_fn(PRINTF_STR.as_ptr() as *const c_char, cmsg.as_ptr());
+        Some(())
+    })
+    .flatten();
+    if logged.is_none() {
+        eprintln!("{}", msg);
+    }
+}
+
 /// Prompt user for input
 fn prompt(prompt_text: &str) -> Option<String> {
     with_api(|api| unsafe {
@@ -422,6 +1387,77 @@ fn prompt(prompt_text: &str) -> Option<String> {
     })?
 }
 
+/// Ask a yes/no question; false if the editor can't ask
+fn prompt_yn(question: &str) -> bool {
+    with_api(|api| unsafe {
+        let prompt_yn_fn = api.prompt_yn?;
+        let cquestion = CString::new(question).ok()?;
+        Some(prompt_yn_fn(cquestion.as_ptr()) != 0)
+    })
+    .flatten()
+    .unwrap_or(false)
+}
+
+/// Attempts allowed to fix an invalid pattern before giving up
+const MAX_PATTERN_ATTEMPTS: usize = 3;
+
+/// Prompt for a search pattern, re-prompting while it fails to compile
+///
+/// The answer may start with ripgrep-style flags (e.g. `--sort=modified`)
+/// which are applied on top of `base`. Reports "Cancelled" itself; returns
+/// None when the user aborts, enters nothing, or runs out of attempts.
+fn prompt_search(prompt_text: &str, base: SearchOptions) -> Option<(String, SearchOptions)> {
+    prompt_search_with(prompt_text, base, |pattern, _| Ok(pattern))
+}
+
+/// prompt_search, with `build` turning the text after any flags into the
+/// pattern to search for (and adjusting the options to suit)
+fn prompt_search_with(
+    prompt_text: &str,
+    base: SearchOptions,
+    build: impl Fn(String, &mut SearchOptions) -> Result<String, String>,
+) -> Option<(String, SearchOptions)> {
+    // The prompt API can't pre-fill, so offer the newest pattern as the
+    // answer to an empty reply instead
+    let default = lock(&HISTORY).latest().map(str::to_string);
+    let prompt_text = match (&default, prompt_text.strip_suffix(": ")) {
+        (Some(d), Some(head)) => format!("{} [{}]: ", head, d),
+        _ => prompt_text.to_string(),
+    };
+    let mut text = prompt_text.clone();
+
+    for _ in 0..MAX_PATTERN_ATTEMPTS {
+        let input = match (prompt(&text), &default) {
+            (Some(p), _) if !p.is_empty() => p,
+            (Some(_), Some(d)) => d.clone(),
+            _ => {
+                message("Cancelled");
+                return None;
+            }
+        };
+
+        let mut opts = base.clone();
+        let checked = search::apply_flags(&input, &mut opts).and_then(|pattern| {
+            if pattern.is_empty() {
+                return Err("No pattern given".to_string());
+            }
+            let pattern = build(pattern, &mut opts)?;
+            search::validate_pattern(&pattern, &opts).map(|()| pattern)
+        });
+
+        match checked {
+            Ok(pattern) => return Some((pattern, opts)),
+            Err(e) => {
+                message(&e);
+                text = format!("{} - {}", e, prompt_text);
+            }
+        }
+    }
+
+    message("Too many invalid patterns - giving up");
+    None
+}
+
 /// Get word at cursor
 fn get_word_at_point() -> Option<String> {
     with_api(|api| unsafe {
@@ -460,6 +1496,24 @@ fn get_current_line() -> Option<String> {
     })?
 }
 
+/// Get the text of a line (1-indexed) in a buffer
+fn get_line_at(bp: *mut c_void, line: i32) -> Option<String> {
+    with_api(|api| unsafe {
+        let get_line_fn = api.get_line_at?;
+        let ptr = get_line_fn(bp, line);
+        if ptr.is_null() {
+            return None;
+        }
+        let result = CStr::from_ptr(ptr).to_string_lossy().to_string();
+
+        if let Some(free_fn) = api.free {
+            free_fn(ptr as *mut _);
+        }
+
+        Some(result)
+    })?
+}
+
 /// Create or get a buffer by name
 fn get_or_create_buffer(name: &str) -> Option<*mut c_void> {
     with_api(|api| unsafe {
@@ -531,273 +1585,4303 @@ fn update_display() {
     });
 }
 
-/// Move cursor to a specific line (1-indexed)
+/// Move cursor to the start of a line (1-indexed)
 fn goto_line(line: i32) {
+    set_point(line, 0);
+}
+
+/// Move cursor to a byte column (0-based) of a line (1-indexed)
+///
+/// The editor's set_point counts columns from 1 (as c_mouse does when it
+/// places a click); columns here count from 0 like the matcher's offsets,
+/// so the shift happens once, here and in get_point.
+fn set_point(line: i32, column: usize) {
     with_api(|api| unsafe {
         if let Some(set_point_fn) = api.set_point {
-            set_point_fn(line, 0);
+            set_point_fn(line, column as c_int + 1);
         }
     });
 }
 
-/// Get the directory of the current buffer's file
-fn get_buffer_directory() -> Option<String> {
+/// Get the focused window
+fn current_window() -> Option<*mut c_void> {
     with_api(|api| unsafe {
-        let current_buf_fn = api.current_buffer?;
-        let current_buf = current_buf_fn();
-        if current_buf.is_null() {
-            return None;
-        }
-        let filename_fn = api.buffer_filename?;
-        let filename_ptr = filename_fn(current_buf);
-        if filename_ptr.is_null() {
-            return None;
-        }
-        let filename = CStr::from_ptr(filename_ptr).to_string_lossy().to_string();
-        if filename.is_empty() {
-            return None;
-        }
-        if let Some(pos) = filename.rfind('/') {
-            Some(filename[..pos].to_string())
-        } else {
+        let current_fn = api.current_window?;
+        let wp = current_fn();
+        if wp.is_null() {
             None
+        } else {
+            Some(wp)
         }
-    })?
+    })
+    .flatten()
 }
 
-/// Get the current buffer's name
-fn get_buffer_name() -> Option<String> {
+/// Find a window other than `current` by probing screen rows
+fn other_window(current: *mut c_void) -> Option<*mut c_void> {
     with_api(|api| unsafe {
-        let current_buf_fn = api.current_buffer?;
-        let current_buf = current_buf_fn();
-        if current_buf.is_null() {
-            return None;
-        }
-        let name_fn = api.buffer_name?;
-        let name_ptr = name_fn(current_buf);
-        if name_ptr.is_null() {
-            return None;
-        }
-        Some(CStr::from_ptr(name_ptr).to_string_lossy().to_string())
-    })?
+        let at_row_fn = api.window_at_row?;
+        (0..MAX_WINDOW_ROWS)
+            .map(|row| at_row_fn(row))
+            .find(|wp| !wp.is_null() && *wp != current)
+    })
+    .flatten()
 }
 
-/// Check if we're in the results buffer
-fn in_results_buffer() -> bool {
-    get_buffer_name()
-        .map(|name| name == RE2_RESULTS_BUFFER)
-        .unwrap_or(false)
+/// Give focus to a window; false if the editor lacks window_switch
+fn switch_to_window(wp: *mut c_void) -> bool {
+    with_api(|api| unsafe {
+        let switch_fn = api.window_switch?;
+        switch_fn(wp);
+        Some(())
+    })
+    .flatten()
+    .is_some()
 }
 
-/// Perform the search and display results
-fn do_search(pattern: &str) -> bool {
-    {
-        let mut guard = LAST_PATTERN.lock().unwrap();
-        *guard = Some(pattern.to_string());
+/// Buffer position (line, byte offset) under a screen cell of a window
+fn screen_to_buffer_pos(wp: *mut c_void, row: i32, col: i32) -> Option<(i32, i32)> {
+    with_api(|api| unsafe {
+        let pos_fn = api.screen_to_buffer_pos?;
+        let (mut line, mut offset): (c_int, c_int) = (0, 0);
+        (pos_fn(wp, row, col, &mut line, &mut offset) == 0).then_some((line, offset))
+    })?
+}
+
+/// Window showing a screen row
+fn window_at_row(row: i32) -> Option<*mut c_void> {
+    with_api(|api| unsafe {
+        let wp = api.window_at_row?(row);
+        (!wp.is_null()).then_some(wp)
+    })?
+}
+
+/// Get the cursor position as (line, 0-based column)
+fn get_point() -> Option<(i32, i32)> {
+    with_api(|api| unsafe {
+        let get_point_fn = api.get_point?;
+        let (mut line, mut col): (c_int, c_int) = (0, 0);
+        get_point_fn(&mut line, &mut col);
+        Some((line, (col - 1).max(0)))
+    })?
+}
+
+/// Get the mark position as (line, 0-based column), if a mark is set
+fn get_mark() -> Option<(i32, i32)> {
+    with_api(|api| unsafe {
+        let get_mark_fn = api.get_mark?;
+        let (mut line, mut col): (c_int, c_int) = (0, 0);
+        get_mark_fn(&mut line, &mut col);
+        // Editor reports line 0 when no mark is active
+        if line > 0 {
+            Some((line, (col - 1).max(0)))
+        } else {
+            None
+        }
+    })?
+}
+
+/// Get the current buffer's file name
+fn get_buffer_filename() -> Option<String> {
+    buffer_filename_of(current_buffer()?)
+}
+
+/// Get the file a buffer visits
+fn buffer_filename_of(bp: *mut c_void) -> Option<String> {
+    with_api(|api| unsafe {
+        let filename_fn = api.buffer_filename?;
+        let filename_ptr = filename_fn(bp);
+        if filename_ptr.is_null() {
+            return None;
+        }
+        let filename = CStr::from_ptr(filename_ptr).to_string_lossy().to_string();
+        if filename.is_empty() {
+            None
+        } else {
+            Some(filename)
+        }
+    })?
+}
+
+/// Get the directory of the current buffer's file
+fn get_buffer_directory() -> Option<String> {
+    let filename = get_buffer_filename()?;
+    filename.rfind('/').map(|pos| filename[..pos].to_string())
+}
+
+/// Map a file name's extension to a ripgrep type name
+fn file_type_for(filename: &str) -> Option<&'static str> {
+    let ext = std::path::Path::new(filename).extension()?.to_str()?;
+    EXTENSION_TYPES
+        .iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        .map(|(_, t)| *t)
+}
+
+/// Get the current buffer's name
+fn get_buffer_name() -> Option<String> {
+    with_api(|api| unsafe {
+        let current_buf_fn = api.current_buffer?;
+        let current_buf = current_buf_fn();
+        if current_buf.is_null() {
+            return None;
+        }
+        let name_fn = api.buffer_name?;
+        let name_ptr = name_fn(current_buf);
+        if name_ptr.is_null() {
+            return None;
+        }
+        Some(CStr::from_ptr(name_ptr).to_string_lossy().to_string())
+    })?
+}
+
+/// Get a buffer's contents (includes unsaved edits)
+fn get_buffer_contents(bp: *mut c_void) -> Option<Vec<u8>> {
+    with_api(|api| unsafe {
+        let contents_fn = api.buffer_contents?;
+        let mut len: usize = 0;
+        let ptr = contents_fn(bp, &mut len);
+        if ptr.is_null() {
+            return None;
+        }
+        // Copy by explicit length - contents may be large and are not
+        // guaranteed to be NUL-terminated
+        let bytes = std::slice::from_raw_parts(ptr as *const u8, len).to_vec();
+
+        if let Some(free_fn) = api.free {
+            free_fn(ptr as *mut _);
+        }
+
+        Some(bytes)
+    })?
+}
+
+/// Whether a buffer has unsaved changes; without the editor's
+/// buffer_modified every buffer is assumed to have some
+fn buffer_modified(bp: *mut c_void) -> bool {
+    with_api(|api| unsafe { api.buffer_modified.is_none_or(|modified_fn| modified_fn(bp)) })
+        .unwrap_or(true)
+}
+
+/// Mark a buffer as matching its file
+fn set_unmodified(bp: *mut c_void) {
+    with_api(|api| unsafe {
+        if let Some(unmodified_fn) = api.buffer_set_unmodified {
+            unmodified_fn(bp);
+        }
+    });
+}
+
+/// Get the current buffer pointer
+fn current_buffer() -> Option<*mut c_void> {
+    with_api(|api| unsafe {
+        let current_buf_fn = api.current_buffer?;
+        let bp = current_buf_fn();
+        if bp.is_null() {
+            None
+        } else {
+            Some(bp)
+        }
+    })?
+}
+
+/// Get a buffer's name
+fn buffer_name_of(bp: *mut c_void) -> Option<String> {
+    with_api(|api| unsafe {
+        let name_fn = api.buffer_name?;
+        let name_ptr = name_fn(bp);
+        if name_ptr.is_null() {
+            return None;
+        }
+        Some(CStr::from_ptr(name_ptr).to_string_lossy().to_string())
+    })?
+}
+
+/// Enumerate all open buffers
+fn list_buffers() -> Vec<*mut c_void> {
+    with_api(|api| unsafe {
+        let mut buffers = Vec::new();
+        let (Some(first_fn), Some(next_fn)) = (api.buffer_first, api.buffer_next) else {
+            return buffers;
+        };
+        let mut bp = first_fn();
+        while !bp.is_null() {
+            buffers.push(bp);
+            bp = next_fn(bp);
+        }
+        buffers
+    })
+    .unwrap_or_default()
+}
+
+/// Special buffers are named like *results*
+fn is_special_buffer(name: &str) -> bool {
+    name.len() >= 2 && name.starts_with('*') && name.ends_with('*')
+}
+
+/// Find an existing buffer by name
+fn find_named_buffer(name: &str) -> Option<*mut c_void> {
+    with_api(|api| unsafe {
+        let find_fn = api.find_buffer?;
+        let cname = CString::new(name).ok()?;
+        let bp = find_fn(cname.as_ptr());
+        if bp.is_null() {
+            None
+        } else {
+            Some(bp)
+        }
+    })
+    .flatten()
+}
+
+/// Find the buffer visiting a file, if one is open
+fn find_file_buffer(path: &str) -> Option<*mut c_void> {
+    let canonical = |p: &str| std::fs::canonicalize(p).unwrap_or_else(|_| p.into());
+    let wanted = canonical(path);
+    list_buffers()
+        .into_iter()
+        .find(|&bp| buffer_filename_of(bp).is_some_and(|f| canonical(&f) == wanted))
+}
+
+/// Switch to an existing buffer by name
+fn switch_to_named_buffer(name: &str) -> bool {
+    find_named_buffer(name).map(switch_to_buffer).unwrap_or(false)
+}
+
+/// Switch to an emptied results buffer, remembering where we came from
+fn open_results_buffer() -> Option<*mut c_void> {
+    let name = results_buffer();
+    let bp = get_or_create_buffer(&name)?;
+    forget_listing(&name);
+    if let Some(name) = get_buffer_name().filter(|n| !is_results_buffer(n)) {
+        remember_jump();
+        *lock(&ORIGIN_BUFFER) = Some(name);
+    }
+    switch_to_buffer(bp);
+    clear_buffer(bp);
+    Some(bp)
+}
+
+/// Leave a results buffer (q) for the buffer the search started from
+///
+/// Falls back to any other non-special buffer if that one is gone.
+/// Config `clear_on_quit` also empties the results buffer.
+fn close_results() -> bool {
+    let results_bp = current_buffer();
+    let results_name = get_buffer_name();
+    let origin = lock(&ORIGIN_BUFFER).clone();
+
+    if !origin.as_deref().is_some_and(switch_to_named_buffer) {
+        let fallback = list_buffers().into_iter().find_map(|bp| {
+            let name = buffer_name_of(bp)?;
+            (!is_special_buffer(&name)).then_some((bp, name))
+        });
+        let Some((bp, name)) = fallback else {
+            message("No other buffer to return to");
+            return false;
+        };
+        switch_to_buffer(bp);
+        match origin {
+            Some(gone) => message(&format!("{} no longer exists - switched to {}", gone, name)),
+            None => message(&format!("Switched to {}", name)),
+        }
+    }
+
+    if config_bool("clear_on_quit", false) {
+        if let Some(bp) = results_bp {
+            clear_buffer(bp);
+        }
+        if let Some(name) = &results_name {
+            forget_listing(name);
+        }
+    }
+    true
+}
+
+/// Configured results buffer name
+fn results_base() -> String {
+    let base = lock(&RESULTS_BASE).clone();
+    if base.is_empty() {
+        DEFAULT_RESULTS_BUFFER.to_string()
+    } else {
+        base
+    }
+}
+
+/// Buffer the current search writes its results to
+fn results_buffer() -> String {
+    lock(&RESULTS_TARGET).clone().unwrap_or_else(results_base)
+}
+
+/// The configured results buffer or one of re2-new's numbered ones
+fn is_results_buffer(name: &str) -> bool {
+    let base = results_base();
+    if name == base {
+        return true;
+    }
+    // `*re2-results*` numbers as `*re2-results-2*`
+    let stem = base.strip_suffix('*').unwrap_or(&base);
+    name.strip_prefix(stem)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .and_then(|rest| rest.strip_suffix('*'))
+        .is_some_and(|n| n.parse::<usize>().is_ok())
+}
+
+/// Check if we're in a results buffer
+fn in_results_buffer() -> bool {
+    get_buffer_name()
+        .map(|name| is_results_buffer(&name))
+        .unwrap_or(false)
+}
+
+/// Record a search as the last one and add its pattern to the history
+fn remember_search(pattern: &str, opts: &SearchOptions, target: SearchTarget) {
+    *lock(&LAST_PATTERN) = Some(pattern.to_string());
+    *lock(&LAST_OPTIONS) = Some(opts.clone());
+    *lock(&LAST_TARGET) = Some(target);
+    lock(&HISTORY).push(pattern);
+    persist_state();
+}
+
+/// Write history and saved searches to the state file
+fn persist_state() {
+    let result = {
+        let mut guard = lock(&SAVED_STATE);
+        let Some(state) = guard.as_mut() else {
+            return;
+        };
+        state.history = lock(&HISTORY).iter().map(str::to_string).collect();
+        state::save(state)
+    };
+    if let Err(e) = result {
+        log_warn(&format!("rust_re2: cannot save state: {}", e));
+    }
+}
+
+/// Nearest ancestor of `dir` holding a .git entry, else `dir` itself
+fn project_root(dir: &str) -> String {
+    std::path::Path::new(dir)
+        .ancestors()
+        .find(|d| d.join(".git").exists())
+        .map(|d| d.to_string_lossy().into_owned())
+        .unwrap_or_else(|| dir.to_string())
+}
+
+/// Clears SEARCH_ACTIVE when dropped, so every exit path (errors and
+/// panics included) frees the slot for the next search
+struct ActiveSearch;
+
+impl Drop for ActiveSearch {
+    fn drop(&mut self) {
+        SEARCH_ACTIVE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Run a directory search that can be aborted through SEARCH_CANCEL
+///
+/// Returns Ok(None) when the search was cancelled; callers stay silent then.
+fn run_search(
+    pattern: &str,
+    dir: &str,
+    opts: &SearchOptions,
+    on_batch: &mut dyn FnMut(&[search::Match], &search::Progress),
+) -> Result<Option<search::SearchResult>, String> {
+    run_guarded(pattern, dir, || search_with_progress(pattern, dir, opts, on_batch))
+}
+
+/// Run `search` holding the search slot, between re2:search:start and
+/// re2:search:done, as run_search does
+///
+/// `search` should stop when SEARCH_CANCEL is set. Returns Ok(None) when
+/// another search held the slot or this one was cancelled.
+fn run_guarded(
+    pattern: &str,
+    dir: &str,
+    search: impl FnOnce() -> Result<search::SearchResult, String>,
+) -> Result<Option<search::SearchResult>, String> {
+    let Some(active) = begin_search(pattern, dir) else {
+        return Ok(None);
+    };
+    let result = search();
+    drop(active);
+    emit_search_done(result.as_ref().ok());
+    settle_search(result)
+}
+
+/// Claim the search slot and announce the search
+///
+/// None (after telling the user) if another search holds the slot.
+fn begin_search(pattern: &str, dir: &str) -> Option<ActiveSearch> {
+    // One search at a time: a second would interleave results and race
+    // on the results buffer and LAST_PATTERN
+    if SEARCH_ACTIVE
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        message("Search already running - re2-cancel to stop it");
+        return None;
+    }
+    SEARCH_CANCEL.store(false, Ordering::SeqCst);
+    CANCEL_BY_USER.store(false, Ordering::SeqCst);
+    SEARCH_DETACHED.store(false, Ordering::SeqCst);
+    emit_search_start(pattern, dir);
+    Some(ActiveSearch)
+}
+
+/// Run the search itself, publishing throttled progress events
+///
+/// The search holds the editor thread, so between batches it reads ESC
+/// and C-g from the terminal itself.
+fn search_with_progress(
+    pattern: &str,
+    dir: &str,
+    opts: &SearchOptions,
+    on_batch: &mut dyn FnMut(&[search::Match], &search::Progress),
+) -> Result<search::SearchResult, String> {
+    let mut last_progress = None;
+    let mut forward = |batch: &[search::Match], progress: &search::Progress| {
+        check_cancel_keys();
+        if !batch.is_empty() {
+            on_batch(batch, progress);
+        }
+        if throttle_due(&mut last_progress, PROGRESS_EVENT_MS) {
+            emit_search_progress(progress);
+        }
+    };
+    search::search_parallel(pattern, dir, opts, &SEARCH_CANCEL, &SEARCH_FILES_SEARCHED, &mut forward)
+}
+
+fn emit_search_progress(progress: &search::Progress) {
+    emit(
+        SEARCH_PROGRESS_EVENT,
+        &mut SearchProgressEvent {
+            files_searched: progress.files_searched,
+            matches: progress.matches,
+        },
+    );
+}
+
+/// Decide what a finished search shows; Ok(None) means nothing
+fn settle_search(
+    result: Result<search::SearchResult, String>,
+) -> Result<Option<search::SearchResult>, String> {
+    let result = result?;
+    if !result.stats.cancelled {
+        return Ok(Some(result));
+    }
+
+    // Leaving the results flow aborts silently; an explicit cancel reports
+    // and may keep what was found so far
+    if !CANCEL_BY_USER.load(Ordering::SeqCst) {
+        return Ok(None);
+    }
+    if config_bool("show_cancelled", false) && !result.matches.is_empty() {
+        return Ok(Some(result));
+    }
+    message("Search cancelled");
+    Ok(None)
+}
+
+/// Minimum time between redraws while results stream in
+const STREAM_REDRAW_MS: u128 = 250;
+
+/// Appends each file's matches to the results buffer as the search runs
+///
+/// The buffer is only opened once the first match arrives, so searches
+/// with no hits never touch it. show_results rewrites it with the sorted,
+/// complete output and the final header when the search ends.
+struct ResultsStream {
+    pattern: String,
+    layout: Layout,
+    matches: usize,
+    files: usize,
+    last_redraw: Option<std::time::Instant>,
+}
+
+impl ResultsStream {
+    fn new(pattern: &str) -> Self {
+        ResultsStream {
+            pattern: pattern.to_string(),
+            layout: Layout::of(&get_search_options()),
+            matches: 0,
+            files: 0,
+            last_redraw: None,
+        }
+    }
+
+    fn push(&mut self, batch: &[search::Match]) {
+        if self.files == 0 && !self.open() {
+            return;
+        }
+        record_highlights(batch, &self.layout);
+        buffer_insert(&search::format_batch(batch, &self.layout, self.files == 0));
+        self.matches += batch.len();
+        self.files += 1;
+
+        if throttle_due(&mut self.last_redraw, STREAM_REDRAW_MS) {
+            message(&format!(
+                "Searching for: {}... {} matches in {} files so far",
+                self.pattern, self.matches, self.files
+            ));
+            update_display();
+        }
+    }
+
+    /// Prepare the results buffer; lines already shown can be jumped to
+    fn open(&mut self) -> bool {
+        if open_results_buffer().is_none() {
+            return false;
+        }
+        set_results_kind(ResultsKind::Files);
+        *lock(&RESULTS_LAYOUT) = Some(self.layout.clone());
+        buffer_insert(&format!("Searching for: {}...\n\n", self.pattern))
     }
+}
 
+fn emit_search_start(pattern: &str, dir: &str) {
+    let (Ok(cpattern), Ok(cdir)) = (CString::new(pattern), CString::new(dir)) else {
+        return;
+    };
+    emit(
+        SEARCH_START_EVENT,
+        &mut SearchStartEvent {
+            pattern: cpattern.as_ptr(),
+            directory: cdir.as_ptr(),
+        },
+    );
+}
+
+/// Announce the end of a search; a failed search reports zero matches
+fn emit_search_done(result: Option<&search::SearchResult>) {
+    let buffer = CString::new(results_buffer()).unwrap_or_default();
+    let stats = result.map(|r| r.stats.clone()).unwrap_or_default();
+    emit(
+        SEARCH_DONE_EVENT,
+        &mut SearchDoneEvent {
+            matches: stats.matches,
+            files_searched: stats.files_searched,
+            elapsed_ms: stats.elapsed_ms,
+            results_buffer: buffer.as_ptr(),
+            cancelled: stats.cancelled,
+        },
+    );
+}
+
+/// Perform the search and display results
+fn do_search(pattern: &str, opts: SearchOptions) -> bool {
     let search_dir = get_buffer_directory().unwrap_or_else(|| ".".to_string());
+    do_search_in(pattern, &search_dir, opts)
+}
+
+/// Search a given directory and display results
+fn do_search_in(pattern: &str, search_dir: &str, opts: SearchOptions) -> bool {
+    // With reuse_buffer off, a search that would overwrite another's
+    // results goes to a new numbered buffer instead
+    let fresh = !config_bool("reuse_buffer", true)
+        && lock(&RESULTS_TARGET).is_none()
+        && results_search(&results_base(), |_| ()).is_some();
+    if fresh {
+        *lock(&RESULTS_TARGET) = Some(next_results_buffer());
+    }
+    let found = search_dir_into_target(pattern, search_dir, opts);
+    if fresh {
+        *lock(&RESULTS_TARGET) = None;
+    }
+    found
+}
+
+/// do_search_in, writing to whichever buffer results_buffer() names
+fn search_dir_into_target(pattern: &str, search_dir: &str, opts: SearchOptions) -> bool {
+    let (opts, project_error) = with_project_options(search_dir, opts);
+    let case = search::CaseMode::of(&opts).effective_label(pattern, opts.fixed_strings);
+    let note = project_error.map(|e| format!(" (ignoring {})", e)).unwrap_or_default();
+    message(&format!("Searching for: {} in {} (case: {})...{}", pattern, search_dir, case, note));
+    update_display();
+
+    // background_search is the older name for detached_search
+    if config_bool("detached_search", config_bool("background_search", false)) {
+        if !spawn_search(pattern, search_dir, opts) {
+            return false;
+        }
+        message(&format!("Searching for: {} in {} - any key shows progress", pattern, search_dir));
+        return true;
+    }
+
+    let mut stream = ResultsStream::new(pattern);
+    let result = run_search(pattern, search_dir, &opts, &mut |batch, _| stream.push(batch));
+    show_directory_search(pattern, search_dir, &opts, result)
+}
+
+/// Layer the .uemacs-rg.toml governing `dir`, if any, over `opts`
+///
+/// The file is reread every time. One that doesn't parse is skipped, and
+/// its error (naming the file and line) returned for the caller to show.
+fn with_project_options(dir: &str, mut opts: SearchOptions) -> (SearchOptions, Option<String>) {
+    let Some(file) = project::find(std::path::Path::new(dir)) else {
+        return (opts, None);
+    };
+    match project::load(&file) {
+        Ok(project) => {
+            project.layer(&mut opts, &get_search_options());
+            (opts, None)
+        }
+        Err(e) => {
+            log_warn(&format!("rust_re2: {}", e));
+            (opts, Some(e))
+        }
+    }
+}
+
+/// Display a finished directory search and make it the last search
+fn show_directory_search(
+    pattern: &str,
+    search_dir: &str,
+    opts: &SearchOptions,
+    result: Result<Option<search::SearchResult>, String>,
+) -> bool {
+    let result = match result {
+        Ok(Some(r)) => r,
+        Ok(None) => return false,
+        Err(e) => {
+            message(&format!("Search error: {}", e));
+            return false;
+        }
+    };
+
+    // Recorded only once this search owns the results, so a rejected
+    // concurrent search can't clobber what re2-again repeats
+    remember_search(pattern, opts, SearchTarget::Dir(search_dir.to_string()));
+    *lock(&LAST_DIR) = Some(search_dir.to_string());
+
+    set_results_kind(ResultsKind::Files);
+    let shown = show_results(&result);
+    register_results_search(ResultsSearch {
+        buffer: results_buffer(),
+        pattern: pattern.to_string(),
+        dir: search_dir.to_string(),
+        opts: opts.clone(),
+        layout: results_layout(),
+        refine: Refine {
+            full: result,
+            filters: Vec::new(),
+        },
+    });
+    shown
+}
+
+/// Record the search a results buffer now shows
+///
+/// Past config `results_buffers` (default 5) the oldest buffers are
+/// cleared and forgotten.
+fn register_results_search(search: ResultsSearch) {
+    let keep = config_int("results_buffers", DEFAULT_RESULTS_BUFFERS as i32).max(1) as usize;
+    let evicted: Vec<String> = {
+        let mut searches = lock(&RESULTS_SEARCHES);
+        searches.retain(|s| s.buffer != search.buffer);
+        searches.push(search);
+        let excess = searches.len().saturating_sub(keep);
+        searches.drain(..excess).map(|s| s.buffer).collect()
+    };
+    for name in evicted {
+        forget_listing(&name);
+        if let Some(bp) = find_named_buffer(&name) {
+            clear_buffer(bp);
+        }
+    }
+}
+
+/// Forget the search a results buffer held, once it shows something else
+fn forget_results_search(buffer: &str) {
+    lock(&RESULTS_SEARCHES).retain(|s| s.buffer != buffer);
+}
+
+/// Look at the directory search shown in `buffer`, if it holds one
+fn results_search<R>(buffer: &str, f: impl FnOnce(&mut ResultsSearch) -> R) -> Option<R> {
+    lock(&RESULTS_SEARCHES).iter_mut().find(|s| s.buffer == buffer).map(f)
+}
+
+/// results_search for the current buffer
+fn results_search_here<R>(f: impl FnOnce(&mut ResultsSearch) -> R) -> Option<R> {
+    results_search(&get_buffer_name()?, f)
+}
+
+/// Name for a new numbered results buffer, e.g. `*re2-results-2*`
+fn next_results_buffer() -> String {
+    let base = results_base();
+    let stem = base.strip_suffix('*').unwrap_or(&base);
+    let number = NEXT_RESULTS_NUMBER.fetch_add(1, Ordering::SeqCst);
+    format!("{}-{}*", stem, number)
+}
+
+/// A detached search's outcome, waiting in DETACHED_MAILBOX
+struct FinishedSearch {
+    pattern: String,
+    dir: String,
+    opts: SearchOptions,
+    /// Buffer the search was started for (re2-new, re2-again in results)
+    target: Option<String>,
+    result: Result<search::SearchResult, String>,
+    /// Holds the search slot until the results are shown
+    active: ActiveSearch,
+}
+
+/// A detached search's hand-off to the editor thread
+///
+/// The search thread only writes here. Events, messages and buffer
+/// writes all happen when the editor thread collects it.
+struct Mailbox {
+    pattern: String,
+    /// Latest totals, not yet published
+    progress: Option<search::Progress>,
+    finished: Option<FinishedSearch>,
+}
+
+/// Start a directory search on its own thread, leaving the editor usable
+///
+/// Nothing is shown until the search ends; its result waits in
+/// DETACHED_MAILBOX for collect_detached_search. The editor has no way
+/// to be woken from another thread, so that happens at the next key press
+/// or click, not when the search ends. False if the search couldn't
+/// start.
+fn spawn_search(pattern: &str, dir: &str, opts: SearchOptions) -> bool {
+    let Some(active) = begin_search(pattern, dir) else {
+        return false;
+    };
+    SEARCH_DETACHED.store(true, Ordering::SeqCst);
+    let (pattern, dir) = (pattern.to_string(), dir.to_string());
+    let target = lock(&RESULTS_TARGET).clone();
+    *lock(&DETACHED_MAILBOX) = Mailbox {
+        pattern: pattern.clone(),
+        progress: None,
+        finished: None,
+    };
+
+    let spawned = std::thread::Builder::new()
+        .name("re2-search".to_string())
+        .spawn(move || {
+            let mut post = |_: &[search::Match], progress: &search::Progress| {
+                lock(&DETACHED_MAILBOX).progress = Some(*progress);
+            };
+            let result = search::search_parallel(
+                &pattern,
+                &dir,
+                &opts,
+                &SEARCH_CANCEL,
+                &SEARCH_FILES_SEARCHED,
+                &mut post,
+            );
+
+            lock(&DETACHED_MAILBOX).finished = Some(FinishedSearch {
+                pattern,
+                dir,
+                opts,
+                target,
+                result,
+                active,
+            });
+        });
+    match spawned {
+        Ok(handle) => {
+            // The previous thread has already handed its result over
+            if let Some(previous) = lock(&SEARCH_THREAD).replace(handle) {
+                let _ = previous.join();
+            }
+            true
+        }
+        Err(e) => {
+            message(&format!("Search error: cannot start search thread: {}", e));
+            false
+        }
+    }
+}
+
+/// Cancel a detached search and wait for its thread to end, dropping
+/// whatever it left uncollected
+fn stop_search_thread() {
+    let Some(handle) = lock(&SEARCH_THREAD).take() else {
+        return;
+    };
+    SEARCH_CANCEL.store(true, Ordering::SeqCst);
+    let _ = handle.join();
+    lock(&DETACHED_MAILBOX).finished = None;
+    SEARCH_DETACHED.store(false, Ordering::SeqCst);
+}
+
+/// Search the current buffer's contents and display results
+fn do_search_buffer(pattern: &str, opts: SearchOptions) -> bool {
+    match get_buffer_name() {
+        Some(name) => search_in_buffer(pattern, SearchTarget::Buffer(name), opts),
+        None => {
+            message("No current buffer");
+            false
+        }
+    }
+}
+
+/// Search the region between mark and point and display results
+fn do_search_region(pattern: &str, first_line: u64, last_line: u64, opts: SearchOptions) -> bool {
+    match get_buffer_name() {
+        Some(buffer) => {
+            let target = SearchTarget::Region {
+                buffer,
+                first: first_line,
+                last: last_line,
+            };
+            search_in_buffer(pattern, target, opts)
+        }
+        None => {
+            message("No current buffer");
+            false
+        }
+    }
+}
+
+/// Search a named buffer, or some of its lines, and display results
+fn search_in_buffer(pattern: &str, target: SearchTarget, opts: SearchOptions) -> bool {
+    let (name, lines) = match &target {
+        SearchTarget::Buffer(name) => (name, None),
+        SearchTarget::Region {
+            buffer,
+            first,
+            last,
+        } => (buffer, Some((*first, *last))),
+        SearchTarget::Dir(_) | SearchTarget::Buffers => return false,
+    };
+
+    let contents = match find_named_buffer(name).and_then(get_buffer_contents) {
+        Some(c) => c,
+        None => {
+            message(&format!("Failed to read buffer {}", name));
+            return false;
+        }
+    };
+
+    let result = match lines {
+        Some((first, last)) => {
+            search::search_buffer_lines(pattern, name, &contents, first, last, &opts)
+        }
+        None => search::search_buffer(pattern, name, &contents, &opts),
+    };
+    show_buffer_search(pattern, &opts, target, result)
+}
+
+/// Search every open buffer's contents and display results
+fn do_search_buffers(pattern: &str, opts: SearchOptions) -> bool {
+    let include_special = config_bool("special_buffers", false);
+    let buffers: Vec<(String, Vec<u8>)> = list_buffers()
+        .into_iter()
+        .filter_map(|bp| {
+            let name = buffer_name_of(bp)?;
+            if !include_special && is_special_buffer(&name) {
+                return None;
+            }
+            if is_results_buffer(&name) {
+                return None;
+            }
+            Some((name, get_buffer_contents(bp)?))
+        })
+        .collect();
+
+    if buffers.is_empty() {
+        message("No buffers to search");
+        return false;
+    }
+
+    let result = search::search_buffers(pattern, &buffers, &opts);
+    show_buffer_search(pattern, &opts, SearchTarget::Buffers, result)
+}
+
+/// Display a buffer search's results, announcing its end through
+/// re2:search:done as a directory search does, and make it the last search
+fn show_buffer_search(
+    pattern: &str,
+    opts: &SearchOptions,
+    target: SearchTarget,
+    result: Result<search::SearchResult, String>,
+) -> bool {
+    emit_search_done(result.as_ref().ok());
+    let result = match result {
+        Ok(r) => r,
+        Err(e) => {
+            message(&format!("Search error: {}", e));
+            return false;
+        }
+    };
+    remember_search(pattern, opts, target);
+
+    set_results_kind(ResultsKind::Buffers);
+    show_results(&result)
+}
+
+/// Count matches per file and display the counts
+fn do_count(pattern: &str, opts: SearchOptions) -> bool {
+    let search_dir = get_buffer_directory().unwrap_or_else(|| ".".to_string());
+
+    message(&format!("Counting: {} in {}...", pattern, search_dir));
+    update_display();
+
+    let count_opts = SearchOptions {
+        count_only: true,
+        ..opts.clone()
+    };
+    let result = match run_search(pattern, &search_dir, &count_opts, &mut |_, _| {}) {
+        Ok(Some(r)) => r,
+        Ok(None) => return false,
+        Err(e) => {
+            message(&format!("Search error: {}", e));
+            return false;
+        }
+    };
+    remember_search(pattern, &opts, SearchTarget::Dir(search_dir.clone()));
+    let error_note = write_errors(&result.errors);
+
+    if result.counts.is_empty() {
+        let errors = error_note.map(|n| format!(" - {}", n)).unwrap_or_default();
+        message(&format!(
+            "No matches ({} files searched in {}ms){}",
+            result.stats.files_searched, result.stats.elapsed_ms, errors
+        ));
+        return true;
+    }
+
+    if open_results_buffer().is_none() {
+        message("Failed to create results buffer");
+        return false;
+    }
+    let mut output = search::format_counts(&result);
+    if let Some(note) = &error_note {
+        output.push_str(&format!("\n{}\n", note));
+    }
+    buffer_insert(&output);
+    goto_line(3);
+
+    set_results_kind(ResultsKind::Counts);
+    message(&format!(
+        "{} matches in {} files ({}ms) - Enter to open",
+        result.stats.matches, result.stats.files_matched, result.stats.elapsed_ms
+    ));
+    true
+}
+
+/// Record what the results buffer now contains
+///
+/// Anything but directory search results replaces the search the buffer
+/// was registered with.
+fn set_results_kind(kind: ResultsKind) {
+    if !matches!(kind, ResultsKind::Files) {
+        forget_results_search(&results_buffer());
+    }
+    *lock(&RESULTS_KIND) = kind;
+    lock(&RESULT_HIGHLIGHTS).clear();
+}
+
+/// Remember highlight spans for matches about to be written
+fn record_highlights(matches: &[search::Match], layout: &Layout) {
+    if SYNTAX_ADD_TOKEN.load(Ordering::SeqCst).is_null() {
+        return;
+    }
+    let mut highlights = lock(&RESULT_HIGHLIGHTS);
+    for group in matches.chunk_by(|a, b| a.file == b.file) {
+        highlights.extend(search::highlight_batch(group, layout));
+    }
+}
+
+/// Lexer for the results buffer: replays the spans recorded for a line
+extern "C" fn re2_results_lexer(
+    _lang: *const c_void,
+    _bp: *mut c_void,
+    _line_num: c_int,
+    line: *const c_char,
+    len: c_int,
+    _prev: LexerState,
+    out: *mut c_void,
+) -> LexerState {
+    guarded("re2_results_lexer", LexerState::default(), || {
+        let add_token = SYNTAX_ADD_TOKEN.load(Ordering::SeqCst);
+        if add_token.is_null() || line.is_null() || out.is_null() || len <= 0 {
+            return LexerState::default();
+        }
+        let add_token: SyntaxAddTokenFn = unsafe { std::mem::transmute(add_token) };
+
+        let bytes = unsafe { std::slice::from_raw_parts(line as *const u8, len as usize) };
+        let text = String::from_utf8_lossy(bytes);
+        let Some(spans) = lock(&RESULT_HIGHLIGHTS).get(text.as_ref()).cloned() else {
+            return LexerState::default();
+        };
+
+        for (end, kind) in spans {
+            let face = match kind {
+                Highlight::Plain => FACE_DEFAULT,
+                Highlight::Path => FACE_FUNCTION,
+                Highlight::Location => FACE_NUMBER,
+                Highlight::Match => FACE_KEYWORD,
+            };
+            unsafe {
+                add_token(out, end as c_int, face);
+            }
+        }
+        LexerState::default()
+    })
+}
+
+/// List files under the buffer's directory and display them
+fn do_list_files(filter: Option<&str>) -> bool {
+    let search_dir = get_buffer_directory().unwrap_or_else(|| ".".to_string());
+    let limit = config_int("files_limit", 10_000).max(1) as usize;
+
+    let opts = get_search_options();
+    let list = match search::list_files(&search_dir, filter, limit, &opts) {
+        Ok(l) => l,
+        Err(e) => {
+            message(&format!("File listing error: {}", e));
+            return false;
+        }
+    };
+
+    if list.files.is_empty() {
+        message(&format!("No files found in {}", search_dir));
+        return true;
+    }
+
+    if open_results_buffer().is_none() {
+        message("Failed to create results buffer");
+        return false;
+    }
+    buffer_insert(&search::format_file_list(&list, &search_dir));
+    goto_line(3);
+
+    set_results_kind(ResultsKind::FileList(search_dir));
+    message(&format!("{} files - Enter to open", list.files.len()));
+    true
+}
+
+/// Write a search's errors to the errors buffer
+///
+/// The buffer is only created when there are errors; a clean search just
+/// empties one left from an earlier search. Returns the summary line for
+/// the results buffer, if any.
+fn write_errors(errors: &[String]) -> Option<String> {
+    if errors.is_empty() {
+        if let Some(bp) = find_named_buffer(RE2_ERRORS_BUFFER) {
+            clear_buffer(bp);
+        }
+        return None;
+    }
+
+    let previous = current_buffer();
+    let bp = get_or_create_buffer(RE2_ERRORS_BUFFER)?;
+    switch_to_buffer(bp);
+    clear_buffer(bp);
+    buffer_insert(&search::format_errors(errors));
+    goto_line(1);
+    if let Some(prev) = previous {
+        switch_to_buffer(prev);
+    }
+
+    Some(format!("{} errors (see {})", errors.len(), RE2_ERRORS_BUFFER))
+}
+
+/// Write search results into the results buffer
+///
+/// The result is kept as the base for re2-refine.
+fn show_results(result: &search::SearchResult) -> bool {
+    *lock(&REFINE) = Some(Refine {
+        full: result.clone(),
+        filters: Vec::new(),
+    });
+    render_results(result, &Layout::of(&get_search_options()), &[])
+}
+
+/// Write a (possibly refined) result into the results buffer
+fn render_results(result: &search::SearchResult, layout: &Layout, filters: &[String]) -> bool {
+    let error_note = write_errors(&result.errors);
+
+    if result.matches.is_empty() {
+        let errors = error_note.map(|n| format!(" - {}", n)).unwrap_or_default();
+        message(&format!(
+            "No matches ({} files searched in {}ms){}",
+            result.stats.files_searched, result.stats.elapsed_ms, errors
+        ));
+        return true;
+    }
+
+    if open_results_buffer().is_none() {
+        message("Failed to create results buffer");
+        return false;
+    }
+
+    *lock(&RESULTS_LAYOUT) = Some(layout.clone());
+    record_highlights(&result.matches, layout);
+    let mut output = if filters.is_empty() {
+        String::new()
+    } else {
+        search::format_refined(filters)
+    };
+    output += &search::format_results(result, layout);
+    if let Some(note) = &error_note {
+        output.push_str(&format!("\n{}\n", note));
+    }
+    buffer_insert(&output);
+    record_listing(result, layout, &output);
+
+    // A capped search has an extra truncation line above the stats, and
+    // a refined one a line naming the filters
+    let header_lines = 2 + c_int::from(result.stats.capped) + c_int::from(!filters.is_empty());
+    goto_line(header_lines + 1);
+
+    let shown = if result.stats.capped {
+        format!("{} of {}+", result.stats.matches, result.stats.matches_found)
+    } else {
+        result.stats.matches.to_string()
+    };
+    message(&format!(
+        "{} matches in {} files ({}ms) - Enter to jump",
+        shown, result.stats.files_matched, result.stats.elapsed_ms
+    ));
+    true
+}
+
+/// Command: re2
+extern "C" fn cmd_re2_search(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search", 0, || {
+        let (pattern, opts) = match prompt_search("RE2 pattern: ", get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-new
+///
+/// Like re2, but the results go to a new numbered buffer so earlier
+/// results stay put.
+extern "C" fn cmd_re2_search_new(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_new", 0, || {
+        let (pattern, opts) = match prompt_search("RE2 pattern (new buffer): ", get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        *lock(&RESULTS_TARGET) = Some(next_results_buffer());
+        let found = do_search(&pattern, opts);
+        *lock(&RESULTS_TARGET) = None;
+
+        if found { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-literal
+extern "C" fn cmd_re2_search_literal(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_literal", 0, || {
+        let mut base = get_search_options();
+        base.fixed_strings = true;
+        let (pattern, opts) = match prompt_search("RE2 literal: ", base) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-search-any
+///
+/// The terms are ORed into one pattern, so a line matching any of them
+/// is listed once. With fixed_strings on (or `-F` at the prompt) each
+/// term is taken literally.
+extern "C" fn cmd_re2_search_any(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_any", 0, || {
+        let any = |terms: String, opts: &mut SearchOptions| {
+            let pattern = search::any_pattern(&terms, opts.fixed_strings)?;
+            // The terms are escaped already
+            opts.fixed_strings = false;
+            Ok(pattern)
+        };
+        let base = get_search_options();
+        let (pattern, opts) = match prompt_search_with("RE2 any of (space-separated): ", base, any) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Literal pattern for the word at point, with its word_boundary setting
+///
+/// Whole words only, unless `substring` (a prefix argument) asks for any
+/// occurrence.
+fn word_at_point_pattern(substring: bool) -> Option<(String, bool)> {
+    let word = match get_word_at_point() {
+        Some(w) if !w.is_empty() => w,
+        _ => {
+            message("No word at point");
+            return None;
+        }
+    };
+
+    if substring {
+        Some((regex_syntax::escape(&word), false))
+    } else {
+        Some(search::whole_word_pattern(&word))
+    }
+}
+
+/// Command: re2-word
+///
+/// With a prefix argument, matches inside longer words too.
+extern "C" fn cmd_re2_search_word(f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_word", 0, || {
+        let Some((pattern, word_boundary)) = word_at_point_pattern(f != 0) else {
+            return 0;
+        };
+
+        let mut opts = get_search_options();
+        opts.word_boundary = word_boundary;
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-def
+///
+/// Look for the definition of the word at point from the project root,
+/// with the pattern for the buffer's file type (config `def_<type>`
+/// overrides the built-in one). A single candidate is jumped to; more
+/// are listed as results.
+extern "C" fn cmd_re2_def(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_def", 0, || {
+        let Some(word) = get_word_at_point().filter(|w| !w.is_empty()) else {
+            message("No word at point");
+            return 0;
+        };
+        let Some(file_type) = get_buffer_filename().as_deref().and_then(file_type_for) else {
+            message("re2-def needs a file with a known extension");
+            return 0;
+        };
+        let custom = config_string(&format!("def_{}", file_type), "");
+        let custom = Some(custom.as_str()).filter(|c| !c.is_empty());
+        let Some(pattern) = search::definition_pattern(file_type, &word, custom) else {
+            message(&format!("No definition pattern for {} files (set def_{})", file_type, file_type));
+            return 0;
+        };
+
+        let opts = SearchOptions {
+            case_insensitive: false,
+            smart_case: false,
+            word_boundary: false,
+            fixed_strings: false,
+            invert_match: false,
+            multiline: false,
+            count_only: false,
+            file_types: vec![file_type.to_string()],
+            ..get_search_options()
+        };
+        let dir = project_root(&get_buffer_directory().unwrap_or_else(|| ".".to_string()));
+        let (opts, _) = with_project_options(&dir, opts);
+
+        message(&format!("Looking for the definition of {}...", word));
+        update_display();
+        let result = match run_search(&pattern, &dir, &opts, &mut |_, _| {}) {
+            Ok(Some(r)) => r,
+            Ok(None) => return 0,
+            Err(e) => {
+                message(&format!("Search error: {}", e));
+                return 0;
+            }
+        };
+
+        let [m] = result.matches.as_slice() else {
+            if result.matches.is_empty() {
+                message(&format!("No definition of {} found", word));
+                return 0;
+            }
+            return if show_directory_search(&pattern, &dir, &opts, Ok(Some(result))) { 1 } else { 0 };
+        };
+        let (line, column) = (m.line_number as i32, m.file_column.unwrap_or(m.column));
+        let target = JumpTarget::File {
+            path: resolve_result_path(Some(&dir), &m.file.to_string_lossy()),
+            line,
+            column: column + 1,
+        };
+        remember_jump();
+        if !open_target(&target) {
+            return 0;
+        }
+        set_point(line, column);
+        message(&format!("Definition of {}: {}", word, target.label()));
+        1
+    })
+}
+
+/// Command: re2-whole-word
+extern "C" fn cmd_re2_search_whole_word(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_whole_word", 0, || {
+        let mut base = get_search_options();
+        base.word_boundary = true;
+        let (pattern, opts) = match prompt_search("RE2 whole word: ", base) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-word-type
+///
+/// With a prefix argument, matches inside longer words too.
+extern "C" fn cmd_re2_search_word_type(f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_word_type", 0, || {
+        let Some((pattern, word_boundary)) = word_at_point_pattern(f != 0) else {
+            return 0;
+        };
+
+        let mut opts = get_search_options();
+        opts.word_boundary = word_boundary;
+
+        // Unknown extensions search every file type
+        if let Some(file_type) = get_buffer_filename().as_deref().and_then(file_type_for) {
+            opts.file_types = vec![file_type.to_string()];
+        }
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-dir
+///
+/// Prompt for a directory, then a pattern, and search there.
+extern "C" fn cmd_re2_search_dir(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_dir", 0, || {
+        let default = lock(&CHOSEN_DIR)
+            .clone()
+            .or_else(get_buffer_directory)
+            .unwrap_or_else(|| ".".to_string());
+        let answer = match prompt(&format!("Search directory [{}]: ", default)) {
+            Some(d) => d.trim().to_string(),
+            None => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+
+        let dir = if answer.is_empty() { default } else { expand_home(answer) };
+        if !std::path::Path::new(&dir).is_dir() {
+            message(&format!("Not a directory: {}", dir));
+            return 0;
+        }
+        *lock(&CHOSEN_DIR) = Some(dir.clone());
+
+        let (pattern, opts) = match prompt_search(&format!("RE2 pattern in {}: ", dir), get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search_in(&pattern, &dir, opts) { 1 } else { 0 }
+    })
+}
+
+/// Expand a leading `~` in a path typed at a prompt
+fn expand_home(path: String) -> String {
+    match (path.as_str(), std::env::var("HOME")) {
+        ("~", Ok(home)) => home,
+        (p, Ok(home)) if p.starts_with("~/") => format!("{}/{}", home, &p[2..]),
+        _ => path,
+    }
+}
+
+/// Command: re2-tracked
+///
+/// Like re2, but only files git tracks are searched. Outside a git
+/// repository every file is searched as usual.
+extern "C" fn cmd_re2_search_tracked(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_tracked", 0, || {
+        let mut base = get_search_options();
+        base.git_tracked_only = true;
+        let (pattern, opts) = match prompt_search("RE2 pattern (tracked files): ", base) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-search-dirty
+///
+/// Searches just the files `git status` lists: modified, staged and
+/// untracked. With a prefix argument, asks for a ref and searches the
+/// files `git diff` says differ from it instead. Searches start from the
+/// project root; re2-again searches the same files again.
+extern "C" fn cmd_re2_search_dirty(f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_dirty", 0, || {
+        let base = if f != 0 {
+            match prompt("Changed since (git ref): ") {
+                Some(b) if !b.trim().is_empty() => Some(b.trim().to_string()),
+                Some(_) => None,
+                None => {
+                    message("Cancelled");
+                    return 0;
+                }
+            }
+        } else {
+            None
+        };
+
+        let dir = project_root(&get_buffer_directory().unwrap_or_else(|| ".".to_string()));
+        let files = match git::changed_files(std::path::Path::new(&dir), base.as_deref()) {
+            Ok(files) => files,
+            Err(e) => {
+                message(&format!("re2-search-dirty: {}", e));
+                return 0;
+            }
+        };
+        let source = git::describe_base(base.as_deref());
+        if files.is_empty() {
+            message(&format!("No changed files ({})", source));
+            return 0;
+        }
+
+        let mut opts = get_search_options();
+        opts.path_list = Some(search::PathList {
+            paths: files,
+            source,
+            lines: None,
+        });
+        let (pattern, opts) = match prompt_search("RE2 pattern (changed files): ", opts) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search_in(&pattern, &dir, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-search-changed
+///
+/// Searches the files `git diff -U0` lists against config `diff_base`
+/// (default HEAD), keeping only matches on added or modified lines. With
+/// a prefix argument, asks for the ref instead.
+extern "C" fn cmd_re2_search_changed(f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_changed", 0, || {
+        let configured = config_string("diff_base", "HEAD");
+        let base = if f != 0 {
+            match prompt(&format!("Changed lines since (git ref) [{}]: ", configured)) {
+                Some(b) if !b.trim().is_empty() => b.trim().to_string(),
+                Some(_) => configured,
+                None => {
+                    message("Cancelled");
+                    return 0;
+                }
+            }
+        } else {
+            configured
+        };
+
+        let dir = project_root(&get_buffer_directory().unwrap_or_else(|| ".".to_string()));
+        let lines = match git::changed_lines(std::path::Path::new(&dir), &base) {
+            Ok(lines) => lines,
+            Err(e) => {
+                message(&format!("re2-search-changed: {}", e));
+                return 0;
+            }
+        };
+        let source = format!("git diff -U0 {}", base);
+        if lines.is_empty() {
+            message(&format!("No changed lines ({})", source));
+            return 0;
+        }
+
+        let mut opts = get_search_options();
+        opts.path_list = Some(search::PathList {
+            paths: lines.keys().cloned().collect(),
+            source,
+            lines: Some(lines),
+        });
+        let prompt_text = format!("RE2 pattern (lines changed since {}): ", base);
+        let (pattern, opts) = match prompt_search(&prompt_text, opts) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search_in(&pattern, &dir, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-unrestricted
+extern "C" fn cmd_re2_search_unrestricted(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_unrestricted", 0, || {
+        let mut base = get_search_options();
+        base.hidden = true;
+        base.no_ignore = true;
+        let (pattern, opts) = match prompt_search("RE2 pattern (hidden + ignored files): ", base) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-invert
+///
+/// Every non-matching line of a whole tree is rarely wanted, so this
+/// insists on a glob or file type to narrow the files first.
+extern "C" fn cmd_re2_search_invert(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_invert", 0, || {
+        let filters = match prompt("Invert in files (glob or type, e.g. *.toml or rust): ") {
+            Some(t) => parse_csv(&t),
+            None => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+        if filters.is_empty() {
+            message("re2-invert needs a glob or file type");
+            return 0;
+        }
+
+        let mut base = get_search_options();
+        for filter in &filters {
+            if filter.contains(['*', '?', '[', '/', '.']) {
+                base.globs.push((true, filter.clone()));
+            } else if search::unknown_file_types(std::slice::from_ref(filter)).is_empty() {
+                base.file_types.push(filter.clone());
+            } else {
+                message(&format!("Not a glob or known file type: {} (see rg --type-list)", filter));
+                return 0;
+            }
+        }
+        base.invert_match = true;
+        base.multiline = false;
+
+        let prompt_text = format!("RE2 invert pattern [{}]: ", filters.join(","));
+        let (pattern, opts) = match prompt_search(&prompt_text, base) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-type
+extern "C" fn cmd_re2_search_type(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_type", 0, || {
+        let types = match prompt("File types (e.g. rust,toml): ") {
+            Some(t) => parse_csv(&t),
+            None => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+        if types.is_empty() {
+            message("No file types given");
+            return 0;
+        }
+
+        let unknown = search::unknown_file_types(&types);
+        if !unknown.is_empty() {
+            message(&format!(
+                "Unknown file type(s): {} (see rg --type-list)",
+                unknown.join(", ")
+            ));
+            return 0;
+        }
+
+        let mut base = get_search_options();
+        base.file_types = types;
+        let prompt_text = format!("RE2 pattern [{}]: ", base.file_types.join(","));
+        let (pattern, opts) = match prompt_search(&prompt_text, base) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-buffer
+extern "C" fn cmd_re2_search_buffer(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_buffer", 0, || {
+        if in_results_buffer() {
+            message("Already in results buffer");
+            return 0;
+        }
+
+        let (pattern, opts) = match prompt_search("RE2 buffer pattern: ", get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search_buffer(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-buffers
+extern "C" fn cmd_re2_search_buffers(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_buffers", 0, || {
+        let (pattern, opts) = match prompt_search("RE2 pattern (all buffers): ", get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search_buffers(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-region
+///
+/// Without a mark there is no region, so the whole buffer is searched
+/// like re2-buffer.
+extern "C" fn cmd_re2_search_region(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_region", 0, || {
+        let Some(mark) = get_mark() else {
+            let (pattern, opts) = match prompt_search("RE2 pattern (no mark, whole buffer): ", get_search_options()) {
+                Some(p) => p,
+                None => return 0,
+            };
+            return if do_search_buffer(&pattern, opts) { 1 } else { 0 };
+        };
+        let point = match get_point() {
+            Some(p) => p,
+            None => {
+                message("Cannot read point");
+                return 0;
+            }
+        };
+
+        let first_line = mark.0.min(point.0).max(1) as u64;
+        let last_line = mark.0.max(point.0).max(1) as u64;
+
+        let (pattern, opts) = match prompt_search("RE2 region pattern: ", get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search_region(&pattern, first_line, last_line, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-files
+extern "C" fn cmd_re2_files(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_files", 0, || {
+        // An empty answer lists everything; only an aborted prompt cancels
+        let filter = match prompt("List files (glob or substring, empty for all): ") {
+            Some(f) => f,
+            None => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+        let filter = filter.trim();
+
+        if do_list_files(if filter.is_empty() { None } else { Some(filter) }) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-count
+extern "C" fn cmd_re2_count(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_count", 0, || {
+        let (pattern, opts) = match prompt_search("RE2 count pattern: ", get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_count(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-count-total
+///
+/// Count matching lines under the buffer's directory and show only the
+/// total, without keeping any match text.
+extern "C" fn cmd_re2_count_total(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_count_total", 0, || {
+        let (pattern, opts) = match prompt_search("RE2 count total: ", get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+        let search_dir = get_buffer_directory().unwrap_or_else(|| ".".to_string());
+        let (opts, _) = with_project_options(&search_dir, opts);
+
+        message(&format!("Counting: {} in {}...", pattern, search_dir));
+        update_display();
+        let start = std::time::Instant::now();
+        let result = run_guarded(&pattern, &search_dir, || {
+            let total = search::count_matches(
+                &pattern,
+                &search_dir,
+                &opts,
+                &SEARCH_CANCEL,
+                &SEARCH_FILES_SEARCHED,
+                &mut check_cancel_keys,
+            )?;
+            Ok(totals_only(total, start))
+        });
+        match result {
+            Ok(Some(result)) => {
+                remember_search(&pattern, &opts, SearchTarget::Dir(search_dir.clone()));
+                message(&format!(
+                    "{} matches for {} ({}ms)",
+                    result.stats.matches, pattern, result.stats.elapsed_ms
+                ));
+                1
+            }
+            Ok(None) => 0,
+            Err(e) => {
+                message(&format!("Search error: {}", e));
+                0
+            }
+        }
+    })
+}
+
+/// Result of a search that keeps no matches, only how many it found
+fn totals_only(matches: usize, start: std::time::Instant) -> search::SearchResult {
+    search::SearchResult {
+        matches: Vec::new(),
+        stats: search::SearchStats {
+            matches,
+            matches_found: matches,
+            files_searched: SEARCH_FILES_SEARCHED.load(Ordering::SeqCst),
+            elapsed_ms: start.elapsed().as_millis() as u64,
+            cancelled: SEARCH_CANCEL.load(Ordering::SeqCst),
+            ..Default::default()
+        },
+        errors: Vec::new(),
+        truncated_files: Vec::new(),
+        counts: Vec::new(),
+        transcoded: Vec::new(),
+    }
+}
+
+/// Command: re2-todo
+///
+/// Search the project root for the markers config `todo_markers` (a
+/// regex) matches, and list them grouped by kind in the order config
+/// `todo_order` gives. Marker case is matched exactly.
+extern "C" fn cmd_re2_todo(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_todo", 0, || {
+        let dir = project_root(&get_buffer_directory().unwrap_or_else(|| ".".to_string()));
+        let pattern = config_string("todo_markers", search::DEFAULT_TODO_MARKERS);
+        let order: Vec<String> = config_string("todo_order", search::DEFAULT_TODO_ORDER)
+            .split(',')
+            .map(|kind| kind.trim().to_string())
+            .filter(|kind| !kind.is_empty())
+            .collect();
+
+        let mut opts = SearchOptions {
+            case_insensitive: false,
+            smart_case: false,
+            word_boundary: false,
+            fixed_strings: false,
+            invert_match: false,
+            multiline: false,
+            count_only: false,
+            ..get_search_options()
+        };
+        // Not git's own files, even with hidden on, nor exported results,
+        // which repeat the markers they list
+        for glob in [".git", EXPORT_FILE, EXPORT_JSON_FILE] {
+            opts.globs.push((false, glob.to_string()));
+        }
+        let (opts, _) = with_project_options(&dir, opts);
+
+        message(&format!("Scanning {} for markers...", dir));
+        update_display();
+        match run_search(&pattern, &dir, &opts, &mut |_, _| {}) {
+            Ok(Some(result)) => {
+                if show_todo(result, &order) { 1 } else { 0 }
+            }
+            Ok(None) => 0,
+            Err(e) => {
+                message(&format!("Search error: {}", e));
+                0
+            }
+        }
+    })
+}
+
+/// List re2-todo's markers by kind in the results buffer
+///
+/// The matches are kept in listed order, so re2-refine and
+/// re2-next-match follow the listing.
+fn show_todo(result: search::SearchResult, order: &[String]) -> bool {
+    let error_note = write_errors(&result.errors);
+    if result.matches.is_empty() {
+        let errors = error_note.map(|n| format!(" - {}", n)).unwrap_or_default();
+        message(&format!(
+            "No markers ({} files searched in {}ms){}",
+            result.stats.files_searched, result.stats.elapsed_ms, errors
+        ));
+        return true;
+    }
+
+    if open_results_buffer().is_none() {
+        message("Failed to create results buffer");
+        return false;
+    }
+    set_results_kind(ResultsKind::Files);
+
+    let opts = get_search_options();
+    let template = search::Template::parse(&opts.format_template).unwrap_or_default();
+    let layout = Layout {
+        style: Style::Flat(template),
+        max_line_len: opts.max_line_len,
+    };
+    let groups = search::group_markers(&result.matches, order);
+    let mut output = search::format_todo(&result, &groups, &layout);
+    let summary: Vec<String> = groups.iter().map(|(kind, g)| format!("{} {}", kind, g.len())).collect();
+    let total = result.matches.len();
+    let listed = search::SearchResult {
+        matches: groups.into_iter().flat_map(|(_, g)| g).cloned().collect(),
+        ..result
+    };
+    if let Some(note) = &error_note {
+        output.push_str(&format!("\n{}\n", note));
+    }
+
+    *lock(&RESULTS_LAYOUT) = Some(layout.clone());
+    record_highlights(&listed.matches, &layout);
+    buffer_insert(&output);
+    record_listing(&listed, &layout, &output);
+    *lock(&REFINE) = Some(Refine {
+        full: listed,
+        filters: Vec::new(),
+    });
+    goto_line(4);
+
+    message(&format!("{} markers: {} - Enter to jump", total, summary.join(", ")));
+    true
+}
+
+/// Command: re2-exists
+///
+/// Say whether the pattern matches anywhere under the buffer's
+/// directory, stopping at the first match.
+extern "C" fn cmd_re2_exists(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_exists", 0, || {
+        let (pattern, opts) = match prompt_search("RE2 exists: ", get_search_options()) {
+            Some(p) => p,
+            None => return 0,
+        };
+        let search_dir = get_buffer_directory().unwrap_or_else(|| ".".to_string());
+        let (opts, _) = with_project_options(&search_dir, opts);
+
+        message(&format!("Looking for: {} in {}...", pattern, search_dir));
+        update_display();
+        let start = std::time::Instant::now();
+        let result = run_guarded(&pattern, &search_dir, || {
+            let found = search::search_exists(
+                &pattern,
+                &search_dir,
+                &opts,
+                &SEARCH_CANCEL,
+                &SEARCH_FILES_SEARCHED,
+                &mut check_cancel_keys,
+            )?;
+            Ok(totals_only(found as usize, start))
+        });
+        match result {
+            Ok(Some(result)) if result.stats.matches > 0 => {
+                message("Found");
+                1
+            }
+            Ok(Some(_)) => {
+                message("Not found");
+                0
+            }
+            Ok(None) => 0,
+            Err(e) => {
+                message(&format!("Search error: {}", e));
+                0
+            }
+        }
+    })
+}
+
+/// Command: re2-export-results
+///
+/// Write the matches of the results buffer's search (or the last one)
+/// to a file as `path:line:col:text` lines, without the header, for
+/// other tools. Paths are relative to the search directory; with a
+/// prefix argument they are absolute. Refine filters are not applied.
+extern "C" fn cmd_re2_export_results(f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_export_results", 0, || {
+        let Some(result) = with_refine(|state, _| state.full.clone()) else {
+            message("No search results to export");
+            return 0;
+        };
+        let root = export_root();
+        let Some(dest) = prompt_export_file(&root, EXPORT_FILE) else {
+            return 0;
+        };
+
+        let relative_to = (f == 0).then(|| std::path::Path::new(&root));
+        let output = search::format_grep(&result, relative_to);
+        if write_export(&dest, &output, result.matches.len()) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-export-json
+///
+/// Write the matches of the results buffer's search (or the last one)
+/// as newline-delimited JSON in the format of `rg --json`, to a file,
+/// or with a prefix argument into *re2-json*. Paths are relative to the
+/// search directory. Refine filters are not applied.
+extern "C" fn cmd_re2_export_json(f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_export_json", 0, || {
+        let Some(result) = with_refine(|state, _| state.full.clone()) else {
+            message("No search results to export");
+            return 0;
+        };
+        let root = export_root();
+
+        // Every occurrence on a line needs the pattern; without it only
+        // the first is listed
+        let search = results_search_here(|s| (s.pattern.clone(), s.opts.clone())).or_else(|| {
+            let pattern = lock(&LAST_PATTERN).clone()?;
+            let opts = lock(&LAST_OPTIONS).clone().unwrap_or_else(get_search_options);
+            Some((pattern, opts))
+        });
+        let engine = search.and_then(|(pattern, opts)| search::build_engine(&pattern, &opts).ok());
+
+        let relative_to = Some(std::path::Path::new(&root));
+        let output = match json::format_json(&result, engine.as_ref(), relative_to) {
+            Ok(output) => output,
+            Err(e) => {
+                message(&e);
+                return 0;
+            }
+        };
+
+        if f == 0 {
+            let Some(dest) = prompt_export_file(&root, EXPORT_JSON_FILE) else {
+                return 0;
+            };
+            return if write_export(&dest, &output, result.matches.len()) { 1 } else { 0 };
+        }
+
+        let Some(bp) = get_or_create_buffer(RE2_JSON_BUFFER) else {
+            message("Failed to create JSON buffer");
+            return 0;
+        };
+        switch_to_buffer(bp);
+        clear_buffer(bp);
+        buffer_insert(&output);
+        goto_line(1);
+        message(&format!("{} matches as JSON", result.matches.len()));
+        1
+    })
+}
+
+/// Directory exports are written to and relative to: the results
+/// buffer's search directory, else the last search's
+fn export_root() -> String {
+    results_search_here(|s| s.dir.clone())
+        .or_else(|| lock(&LAST_DIR).clone())
+        .unwrap_or_else(|| ".".to_string())
+}
+
+/// Ask where to export, defaulting to `default_name` in `root`
+///
+/// None when cancelled or when the user won't overwrite an existing
+/// file; either way the reason has been shown.
+fn prompt_export_file(root: &str, default_name: &str) -> Option<std::path::PathBuf> {
+    let default = std::path::Path::new(root).join(default_name);
+    let Some(answer) = prompt(&format!("Export results to [{}]: ", default.display())) else {
+        message("Cancelled");
+        return None;
+    };
+    let answer = answer.trim().to_string();
+    let dest = if answer.is_empty() {
+        default
+    } else {
+        std::path::Path::new(root).join(expand_home(answer))
+    };
+    if dest.exists() && !prompt_yn(&format!("Overwrite {}?", dest.display())) {
+        message("Not exported");
+        return None;
+    }
+    Some(dest)
+}
+
+/// Write an export, reporting how many matches went where
+fn write_export(dest: &std::path::Path, output: &str, matches: usize) -> bool {
+    match std::fs::write(dest, output) {
+        Ok(()) => {
+            message(&format!("Exported {} matches to {}", matches, dest.display()));
+            true
+        }
+        Err(e) => {
+            message(&format!("Export failed: {}: {}", dest.display(), e));
+            false
+        }
+    }
+}
+
+/// Command: re2-multiline
+extern "C" fn cmd_re2_search_multiline(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_search_multiline", 0, || {
+        let mut base = get_search_options();
+        base.multiline = true;
+        base.multiline_dotall = true;
+        let (pattern, opts) = match prompt_search("RE2 multiline pattern: ", base) {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        if do_search(&pattern, opts) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-live
+extern "C" fn cmd_re2_live(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_live", 0, || {
+        let mut live = lock(&LIVE);
+        if live.is_some() {
+            message("Live grep already active");
+            return 0;
+        }
+
+        *live = Some(LiveState {
+            pattern: String::new(),
+            previous_buffer: get_buffer_name(),
+            search_dir: get_buffer_directory().unwrap_or_else(|| ".".to_string()),
+            opts: get_search_options(),
+            last_key: None,
+            stale: false,
+        });
+        drop(live);
+
+        message("Live grep: (type a pattern, Enter to finish, ESC to abort)");
+        1
+    })
+}
+
+/// What a key does to the live-grep session
+enum LiveAction {
+    Refresh,
+    Finish,
+    Abort,
+    Ignore,
+}
+
+/// Feed a key to the live-grep session; returns true if it was consumed
+fn live_key(key: c_int) -> bool {
+    let mut guard = lock(&LIVE);
+    let Some(live) = guard.as_mut() else {
+        return false;
+    };
+
+    let action = match key {
+        27 | 7 => LiveAction::Abort, // ESC, C-g
+        13 | 10 => LiveAction::Finish,
+        127 | 8 => {
+            live.pattern.pop();
+            LiveAction::Refresh
+        }
+        32..=126 => {
+            live.pattern.push(key as u8 as char);
+            LiveAction::Refresh
+        }
+        _ => LiveAction::Ignore,
+    };
+
+    match action {
+        LiveAction::Ignore => false,
+        LiveAction::Abort => {
+            let previous = live.previous_buffer.take();
+            *guard = None;
+            drop(guard);
+            if let Some(name) = previous {
+                switch_to_named_buffer(&name);
+            }
+            message("Live grep aborted");
+            true
+        }
+        LiveAction::Finish => {
+            let live = guard.take().unwrap();
+            drop(guard);
+            if live.pattern.chars().count() < LIVE_MIN_CHARS {
+                message("Live grep finished");
+                return true;
+            }
+            let target = SearchTarget::Dir(live.search_dir.clone());
+            remember_search(&live.pattern, &live.opts, target);
+            // A debounced final edit still needs its search
+            if live.stale {
+                live_refresh(&live);
+            }
+            message(&format!("Live grep: {} - Enter to jump", live.pattern));
+            true
+        }
+        LiveAction::Refresh => {
+            let now = std::time::Instant::now();
+            let typing_fast = live
+                .last_key
+                .map(|t| now.duration_since(t).as_millis() < LIVE_DEBOUNCE_MS)
+                .unwrap_or(false);
+            live.last_key = Some(now);
+
+            let long_enough = live.pattern.chars().count() >= LIVE_MIN_CHARS;
+            if typing_fast || !long_enough {
+                live.stale = long_enough;
+                message(&format!("Live grep: {}", live.pattern));
+                return true;
+            }
+
+            live.stale = false;
+            let snapshot = LiveState {
+                pattern: live.pattern.clone(),
+                previous_buffer: None,
+                search_dir: live.search_dir.clone(),
+                opts: live.opts.clone(),
+                last_key: None,
+                stale: false,
+            };
+            drop(guard);
+            live_refresh(&snapshot);
+            true
+        }
+    }
+}
+
+/// Re-run the live search and redraw the results buffer
+fn live_refresh(live: &LiveState) {
+    if let Err(e) = search::validate_pattern(&live.pattern, &live.opts) {
+        message(&format!("Live grep: {} [{}]", live.pattern, e));
+        return;
+    }
+
+    let result = match run_search(&live.pattern, &live.search_dir, &live.opts, &mut |_, _| {}) {
+        Ok(Some(r)) => r,
+        Ok(None) => return,
+        Err(e) => {
+            message(&format!("Live grep: {} [{}]", live.pattern, e));
+            return;
+        }
+    };
+
+    set_results_kind(ResultsKind::Files);
+    if result.matches.is_empty() {
+        // Don't leave the previous pattern's hits on screen
+        open_results_buffer();
+    } else {
+        show_results(&result);
+    }
+    update_display();
+
+    message(&format!(
+        "Live grep: {} ({} matches)",
+        live.pattern, result.stats.matches
+    ));
+}
+
+/// Command: re2-settings
+extern "C" fn cmd_re2_settings(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_settings", 0, || {
+        let listing = format_settings(&get_search_options());
+        let Some(bp) = get_or_create_buffer(RE2_SETTINGS_BUFFER) else {
+            message("Failed to create settings buffer");
+            return 0;
+        };
+        switch_to_buffer(bp);
+        clear_buffer(bp);
+        buffer_insert(&listing);
+        goto_line(1);
+        1
+    })
+}
+
+/// Command: re2-reload-config
+///
+/// Options changed with the toggle commands go back to their configured
+/// values.
+extern "C" fn cmd_re2_reload_config(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_reload_config", 0, || {
+        apply_config();
+        message("rust_re2: config reloaded");
+        1
+    })
+}
+
+/// Command: re2-history
+extern "C" fn cmd_re2_history(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_history", 0, || {
+        let listing: String = {
+            let history = lock(&HISTORY);
+            if history.is_empty() {
+                drop(history);
+                message("No search history");
+                return 0;
+            }
+            history.iter().map(|p| format!("{}\n", p)).collect()
+        };
+
+        let Some(bp) = get_or_create_buffer(RE2_HISTORY_BUFFER) else {
+            message("Failed to create history buffer");
+            return 0;
+        };
+        switch_to_buffer(bp);
+        clear_buffer(bp);
+        buffer_insert(&listing);
+        goto_line(1);
+        message("Search history - Enter to search again");
+        1
+    })
+}
+
+/// Re-run the history entry under point
+fn rerun_history_entry() -> bool {
+    match get_current_line() {
+        Some(pattern) if !pattern.is_empty() => do_search(&pattern, get_search_options()),
+        _ => {
+            message("Not on a pattern line");
+            false
+        }
+    }
+}
+
+/// Command: re2-again
+///
+/// With a prefix argument, prompt first; the old pattern is the default.
+extern "C" fn cmd_re2_again(f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_again", 0, || {
+        if search_again(f != 0) { 1 } else { 0 }
+    })
+}
+
+/// Re-run the last search where it ran, with its options
+fn search_again(edit: bool) -> bool {
+    search_again_with(edit, |_| {})
+}
+
+/// Repeat the last search with its options changed by `tweak`
+///
+/// Run from a results buffer, the new results replace that buffer's.
+fn search_again_with(edit: bool, tweak: impl FnOnce(&mut SearchOptions)) -> bool {
+    // A results buffer repeats its own search, not necessarily the last
+    let here = results_search_here(|s| {
+        (s.pattern.clone(), s.opts.clone(), SearchTarget::Dir(s.dir.clone()))
+    });
+    let (pattern, mut opts, target) = match here {
+        Some(search) => search,
+        None => {
+            let Some(pattern) = lock(&LAST_PATTERN).clone() else {
+                message("No previous search");
+                return false;
+            };
+            let opts = lock(&LAST_OPTIONS)
+                .clone()
+                .unwrap_or_else(get_search_options);
+            let target = lock(&LAST_TARGET).clone().unwrap_or_else(|| {
+                SearchTarget::Dir(get_buffer_directory().unwrap_or_else(|| ".".to_string()))
+            });
+            (pattern, opts, target)
+        }
+    };
+    tweak(&mut opts);
+
+    let (pattern, opts) = if edit {
+        match prompt_search("RE2 pattern: ", opts) {
+            Some(p) => p,
+            None => return false,
+        }
+    } else {
+        (pattern, opts)
+    };
+
+    *lock(&RESULTS_TARGET) = get_buffer_name().filter(|name| is_results_buffer(name));
+    let found = match target {
+        SearchTarget::Dir(dir) => do_search_in(&pattern, &dir, opts),
+        SearchTarget::Buffers => do_search_buffers(&pattern, opts),
+        buffer => search_in_buffer(&pattern, buffer, opts),
+    };
+    *lock(&RESULTS_TARGET) = None;
+    found
+}
+
+/// Command: re2-refine
+///
+/// Narrow the results shown to those matching (or, with `!`, not
+/// matching) another pattern; `path:` tests the file path instead.
+extern "C" fn cmd_re2_refine(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_refine", 0, || {
+        let refinable = in_results_buffer()
+            && (results_search_here(|_| ()).is_some()
+                || matches!(*lock(&RESULTS_KIND), ResultsKind::Files | ResultsKind::Buffers)
+                    && lock(&REFINE).is_some());
+        if !refinable {
+            message("re2-refine works in a results buffer of matches");
+            return 0;
+        }
+
+        let filter = match prompt("Refine (regex, path:regex, !exclude): ") {
+            Some(f) if !f.trim().is_empty() => f.trim().to_string(),
+            _ => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+
+        let Some((full, mut filters, layout)) =
+            with_refine(|state, layout| (state.full.clone(), state.filters.clone(), layout))
+        else {
+            return 0;
+        };
+        filters.push(filter);
+        let refined = match filters.iter().try_fold(full, |r, f| search::refine(&r, f)) {
+            Ok(r) if r.matches.is_empty() => {
+                message("No results left after that filter - not applied");
+                return 0;
+            }
+            Ok(r) => r,
+            Err(e) => {
+                message(&e);
+                return 0;
+            }
+        };
+        with_refine(|state, _| state.filters = filters.clone());
+
+        lock(&RESULT_HIGHLIGHTS).clear();
+        if render_here(&refined, &layout, &filters) { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-refine-reset
+extern "C" fn cmd_re2_refine_reset(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_refine_reset", 0, || {
+        let reset = with_refine(|state, layout| {
+            (!state.filters.is_empty()).then(|| {
+                state.filters.clear();
+                (state.full.clone(), layout)
+            })
+        });
+        let Some((full, layout)) = reset.flatten() else {
+            message("Results are not refined");
+            return 0;
+        };
+
+        lock(&RESULT_HIGHLIGHTS).clear();
+        if render_here(&full, &layout, &[]) { 1 } else { 0 }
+    })
+}
+
+/// Run `f` on the refine state for the current results buffer, with
+/// the layout of its output
+///
+/// A buffer holding a registered directory search has its own; others
+/// share the last search's.
+fn with_refine<R>(f: impl FnOnce(&mut Refine, Layout) -> R) -> Option<R> {
+    let name = get_buffer_name()?;
+    let mut searches = lock(&RESULTS_SEARCHES);
+    if let Some(search) = searches.iter_mut().find(|s| s.buffer == name) {
+        let layout = search.layout.clone();
+        return Some(f(&mut search.refine, layout));
+    }
+    drop(searches);
+    let layout = results_layout();
+    lock(&REFINE).as_mut().map(|state| f(state, layout))
+}
+
+/// Layout of the output last written to a results buffer
+fn results_layout() -> Layout {
+    lock(&RESULTS_LAYOUT).clone().unwrap_or_default()
+}
+
+/// Layout of the current results buffer's output
+fn layout_here() -> Layout {
+    results_search_here(|s| s.layout.clone()).unwrap_or_else(results_layout)
+}
+
+/// Rewrite the current results buffer, rather than the one the next
+/// search would use
+fn render_here(result: &search::SearchResult, layout: &Layout, filters: &[String]) -> bool {
+    let previous = std::mem::replace(&mut *lock(&RESULTS_TARGET), get_buffer_name());
+    let rendered = render_results(result, layout, filters);
+    *lock(&RESULTS_TARGET) = previous;
+    rendered
+}
+
+/// Command: re2-results-list
+extern "C" fn cmd_re2_results_list(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_results_list", 0, || {
+        let listing: String = lock(&RESULTS_SEARCHES)
+            .iter()
+            .rev()
+            .map(|s| format!("{}: {}  (in {})\n", s.buffer, s.pattern, s.dir))
+            .collect();
+        if listing.is_empty() {
+            message("No results buffers");
+            return 0;
+        }
+
+        let Some(bp) = get_or_create_buffer(RE2_RESULTS_LIST_BUFFER) else {
+            message("Failed to create results list buffer");
+            return 0;
+        };
+        switch_to_buffer(bp);
+        clear_buffer(bp);
+        buffer_insert(&listing);
+        goto_line(1);
+        message("Results buffers, newest first - Enter to switch");
+        1
+    })
+}
+
+/// Switch to the results buffer listed on the current line
+fn switch_to_listed_results() -> bool {
+    let line = get_current_line().unwrap_or_default();
+    let name = lock(&RESULTS_SEARCHES)
+        .iter()
+        .find(|s| line.starts_with(&format!("{}: ", s.buffer)))
+        .map(|s| s.buffer.clone());
+    match name {
+        Some(name) if switch_to_named_buffer(&name) => true,
+        Some(name) => {
+            message(&format!("Buffer no longer exists: {}", name));
+            false
+        }
+        None => {
+            message("Not on a results buffer line");
+            false
+        }
+    }
+}
+
+/// Edits worked out by plan_replace, with notes for the summary message
+struct ReplacePlan {
+    edits: Vec<replace::LineEdit>,
+    errors: Vec<String>,
+    /// Search stopped at this many matches
+    capped: Option<usize>,
+}
+
+/// Prompt for a pattern and replacement, search, and work out the edits
+///
+/// Reports cancellation and failures itself.
+fn plan_replace(prompt_text: &str) -> Option<ReplacePlan> {
+    let (pattern, opts) = prompt_search(prompt_text, get_search_options())?;
+    let Some(replacement) = prompt(&format!("Replace {} with: ", pattern)) else {
+        message("Cancelled");
+        return None;
+    };
+
+    let dir = get_buffer_directory().unwrap_or_else(|| ".".to_string());
+    let result = match run_search(&pattern, &dir, &opts, &mut |_, _| {}) {
+        Ok(result) => result?,
+        Err(e) => {
+            message(&e);
+            return None;
+        }
+    };
+    let (edits, mut errors) = match replace::plan(&result, &pattern, &replacement, &opts) {
+        Ok(planned) => planned,
+        Err(e) => {
+            message(&e);
+            return None;
+        }
+    };
+    errors.extend(result.errors.iter().cloned());
+
+    Some(ReplacePlan {
+        edits,
+        errors,
+        capped: result.stats.capped.then_some(result.stats.matches),
+    })
+}
+
+/// Write replace edits, keeping buffers visiting the files in step
+///
+/// A file whose buffer has unsaved changes is skipped, since saving the
+/// buffer would put the old lines back. Buffers visiting a written file
+/// are refilled from it.
+fn apply_replace(edits: &[replace::LineEdit]) -> (usize, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut writable = Vec::new();
+    let mut visited = Vec::new();
+    for group in edits.chunk_by(|a, b| a.file == b.file) {
+        let file = &group[0].file;
+        let bp = find_file_buffer(&file.to_string_lossy());
+        if bp.is_some_and(buffer_modified) {
+            errors.push(format!("{}: buffer has unsaved changes, skipped", file.display()));
+            continue;
+        }
+        writable.extend_from_slice(group);
+        visited.extend(bp.map(|bp| (bp, file)));
+    }
+
+    let (applied, write_errors) = replace::apply(&writable);
+    errors.extend(write_errors);
+    for (bp, file) in visited {
+        reload_buffer(bp, file);
+    }
+    (applied, errors)
+}
+
+/// Refill an unmodified buffer from its file, keeping the cursor where
+/// it was
+fn reload_buffer(bp: *mut c_void, file: &std::path::Path) {
+    let Ok(contents) = std::fs::read(file) else {
+        return;
+    };
+    // A NUL can't be inserted, so such a buffer is left as it is rather
+    // than emptied
+    if contents.contains(&0) || get_buffer_contents(bp).as_deref() == Some(&contents[..]) {
+        return;
+    }
+    let previous = current_buffer();
+    if !switch_to_buffer(bp) {
+        return;
+    }
+    let point = get_point();
+    clear_buffer(bp);
+    buffer_insert(&String::from_utf8_lossy(&contents));
+    if let Some((line, column)) = point {
+        set_point(line, column as usize);
+    }
+    set_unmodified(bp);
+    if let Some(previous) = previous.filter(|&p| p != bp) {
+        switch_to_buffer(previous);
+    }
+}
+
+/// Command: re2-replace-preview
+///
+/// Searches like re2, then lists each changed line before and after in
+/// *re2-replace*. Nothing is written until re2-replace-apply.
+extern "C" fn cmd_re2_replace_preview(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_replace_preview", 0, || {
+        let Some(ReplacePlan { edits, errors, capped }) = plan_replace("RE2 replace pattern: ") else {
+            return 0;
+        };
+
+        let Some(bp) = get_or_create_buffer(RE2_REPLACE_BUFFER) else {
+            message("Failed to create replace buffer");
+            return 0;
+        };
+        switch_to_buffer(bp);
+        clear_buffer(bp);
+        buffer_insert(&replace::format_preview(&edits));
+        goto_line(1);
+
+        let mut summary = format!(
+            "{} lines to change in {} files - re2-replace-apply writes them",
+            edits.len(),
+            replace::file_count(&edits)
+        );
+        if let Some(matches) = capped {
+            summary.push_str(&format!(" (search stopped at {} matches)", matches));
+        }
+        if let Some(note) = write_errors(&errors) {
+            summary = format!("{}; {}", summary, note);
+        }
+        message(&summary);
+
+        *lock(&REPLACE_PLAN) = Some(edits);
+        1
+    })
+}
+
+/// Command: re2-replace-interactive
+///
+/// Visits each line a replacement would change and asks y/n/a/q. Accepted
+/// edits are written together at the end, each file once, and the
+/// buffers visited along the way are refilled from the written files.
+extern "C" fn cmd_re2_replace_interactive(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_replace_interactive", 0, || {
+        let Some(ReplacePlan { edits, mut errors, capped }) =
+            plan_replace("RE2 replace interactively: ")
+        else {
+            return 0;
+        };
+        if edits.is_empty() {
+            message("Nothing to replace");
+            return 0;
+        }
+
+        let total = edits.len();
+        let mut accepted = Vec::new();
+        let mut all = false;
+        'edits: for (i, edit) in edits.into_iter().enumerate() {
+            while !all {
+                let path = edit.file.to_string_lossy();
+                if find_file_line(&path, edit.line_number as i32) {
+                    set_point(edit.line_number as i32, replace::first_change(&edit));
+                }
+                update_display();
+
+                let question = format!("Replace this? (y/n/a/q) [{}/{}] {}: ", i + 1, total, edit.after.trim());
+                match prompt(&question).as_deref().map(replace::Answer::parse) {
+                    Some(Some(replace::Answer::Yes)) => break,
+                    Some(Some(replace::Answer::No)) => continue 'edits,
+                    Some(Some(replace::Answer::All)) => all = true,
+                    Some(Some(replace::Answer::Quit)) | None => break 'edits,
+                    Some(None) => message("Answer y, n, a or q"),
+                }
+            }
+            accepted.push(edit);
+        }
+
+        if accepted.is_empty() {
+            message("Nothing replaced");
+            return 0;
+        }
+        let (applied, write_errs) = apply_replace(&accepted);
+        errors.extend(write_errs);
+
+        let mut summary = format!(
+            "Replaced {} of {} lines in {} files",
+            applied,
+            total,
+            replace::file_count(&accepted)
+        );
+        if let Some(matches) = capped {
+            summary.push_str(&format!(" (search stopped at {} matches)", matches));
+        }
+        if let Some(note) = write_errors(&errors) {
+            summary = format!("{}; {}", summary, note);
+        }
+        message(&summary);
+        if applied > 0 { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-replace-apply
+extern "C" fn cmd_re2_replace_apply(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_replace_apply", 0, || {
+        let edits = match lock(&REPLACE_PLAN).clone() {
+            Some(edits) if !edits.is_empty() => edits,
+            _ => {
+                message("Nothing to replace - run re2-replace-preview first");
+                return 0;
+            }
+        };
+
+        let files = replace::file_count(&edits);
+        if !prompt_yn(&format!("Replace {} lines in {} files?", edits.len(), files)) {
+            message("Cancelled");
+            return 0;
+        }
+
+        let (applied, errors) = apply_replace(&edits);
+        *lock(&REPLACE_PLAN) = None;
+
+        let mut summary = format!("Replaced {} of {} lines in {} files", applied, edits.len(), files);
+        if let Some(note) = write_errors(&errors) {
+            summary = format!("{}; {}", summary, note);
+        }
+        message(&summary);
+        if applied > 0 { 1 } else { 0 }
+    })
+}
+
+/// Command: re2-save-search
+extern "C" fn cmd_re2_save_search(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_save_search", 0, || {
+        let Some(pattern) = lock(&LAST_PATTERN).clone() else {
+            message("No search to save yet");
+            return 0;
+        };
+        let opts = lock(&LAST_OPTIONS)
+            .clone()
+            .unwrap_or_else(get_search_options);
+
+        let name = match prompt(&format!("Save search '{}' as: ", pattern)) {
+            Some(n) if !n.trim().is_empty() => n.trim().to_string(),
+            _ => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+
+        let exists = lock(&SAVED_STATE)
+            .as_ref()
+            .is_some_and(|s| s.find_saved(&name).is_some());
+        if exists && !prompt_yn(&format!("Overwrite saved search '{}'?", name)) {
+            message("Not saved");
+            return 0;
+        }
+
+        lock(&SAVED_STATE)
+            .get_or_insert_with(State::default)
+            .put_saved(SavedSearch {
+                name: name.clone(),
+                pattern,
+                opts,
+            });
+        persist_state();
+        message(&format!("Saved search '{}'", name));
+        1
+    })
+}
+
+/// Command: re2-run-saved
+extern "C" fn cmd_re2_run_saved(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_run_saved", 0, || {
+        let names: Vec<String> = lock(&SAVED_STATE)
+            .as_ref()
+            .map(|s| s.saved.iter().map(|saved| saved.name.clone()).collect())
+            .unwrap_or_default();
+        if names.is_empty() {
+            message("No saved searches (use re2-save-search)");
+            return 0;
+        }
+
+        let name = match prompt("Run saved search (empty to list): ") {
+            Some(n) => n.trim().to_string(),
+            None => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+
+        if name.is_empty() {
+            return if show_saved_searches() { 1 } else { 0 };
+        }
+        if run_saved_search(&name) { 1 } else { 0 }
+    })
+}
+
+/// List saved searches as `name: pattern` lines
+fn show_saved_searches() -> bool {
+    let listing: String = match lock(&SAVED_STATE).as_ref() {
+        Some(state) => state
+            .saved
+            .iter()
+            .map(|s| format!("{}: {}\n", s.name, s.pattern))
+            .collect(),
+        None => return false,
+    };
+
+    let Some(bp) = get_or_create_buffer(RE2_SAVED_BUFFER) else {
+        message("Failed to create saved searches buffer");
+        return false;
+    };
+    switch_to_buffer(bp);
+    clear_buffer(bp);
+    buffer_insert(&listing);
+    goto_line(1);
+    message("Saved searches - Enter to run");
+    true
+}
+
+/// Run a saved search by name from the project root
+fn run_saved_search(name: &str) -> bool {
+    let saved = lock(&SAVED_STATE)
+        .as_ref()
+        .and_then(|s| s.find_saved(name).cloned());
+    let Some(saved) = saved else {
+        message(&format!("No saved search named '{}'", name));
+        return false;
+    };
+
+    let dir = get_buffer_directory().unwrap_or_else(|| ".".to_string());
+    do_search_in(&saved.pattern, &project_root(&dir), saved.opts)
+}
+
+/// Run the saved search listed on the current line
+fn run_saved_entry() -> bool {
+    let line = get_current_line().unwrap_or_default();
+    let name = lock(&SAVED_STATE).as_ref().and_then(|state| {
+        state
+            .saved
+            .iter()
+            .find(|s| line.starts_with(&format!("{}: ", s.name)))
+            .map(|s| s.name.clone())
+    });
+    match name {
+        Some(name) => run_saved_search(&name),
+        None => {
+            message("Not on a saved search line");
+            false
+        }
+    }
+}
+
+/// Command: re2-case
+extern "C" fn cmd_re2_toggle_case(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_toggle_case", 0, || {
+        let mut new_val = false;
+        update_search_options(|opts| {
+            opts.case_insensitive = !opts.case_insensitive;
+            new_val = opts.case_insensitive;
+        });
+        message(&format!(
+            "Case insensitive: {}",
+            if new_val { "ON" } else { "OFF" }
+        ));
+        1
+    })
+}
+
+/// Command: re2-smart
+extern "C" fn cmd_re2_toggle_smart(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_toggle_smart", 0, || {
+        let mut new_val = false;
+        update_search_options(|opts| {
+            opts.smart_case = !opts.smart_case;
+            new_val = opts.smart_case;
+        });
+        message(&format!(
+            "Smart case: {}",
+            if new_val { "ON" } else { "OFF" }
+        ));
+        1
+    })
+}
+
+/// Command: re2-word-boundary
+extern "C" fn cmd_re2_toggle_word_boundary(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_toggle_word_boundary", 0, || {
+        let mut new_val = false;
+        update_search_options(|opts| {
+            opts.word_boundary = !opts.word_boundary;
+            new_val = opts.word_boundary;
+        });
+        message(&format!(
+            "Word boundary: {}",
+            if new_val { "ON" } else { "OFF" }
+        ));
+        1
+    })
+}
+
+/// Command: re2-hidden
+extern "C" fn cmd_re2_toggle_hidden(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_toggle_hidden", 0, || {
+        let mut new_val = false;
+        update_search_options(|opts| {
+            opts.hidden = !opts.hidden;
+            new_val = opts.hidden;
+        });
+        message(&format!(
+            "Hidden files: {}",
+            if new_val { "INCLUDED" } else { "EXCLUDED" }
+        ));
+        1
+    })
+}
+
+/// Command: re2-gitignore
+extern "C" fn cmd_re2_toggle_gitignore(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_toggle_gitignore", 0, || {
+        let mut new_val = false;
+        update_search_options(|opts| {
+            opts.git_ignore = !opts.git_ignore;
+            new_val = opts.git_ignore;
+        });
+        message(&format!(
+            ".gitignore: {}",
+            if new_val { "RESPECTED" } else { "IGNORED" }
+        ));
+        1
+    })
+}
+
+/// Command: re2-mmap
+extern "C" fn cmd_re2_toggle_mmap(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_toggle_mmap", 0, || {
+        let mut new_val = false;
+        update_search_options(|opts| {
+            opts.mmap = !opts.mmap;
+            new_val = opts.mmap;
+        });
+        message(&format!(
+            "Memory-mapped reads: {}",
+            if new_val { "ON" } else { "OFF (buffered)" }
+        ));
+        1
+    })
+}
+
+/// Command: re2-grouping
+extern "C" fn cmd_re2_toggle_grouping(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_toggle_grouping", 0, || {
+        let mut new_val = false;
+        update_search_options(|opts| {
+            opts.group_by_file = !opts.group_by_file;
+            new_val = opts.group_by_file;
+        });
+        message(&format!(
+            "Results format: {}",
+            if new_val { "GROUPED (heading per file)" } else { "FLAT (file:line:col)" }
+        ));
+        1
+    })
+}
+
+/// Command: re2-threads
+extern "C" fn cmd_re2_set_threads(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_set_threads", 0, || {
+        let answer = match prompt("Search threads (0 = auto): ") {
+            Some(a) => a,
+            None => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+
+        let requested: usize = match answer.trim().parse() {
+            Ok(n) => n,
+            Err(_) => {
+                message(&format!("Not a thread count: {}", answer.trim()));
+                return 0;
+            }
+        };
+
+        let (threads, clamped) = search::clamp_threads(requested);
+        update_search_options(|opts| opts.threads = threads);
+
+        if clamped {
+            message(&format!("Threads: {} (clamped from {})", threads, requested));
+        } else if threads == 0 {
+            message("Threads: auto");
+        } else {
+            message(&format!("Threads: {}", threads));
+        }
+        1
+    })
+}
+
+/// Command: re2-max-filesize
+extern "C" fn cmd_re2_set_max_filesize(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_set_max_filesize", 0, || {
+        let answer = match prompt("Max file size (e.g. 500K, 2M, 1G; 0 = none): ") {
+            Some(a) => a,
+            None => {
+                message("Cancelled");
+                return 0;
+            }
+        };
+
+        let size = match search::parse_size(&answer) {
+            Ok(size) => size,
+            Err(e) => {
+                message(&e);
+                return 0;
+            }
+        };
+        update_search_options(|opts| opts.max_filesize = size);
+
+        match size {
+            Some(bytes) => message(&format!("Max file size: {}", search::format_size(bytes))),
+            None => message("Max file size: none"),
+        }
+        1
+    })
+}
+
+/// Where a results line points; columns are 1-based
+#[derive(Clone)]
+enum JumpTarget {
+    File { path: String, line: i32, column: usize },
+    Buffer { name: String, line: i32, column: usize },
+}
+
+impl JumpTarget {
+    /// `file:line` or `buffer:line`, also used to detect repeat previews
+    fn label(&self) -> String {
+        match self {
+            JumpTarget::File { path, line, .. } => format!("{}:{}", path, line),
+            JumpTarget::Buffer { name, line, .. } => format!("{}:{}", name, line),
+        }
+    }
+}
+
+/// Core goto logic - jump to file:line from current line
+///
+/// With config `jump_split` the file opens in the other window, leaving
+/// the results visible; focus moves to the file either way.
+fn do_goto() -> bool {
+    let target = match target_at_point() {
+        Ok(target) => target,
+        Err(msg) => {
+            message(&msg);
+            return false;
+        }
+    };
+    note_visited();
+    remember_jump();
+
+    if config_bool("jump_split", false) {
+        if let Some(file_wp) = current_window().and_then(other_window) {
+            if switch_to_window(file_wp) {
+                return open_target(&target);
+            }
+        }
+    }
+    open_target(&target)
+}
+
+/// Push a place onto the jump stack, ignoring a repeat of the newest
+fn push_jump(stack: &mut Vec<Jump>, jump: Jump) {
+    if stack.last() == Some(&jump) {
+        return;
+    }
+    stack.push(jump);
+    if stack.len() > JUMP_STACK_DEPTH {
+        stack.remove(0);
+    }
+}
+
+/// Remember the cursor position before leaving it, for re2-jump-back
+fn remember_jump() {
+    let (Some(buffer), Some((line, column))) = (get_buffer_name(), get_point()) else {
+        return;
+    };
+    push_jump(&mut lock(&JUMP_STACK), Jump { buffer, line, column });
+}
+
+/// Command: re2-jump-back
+///
+/// Returns to where the cursor was before the last jump to a result, or
+/// before the last search. Places in buffers killed since are skipped.
+extern "C" fn cmd_re2_jump_back(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_jump_back", 0, || loop {
+        let Some(jump) = lock(&JUMP_STACK).pop() else {
+            message("No earlier location to jump back to");
+            return 0;
+        };
+        if switch_to_named_buffer(&jump.buffer) {
+            set_point(jump.line, jump.column.max(0) as usize);
+            let left = lock(&JUMP_STACK).len();
+            message(&format!("Back to {}:{} ({} more)", jump.buffer, jump.line, left));
+            return 1;
+        }
+    })
+}
+
+/// Record the matches a results buffer lists, and step through them next
+///
+/// Each match pairs with the output's next result line.
+fn record_listing(result: &search::SearchResult, layout: &Layout, output: &str) {
+    let buffer = results_buffer();
+    // A registered buffer holds a directory search, as in target_at_point
+    let registered = results_search(&buffer, |s| s.dir.clone());
+    let buffers = registered.is_none() && matches!(*lock(&RESULTS_KIND), ResultsKind::Buffers);
+    let dir = registered.or_else(|| lock(&LAST_DIR).clone());
+
+    let lines = output
+        .lines()
+        .enumerate()
+        .filter(|(_, text)| search::is_result_line(text, layout))
+        .map(|(i, _)| i as i32 + 1);
+    let steps = result
+        .matches
+        .iter()
+        .zip(lines)
+        .map(|(m, results_line)| {
+            let file = m.file.to_string_lossy().into_owned();
+            let (line, column) = (m.line_number as i32, m.file_column.unwrap_or(m.column) + 1);
+            let target = if buffers {
+                JumpTarget::Buffer { name: file, line, column }
+            } else {
+                let path = resolve_result_path(dir.as_deref(), &file);
+                JumpTarget::File { path, line, column }
+            };
+            MatchStep { target, results_line }
+        })
+        .collect();
+
+    forget_listing(&buffer);
+    *lock(&STEPPING) = Some(buffer.clone());
+    lock(&LISTINGS).push(Listing { buffer, steps, index: None });
+}
+
+/// Drop a results buffer's listing, as its contents are going
+fn forget_listing(buffer: &str) {
+    lock(&LISTINGS).retain(|l| l.buffer != buffer);
+}
+
+/// Index of the match listed on a results buffer line
+fn listed_index(listing: &Listing, line: i32) -> Option<usize> {
+    listing.steps.binary_search_by_key(&line, |s| s.results_line).ok()
+}
+
+/// The match listed on the line under point, if this buffer has a listing
+fn listed_target_at_point() -> Option<JumpTarget> {
+    let (buffer, (line, _)) = (get_buffer_name()?, get_point()?);
+    let listings = lock(&LISTINGS);
+    let listing = listings.iter().find(|l| l.buffer == buffer)?;
+    Some(listing.steps[listed_index(listing, line)?].target.clone())
+}
+
+/// Note that the results line under point was visited (Enter, n/p), so
+/// re2-next-match continues from there
+fn note_visited() {
+    let (Some(buffer), Some((line, _))) = (get_buffer_name(), get_point()) else {
+        return;
+    };
+    let mut listings = lock(&LISTINGS);
+    let Some(listing) = listings.iter_mut().find(|l| l.buffer == buffer) else {
+        return;
+    };
+    if let Some(i) = listed_index(listing, line) {
+        listing.index = Some(i);
+        *lock(&STEPPING) = Some(buffer);
+    }
+}
+
+/// Command: re2-next-match
+extern "C" fn cmd_re2_next_match(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_next_match", 0, || if step_match(1) { 1 } else { 0 })
+}
+
+/// Command: re2-prev-match
+extern "C" fn cmd_re2_prev_match(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_prev_match", 0, || if step_match(-1) { 1 } else { 0 })
+}
+
+/// Visit the match after (or before) the last one visited, from any
+/// buffer, wrapping at the ends
+///
+/// Called from the results buffer itself, the match opens in the other
+/// window when there is one. A visible results buffer moves its cursor
+/// to the match's line.
+fn step_match(step: c_int) -> bool {
+    let stepping = lock(&STEPPING).clone();
+    let visit = lock(&LISTINGS)
+        .iter_mut()
+        .find(|l| Some(&l.buffer) == stepping.as_ref())
+        .and_then(|listing| {
+            let (index, wrapped) = step_index(listing.index, listing.steps.len(), step)?;
+            listing.index = Some(index);
+            let total = listing.steps.len();
+            Some((listing.buffer.clone(), listing.steps[index].clone(), index, total, wrapped))
+        });
+    let Some((buffer, visit, index, total, wrapped)) = visit else {
+        message("No search results to step through");
+        return false;
+    };
+
+    let in_results = get_buffer_name().as_deref() == Some(buffer.as_str());
+    if in_results {
+        goto_line(visit.results_line);
+        if let Some(file_wp) = current_window().and_then(other_window) {
+            switch_to_window(file_wp);
+        }
+    } else {
+        move_results_cursor(&buffer, visit.results_line);
+    }
+
+    if !open_target(&visit.target) {
+        return false;
+    }
+
+    let wrap_note = match (wrapped, step > 0) {
+        (false, _) => "",
+        (true, true) => ", wrapped to first result",
+        (true, false) => ", wrapped to last result",
+    };
+    message(&format!("{} ({} of {}{})", visit.target.label(), index + 1, total, wrap_note));
+    update_display();
+    true
+}
+
+/// Index `step` moves to from `current` among `len` matches, and whether
+/// it wrapped; nothing is current before the first step
+fn step_index(current: Option<usize>, len: usize, step: c_int) -> Option<(usize, bool)> {
+    let last = len.checked_sub(1)?;
+    Some(match (current, step > 0) {
+        (None, true) => (0, false),
+        (None, false) => (last, false),
+        (Some(i), true) if i >= last => (0, true),
+        (Some(i), true) => (i + 1, false),
+        (Some(0), false) => (last, true),
+        (Some(i), false) => (i - 1, false),
+    })
+}
+
+/// Put the cursor of a window showing `buffer` on `line`, if one is on
+/// screen, keeping focus where it is
+fn move_results_cursor(buffer: &str, line: i32) {
+    let Some(here_wp) = current_window() else {
+        return;
+    };
+    let mut seen = Vec::new();
+    for wp in (0..MAX_WINDOW_ROWS).filter_map(window_at_row) {
+        if wp == here_wp || seen.contains(&wp) {
+            continue;
+        }
+        seen.push(wp);
+        if !switch_to_window(wp) {
+            return;
+        }
+        if get_buffer_name().as_deref() == Some(buffer) {
+            goto_line(line);
+        }
+    }
+    switch_to_window(here_wp);
+}
+
+/// Open a jump target in the current window, with the cursor on its column
+fn open_target(target: &JumpTarget) -> bool {
+    match target {
+        JumpTarget::Buffer { name, line, column } => {
+            if switch_to_named_buffer(name) {
+                set_point(*line, column.saturating_sub(1));
+                message(&target.label());
+                return true;
+            }
+            message(&format!("Buffer no longer exists: {}", name));
+            false
+        }
+        JumpTarget::File { path, line, column } => {
+            if search::is_compressed(std::path::Path::new(path)) {
+                message(&format!("Can't jump into a compressed file yet: {}", target.label()));
+                return false;
+            }
+            if find_file_line(path, *line) {
+                set_point(*line, column.saturating_sub(1));
+                message(&target.label());
+                true
+            } else {
+                message(&format!("Failed to open: {}", path));
+                false
+            }
+        }
+    }
+}
+
+/// Resolve the results line under point; errors are user-facing messages
+fn target_at_point() -> Result<JumpTarget, String> {
+    if let Some(target) = listed_target_at_point() {
+        return Ok(target);
+    }
+    let line = get_current_line().ok_or("No line content")?;
+
+    // A registered buffer holds a directory search, whatever was shown last
+    if let Some((dir, layout)) = results_search_here(|s| (s.dir.clone(), s.layout.clone())) {
+        let loc = result_location(&line, &layout)?;
+        return Ok(JumpTarget::File {
+            path: resolve_result_path(Some(&dir), &loc.file),
+            line: loc.line as i32,
+            column: loc.column,
+        });
+    }
+
+    // File listings hold bare relative paths rather than file:line results
+    let list_root = match &*lock(&RESULTS_KIND) {
+        ResultsKind::FileList(root) => Some(root.clone()),
+        _ => None,
+    };
+    if let Some(root) = list_root {
+        return listed_file_target(&root, &line);
+    }
+
+    // Count lines are `file: N` - open the file at its top
+    if matches!(*lock(&RESULTS_KIND), ResultsKind::Counts) {
+        return match line.rsplit_once(": ") {
+            Some((file, count)) if count.trim().parse::<u64>().is_ok() => {
+                listed_file_target("", file)
+            }
+            _ => Err("Not on a count line".to_string()),
+        };
+    }
+
+    let loc = result_location(&line, &results_layout())?;
+
+    // Buffer results have no file on disk - switch to the buffer instead
+    if matches!(*lock(&RESULTS_KIND), ResultsKind::Buffers) {
+        Ok(JumpTarget::Buffer {
+            name: loc.file,
+            line: loc.line as i32,
+            column: loc.column,
+        })
+    } else {
+        Ok(JumpTarget::File {
+            path: resolve_result_path(lock(&LAST_DIR).as_deref(), &loc.file),
+            line: loc.line as i32,
+            column: loc.column,
+        })
+    }
+}
+
+/// File (or buffer), line and column a flat results line, read with the
+/// template it was written with, or a grouped entry under its heading,
+/// points at
+fn result_location(line: &str, layout: &Layout) -> Result<search::Location, String> {
+    let template = match &layout.style {
+        Style::Grouped => return grouped_location(),
+        Style::Flat(template) => template,
+    };
+    if line.contains(" ACROSS ") || line.contains(RE2_ERRORS_BUFFER) || line.is_empty() {
+        return Err("Not on a result line".to_string());
+    }
+
+    template.parse_line(line).ok_or_else(|| "Not a valid result line".to_string())
+}
+
+/// File to open for a result path: relative paths are joined onto the
+/// directory the search ran in, so jumps don't depend on the editor's cwd
+fn resolve_result_path(root: Option<&str>, file: &str) -> String {
+    let path = std::path::Path::new(file);
+    match root {
+        Some(root) if path.is_relative() => std::path::Path::new(root).join(path).to_string_lossy().into_owned(),
+        _ => file.to_string(),
+    }
+}
+
+/// Locate a grouped-output entry, pairing it with its file heading
+fn grouped_location() -> Result<search::Location, String> {
+    let (Some(bp), Some((cur_line, _))) = (current_buffer(), get_point()) else {
+        return Err("Cannot read results buffer".to_string());
+    };
+
+    search::parse_grouped_location(cur_line.max(1) as usize, |n| get_line_at(bp, n as i32))
+        .ok_or_else(|| "Not on a result line".to_string())
+}
+
+/// Resolve a re2-files listing entry to line 1 of the file
+fn listed_file_target(root: &str, line: &str) -> Result<JumpTarget, String> {
+    let rel = line.trim();
+    let path = std::path::Path::new(root).join(rel);
+    if rel.is_empty() || !path.is_file() {
+        return Err("Not on a file line".to_string());
+    }
+
+    Ok(JumpTarget::File {
+        path: path.to_string_lossy().into_owned(),
+        line: 1,
+        column: 1,
+    })
+}
+
+/// Command: re2-preview
+extern "C" fn cmd_re2_toggle_preview(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_toggle_preview", 0, || {
+        let enabled = !PREVIEW_MODE.fetch_xor(true, Ordering::SeqCst);
+        *lock(&PREVIEW) = PreviewState {
+            last_move: None,
+            pending: false,
+            shown: None,
+        };
+        message(&format!(
+            "Preview mode: {}",
+            if enabled { "ON (C-n/C-p in results)" } else { "OFF" }
+        ));
+        1
+    })
+}
+
+/// Toggle follow mode (F), previewing the result under point at once
+fn toggle_auto_preview() {
+    let enabled = !AUTO_PREVIEW.fetch_xor(true, Ordering::SeqCst);
+    *lock(&PREVIEW) = PreviewState {
+        last_move: None,
+        pending: false,
+        shown: None,
+    };
+    if enabled {
+        message("Auto preview: ON (C-n/C-p show each result's context)");
+        preview_at_point();
+    } else {
+        message("Auto preview: OFF");
+    }
+}
+
+/// Handle a key in the results buffer while preview or follow mode is on
+///
+/// C-n/C-p are consumed here so the preview sees the new point. Other keys
+/// flush a preview the debounce held back, then pass through.
+fn preview_key(key: c_int) -> bool {
+    let step = match key {
+        14 => 1,  // C-n
+        16 => -1, // C-p
+        _ => {
+            let pending = std::mem::take(&mut lock(&PREVIEW).pending);
+            if pending {
+                preview_at_point();
+            }
+            return false;
+        }
+    };
+
+    let Some((line, _)) = get_point() else {
+        return false;
+    };
+    goto_line((line + step).max(1));
+
+    let now = std::time::Instant::now();
+    let moving_fast = {
+        let mut state = lock(&PREVIEW);
+        let fast = state
+            .last_move
+            .map(|t| now.duration_since(t).as_millis() < PREVIEW_DEBOUNCE_MS)
+            .unwrap_or(false);
+        state.last_move = Some(now);
+        state.pending = fast;
+        fast
+    };
+
+    if !moving_fast {
+        preview_at_point();
+    }
+    update_display();
+    true
+}
+
+/// Show the result under point in the other window, keeping focus here
+///
+/// Follow mode shows its surrounding lines; otherwise the file opens.
+fn preview_at_point() {
+    let Ok(target) = target_at_point() else {
+        return;
+    };
+    let label = target.label();
+    if lock(&PREVIEW).shown.as_deref() == Some(label.as_str()) {
+        return;
+    }
+
+    if AUTO_PREVIEW.load(Ordering::SeqCst) {
+        if show_context(&target) {
+            lock(&PREVIEW).shown = Some(label);
+        }
+        return;
+    }
+    match open_in_other_window(&target) {
+        Some(true) => {
+            message(&format!("Preview: {}", label));
+            lock(&PREVIEW).shown = Some(label);
+        }
+        Some(false) => {}
+        None => message("Preview needs a second window"),
+    }
+}
+
+/// Open a target in the other window and come back to this one
+///
+/// Returns None when there is no other window.
+fn open_in_other_window(target: &JumpTarget) -> Option<bool> {
+    let results_wp = current_window()?;
+    let other_wp = other_window(results_wp)?;
 
-    message(&format!("Searching for: {} in {}...", pattern, search_dir));
-    update_display();
+    if !switch_to_window(other_wp) {
+        return Some(false);
+    }
+    let opened = open_target(target);
+    switch_to_window(results_wp);
+    Some(opened)
+}
 
-    let opts = get_search_options();
-    let result = match search::search_parallel(pattern, &search_dir, &opts) {
-        Ok(r) => r,
-        Err(e) => {
-            message(&format!("Search error: {}", e));
+/// Open the result under point in the other window, staying here (o)
+///
+/// With a single window this jumps in place like Enter, since the editor
+/// API has no way to split.
+fn open_other_at_point() -> bool {
+    let target = match target_at_point() {
+        Ok(t) => t,
+        Err(msg) => {
+            message(&msg);
             return false;
         }
     };
 
-    if result.matches.is_empty() {
-        message(&format!(
-            "No matches ({} files searched in {}ms)",
-            result.stats.files_searched, result.stats.elapsed_ms
-        ));
-        return true;
+    match open_in_other_window(&target) {
+        Some(opened) => opened,
+        None => {
+            let opened = open_target(&target);
+            if opened {
+                message(&format!("{} (only one window - split to keep the results visible)", target.label()));
+            }
+            opened
+        }
     }
+}
 
-    let bp = match get_or_create_buffer(RE2_RESULTS_BUFFER) {
-        Some(b) => b,
-        None => {
-            message("Failed to create results buffer");
+/// Show the lines around the result under point (v)
+fn preview_context_at_point() -> bool {
+    match target_at_point() {
+        Ok(target) => show_context(&target),
+        Err(msg) => {
+            message(&msg);
+            false
+        }
+    }
+}
+
+/// Show the lines around a target
+///
+/// They go to *re2-preview* in the other window, with the match line
+/// marked, and point stays in the results. With a single window only the
+/// match line is shown, in the message area.
+fn show_context(target: &JumpTarget) -> bool {
+    let (JumpTarget::File { line, column, .. } | JumpTarget::Buffer { line, column, .. }) = *target;
+    let line = line.max(1) as u64;
+
+    let lines = match read_context(target, line) {
+        Ok(lines) => lines,
+        Err(msg) => {
+            message(&msg);
             return false;
         }
     };
+    let Some(at) = lines.iter().position(|(n, _)| *n == line) else {
+        message(&format!("{} is past the end of the file now", target.label()));
+        return false;
+    };
 
-    switch_to_buffer(bp);
-    clear_buffer(bp);
+    let preview = format!(
+        "{}\n\n{}",
+        target.label(),
+        search::format_context(&lines, line, column)
+    );
+    // Title, blank line, then the context lines up to the match
+    match show_in_other_window(RE2_PREVIEW_BUFFER, &preview, at as i32 + 3) {
+        Some(true) => true,
+        Some(false) => false,
+        None => {
+            message(&format!("{}: {}", target.label(), lines[at].1.trim()));
+            true
+        }
+    }
+}
 
-    let output = search::format_results_with_stats(&result);
-    buffer_insert(&output);
+/// Lines around `line` of a target, numbered from 1
+///
+/// An open buffer is read in preference to the file, so unsaved edits
+/// show. Errors are user-facing messages.
+fn read_context(target: &JumpTarget, line: u64) -> Result<Vec<(u64, String)>, String> {
+    let from_buffer = |bp| {
+        let contents = get_buffer_contents(bp).ok_or("Cannot read buffer")?;
+        search::context_lines(&contents[..], line, PREVIEW_CONTEXT_LINES).map_err(|e| e.to_string())
+    };
 
-    goto_line(3);
+    let path = match target {
+        JumpTarget::Buffer { name, .. } => {
+            let bp = find_named_buffer(name).ok_or_else(|| format!("Buffer no longer exists: {}", name))?;
+            return from_buffer(bp);
+        }
+        JumpTarget::File { path, .. } => path,
+    };
+    if let Some(bp) = find_file_buffer(path) {
+        return from_buffer(bp);
+    }
+    if search::is_compressed(std::path::Path::new(path)) {
+        return Err(format!("Can't preview a compressed file yet: {}", path));
+    }
 
-    message(&format!(
-        "{} matches in {} files ({}ms) - Enter to jump",
-        result.stats.matches, result.stats.files_matched, result.stats.elapsed_ms
-    ));
-    true
+    let file = std::fs::File::open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => format!("File no longer exists: {}", path),
+        _ => format!("{}: {}", path, e),
+    })?;
+    let size = file.metadata().map_or(0, |meta| meta.len());
+    let reader = std::io::BufReader::new(std::io::Read::take(file, PREVIEW_SCAN_BYTES));
+    let lines = search::context_lines(reader, line, PREVIEW_CONTEXT_LINES)
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    if size > PREVIEW_SCAN_BYTES && !lines.iter().any(|(n, _)| *n == line) {
+        return Err(format!(
+            "{}:{} is too far into a large file to preview",
+            path, line
+        ));
+    }
+    Ok(lines)
 }
 
-/// Command: re2
-extern "C" fn cmd_re2_search(_f: c_int, _n: c_int) -> c_int {
-    let pattern = match prompt("RE2 pattern: ") {
-        Some(p) if !p.is_empty() => p,
-        _ => {
-            message("Cancelled");
-            return 0;
+/// Fill a buffer with `text` in the other window, put its point on
+/// `line`, and come back to this one
+///
+/// Returns None when there is no other window.
+fn show_in_other_window(name: &str, text: &str, line: i32) -> Option<bool> {
+    let here_wp = current_window()?;
+    let other_wp = other_window(here_wp)?;
+
+    if !switch_to_window(other_wp) {
+        return Some(false);
+    }
+    let shown = match get_or_create_buffer(name) {
+        Some(bp) => {
+            switch_to_buffer(bp);
+            clear_buffer(bp);
+            buffer_insert(text);
+            goto_line(line);
+            true
         }
+        None => false,
     };
+    switch_to_window(here_wp);
+    Some(shown)
+}
 
-    if do_search(&pattern) { 1 } else { 0 }
+/// Whether n/p stop on a results line
+fn is_result_line(text: &str) -> bool {
+    match &*lock(&RESULTS_KIND) {
+        ResultsKind::FileList(root) => listed_file_target(root, text).is_ok(),
+        ResultsKind::Counts => text
+            .rsplit_once(": ")
+            .is_some_and(|(_, count)| count.trim().parse::<u64>().is_ok()),
+        ResultsKind::Files | ResultsKind::Buffers => search::is_result_line(text, &layout_here()),
+    }
 }
 
-/// Command: re2-word
-extern "C" fn cmd_re2_search_word(_f: c_int, _n: c_int) -> c_int {
-    let word = match get_word_at_point() {
-        Some(w) if !w.is_empty() => w,
-        _ => {
-            message("No word at point");
-            return 0;
+/// Move to the next (`step` 1) or previous (-1) result and open it
+///
+/// Wraps around at either end. The result opens in the other window
+/// unless config `np_other_window` is off.
+fn step_result(step: c_int) -> bool {
+    let (Some(bp), Some((cur, _))) = (current_buffer(), get_point()) else {
+        return false;
+    };
+    let Some(contents) = get_buffer_contents(bp) else {
+        return false;
+    };
+    let text = String::from_utf8_lossy(&contents);
+    let results: Vec<c_int> = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| is_result_line(line))
+        .map(|(i, _)| i as c_int + 1)
+        .collect();
+    let (Some(&first), Some(&last)) = (results.first(), results.last()) else {
+        message("No results");
+        return true;
+    };
+
+    let next = if step > 0 {
+        results.iter().find(|&&n| n > cur)
+    } else {
+        results.iter().rev().find(|&&n| n < cur)
+    };
+    let (line, wrapped) = match next {
+        Some(&n) => (n, false),
+        None if step > 0 => (first, true),
+        None => (last, true),
+    };
+    goto_line(line);
+
+    let target = match target_at_point() {
+        Ok(t) => t,
+        Err(msg) => {
+            message(&msg);
+            return true;
         }
     };
+    note_visited();
+    if config_bool("np_other_window", true) {
+        if open_in_other_window(&target).is_none() {
+            message("n/p needs a second window (or set np_other_window = false)");
+            return true;
+        }
+    } else {
+        open_target(&target);
+    }
 
-    if do_search(&word) { 1 } else { 0 }
+    if wrapped {
+        message(if step > 0 {
+            "Wrapped to first result"
+        } else {
+            "Wrapped to last result"
+        });
+    }
+    update_display();
+    true
 }
 
-/// Command: re2-case
-extern "C" fn cmd_re2_toggle_case(_f: c_int, _n: c_int) -> c_int {
-    let mut new_val = false;
-    update_search_options(|opts| {
-        opts.case_insensitive = !opts.case_insensitive;
-        new_val = opts.case_insensitive;
-    });
-    message(&format!(
-        "Case insensitive: {}",
-        if new_val { "ON" } else { "OFF" }
-    ));
-    1
+/// Command: re2-cancel
+extern "C" fn cmd_re2_cancel(_f: c_int, _n: c_int) -> c_int {
+    guarded("cmd_re2_cancel", 0, || {
+        if cancel_search() {
+            1
+        } else {
+            message("No search running");
+            0
+        }
+    })
 }
 
-/// Command: re2-smart
-extern "C" fn cmd_re2_toggle_smart(_f: c_int, _n: c_int) -> c_int {
-    let mut new_val = false;
-    update_search_options(|opts| {
-        opts.smart_case = !opts.smart_case;
-        new_val = opts.smart_case;
-    });
-    message(&format!(
-        "Smart case: {}",
-        if new_val { "ON" } else { "OFF" }
-    ));
-    1
+/// Ask the running search to stop; false if none is running
+fn cancel_search() -> bool {
+    if !SEARCH_ACTIVE.load(Ordering::SeqCst) {
+        return false;
+    }
+    CANCEL_BY_USER.store(true, Ordering::SeqCst);
+    SEARCH_CANCEL.store(true, Ordering::SeqCst);
+    message("Cancelling search...");
+    true
 }
 
-/// Command: re2-word-boundary
-extern "C" fn cmd_re2_toggle_word_boundary(_f: c_int, _n: c_int) -> c_int {
-    let mut new_val = false;
-    update_search_options(|opts| {
-        opts.word_boundary = !opts.word_boundary;
-        new_val = opts.word_boundary;
-    });
-    message(&format!(
-        "Word boundary: {}",
-        if new_val { "ON" } else { "OFF" }
-    ));
-    1
+/// Read keys typed while a search holds the editor thread
+///
+/// The editor only runs the key handler once the command returns, so
+/// ESC and C-g are picked up from the terminal here instead. Other keys
+/// typed meanwhile can't be handed back to the editor; they're dropped,
+/// with a reminder of how to cancel. re2-live's searches are left alone,
+/// as the keys typed over them are the next edits to the pattern.
+fn check_cancel_keys() {
+    if lock(&LIVE).is_some() {
+        return;
+    }
+    let mut keys = [0u8; 64];
+    let read = unsafe {
+        let mut stdin = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if libc::isatty(libc::STDIN_FILENO) == 0 || libc::poll(&mut stdin, 1, 0) <= 0 {
+            return;
+        }
+        libc::read(libc::STDIN_FILENO, keys.as_mut_ptr() as *mut c_void, keys.len())
+    };
+    let Ok(read) = usize::try_from(read) else {
+        return;
+    };
+    if read == 0 {
+        return;
+    }
+    if is_cancel_key(&keys[..read]) {
+        cancel_search();
+    } else {
+        message("Search running - ESC or C-g cancels it");
+    }
+    update_display();
 }
 
-/// Command: re2-hidden
-extern "C" fn cmd_re2_toggle_hidden(_f: c_int, _n: c_int) -> c_int {
-    let mut new_val = false;
-    update_search_options(|opts| {
-        opts.hidden = !opts.hidden;
-        new_val = opts.hidden;
-    });
-    message(&format!(
-        "Hidden files: {}",
-        if new_val { "INCLUDED" } else { "EXCLUDED" }
-    ));
-    1
+/// Whether raw terminal input holds C-g, or an ESC that doesn't start an
+/// escape sequence (arrow keys send ESC [ A)
+fn is_cancel_key(keys: &[u8]) -> bool {
+    keys.iter()
+        .enumerate()
+        .any(|(i, &key)| key == 7 || (key == 27 && !matches!(keys.get(i + 1), Some(b'[' | b'O'))))
 }
 
-/// Command: re2-gitignore
-extern "C" fn cmd_re2_toggle_gitignore(_f: c_int, _n: c_int) -> c_int {
-    let mut new_val = false;
-    update_search_options(|opts| {
-        opts.git_ignore = !opts.git_ignore;
-        new_val = opts.git_ignore;
-    });
-    message(&format!(
-        ".gitignore: {}",
-        if new_val { "RESPECTED" } else { "IGNORED" }
-    ));
-    1
+/// Event handler for buffer switches - abandon a detached search the
+/// user left
+///
+/// Only a detached search can see the user switch; a foreground one
+/// holds the editor until it ends (ESC and C-g still reach it through
+/// check_cancel_keys), and switches to the results buffer itself.
+extern "C" fn re2_buffer_switch_handler(_event: *mut UemacsEvent, _user_data: *mut c_void) -> bool {
+    guarded("re2_buffer_switch_handler", false, || {
+        if SEARCH_DETACHED.load(Ordering::SeqCst) && !in_results_buffer() {
+            SEARCH_CANCEL.store(true, Ordering::SeqCst);
+        }
+        false
+    })
 }
 
-/// Core goto logic - jump to file:line from current line
-fn do_goto() -> bool {
-    let line = match get_current_line() {
-        Some(l) => l,
-        None => {
-            message("No line content");
-            return false;
+/// Report a detached search's progress, and show its results once it
+/// has finished
+///
+/// Runs on the editor thread, from the key and mouse handlers, since
+/// buffer writes, messages and events must not come from the search
+/// thread. Progress shows "N files searched" at most every
+/// PROGRESS_EVENT_MS, along with a re2:search:progress event.
+fn collect_detached_search() {
+    if !SEARCH_DETACHED.load(Ordering::SeqCst) {
+        return;
+    }
+    let finished = {
+        let mut mailbox = lock(&DETACHED_MAILBOX);
+        match mailbox.finished.take() {
+            Some(finished) => finished,
+            None if throttle_due(&mut lock(&DETACHED_PROGRESS_AT), PROGRESS_EVENT_MS) => {
+                let (pattern, progress) = (mailbox.pattern.clone(), mailbox.progress.take());
+                drop(mailbox);
+                if let Some(progress) = progress {
+                    emit_search_progress(&progress);
+                }
+                message(&format!(
+                    "Searching for: {}... {} files searched",
+                    pattern,
+                    SEARCH_FILES_SEARCHED.load(Ordering::Relaxed)
+                ));
+                return;
+            }
+            None => return,
         }
     };
+    let FinishedSearch {
+        pattern,
+        dir,
+        opts,
+        target,
+        result,
+        active,
+    } = finished;
 
-    if line.contains(" ACROSS ") || line.contains("errors encountered") || line.is_empty() {
-        message("Not on a result line");
-        return false;
-    }
+    *lock(&RESULTS_TARGET) = target;
+    emit_search_done(result.as_ref().ok());
+    show_directory_search(&pattern, &dir, &opts, settle_search(result));
+    *lock(&RESULTS_TARGET) = None;
+    // Only now that the results are written may another search start
+    SEARCH_DETACHED.store(false, Ordering::SeqCst);
+    drop(active);
+    update_display();
+}
 
-    let parts: Vec<&str> = line.splitn(4, ':').collect();
-    if parts.len() < 2 {
-        message("Not a valid result line");
-        return false;
+/// Event handler for re2:search:request - search the current buffer's
+/// directory for the pattern another extension sent, as re2 would
+///
+/// Returns true (consumed) when the search ran, or started in detached
+/// mode. A missing or unreadable pattern, or a search that was refused
+/// (another running, an invalid pattern), is left for other handlers.
+extern "C" fn re2_search_request_handler(event: *mut UemacsEvent, _user_data: *mut c_void) -> bool {
+    guarded("re2_search_request_handler", false, || {
+        let Some(pattern) = (unsafe { request_pattern(event) }) else {
+            return false;
+        };
+        do_search(&pattern, get_search_options())
+    })
+}
+
+/// The pattern a re2:search:request event carries
+///
+/// The NUL is looked for within `data_size` bytes when the sender gave
+/// a size, else within MAX_REQUEST_PATTERN. No NUL there, invalid UTF-8
+/// or a blank pattern give None.
+///
+/// # Safety
+/// `event`, if not null, must point to a valid event whose data, if not
+/// null, is readable up to its NUL or size.
+unsafe fn request_pattern(event: *const UemacsEvent) -> Option<String> {
+    if event.is_null() || (*event).data.is_null() {
+        return None;
     }
+    let data = (*event).data as *const u8;
+    let limit = match (*event).data_size {
+        0 => MAX_REQUEST_PATTERN,
+        size => size,
+    };
+    let len = (0..limit).find(|&i| *data.add(i) == 0)?;
+    let pattern = std::str::from_utf8(std::slice::from_raw_parts(data, len)).ok()?;
+    (!pattern.trim().is_empty()).then(|| pattern.to_string())
+}
 
-    let file = parts[0];
-    let line_num: i32 = match parts[1].parse() {
-        Ok(n) => n,
-        Err(_) => {
-            message("Invalid line number");
+/// Event handler for mouse input - a left click on a result jumps to it
+///
+/// Clicks outside the results buffer are left to other handlers (c_mouse
+/// positions the cursor for those).
+extern "C" fn re2_mouse_event_handler(event: *mut UemacsEvent, _user_data: *mut c_void) -> bool {
+    guarded("re2_mouse_event_handler", false, || {
+        collect_detached_search();
+        let evt = unsafe {
+            if event.is_null() || (*event).data.is_null() {
+                return false;
+            }
+            &*((*event).data as *const InputKeyEvent)
+        };
+        // Left button press only
+        if evt.code != 0 || evt.mouse_button != 0 {
+            return false;
+        }
+
+        let (row, col) = (evt.mouse_y as i32, evt.mouse_x as i32);
+        let (Some(clicked), Some(current)) = (window_at_row(row), current_window()) else {
+            return false;
+        };
+        if clicked != current && !switch_to_window(clicked) {
+            return false;
+        }
+        if !in_results_buffer() {
+            if clicked != current {
+                switch_to_window(current);
+            }
             return false;
         }
-    };
 
-    if find_file_line(file, line_num) {
-        message(&format!("{}:{}", file, line_num));
+        let Some((line, offset)) = screen_to_buffer_pos(clicked, row, col) else {
+            return true;
+        };
+        set_point(line, offset.max(0) as usize);
+        do_goto();
+        update_display();
         true
-    } else {
-        message(&format!("Failed to open: {}", file));
-        false
-    }
+    })
 }
 
 /// Event handler for key input
 extern "C" fn re2_key_event_handler(event: *mut UemacsEvent, _user_data: *mut c_void) -> bool {
-    if event.is_null() {
-        return false;
+    guarded("re2_key_event_handler", false, || {
+        collect_detached_search();
+        if event.is_null() {
+            return false;
+        }
+
+        unsafe {
+            let key_ptr = (*event).data as *const c_int;
+            if key_ptr.is_null() {
+                return false;
+            }
+            let key = *key_ptr;
+
+            // ESC / C-g stop a running search
+            if (key == 27 || key == 7) && cancel_search() {
+                return true;
+            }
+
+            if live_key(key) {
+                return true;
+            }
+
+            if key == 'g' as c_int && in_results_buffer() {
+                search_again(false);
+                return true;
+            }
+
+            if key == 'o' as c_int && in_results_buffer() {
+                open_other_at_point();
+                return true;
+            }
+
+            if key == 'v' as c_int && in_results_buffer() {
+                preview_context_at_point();
+                update_display();
+                return true;
+            }
+
+            if key == 'F' as c_int && in_results_buffer() {
+                toggle_auto_preview();
+                update_display();
+                return true;
+            }
+
+            // C cycles case matching, W toggles whole words; both re-run
+            if key == 'C' as c_int && in_results_buffer() {
+                search_again_with(false, |opts| {
+                    search::CaseMode::of(opts).next().apply(opts);
+                });
+                return true;
+            }
+            if key == 'W' as c_int && in_results_buffer() {
+                search_again_with(false, |opts| opts.word_boundary = !opts.word_boundary);
+                return true;
+            }
+
+            if key == 'q' as c_int && in_results_buffer() {
+                close_results();
+                return true;
+            }
+
+            if (key == 'n' as c_int || key == 'p' as c_int) && in_results_buffer() {
+                step_result(if key == 'n' as c_int { 1 } else { -1 });
+                return true;
+            }
+
+            if key != '\r' as c_int && key != '\n' as c_int {
+                let previewing = PREVIEW_MODE.load(Ordering::SeqCst) || AUTO_PREVIEW.load(Ordering::SeqCst);
+                if previewing && in_results_buffer() {
+                    return preview_key(key);
+                }
+                return false;
+            }
+
+            match get_buffer_name().as_deref() {
+                Some(RE2_HISTORY_BUFFER) => {
+                    rerun_history_entry();
+                    return true;
+                }
+                Some(RE2_SAVED_BUFFER) => {
+                    run_saved_entry();
+                    return true;
+                }
+                Some(RE2_RESULTS_LIST_BUFFER) => {
+                    switch_to_listed_results();
+                    return true;
+                }
+                _ => {}
+            }
+
+            if !in_results_buffer() {
+                return false;
+            }
+
+            do_goto();
+            true
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::fixture_dir;
+
+    static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Held by tests that install a fake API, which is process-wide
+    static FAKE_API: Mutex<()> = Mutex::new(());
+
+    /// Fake `emit` standing in for another extension's event consumer
+    unsafe extern "C" fn record_emit(name: *const c_char, data: *mut c_void) -> bool {
+        let text = |p: *const c_char| CStr::from_ptr(p).to_string_lossy().into_owned();
+        let entry = match text(name).as_str() {
+            "re2:search:start" => {
+                let e = &*(data as *const SearchStartEvent);
+                format!("start {}", text(e.pattern))
+            }
+            "re2:search:progress" => {
+                let e = &*(data as *const SearchProgressEvent);
+                format!("progress {}", e.matches)
+            }
+            "re2:search:done" => {
+                let e = &*(data as *const SearchDoneEvent);
+                format!("done {} {}", e.matches, text(e.results_buffer))
+            }
+            other => other.to_string(),
+        };
+        lock(&EVENTS).push(entry);
+        true
     }
 
-    unsafe {
-        let key_ptr = (*event).data as *const c_int;
-        if key_ptr.is_null() {
-            return false;
+    #[test]
+    fn test_numbered_results_buffers_are_results_buffers() {
+        assert!(is_results_buffer("*re2-results*"));
+        assert!(is_results_buffer("*re2-results-2*"));
+        assert!(!is_results_buffer("*re2-results-x*"));
+        assert!(!is_results_buffer("*re2-history*"));
+    }
+
+    #[test]
+    fn test_search_emits_start_progress_done() {
+        let _api = lock(&FAKE_API);
+        lock(&EVENTS).clear();
+        *lock(&API) = Some(Api {
+            emit: Some(record_emit),
+            ..Api::default()
+        });
+        let dir = fixture_dir("events", &[("a.txt", "hit\nhit\n"), ("b.txt", "miss\nhit\n")]);
+
+        let opts = SearchOptions::default();
+        let result = run_search("hit", dir.to_str().unwrap(), &opts, &mut |_, _| {})
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.stats.matches, 3);
+
+        let events = lock(&EVENTS).clone();
+        assert_eq!(events.first().map(String::as_str), Some("start hit"));
+        assert_eq!(events.last().map(String::as_str), Some("done 3 *re2-results*"));
+        // The first batch always reports; later ones are throttled
+        let progress = &events[1..events.len() - 1];
+        assert!(!progress.is_empty());
+        assert!(progress.iter().all(|e| e.starts_with("progress ")));
+
+        *lock(&API) = None;
+    }
+
+    #[test]
+    fn test_buffer_search_emits_done() {
+        let _api = lock(&FAKE_API);
+        lock(&EVENTS).clear();
+        *lock(&API) = Some(Api {
+            emit: Some(record_emit),
+            ..Api::default()
+        });
+
+        let target = SearchTarget::Buffer("main.rs".to_string());
+        let failed = Err("Invalid pattern".to_string());
+        assert!(!show_buffer_search("unclosed(", &SearchOptions::default(), target, failed));
+        assert_eq!(lock(&EVENTS).clone(), vec!["done 0 *re2-results*".to_string()]);
+        // A failed search is not one for re2-again to repeat
+        assert_ne!(lock(&LAST_PATTERN).as_deref(), Some("unclosed("));
+
+        *lock(&API) = None;
+    }
+
+    #[test]
+    fn test_search_request_pattern_is_bounded() {
+        let event = |data: &[u8], data_size| UemacsEvent {
+            name: SEARCH_REQUEST_EVENT.as_ptr() as *const c_char,
+            data: data.as_ptr() as *mut c_void,
+            data_size,
+            consumed: false,
+        };
+        let read = |e: UemacsEvent| unsafe { request_pattern(&e) };
+
+        assert_eq!(read(event(b"fn main\0", 0)), Some("fn main".to_string()));
+        assert_eq!(read(event(b"fn main\0", 8)), Some("fn main".to_string()));
+        // No NUL within the given size
+        assert_eq!(read(event(b"fn main\0", 7)), None);
+        assert_eq!(read(event(b"  \0", 0)), None);
+        assert_eq!(read(event(b"\xff\0", 0)), None);
+        let none = UemacsEvent { data: std::ptr::null_mut(), ..event(b"", 0) };
+        assert_eq!(read(none), None);
+        assert_eq!(unsafe { request_pattern(std::ptr::null()) }, None);
+    }
+
+    #[test]
+    fn test_cancel_keys_skip_escape_sequences() {
+        assert!(is_cancel_key(b"\x07"));
+        assert!(is_cancel_key(b"\x1b"));
+        assert!(is_cancel_key(b"ab\x1b"));
+        assert!(is_cancel_key(b"\x1b[A\x1b"));
+        assert!(!is_cancel_key(b"\x1b[A"));
+        assert!(!is_cancel_key(b"\x1bOB"));
+        assert!(!is_cancel_key(b"x"));
+    }
+
+    #[test]
+    fn test_detached_search_hands_result_back() {
+        let _api = lock(&FAKE_API);
+        lock(&EVENTS).clear();
+        *lock(&API) = Some(Api {
+            emit: Some(record_emit),
+            ..Api::default()
+        });
+        let dir = fixture_dir("detached", &[("a.txt", "hit\nmiss\nhit\n")]);
+
+        assert!(spawn_search("hit", dir.to_str().unwrap(), SearchOptions::default()));
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let finished = loop {
+            if let Some(finished) = lock(&DETACHED_MAILBOX).finished.take() {
+                break finished;
+            }
+            assert!(std::time::Instant::now() < deadline, "search never finished");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert_eq!(finished.pattern, "hit");
+        assert_eq!(finished.result.as_ref().map(|r| r.stats.matches), Ok(2));
+        // The slot stays taken until the editor thread has shown the results
+        assert!(SEARCH_ACTIVE.load(Ordering::SeqCst));
+        drop(finished);
+        assert!(!SEARCH_ACTIVE.load(Ordering::SeqCst));
+        // Only the start, from the calling thread; the rest is left to the
+        // editor thread
+        assert_eq!(*lock(&EVENTS), vec!["start hit"]);
+        // Cleanup waits for the thread, so nothing outlives the library
+        stop_search_thread();
+        assert!(lock(&SEARCH_THREAD).is_none());
+
+        *lock(&API) = None;
+    }
+
+    #[test]
+    fn test_jump_stack_skips_repeats_and_caps_depth() {
+        let jump = |line| Jump { buffer: "main.rs".into(), line, column: 0 };
+        let mut stack = Vec::new();
+        push_jump(&mut stack, jump(1));
+        push_jump(&mut stack, jump(1));
+        assert_eq!(stack, vec![jump(1)]);
+
+        for line in 2..=JUMP_STACK_DEPTH as i32 + 5 {
+            push_jump(&mut stack, jump(line));
         }
-        let key = *key_ptr;
+        assert_eq!(stack.len(), JUMP_STACK_DEPTH);
+        assert_eq!(stack.first(), Some(&jump(6)));
+        assert_eq!(stack.pop(), Some(jump(JUMP_STACK_DEPTH as i32 + 5)));
+    }
 
-        if key != '\r' as c_int && key != '\n' as c_int {
-            return false;
+    #[test]
+    fn test_jump_to_file_places_cursor_on_match_column() {
+        unsafe extern "C" fn open_file(path: *const c_char, line: c_int) -> c_int {
+            let path = CStr::from_ptr(path).to_string_lossy();
+            lock(&EVENTS).push(format!("open {}:{}", path, line));
+            0
+        }
+        unsafe extern "C" fn place(line: c_int, column: c_int) {
+            lock(&EVENTS).push(format!("point {}:{}", line, column));
         }
 
-        if !in_results_buffer() {
-            return false;
+        let _api = lock(&FAKE_API);
+        lock(&EVENTS).clear();
+        *lock(&API) = Some(Api {
+            find_file_line: Some(open_file),
+            set_point: Some(place),
+            ..Api::default()
+        });
+
+        let target = JumpTarget::File {
+            path: "src/main.rs".into(),
+            line: 12,
+            column: 7,
+        };
+        assert!(open_target(&target));
+        // Results columns and the editor's set_point both count from 1
+        assert_eq!(lock(&EVENTS).clone(), vec!["open src/main.rs:12", "point 12:7"]);
+
+        *lock(&API) = None;
+    }
+
+    #[test]
+    fn test_listing_pairs_matches_with_result_lines() {
+        let opts = SearchOptions::default();
+        let text = "hit\nmiss\nhit again\n";
+        let result = search::search_buffer("hit", "/p/a.rs", text.as_bytes(), &opts).unwrap();
+
+        let grouped = Layout {
+            style: Style::Grouped,
+            ..Layout::default()
+        };
+        for layout in [Layout::default(), grouped] {
+            let output = search::format_results(&result, &layout);
+            record_listing(&result, &layout, &output);
+            let listing = lock(&LISTINGS).pop().unwrap();
+            assert_eq!(listing.steps.len(), 2);
+            let lines: Vec<&str> = output.lines().collect();
+            for (step, line) in listing.steps.iter().zip([1, 3]) {
+                let listed = lines[step.results_line as usize - 1];
+                assert!(listed.contains(&format!("{}:1", line)), "{}", listed);
+                assert!(matches!(step.target, JumpTarget::File { line: l, column: 1, .. } if l == line));
+            }
+            // Header and heading lines list nothing
+            assert_eq!(listed_index(&listing, listing.steps[1].results_line), Some(1));
+            assert_eq!(listed_index(&listing, 1), None);
         }
 
-        do_goto();
-        true
+        assert_eq!(step_index(None, 3, 1), Some((0, false)));
+        assert_eq!(step_index(None, 3, -1), Some((2, false)));
+        assert_eq!(step_index(Some(2), 3, 1), Some((0, true)));
+        assert_eq!(step_index(Some(0), 3, -1), Some((2, true)));
+        assert_eq!(step_index(Some(1), 3, 1), Some((2, false)));
+        assert_eq!(step_index(None, 0, 1), None);
+    }
+
+    #[test]
+    fn test_settings_list_config_keys() {
+        let opts = SearchOptions {
+            max_filesize: Some(2 << 20),
+            globs: vec![(false, "*.log".into()), (false, "*.tmp".into())],
+            ..SearchOptions::default()
+        };
+        let listing = format_settings(&opts);
+        assert!(listing.contains("\nsmart_case         = true "), "{}", listing);
+        assert!(listing.contains("\nmax_filesize       = 2M "));
+        assert!(listing.contains("\nglob_exclude       = \"*.log,*.tmp\" "));
+        assert!(listing.contains("\nencoding           = auto "));
+    }
+
+    #[test]
+    fn test_results_buffers_keep_newest_searches() {
+        let opts = SearchOptions::default();
+        let empty = search::search_buffer("x", "b", b"", &opts).unwrap();
+        let search = |buffer: &str, pattern: &str| ResultsSearch {
+            buffer: buffer.to_string(),
+            pattern: pattern.to_string(),
+            dir: "/src".to_string(),
+            opts: opts.clone(),
+            layout: Layout::default(),
+            refine: Refine {
+                full: empty.clone(),
+                filters: Vec::new(),
+            },
+        };
+
+        for n in 1..=DEFAULT_RESULTS_BUFFERS + 2 {
+            register_results_search(search(&format!("*re2-results-{}*", n), "old"));
+        }
+        // Searching again in a buffer replaces its entry and makes it newest
+        register_results_search(search("*re2-results-4*", "new"));
+
+        let searches = lock(&RESULTS_SEARCHES);
+        let names: Vec<&str> = searches.iter().map(|s| s.buffer.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["*re2-results-3*", "*re2-results-5*", "*re2-results-6*", "*re2-results-7*", "*re2-results-4*"]
+        );
+        assert_eq!(searches.last().unwrap().pattern, "new");
+    }
+
+    #[test]
+    fn test_resolve_result_path() {
+        assert_eq!(resolve_result_path(Some("/work/proj"), "src/main.rs"), "/work/proj/src/main.rs");
+        assert_eq!(resolve_result_path(Some("/work/proj"), "/etc/hosts"), "/etc/hosts");
+        assert_eq!(resolve_result_path(None, "src/main.rs"), "src/main.rs");
     }
 }
@@ -0,0 +1,193 @@
+//! Per-project search options
+//!
+//! A `.uemacs-rg.toml` in the search directory, or in a parent up to the
+//! repository root, overrides the configured defaults for searches there:
+//!
+//! ```toml
+//! glob_exclude = ["third_party/**", "*.min.js"]
+//! file_types = ["rust"]
+//! max_filesize = "50M"
+//! ```
+//!
+//! Keys are the config keys of the same name. The file is read at every
+//! search, so edits apply to the next one.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::search::{self, SearchOptions, SortOrder};
+
+/// Options file looked for at the search root
+pub const PROJECT_FILE: &str = ".uemacs-rg.toml";
+
+/// A size given as bytes (`1048576`) or with a suffix (`"1M"`)
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+/// The options a project file may set; absent keys leave the defaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectOptions {
+    case_insensitive: Option<bool>,
+    smart_case: Option<bool>,
+    word_boundary: Option<bool>,
+    hidden: Option<bool>,
+    follow_symlinks: Option<bool>,
+    git_ignore: Option<bool>,
+    no_ignore: Option<bool>,
+    git_tracked_only: Option<bool>,
+    max_depth: Option<usize>,
+    file_types: Option<Vec<String>>,
+    glob_include: Option<Vec<String>>,
+    glob_exclude: Option<Vec<String>>,
+    max_filesize: Option<Size>,
+    search_compressed: Option<bool>,
+    lossy_utf8: Option<bool>,
+    encoding: Option<String>,
+    fixed_strings: Option<bool>,
+    sort: Option<String>,
+    max_count: Option<u64>,
+    max_results: Option<usize>,
+    timeout_ms: Option<u64>,
+}
+
+/// Nearest `.uemacs-rg.toml` from `dir` up to the repository root
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    for d in dir.ancestors() {
+        let file = d.join(PROJECT_FILE);
+        if file.is_file() {
+            return Some(file);
+        }
+        if d.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
+
+/// Read and parse a project file
+///
+/// Errors name the file and line, e.g. `.uemacs-rg.toml:3: ...`.
+pub fn load(file: &Path) -> Result<ProjectOptions, String> {
+    let text = std::fs::read_to_string(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+    parse(&text).map_err(|(line, msg)| format!("{}:{}: {}", file.display(), line, msg))
+}
+
+/// Parse project file text; errors carry their 1-based line
+fn parse(text: &str) -> Result<ProjectOptions, (usize, String)> {
+    let options: ProjectOptions = toml::from_str(text).map_err(|e| {
+        let line = e.span().map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+        (line, e.message().to_string())
+    })?;
+    options.check().map_err(|msg| (1, msg))?;
+    Ok(options)
+}
+
+impl ProjectOptions {
+    /// Reject values that parse as TOML but not as options
+    fn check(&self) -> Result<(), String> {
+        if let Some(Size::Text(size)) = &self.max_filesize {
+            search::parse_size(size).map_err(|e| format!("max_filesize: {}", e))?;
+        }
+        if let Some(encoding) = &self.encoding {
+            search::parse_encoding(encoding).map_err(|e| format!("encoding: {}", e))?;
+        }
+        if let Some(sort) = &self.sort {
+            SortOrder::parse(sort)
+                .ok_or_else(|| format!("sort: expected \"path\" or \"modified\", not \"{}\"", sort))?;
+        }
+        Ok(())
+    }
+
+    /// Set the options this file names on `opts`
+    ///
+    /// A field the invocation already changed from `defaults` (a prompt
+    /// flag, a toggle command) keeps its value, so flags still win.
+    pub fn layer(&self, opts: &mut SearchOptions, defaults: &SearchOptions) {
+        macro_rules! layer {
+            ($field:ident, $value:expr) => {
+                if let Some(value) = &self.$field {
+                    if opts.$field == defaults.$field {
+                        opts.$field = $value(value);
+                    }
+                }
+            };
+        }
+
+        layer!(case_insensitive, |v: &bool| *v);
+        layer!(smart_case, |v: &bool| *v);
+        layer!(word_boundary, |v: &bool| *v);
+        layer!(hidden, |v: &bool| *v);
+        layer!(follow_symlinks, |v: &bool| *v);
+        layer!(git_ignore, |v: &bool| *v);
+        layer!(no_ignore, |v: &bool| *v);
+        layer!(git_tracked_only, |v: &bool| *v);
+        layer!(max_depth, |v: &usize| (*v > 0).then_some(*v));
+        layer!(file_types, |v: &Vec<String>| v.clone());
+        // Both glob keys feed one ordered list; a key the file leaves out
+        // keeps the configured globs of that kind
+        let names_globs = self.glob_include.is_some() || self.glob_exclude.is_some();
+        if names_globs && opts.globs == defaults.globs {
+            let keep = |include| search::globs_of(&defaults.globs, include);
+            opts.globs = search::ordered_globs(
+                &self.glob_include.clone().unwrap_or_else(|| keep(true)),
+                &self.glob_exclude.clone().unwrap_or_else(|| keep(false)),
+            );
+        }
+        layer!(max_filesize, |v: &Size| match v {
+            Size::Bytes(n) => (*n > 0).then_some(*n),
+            Size::Text(s) => search::parse_size(s).unwrap_or(None),
+        });
+        layer!(search_compressed, |v: &bool| *v);
+        layer!(lossy_utf8, |v: &bool| *v);
+        layer!(encoding, |v: &String| search::parse_encoding(v).unwrap_or(None));
+        layer!(fixed_strings, |v: &bool| *v);
+        layer!(sort, |v: &String| SortOrder::parse(v).unwrap_or(SortOrder::Path));
+        layer!(max_count, |v: &u64| (*v > 0).then_some(*v));
+        layer!(max_results, |v: &usize| (*v > 0).then_some(*v));
+        layer!(timeout_ms, |v: &u64| (*v > 0).then_some(*v));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project_options_layer_under_flags() {
+        let project = parse(
+            "glob_exclude = [\"third_party/**\"]\nfile_types = [\"rust\"]\nmax_filesize = \"50M\"\nhidden = true\n",
+        )
+        .unwrap();
+        let defaults = SearchOptions::default();
+        let mut opts = SearchOptions {
+            file_types: vec!["py".into()],
+            ..SearchOptions::default()
+        };
+        project.layer(&mut opts, &defaults);
+
+        assert_eq!(opts.globs, vec![(false, "third_party/**".to_string())]);
+        assert_eq!(opts.max_filesize, Some(50 << 20));
+        assert!(opts.hidden);
+        // The invocation's own type filter wins
+        assert_eq!(opts.file_types, vec!["py".to_string()]);
+    }
+
+    #[test]
+    fn test_project_file_errors_name_the_line() {
+        let (line, msg) = parse("hidden = true\nmax_depth = \"deep\"\n").unwrap_err();
+        assert_eq!(line, 2);
+        assert!(msg.contains("invalid type"), "{}", msg);
+
+        let (line, msg) = parse("hidden = true\n\nsmart = false\n").unwrap_err();
+        assert_eq!(line, 3);
+        assert!(msg.contains("unknown field"), "{}", msg);
+
+        assert!(parse("max_filesize = \"5Q\"").is_err());
+    }
+}
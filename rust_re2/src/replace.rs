@@ -0,0 +1,386 @@
+//! Search and replace
+//!
+//! re2-replace-preview searches as usual, then works out each matched
+//! line's replacement without touching disk. re2-replace-apply writes
+//! exactly that previewed set, skipping any line that changed in between.
+//! re2-replace-interactive plans the same way but asks about each line.
+//!
+//! Replacements may refer to groups: `$0` is the whole match, `$1` or
+//! `${1}` a numbered group, `$name` or `${name}` a named one, and `$$` is
+//! a literal `$`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use grep_matcher::{Captures, Matcher};
+
+use crate::search::{self, Engine, SearchOptions, SearchResult};
+
+/// One line's change, as previewed
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineEdit {
+    pub file: PathBuf,
+    pub line_number: u64,
+    pub before: String,
+    pub after: String,
+}
+
+/// Compute the edits replacing every match of `pattern` in `result`
+///
+/// The pattern gets the engine the search used, PCRE2 included. Lines
+/// are re-read from disk rather than taken from the matches, which may
+/// have been transcoded. Problems with single files come back as
+/// messages alongside the edits.
+pub fn plan(
+    result: &SearchResult,
+    pattern: &str,
+    replacement: &str,
+    opts: &SearchOptions,
+) -> Result<(Vec<LineEdit>, Vec<String>), String> {
+    if opts.multiline || opts.invert_match {
+        return Err("Replace works line by line (no multiline or inverted searches)".to_string());
+    }
+    match search::build_engine(pattern, opts)? {
+        Engine::Rust(matcher) => plan_with(result, &matcher, replacement),
+        #[cfg(feature = "pcre2")]
+        Engine::Pcre2(matcher) => plan_with(result, &matcher, replacement),
+    }
+}
+
+/// `plan`, with the engine's own matcher
+fn plan_with<M: Matcher>(
+    result: &SearchResult,
+    matcher: &M,
+    replacement: &str,
+) -> Result<(Vec<LineEdit>, Vec<String>), String> {
+    check_group_refs(matcher, replacement)?;
+
+    // Matched line numbers per file, in order
+    let mut lines: BTreeMap<&Path, Vec<u64>> = BTreeMap::new();
+    for m in &result.matches {
+        let numbers = lines.entry(m.file.as_path()).or_default();
+        if numbers.last() != Some(&m.line_number) {
+            numbers.push(m.line_number);
+        }
+    }
+
+    let mut edits = Vec::new();
+    let mut errors = Vec::new();
+    for (file, numbers) in lines {
+        let contents = match std::fs::read_to_string(file) {
+            Ok(c) => c,
+            Err(e) => {
+                errors.push(format!("{}: {}", file.display(), e));
+                continue;
+            }
+        };
+        let file_lines: Vec<&str> = contents.split_inclusive('\n').map(line_body).collect();
+
+        for n in numbers {
+            let Some(before) = file_lines.get(n as usize - 1) else {
+                continue;
+            };
+            let after = replace_line(matcher, before, replacement)?;
+            if after != *before {
+                edits.push(LineEdit {
+                    file: file.to_path_buf(),
+                    line_number: n,
+                    before: before.to_string(),
+                    after,
+                });
+            }
+        }
+    }
+
+    Ok((edits, errors))
+}
+
+/// A line without its terminator: the `\n` and at most one `\r` before it
+///
+/// plan and apply must agree on this, or a line ending `\r\r\n` would
+/// never match its preview.
+fn line_body(line: &str) -> &str {
+    match line.strip_suffix('\n') {
+        Some(body) => body.strip_suffix('\r').unwrap_or(body),
+        None => line,
+    }
+}
+
+/// Replace every match in one line, expanding group references
+fn replace_line<M: Matcher>(matcher: &M, line: &str, replacement: &str) -> Result<String, String> {
+    let haystack = line.as_bytes();
+    let mut caps = matcher.new_captures().map_err(|e| format!("Replace failed: {}", e))?;
+    let mut dst = Vec::with_capacity(line.len());
+    matcher
+        .replace_with_captures(haystack, &mut caps, &mut dst, |caps, dst| {
+            caps.interpolate(
+                |name| matcher.capture_index(name),
+                haystack,
+                replacement.as_bytes(),
+                dst,
+            );
+            true
+        })
+        .map_err(|e| format!("Replace failed: {}", e))?;
+    Ok(String::from_utf8_lossy(&dst).into_owned())
+}
+
+/// Group references in a replacement, in order
+///
+/// Follows the interpolation rules: `$$` is a literal `$`, `${...}` runs
+/// to the closing brace, and a bare `$` takes the longest run of letters,
+/// digits and `_`. A `$` followed by none of these is left as it is.
+fn group_refs(replacement: &str) -> Vec<&str> {
+    let mut refs = Vec::new();
+    let mut rest = replacement;
+
+    while let Some(at) = rest.find('$') {
+        rest = &rest[at + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+        } else if let Some((name, after)) = rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            refs.push(name);
+            rest = after;
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if end > 0 {
+                refs.push(&rest[..end]);
+            }
+            rest = &rest[end..];
+        }
+    }
+
+    refs
+}
+
+/// Reject references to groups the pattern doesn't have
+fn check_group_refs<M: Matcher>(matcher: &M, replacement: &str) -> Result<(), String> {
+    let groups = matcher.capture_count();
+    for name in group_refs(replacement) {
+        let known = match name.parse::<usize>() {
+            Ok(n) => n < groups,
+            Err(_) => matcher.capture_index(name).is_some(),
+        };
+        if !known {
+            return Err(format!(
+                "No group ${} in the pattern (it has {} numbered groups)",
+                name,
+                groups - 1
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reply to re2-replace-interactive's question
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Answer {
+    Yes,
+    No,
+    /// This edit and every one after it
+    All,
+    Quit,
+}
+
+impl Answer {
+    /// First letter of the reply, any case; None for anything else
+    pub fn parse(reply: &str) -> Option<Answer> {
+        match reply.trim().chars().next()?.to_ascii_lowercase() {
+            'y' => Some(Answer::Yes),
+            'n' => Some(Answer::No),
+            'a' => Some(Answer::All),
+            'q' => Some(Answer::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// Byte column where an edit's line first differs, for placing the cursor
+pub fn first_change(edit: &LineEdit) -> usize {
+    edit.before
+        .char_indices()
+        .zip(edit.after.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(edit.before.len().min(edit.after.len()), |((i, _), _)| i)
+}
+
+/// Number of distinct files the edits touch
+pub fn file_count(edits: &[LineEdit]) -> usize {
+    edits.chunk_by(|a, b| a.file == b.file).count()
+}
+
+/// Preview listing: a summary line, then `path:line: - old` and
+/// `path:line: + new` for every edit
+pub fn format_preview(edits: &[LineEdit]) -> String {
+    let mut output = format!(
+        "{} LINES TO CHANGE ACROSS {} FILES. M-x re2-replace-apply writes them.\n\n",
+        edits.len(),
+        file_count(edits)
+    );
+    for edit in edits {
+        let at = format!("{}:{}", edit.file.display(), edit.line_number);
+        output.push_str(&format!("{}: - {}\n{}: + {}\n", at, edit.before, at, edit.after));
+    }
+    output
+}
+
+/// Write previewed edits to disk
+///
+/// Returns how many lines were replaced, and a message for each file or
+/// line left alone. A line whose text no longer matches its preview is
+/// skipped rather than overwritten. Line endings are preserved.
+pub fn apply(edits: &[LineEdit]) -> (usize, Vec<String>) {
+    let mut applied = 0;
+    let mut errors = Vec::new();
+
+    for group in edits.chunk_by(|a, b| a.file == b.file) {
+        let file = &group[0].file;
+        let contents = match std::fs::read_to_string(file) {
+            Ok(c) => c,
+            Err(e) => {
+                errors.push(format!("{}: {}", file.display(), e));
+                continue;
+            }
+        };
+        let mut lines: Vec<String> = contents.split_inclusive('\n').map(str::to_string).collect();
+
+        let mut changed = 0;
+        for edit in group {
+            let Some(line) = lines.get_mut(edit.line_number as usize - 1) else {
+                errors.push(format!("{}:{}: line is gone", file.display(), edit.line_number));
+                continue;
+            };
+            let body_len = line_body(line).len();
+            if line[..body_len] != edit.before {
+                errors.push(format!(
+                    "{}:{}: changed since the preview, skipped",
+                    file.display(),
+                    edit.line_number
+                ));
+                continue;
+            }
+            line.replace_range(..body_len, &edit.after);
+            changed += 1;
+        }
+
+        if changed == 0 {
+            continue;
+        }
+        match std::fs::write(file, lines.concat()) {
+            Ok(()) => applied += changed,
+            Err(e) => errors.push(format!("{}: {}", file.display(), e)),
+        }
+    }
+
+    (applied, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::fixture_dir;
+
+    #[test]
+    fn test_preview_then_apply() {
+        let contents = "let old = old();\r\nkeep\nold\n";
+        let dir = fixture_dir("replace", &[("a.rs", contents)]);
+        let file = dir.join("a.rs");
+
+        let opts = SearchOptions::default();
+        let result =
+            search::search_buffer("old", file.to_str().unwrap(), contents.as_bytes(), &opts).unwrap();
+        let (edits, errors) = plan(&result, "old", "new", &opts).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].after, "let new = new();");
+
+        // Planning never writes
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), contents);
+        let preview = format_preview(&edits);
+        assert!(preview.starts_with("2 LINES TO CHANGE ACROSS 1 FILES."));
+        assert!(preview.contains(":1: - let old = old();\n"));
+        assert!(preview.contains(":1: + let new = new();\n"));
+
+        // A line edited after the preview is left alone
+        std::fs::write(&file, "let old = old();\r\nkeep\nold!\n").unwrap();
+        let (applied, errors) = apply(&edits);
+        assert_eq!(applied, 1);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "let new = new();\r\nkeep\nold!\n");
+        assert_eq!(first_change(&edits[0]), 4);
+    }
+
+    #[test]
+    fn test_stray_carriage_return_is_line_text() {
+        assert_eq!(line_body("old\r\r\n"), "old\r");
+        assert_eq!(line_body("old\r"), "old\r");
+
+        let contents = "old\r\r\nold\r\n";
+        let dir = fixture_dir("replace_cr", &[("a.txt", contents)]);
+        let file = dir.join("a.txt");
+
+        let opts = SearchOptions::default();
+        let result =
+            search::search_buffer("old", file.to_str().unwrap(), contents.as_bytes(), &opts).unwrap();
+        let (edits, _) = plan(&result, "old", "new", &opts).unwrap();
+        assert_eq!(edits[0].before, "old\r");
+        assert_eq!(apply(&edits), (2, Vec::new()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "new\r\r\nnew\r\n");
+    }
+
+    #[test]
+    fn test_replacement_group_refs() {
+        let opts = SearchOptions::default();
+        let replace = |pattern: &str, line: &str, replacement: &str| {
+            let matcher = search::build_matcher(pattern, &opts).unwrap();
+            check_group_refs(&matcher, replacement)?;
+            replace_line(&matcher, line, replacement)
+        };
+
+        // Numbered, braced and whole-match references
+        assert_eq!(replace(r"(\w+) = (\w+)", "a = b;", "$2 = $1").unwrap(), "b = a;");
+        assert_eq!(replace(r"(\w+)\(", "f(x)", "${1}_v2(").unwrap(), "f_v2(x)");
+        assert_eq!(replace(r"\d+", "cost 5", "[$0]").unwrap(), "cost [5]");
+
+        // Named groups, and $$ for a literal dollar
+        let named = r"(?P<key>\w+): (?P<value>\d+)";
+        assert_eq!(replace(named, "price: 30", "${value} $$${key}").unwrap(), "30 $price");
+        assert_eq!(replace(named, "n: 1", "$key=$value").unwrap(), "n=1");
+
+        let err = replace(r"(\w+) (\w+)", "a b", "$3").unwrap_err();
+        assert!(err.contains("$3"), "{}", err);
+        assert!(replace(named, "n: 1", "${missing}").is_err());
+        assert_eq!(group_refs("$$1 costs $ 5"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_replace_uses_the_search_engine() {
+        let contents = "foo_bar foo_baz\n";
+        let dir = fixture_dir("replace_pcre2", &[("a.rs", contents)]);
+        let file = dir.join("a.rs");
+        let path = file.to_str().unwrap();
+        let opts = SearchOptions::default();
+        let result = search::search_buffer("foo", path, contents.as_bytes(), &opts).unwrap();
+
+        // Look-ahead needs PCRE2, which -P asks for
+        let opts = SearchOptions { pcre2: true, ..SearchOptions::default() };
+        match plan(&result, r"foo(?=_baz)", "qux", &opts) {
+            Ok((edits, _)) => assert_eq!(edits[0].after, "foo_bar qux_baz"),
+            Err(e) => {
+                assert!(!cfg!(feature = "pcre2") && e.contains("PCRE2 is not built in"), "{}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn test_interactive_answers() {
+        assert_eq!(Answer::parse("y"), Some(Answer::Yes));
+        assert_eq!(Answer::parse(" No"), Some(Answer::No));
+        assert_eq!(Answer::parse("A"), Some(Answer::All));
+        assert_eq!(Answer::parse("q"), Some(Answer::Quit));
+        assert_eq!(Answer::parse(""), None);
+        assert_eq!(Answer::parse("x"), None);
+    }
+}
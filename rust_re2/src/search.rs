@@ -15,6 +15,8 @@
 //! - File type filtering
 //! - Glob patterns for include/exclude
 
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufRead, Read};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
@@ -22,12 +24,151 @@ use std::sync::{Arc, Mutex};
 use crossbeam_channel as channel;
 use grep_matcher::Matcher;
 use grep_regex::RegexMatcherBuilder;
-use grep_searcher::sinks::UTF8;
-use grep_searcher::{BinaryDetection, MmapChoice, Searcher, SearcherBuilder};
-use ignore::overrides::OverrideBuilder;
-use ignore::types::TypesBuilder;
+use grep_searcher::sinks::Bytes;
+use grep_searcher::{
+    BinaryDetection, MmapChoice, Searcher, SearcherBuilder, Sink, SinkContext, SinkContextKind,
+    SinkMatch,
+};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::types::{Types, TypesBuilder};
 use ignore::{WalkBuilder, WalkState};
 
+/// Default cap on matches collected by one search
+pub const DEFAULT_MAX_RESULTS: usize = 5000;
+
+/// Default cap on the characters shown per result line
+pub const DEFAULT_MAX_LINE_LEN: usize = 500;
+
+/// Most bytes read out of one compressed file when max_filesize is unset
+const DEFAULT_MAX_DECOMPRESSED: u64 = 256 * 1024 * 1024;
+
+/// Start of the header line written when max_results was hit
+const CAPPED_NOTE: &str = "RESULTS TRUNCATED AT";
+
+/// Per-file match batches buffered between workers and the collector
+const MATCH_CHANNEL_CAPACITY: usize = 256;
+
+/// Longest the collector waits for a batch before calling `on_batch`
+/// with an empty one, so a search holding the editor can look for keys
+const COLLECT_TICK_MS: u64 = 100;
+
+/// Order in which matched files are listed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    /// By file path (the default)
+    Path,
+    /// Most recently modified files first
+    Modified,
+}
+
+impl SortOrder {
+    /// Parse a `--sort` value or config string
+    pub fn parse(s: &str) -> Option<SortOrder> {
+        match s {
+            "path" => Some(SortOrder::Path),
+            "modified" => Some(SortOrder::Modified),
+            _ => None,
+        }
+    }
+}
+
+/// How a search matches letter case
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CaseMode {
+    /// Insensitive unless the pattern has an uppercase letter
+    #[default]
+    Smart,
+    Sensitive,
+    Insensitive,
+}
+
+impl CaseMode {
+    pub fn of(opts: &SearchOptions) -> CaseMode {
+        if opts.case_insensitive {
+            CaseMode::Insensitive
+        } else if opts.smart_case {
+            CaseMode::Smart
+        } else {
+            CaseMode::Sensitive
+        }
+    }
+
+    /// Set the options' case flags to this mode
+    pub fn apply(self, opts: &mut SearchOptions) {
+        opts.case_insensitive = self == CaseMode::Insensitive;
+        opts.smart_case = self == CaseMode::Smart;
+    }
+
+    /// Smart, then sensitive, then insensitive, then smart again
+    pub fn next(self) -> CaseMode {
+        match self {
+            CaseMode::Smart => CaseMode::Sensitive,
+            CaseMode::Sensitive => CaseMode::Insensitive,
+            CaseMode::Insensitive => CaseMode::Smart,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            CaseMode::Smart => "smart",
+            CaseMode::Sensitive => "sensitive",
+            CaseMode::Insensitive => "insensitive",
+        }
+    }
+
+    /// The mode as it applies to `pattern`, e.g. `smart, insensitive`
+    ///
+    /// Smart case matches exactly once the pattern has an uppercase
+    /// letter of its own; escapes like `\W` don't count.
+    pub fn effective_label(self, pattern: &str, fixed_strings: bool) -> String {
+        if self != CaseMode::Smart {
+            return self.label().to_string();
+        }
+        let sensitive = if fixed_strings {
+            pattern.chars().any(char::is_uppercase)
+        } else {
+            has_uppercase_literal(pattern)
+        };
+        format!("smart, {}", if sensitive { "sensitive" } else { "insensitive" })
+    }
+}
+
+/// Whether a regex spells out an uppercase letter, in or out of a class
+fn has_uppercase_literal(pattern: &str) -> bool {
+    use regex_syntax::ast::{self, Ast, ClassSetItem};
+
+    struct Upper;
+    impl ast::Visitor for Upper {
+        type Output = ();
+        type Err = ();
+
+        fn finish(self) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn visit_pre(&mut self, ast: &Ast) -> Result<(), ()> {
+            match ast {
+                Ast::Literal(lit) if lit.c.is_uppercase() => Err(()),
+                _ => Ok(()),
+            }
+        }
+
+        fn visit_class_set_item_pre(&mut self, item: &ClassSetItem) -> Result<(), ()> {
+            match item {
+                ClassSetItem::Literal(lit) if lit.c.is_uppercase() => Err(()),
+                ClassSetItem::Range(r) if r.start.c.is_uppercase() || r.end.c.is_uppercase() => Err(()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    match ast::parse::Parser::new().parse(pattern) {
+        Ok(ast) => ast::visit(&ast, Upper).is_err(),
+        Err(_) => pattern.chars().any(char::is_uppercase),
+    }
+}
+
 /// Search options - mirrors ripgrep's full option set
 #[derive(Clone, Debug)]
 pub struct SearchOptions {
@@ -45,30 +186,61 @@ pub struct SearchOptions {
     pub invert_match: bool,
     /// Include hidden files
     pub hidden: bool,
-    /// Follow symlinks
+    /// Follow symlinks (--follow, -L); matches keep the linked path
     pub follow_symlinks: bool,
     /// Respect .gitignore files
     pub git_ignore: bool,
-    /// Maximum depth to search (0 = unlimited)
+    /// Ignore every ignore file: .gitignore, .ignore, .rgignore (--no-ignore)
+    pub no_ignore: bool,
+    /// Search only files git tracks (`git ls-files`), when in a repo
+    pub git_tracked_only: bool,
+    /// Search exactly these files instead of walking the directory
+    pub path_list: Option<PathList>,
+    /// Maximum depth to search; 1 is only files directly in the root
+    /// (--max-depth, as in ripgrep)
     pub max_depth: Option<usize>,
     /// Number of threads (0 = auto)
     pub threads: usize,
     /// File type filters (e.g., "rust", "py")
     pub file_types: Vec<String>,
-    /// Glob patterns to include
-    pub glob_include: Vec<String>,
-    /// Glob patterns to exclude
-    pub glob_exclude: Vec<String>,
+    /// Include (true) and exclude (false) globs in the order given; as
+    /// with ripgrep's -g, a later glob wins over an earlier one
+    pub globs: Vec<(bool, String)>,
     /// Maximum file size to search (bytes, 0 = unlimited)
     pub max_filesize: Option<u64>,
     /// Use memory mapping for large files
     pub mmap: bool,
+    /// Search .gz/.bz2/.xz (and similar) files decompressed (-z)
+    pub search_compressed: bool,
+    /// Replace invalid UTF-8 with U+FFFD instead of skipping the file
+    pub lossy_utf8: bool,
+    /// Transcode files without a byte order mark from this encoding
+    /// (-E; None = UTF-8). Files with a BOM always follow it.
+    pub encoding: Option<&'static Encoding>,
     /// Fixed string search (not regex)
     pub fixed_strings: bool,
-    /// Multiline mode
+    /// Multiline mode - matches may span lines (-U)
     pub multiline: bool,
+    /// Let `.` match newlines in multiline mode (--multiline-dotall)
+    pub multiline_dotall: bool,
+    /// Group results under a heading per file instead of flat lines
+    pub group_by_file: bool,
+    /// Layout of flat results lines; see `Template`
+    pub format_template: String,
+    /// Order of matched files in the results (--sort)
+    pub sort: SortOrder,
     /// Maximum matches per file (0 = unlimited)
     pub max_count: Option<u64>,
+    /// Stop the whole search after this many matches (-m, 0 = unlimited)
+    pub max_results: Option<usize>,
+    /// Only count matches per file, never collect match text (-c)
+    pub count_only: bool,
+    /// Characters of each matched line kept for display (None = all)
+    pub max_line_len: Option<usize>,
+    /// Use PCRE2 instead of the default engine (--pcre2, -P)
+    pub pcre2: bool,
+    /// Stop the search after this many milliseconds (None = no limit)
+    pub timeout_ms: Option<u64>,
 }
 
 impl Default for SearchOptions {
@@ -83,27 +255,323 @@ impl Default for SearchOptions {
             hidden: false,
             follow_symlinks: false,
             git_ignore: true,
+            no_ignore: false,
+            git_tracked_only: false,
+            path_list: None,
             max_depth: None,
             threads: 0, // auto-detect
             file_types: Vec::new(),
-            glob_include: Vec::new(),
-            glob_exclude: Vec::new(),
+            globs: Vec::new(),
             max_filesize: None,
             mmap: true,
+            search_compressed: false,
+            lossy_utf8: true,
+            encoding: None,
             fixed_strings: false,
             multiline: false,
+            multiline_dotall: false,
+            group_by_file: false,
+            format_template: DEFAULT_TEMPLATE.to_string(),
+            sort: SortOrder::Path,
             max_count: None,
+            max_results: Some(DEFAULT_MAX_RESULTS),
+            max_line_len: Some(DEFAULT_MAX_LINE_LEN),
+            pcre2: false,
+            count_only: false,
+            timeout_ms: None,
         }
     }
 }
 
+/// Sorted, inclusive (first, last) line ranges per file
+pub type LineRanges = BTreeMap<PathBuf, Vec<(u64, u64)>>;
+
+/// Files a search is limited to, and where the list came from
+#[derive(Clone, Debug)]
+pub struct PathList {
+    /// Relative paths are taken from the search directory
+    pub paths: Vec<PathBuf>,
+    /// Shown in the results header, e.g. `git status vs HEAD`
+    pub source: String,
+    /// When set, only matches starting in these ranges are kept; keys
+    /// are resolved like `paths`. Counting and re2-exists still look at
+    /// whole files.
+    pub lines: Option<LineRanges>,
+}
+
+/// Whether `line` falls in one of the sorted `ranges`
+pub fn in_ranges(ranges: &[(u64, u64)], line: u64) -> bool {
+    let at = ranges.partition_point(|&(_, last)| last < line);
+    ranges.get(at).is_some_and(|&(first, _)| first <= line)
+}
+
 /// A single search match
 #[derive(Debug, Clone)]
 pub struct Match {
     pub file: PathBuf,
     pub line_number: u64,
+    /// Byte offset of the first match in `text` (0-based; shown 1-based)
     pub column: usize,
+    /// Byte offset just past the first match in `text`
+    pub end_column: usize,
     pub text: String,
+    /// Set for lines that did not match (-v); these have no column
+    pub inverted: bool,
+    /// `column` in the file's own bytes, for files transcoded to UTF-8
+    /// before searching (UTF-16, -E latin1, ...)
+    pub file_column: Option<usize>,
+    /// Byte offset of the line's start in what was searched (ripgrep's
+    /// absolute_offset); decompressed and transcoded files count the
+    /// searched text, not the file's own bytes
+    pub offset: u64,
+    /// Lines shown around the match (-A/-B) as `(line, text)`, in line
+    /// order; a line already shown for an earlier match isn't repeated
+    pub context: Vec<(u64, String)>,
+}
+
+/// What a span of a rendered results line shows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Highlight {
+    Plain,
+    Path,
+    /// The `line:col:` prefix
+    Location,
+    /// The matched text itself
+    Match,
+}
+
+/// Highlight spans of one rendered line as `(end byte, kind)` pairs,
+/// each running from the previous span's end
+pub type LineHighlight = Vec<(usize, Highlight)>;
+
+/// Layout of a flat results line when none is configured
+pub const DEFAULT_TEMPLATE: &str = "{path}:{line}:{col}: {text}";
+
+/// One piece of a results line template
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Literal(String),
+    Path,
+    Line,
+    Col,
+    Text,
+}
+
+/// Layout of a flat results line, e.g. `{path}\t{line}\t{text}`
+///
+/// Placeholders are `{path}`, `{line}`, `{col}` and `{text}`; everything
+/// else is copied as it is. `{path}` and `{line}` are required so lines
+/// can be jumped to, placeholders need a separator between them, and
+/// `{text}` must come last. Inverted results have no column, so `{col}`
+/// and the separator before it are left out of those lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template::parse(DEFAULT_TEMPLATE).expect("default template parses")
+    }
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut pieces = Vec::new();
+        let mut rest = template;
+
+        while !rest.is_empty() {
+            let literal_end = rest.find('{').unwrap_or(rest.len());
+            if literal_end > 0 {
+                pieces.push(Piece::Literal(rest[..literal_end].to_string()));
+                rest = &rest[literal_end..];
+                continue;
+            }
+            let end = rest.find('}').ok_or("unclosed { in format_template")?;
+            let piece = match &rest[1..end] {
+                "path" => Piece::Path,
+                "line" => Piece::Line,
+                "col" => Piece::Col,
+                "text" => Piece::Text,
+                other => return Err(format!("unknown placeholder {{{}}} in format_template", other)),
+            };
+            if pieces.contains(&piece) {
+                return Err(format!("{} appears twice in format_template", &rest[..=end]));
+            }
+            if pieces.last().is_some_and(|p| !matches!(p, Piece::Literal(_))) {
+                return Err("format_template needs a separator between placeholders".to_string());
+            }
+            pieces.push(piece);
+            rest = &rest[end + 1..];
+        }
+
+        if !pieces.contains(&Piece::Path) || !pieces.contains(&Piece::Line) {
+            return Err("format_template needs {path} and {line}".to_string());
+        }
+        if pieces.contains(&Piece::Text) && pieces.last() != Some(&Piece::Text) {
+            return Err("{text} must come last in format_template".to_string());
+        }
+        Ok(Template { pieces })
+    }
+
+    /// Pieces for a match: inverted lines drop `{col}` and its separator
+    fn pieces_for(&self, inverted: bool) -> Vec<&Piece> {
+        let mut pieces: Vec<&Piece> = self.pieces.iter().collect();
+        if inverted {
+            if let Some(at) = pieces.iter().position(|p| **p == Piece::Col) {
+                pieces.remove(at);
+                if at > 0 && matches!(pieces[at - 1], Piece::Literal(_)) {
+                    pieces.remove(at - 1);
+                }
+            }
+        }
+        pieces
+    }
+
+    /// Render a match, with highlight spans up to the text and where the
+    /// text starts (the line's end when there is no `{text}`)
+    fn render(&self, m: &Match, text: &str) -> (String, LineHighlight, usize) {
+        let pieces = self.pieces_for(m.inverted);
+        let is_location = |p: Option<&&Piece>| matches!(p, Some(Piece::Line | Piece::Col));
+
+        let mut line = String::new();
+        let mut spans = LineHighlight::new();
+        let mut text_start = None;
+        for (i, piece) in pieces.iter().enumerate() {
+            let kind = match piece {
+                Piece::Literal(lit) => {
+                    line.push_str(lit);
+                    // Separators around line and column read as part of the location
+                    if is_location(pieces.get(i + 1)) || (i > 0 && is_location(pieces.get(i - 1))) {
+                        Highlight::Location
+                    } else {
+                        Highlight::Plain
+                    }
+                }
+                Piece::Path => {
+                    line.push_str(&m.file.display().to_string());
+                    Highlight::Path
+                }
+                Piece::Line => {
+                    line.push_str(&m.line_number.to_string());
+                    Highlight::Location
+                }
+                Piece::Col => {
+                    line.push_str(&(m.file_column.unwrap_or(m.column) + 1).to_string());
+                    Highlight::Location
+                }
+                Piece::Text => {
+                    text_start = Some(line.len());
+                    line.push_str(text);
+                    continue;
+                }
+            };
+            match spans.last_mut() {
+                Some(last) if last.1 == kind => last.0 = line.len(),
+                _ => spans.push((line.len(), kind)),
+            }
+        }
+
+        let text_start = text_start.unwrap_or(line.len());
+        (line, spans, text_start)
+    }
+
+    /// Recover the location a line rendered with this template points at
+    pub fn parse_line(&self, text: &str) -> Option<Location> {
+        Self::parse_pieces(&self.pieces_for(false), text)
+            .or_else(|| Self::parse_pieces(&self.pieces_for(true), text))
+    }
+
+    fn parse_pieces(pieces: &[&Piece], text: &str) -> Option<Location> {
+        let mut rest = text;
+        let (mut file, mut line, mut column) = (None, None, 1);
+
+        for (i, piece) in pieces.iter().enumerate() {
+            if let Piece::Literal(lit) = piece {
+                rest = rest.strip_prefix(lit.as_str())?;
+                continue;
+            }
+            // A field runs to the next separator, or to the end
+            let value = match pieces.get(i + 1) {
+                Some(Piece::Literal(lit)) => {
+                    let at = rest.find(lit.as_str())?;
+                    let (value, after) = rest.split_at(at);
+                    rest = after;
+                    value
+                }
+                _ => std::mem::take(&mut rest),
+            };
+            match piece {
+                Piece::Path if !value.is_empty() => file = Some(value),
+                Piece::Path => return None,
+                Piece::Line => line = Some(value.parse().ok()?),
+                Piece::Col => column = value.parse().ok()?,
+                _ => {}
+            }
+        }
+
+        Some(Location {
+            file: file?.to_string(),
+            line: line?,
+            column,
+        })
+    }
+}
+
+/// How results are laid out in a results buffer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub style: Style,
+    /// Characters of a matched line shown before it's cut off with `…`
+    /// (None = no limit)
+    pub max_line_len: Option<usize>,
+}
+
+/// Shape of the result lines
+#[derive(Debug, Clone, PartialEq)]
+pub enum Style {
+    /// One line per match, following a template
+    Flat(Template),
+    /// A heading per file with indented `line:col: text` entries
+    Grouped,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::flat(Template::default())
+    }
+}
+
+impl Layout {
+    /// Flat lines following `template`, clipped at the default length
+    pub fn flat(template: Template) -> Layout {
+        Layout {
+            style: Style::Flat(template),
+            max_line_len: Some(DEFAULT_MAX_LINE_LEN),
+        }
+    }
+
+    /// Layout the options ask for; an invalid template falls back to the
+    /// default (config loading reports it)
+    pub fn of(opts: &SearchOptions) -> Layout {
+        let style = if opts.group_by_file {
+            Style::Grouped
+        } else {
+            Style::Flat(Template::parse(&opts.format_template).unwrap_or_default())
+        };
+        Layout {
+            style,
+            max_line_len: opts.max_line_len,
+        }
+    }
+}
+
+/// Running totals handed to `search_parallel`'s batch callback
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub files_searched: usize,
+    /// Matches collected so far, including the current batch
+    pub matches: usize,
 }
 
 /// Search statistics
@@ -113,28 +581,69 @@ pub struct SearchStats {
     pub files_searched: usize,
     pub files_matched: usize,
     pub elapsed_ms: u64,
+    /// Set when files were ordered by modification time
+    pub sorted_by_modified: bool,
+    /// Set when max_results stopped the search early
+    pub capped: bool,
+    /// Matches found before stopping; more than `matches` when capped
+    pub matches_found: usize,
+    /// Set when the caller cancelled the search before it finished
+    pub cancelled: bool,
+    /// How letter case was matched
+    pub case_mode: CaseMode,
+    /// Set when only whole words matched (-w)
+    pub whole_word: bool,
+    /// Set when PCRE2 ran the search rather than the default engine
+    pub pcre2: bool,
+    /// Set when ignore files were bypassed (--no-ignore)
+    pub no_ignore: bool,
+    /// Set when hidden files were searched (--hidden)
+    pub hidden: bool,
+    /// Recursion limit the walk ran with (--max-depth)
+    pub max_depth: Option<usize>,
+    /// Files skipped because they weren't valid UTF-8 (lossy_utf8 off)
+    pub skipped_encoding: usize,
+    /// Files skipped for being over max_filesize
+    pub skipped_large: usize,
+    /// The timeout (in ms) that stopped the search, if one did
+    pub timed_out: Option<u64>,
+    /// Where the searched files came from, when not a directory walk
+    pub scope: Option<String>,
+    /// Matches dropped for starting outside the listed line ranges, when
+    /// the search had any
+    pub outside_lines: Option<usize>,
 }
 
 /// Search result containing matches and statistics
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SearchResult {
     pub matches: Vec<Match>,
     pub stats: SearchStats,
     pub errors: Vec<String>,
+    /// Files whose matches were cut off by max_count
+    pub truncated_files: Vec<PathBuf>,
+    /// Per-file match counts (count_only mode), sorted by count descending
+    pub counts: Vec<(PathBuf, u64)>,
+    /// Matched files that were transcoded to UTF-8, with their encoding
+    pub transcoded: Vec<(PathBuf, &'static str)>,
 }
 
 /// Build a regex matcher with the given options
-fn build_matcher(
+pub fn build_matcher(
     pattern: &str,
     opts: &SearchOptions,
 ) -> Result<grep_regex::RegexMatcher, String> {
     let mut builder = RegexMatcherBuilder::new();
 
+    // Unicode mode (the default, set so it can't silently change) makes
+    // \w, \b and the word option treat accented letters as word chars
     builder
+        .unicode(true)
         .case_insensitive(opts.case_insensitive)
         .case_smart(opts.smart_case && !opts.case_insensitive)
         .word(opts.word_boundary)
-        .multi_line(opts.multiline);
+        .multi_line(opts.multiline)
+        .dot_matches_new_line(opts.multiline && opts.multiline_dotall);
 
     if opts.fixed_strings {
         builder.fixed_strings(true);
@@ -143,6 +652,350 @@ fn build_matcher(
     builder.build(pattern).map_err(|e| format!("Invalid pattern: {}", e))
 }
 
+/// The regex engine a search runs with
+pub enum Engine {
+    /// grep-regex, on Rust's linear-time regex crate
+    Rust(grep_regex::RegexMatcher),
+    /// PCRE2, for look-around and backreferences
+    #[cfg(feature = "pcre2")]
+    Pcre2(grep::pcre2::RegexMatcher),
+}
+
+impl Engine {
+    pub fn is_pcre2(&self) -> bool {
+        !matches!(self, Engine::Rust(_))
+    }
+}
+
+/// Run `$body` with `$m` bound to the engine's own matcher
+macro_rules! with_engine {
+    ($engine:expr, $m:ident => $body:expr) => {
+        match $engine {
+            Engine::Rust($m) => $body,
+            #[cfg(feature = "pcre2")]
+            Engine::Pcre2($m) => $body,
+        }
+    };
+}
+
+/// Build the engine for a pattern
+///
+/// `--pcre2` asks for PCRE2 outright. Otherwise the default engine goes
+/// first, and a pattern it rejects as unsupported (look-around,
+/// backreferences) is retried with PCRE2 when the `pcre2` feature is on.
+pub fn build_engine(pattern: &str, opts: &SearchOptions) -> Result<Engine, String> {
+    if opts.pcre2 {
+        return build_pcre2(pattern, opts);
+    }
+    match build_matcher(pattern, opts) {
+        Ok(m) => Ok(Engine::Rust(m)),
+        Err(e) if cfg!(feature = "pcre2") && e.contains("not supported") => {
+            build_pcre2(pattern, opts).map_err(|_| e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(feature = "pcre2")]
+fn build_pcre2(pattern: &str, opts: &SearchOptions) -> Result<Engine, String> {
+    let pattern = if opts.fixed_strings {
+        regex_syntax::escape(pattern)
+    } else {
+        pattern.to_string()
+    };
+    let mut builder = grep::pcre2::RegexMatcherBuilder::new();
+
+    builder
+        .caseless(opts.case_insensitive)
+        .case_smart(opts.smart_case && !opts.case_insensitive)
+        .word(opts.word_boundary)
+        .multi_line(opts.multiline)
+        .dotall(opts.multiline && opts.multiline_dotall)
+        .utf(true)
+        .ucp(true)
+        .jit_if_available(true);
+
+    builder
+        .build(&pattern)
+        .map(Engine::Pcre2)
+        .map_err(|e| format!("Invalid pattern: {}", e))
+}
+
+#[cfg(not(feature = "pcre2"))]
+fn build_pcre2(_pattern: &str, _opts: &SearchOptions) -> Result<Engine, String> {
+    Err("PCRE2 is not built in (rebuild with --features pcre2)".to_string())
+}
+
+/// Apply leading ripgrep-style flags from a prompt answer
+///
+/// Flags come first, separated by whitespace, and `--` ends them. The
+/// rest of the input is returned verbatim as the pattern.
+pub fn apply_flags(input: &str, opts: &mut SearchOptions) -> Result<String, String> {
+    let mut rest = input.trim_start();
+
+    while rest.starts_with('-') {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let flag = &rest[..end];
+        rest = rest[end..].trim_start();
+
+        if flag == "--" {
+            break;
+        }
+
+        if let Some(value) = flag.strip_prefix("--sort=") {
+            opts.sort = SortOrder::parse(value)
+                .ok_or_else(|| format!("Unknown sort '{}' (path, modified)", value))?;
+        } else if let Some(value) = flag.strip_prefix("--max-results=") {
+            opts.max_results = parse_max_results(value)?;
+        } else if flag == "--no-ignore" || flag == "-u" {
+            opts.no_ignore = true;
+        } else if flag == "--follow" || flag == "-L" {
+            opts.follow_symlinks = true;
+        } else if flag == "--hidden" {
+            opts.hidden = true;
+        } else if flag == "-uu" {
+            opts.no_ignore = true;
+            opts.hidden = true;
+        } else if flag == "-U" {
+            opts.multiline = true;
+        } else if flag == "-F" {
+            opts.fixed_strings = true;
+        } else if flag == "-w" {
+            opts.word_boundary = true;
+        } else if flag == "-g" {
+            let glob = take_word(&mut rest);
+            if glob.is_empty() {
+                return Err("-g needs a glob".to_string());
+            }
+            opts.globs.push(parse_glob(glob));
+        } else if let Some(value) = flag.strip_prefix("--glob=") {
+            opts.globs.push(parse_glob(value));
+        } else if flag == "-z" || flag == "--search-zip" {
+            opts.search_compressed = true;
+        } else if let Some(value) = flag.strip_prefix("--max-depth=") {
+            let depth: usize = value
+                .parse()
+                .map_err(|_| format!("--max-depth needs a number, got '{}'", value))?;
+            opts.max_depth = if depth > 0 { Some(depth) } else { None };
+        } else if let Some(value) = flag.strip_prefix("--max-filesize=") {
+            opts.max_filesize = parse_size(value)?;
+        } else if let Some(value) = flag.strip_prefix("--encoding=") {
+            opts.encoding = parse_encoding(value)?;
+        } else if flag == "-E" {
+            opts.encoding = parse_encoding(take_word(&mut rest))?;
+        } else if flag == "--pcre2" || flag == "-P" {
+            opts.pcre2 = true;
+        } else if flag == "-i" {
+            CaseMode::Insensitive.apply(opts);
+        } else if flag == "-s" {
+            CaseMode::Sensitive.apply(opts);
+        } else if flag == "-S" {
+            CaseMode::Smart.apply(opts);
+        } else if let Some(value) = flag.strip_prefix("-m") {
+            // `-mN` or `-m N`
+            let value = if value.is_empty() { take_word(&mut rest) } else { value };
+            opts.max_results = parse_max_results(value)?;
+        } else {
+            return Err(format!(
+                "Unknown flag: {} (use -- before a pattern starting with -)",
+                flag
+            ));
+        }
+    }
+
+    Ok(rest.to_string())
+}
+
+/// A glob as ripgrep's -g takes it: `!` in front excludes
+pub fn parse_glob(glob: &str) -> (bool, String) {
+    match glob.strip_prefix('!') {
+        Some(exclude) => (false, exclude.to_string()),
+        None => (true, glob.to_string()),
+    }
+}
+
+/// Inverse of parse_glob
+pub fn format_glob((include, glob): &(bool, String)) -> String {
+    if *include {
+        glob.clone()
+    } else {
+        format!("!{}", glob)
+    }
+}
+
+/// One ordered glob list from separate include and exclude lists (the
+/// config keys), includes first
+pub fn ordered_globs(include: &[String], exclude: &[String]) -> Vec<(bool, String)> {
+    let include = include.iter().map(|g| (true, g.clone()));
+    include.chain(exclude.iter().map(|g| (false, g.clone()))).collect()
+}
+
+/// The include (or exclude) globs of an ordered list
+pub fn globs_of(globs: &[(bool, String)], include: bool) -> Vec<String> {
+    globs.iter().filter(|g| g.0 == include).map(|g| g.1.clone()).collect()
+}
+
+/// Split the next whitespace-separated word, a flag's value, off `rest`
+fn take_word<'a>(rest: &mut &'a str) -> &'a str {
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let word = &rest[..end];
+    *rest = rest[end..].trim_start();
+    word
+}
+
+/// Parse an encoding label (`latin1`, `utf-16le`, `shift_jis`, ...)
+///
+/// `auto` and UTF-8 itself give None: UTF-8 unless a BOM says otherwise.
+pub fn parse_encoding(label: &str) -> Result<Option<&'static Encoding>, String> {
+    let label = label.trim();
+    if label.is_empty() || label.eq_ignore_ascii_case("auto") {
+        return Ok(None);
+    }
+    match Encoding::for_label_no_replacement(label.as_bytes()) {
+        Some(encoding) if encoding == UTF_8 => Ok(None),
+        Some(encoding) => Ok(Some(encoding)),
+        None => Err(format!("Unknown encoding '{}'", label)),
+    }
+}
+
+/// Parse a file size: bytes, or a number with a K, M or G suffix (`500K`,
+/// `2M`, `1G`, powers of 1024); 0 lifts the limit
+pub fn parse_size(value: &str) -> Result<Option<u64>, String> {
+    let value = value.trim();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match value[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" => 10,
+        "M" | "MB" => 20,
+        "G" | "GB" => 30,
+        _ => return Err(format!("Bad size '{}' (e.g. 500K, 2M, 1G)", value)),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("Bad size '{}' (e.g. 500K, 2M, 1G)", value))?;
+    let bytes = n
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("Size '{}' is too large", value))?;
+    Ok(if bytes > 0 { Some(bytes) } else { None })
+}
+
+/// A byte count in the largest unit that divides it, e.g. `2M`
+pub fn format_size(bytes: u64) -> String {
+    for (shift, unit) in [(30, "G"), (20, "M"), (10, "K")] {
+        if bytes >= 1 << shift && bytes.is_multiple_of(1 << shift) {
+            return format!("{}{}", bytes >> shift, unit);
+        }
+    }
+    bytes.to_string()
+}
+
+/// Parse a result cap; 0 lifts the cap
+fn parse_max_results(value: &str) -> Result<Option<usize>, String> {
+    let n: usize = value
+        .parse()
+        .map_err(|_| format!("-m needs a number of results, got '{}'", value))?;
+    Ok(if n > 0 { Some(n) } else { None })
+}
+
+/// One pattern matching any of the space-separated `terms`:
+/// `(?:p1|p2|p3)`
+///
+/// With `fixed` each term is escaped and matched literally. A single term
+/// is returned as it is; no terms at all is an error.
+pub fn any_pattern(terms: &str, fixed: bool) -> Result<String, String> {
+    let terms: Vec<String> = terms
+        .split_whitespace()
+        .map(|t| if fixed { regex_syntax::escape(t) } else { t.to_string() })
+        .collect();
+    match terms.as_slice() {
+        [] => Err("No terms given".to_string()),
+        [term] => Ok(term.clone()),
+        _ => Ok(format!("(?:{})", terms.join("|"))),
+    }
+}
+
+/// Pattern that finds `word` only as a whole word, literally
+///
+/// Returns the pattern and whether word_boundary (-w) should be on. A word
+/// made of word characters just uses -w. Symbols like C++ `operator<<`
+/// can't, since -w would also demand a non-word character after `<<`, so
+/// they get `\b` only on the edges that are word characters.
+pub fn whole_word_pattern(word: &str) -> (String, bool) {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let escaped = regex_syntax::escape(word);
+    if word.chars().all(is_word) {
+        return (escaped, true);
+    }
+
+    let edge = |c: Option<char>| if c.is_some_and(is_word) { r"\b" } else { "" };
+    let pattern = format!("{}{}{}", edge(word.chars().next()), escaped, edge(word.chars().last()));
+    (pattern, false)
+}
+
+/// Patterns re2-def tries for a definition, by file type; `WORD` stands
+/// for the escaped symbol. Config `def_<type>` overrides one or adds one
+/// for a type not listed
+pub const DEFINITION_PATTERNS: &[(&str, &str)] = &[
+    ("rust", r"\b(fn|struct|enum|union|trait|type|const|static|mod|macro_rules!)\s+WORD\b"),
+    ("py", r"^\s*(async\s+)?(def|class)\s+WORD\b|^WORD\s*(:[^=]*)?="),
+    (
+        "c",
+        r"^WORD\s*\(|^[A-Za-z_][\w\s\*]*[\s\*]WORD\s*\([^;]*$|#\s*define\s+WORD\b|\b(struct|enum|union)\s+WORD\s*\{|typedef\b.*\bWORD\s*;",
+    ),
+    (
+        "cpp",
+        r"^WORD\s*\(|^[A-Za-z_][\w\s\*&:<>]*[\s\*&:]WORD\s*\([^;]*$|#\s*define\s+WORD\b|\b(class|struct|enum|union|namespace)\s+WORD\b[^;]*$|\busing\s+WORD\s*=",
+    ),
+    ("go", r"^func\s+(\([^)]*\)\s*)?WORD\s*[\[(]|^\s*(type|var|const)\s+WORD\b"),
+    ("js", r"\b(function\*?|class)\s+WORD\b|\b(const|let|var)\s+WORD\s*="),
+    ("ts", r"\b(function\*?|class|interface|type|enum|namespace)\s+WORD\b|\b(const|let|var)\s+WORD\s*[:=]"),
+    ("java", r"\b(class|interface|enum|record)\s+WORD\b|^\s*[\w<>\[\],\s]+\s+WORD\s*\([^;]*$"),
+    ("ruby", r"^\s*(def\s+(self\.)?|class\s+|module\s+)WORD\b"),
+    ("sh", r"^\s*(function\s+)?WORD\s*\(\s*\)|^\s*function\s+WORD\b"),
+    ("lua", r"\bfunction\s+([\w.]+[.:])?WORD\s*\(|\blocal\s+(function\s+)?WORD\b"),
+    ("zig", r"\b(fn|const|var)\s+WORD\b"),
+    ("haskell", r"^WORD\s+::|^(data|newtype|type|class)\s+WORD\b"),
+];
+
+/// Definition pattern for `word` in files of `file_type`
+///
+/// Uses `custom` (from config) when given, else the built-in pattern;
+/// None when neither exists.
+pub fn definition_pattern(file_type: &str, word: &str, custom: Option<&str>) -> Option<String> {
+    let template = custom.or_else(|| {
+        DEFINITION_PATTERNS
+            .iter()
+            .find(|(t, _)| *t == file_type)
+            .map(|(_, p)| *p)
+    })?;
+    Some(template.replace("WORD", &regex_syntax::escape(word)))
+}
+
+/// Check that a pattern compiles, describing the syntax error if not
+///
+/// The message names the problem and the byte offset where the regex
+/// parser gave up, e.g. "Invalid pattern: unclosed group (at byte 3)".
+pub fn validate_pattern(pattern: &str, opts: &SearchOptions) -> Result<(), String> {
+    let err = match build_engine(pattern, opts) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+
+    if !opts.fixed_strings && !opts.pcre2 {
+        if let Err(e) = regex_syntax::ast::parse::Parser::new().parse(pattern) {
+            return Err(format!(
+                "Invalid pattern: {} (at byte {})",
+                e.kind(),
+                e.span().start.offset
+            ));
+        }
+    }
+
+    // Parsed fine but was still rejected (e.g. a too-large pattern) - keep
+    // the first line of the matcher's own description
+    Err(err.lines().next().unwrap_or("Invalid pattern").to_string())
+}
+
 /// Build a searcher with the given options
 fn build_searcher(opts: &SearchOptions) -> Searcher {
     let mut builder = SearcherBuilder::new();
@@ -151,238 +1004,1435 @@ fn build_searcher(opts: &SearchOptions) -> Searcher {
         .binary_detection(BinaryDetection::quit(b'\x00'))
         .before_context(opts.context_before)
         .after_context(opts.context_after)
-        .invert_match(opts.invert_match);
+        .invert_match(opts.invert_match)
+        .multi_line(opts.multiline);
 
+    // Without this call the searcher uses buffered reads.
+    //
+    // MmapChoice::auto is unsafe: if another process truncates a mapped file
+    // mid-search the read raises SIGBUS, and network filesystems (NFS) can
+    // fault the same way. Only enter the unsafe block when mmap is requested
+    // so the `mmap = false` escape hatch avoids mapping entirely.
     if opts.mmap {
-        // Use memory mapping for files > 1MB
         unsafe {
             builder.memory_map(MmapChoice::auto());
         }
     }
 
+    // BOM sniffing is on by default, so UTF-16 files with a BOM are
+    // transcoded with or without this
+    if let Some(encoding) = opts.encoding {
+        builder.encoding(grep_searcher::Encoding::new(encoding.name()).ok());
+    }
+
     builder.build()
 }
 
+/// Cap a requested thread count at four per CPU core
+///
+/// Returns the count to use and whether it had to be clamped. Zero means
+/// auto-detect and is passed through unchanged.
+pub fn clamp_threads(requested: usize) -> (usize, bool) {
+    let max = num_cpus::get() * 4;
+    if requested > max {
+        (max, true)
+    } else {
+        (requested, false)
+    }
+}
+
+/// Return the entries of `types` that are not known ripgrep type names
+pub fn unknown_file_types(types: &[String]) -> Vec<String> {
+    let mut builder = TypesBuilder::new();
+    builder.add_defaults();
+    let known: Vec<String> = builder
+        .definitions()
+        .into_iter()
+        .map(|def| def.name().to_string())
+        .collect();
+
+    types
+        .iter()
+        .filter(|t| !known.iter().any(|k| k == *t))
+        .cloned()
+        .collect()
+}
+
 /// Build a directory walker with the given options
+///
+/// `.ignore` files apply everywhere, git repository or not, and
+/// `.rgignore` is read too so ripgrep users' rules carry over.
 fn build_walker(path: &Path, opts: &SearchOptions) -> Result<WalkBuilder, String> {
     let mut builder = WalkBuilder::new(path);
+    let git_ignore = opts.git_ignore && !opts.no_ignore;
 
     builder
         .hidden(!opts.hidden)
-        .git_ignore(opts.git_ignore)
-        .git_global(opts.git_ignore)
-        .git_exclude(opts.git_ignore)
+        .ignore(!opts.no_ignore)
+        .parents(!opts.no_ignore)
+        .git_ignore(git_ignore)
+        .git_global(git_ignore)
+        .git_exclude(git_ignore)
         .follow_links(opts.follow_symlinks)
         .same_file_system(false);
+    if !opts.no_ignore {
+        builder.add_custom_ignore_filename(".rgignore");
+    }
 
     if let Some(depth) = opts.max_depth {
         builder.max_depth(Some(depth));
     }
 
-    let threads = if opts.threads == 0 {
-        num_cpus::get()
-    } else {
-        opts.threads
-    };
-    builder.threads(threads);
-
-    // Add file type filters
-    if !opts.file_types.is_empty() {
-        let mut types_builder = TypesBuilder::new();
-        types_builder.add_defaults();
-        for file_type in &opts.file_types {
-            types_builder.select(file_type);
-        }
-        let types = types_builder
-            .build()
-            .map_err(|e| format!("Failed to build type matcher: {}", e))?;
+    builder.threads(thread_count(opts));
+
+    if let Some(types) = build_types(opts)? {
         builder.types(types);
     }
-
-    // Add glob overrides
-    if !opts.glob_include.is_empty() || !opts.glob_exclude.is_empty() {
-        let mut override_builder = OverrideBuilder::new(path);
-        for glob in &opts.glob_include {
-            override_builder
-                .add(glob)
-                .map_err(|e| format!("Invalid glob '{}': {}", glob, e))?;
-        }
-        for glob in &opts.glob_exclude {
-            override_builder
-                .add(&format!("!{}", glob))
-                .map_err(|e| format!("Invalid glob '{}': {}", glob, e))?;
-        }
-        let overrides = override_builder
-            .build()
-            .map_err(|e| format!("Failed to build glob matcher: {}", e))?;
+    if let Some(overrides) = build_overrides(path, opts)? {
         builder.overrides(overrides);
     }
 
     Ok(builder)
 }
 
-/// Search a single file and collect matches
-fn search_file(
-    matcher: &grep_regex::RegexMatcher,
-    searcher: &mut Searcher,
-    path: &Path,
-    max_count: Option<u64>,
-) -> Result<Vec<Match>, std::io::Error> {
-    let mut matches = Vec::new();
-    let path_str = path.to_path_buf();
-    let match_count = AtomicUsize::new(0);
+/// Worker threads to use; 0 in the options means one per CPU
+fn thread_count(opts: &SearchOptions) -> usize {
+    if opts.threads == 0 {
+        num_cpus::get()
+    } else {
+        opts.threads
+    }
+}
 
-    searcher.search_path(
-        matcher,
-        path,
-        UTF8(|line_num, line| {
-            // Check max count
-            if let Some(max) = max_count {
-                if match_count.load(Ordering::Relaxed) as u64 >= max {
-                    return Ok(false); // Stop searching this file
-                }
-            }
+/// File type filter, if any types are selected
+fn build_types(opts: &SearchOptions) -> Result<Option<Types>, String> {
+    if opts.file_types.is_empty() {
+        return Ok(None);
+    }
+    let mut types_builder = TypesBuilder::new();
+    types_builder.add_defaults();
+    for file_type in &opts.file_types {
+        types_builder.select(file_type);
+    }
+    types_builder
+        .build()
+        .map(Some)
+        .map_err(|e| format!("Failed to build type matcher: {}", e))
+}
 
-            // Find column of match
-            let col = if let Ok(Some(m)) = matcher.find(line.as_bytes()) {
-                m.start()
-            } else {
-                0
-            };
+/// Include/exclude glob overrides rooted at `path`, if any globs are set
+///
+/// Globs are added in the user's order, so a later one decides for the
+/// files an earlier one also matched.
+fn build_overrides(path: &Path, opts: &SearchOptions) -> Result<Option<Override>, String> {
+    if opts.globs.is_empty() {
+        return Ok(None);
+    }
+    let mut override_builder = OverrideBuilder::new(path);
+    for glob in &opts.globs {
+        override_builder
+            .add(&format_glob(glob))
+            .map_err(|e| format!("Invalid glob '{}': {}", glob.1, e))?;
+    }
+    override_builder
+        .build()
+        .map(Some)
+        .map_err(|e| format!("Failed to build glob matcher: {}", e))
+}
 
-            matches.push(Match {
-                file: path_str.clone(),
-                line_number: line_num,
-                column: col,
-                text: line.trim_end_matches(&['\r', '\n'][..]).to_string(),
-            });
+/// Files git tracks under `path`, with type and glob filters applied
+///
+/// Returns None when `path` isn't inside a git work tree (or git isn't
+/// installed) so the caller can walk the directory instead.
+fn git_tracked_files(path: &Path, opts: &SearchOptions) -> Result<Option<Vec<PathBuf>>, String> {
+    let output = match std::process::Command::new("git")
+        .args(["ls-files", "-z", "--cached"])
+        .current_dir(path)
+        .output()
+    {
+        Ok(out) if out.status.success() => out.stdout,
+        _ => return Ok(None),
+    };
 
-            match_count.fetch_add(1, Ordering::Relaxed);
+    let files = output
+        .split(|&b| b == 0)
+        .filter(|rel| !rel.is_empty())
+        .map(|rel| PathBuf::from(String::from_utf8_lossy(rel).as_ref()));
+    filter_listed(path, files, opts).map(Some)
+}
+
+/// The files of a fixed list a search of `path` looks at
+///
+/// Relative entries are joined to `path`. Entries that aren't files
+/// (deleted since listing, directories) are dropped, and type and glob
+/// filters apply as in a walk; ignore files and `hidden` don't.
+fn filter_listed(
+    path: &Path,
+    files: impl IntoIterator<Item = PathBuf>,
+    opts: &SearchOptions,
+) -> Result<Vec<PathBuf>, String> {
+    let types = build_types(opts)?;
+    let overrides = build_overrides(path, opts)?;
+    Ok(files
+        .into_iter()
+        .map(|file| path.join(file))
+        .filter(|file| file.is_file())
+        .filter(|file| !types.as_ref().is_some_and(|t| t.matched(file, false).is_ignore()))
+        .filter(|file| !overrides.as_ref().is_some_and(|o| o.matched(file, false).is_ignore()))
+        .collect())
+}
+
+/// The fixed file list a search of `path` visits, if it doesn't walk:
+/// `path_list` when set, else the git-tracked files with
+/// `git_tracked_only`
+fn listed_files(path: &Path, opts: &SearchOptions) -> Result<Option<Vec<PathBuf>>, String> {
+    if let Some(list) = &opts.path_list {
+        return filter_listed(path, list.paths.iter().cloned(), opts).map(Some);
+    }
+    if opts.git_tracked_only {
+        return git_tracked_files(path, opts);
+    }
+    Ok(None)
+}
+
+/// Visit a fixed list of files on `threads` workers, each with its own
+/// visitor from `make_visitor`
+fn visit_list<F, V>(files: &[PathBuf], threads: usize, make_visitor: F)
+where
+    F: Fn() -> V + Sync,
+    V: FnMut(&Path) -> WalkState,
+{
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|workers| {
+        for _ in 0..threads.max(1) {
+            workers.spawn(|| {
+                let mut visit = make_visitor();
+                while let Some(file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if let WalkState::Quit = visit(file) {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// Build a match record for a line reported by the searcher
+///
+/// Inverted searches report lines the pattern missed, so there is no
+/// span to find and the column stays 0.
+fn make_match<M: Matcher>(
+    matcher: &M,
+    file: &Path,
+    line_num: u64,
+    offset: u64,
+    line: &str,
+    inverted: bool,
+) -> Match {
+    // Find the span of the match
+    let found = if inverted { None } else { matcher.find(line.as_bytes()).ok().flatten() };
+    let (col, end) = found.map_or((0, 0), |m| (m.start(), m.end()));
+
+    Match {
+        file: file.to_path_buf(),
+        line_number: line_num,
+        column: col,
+        end_column: end,
+        text: line.trim_end_matches(&['\r', '\n'][..]).to_string(),
+        inverted,
+        file_column: None,
+        offset,
+        context: Vec::new(),
+    }
+}
+
+/// Byte spans of every match of `engine` in `text`, for exporters that
+/// list each occurrence rather than the first
+pub fn match_spans(engine: &Engine, text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let _ = with_engine!(engine, m => m.find_iter(text.as_bytes(), |found| {
+        spans.push((found.start(), found.end()));
+        true
+    }));
+    spans
+}
+
+/// Sink handing each reported line's number, byte offset and bytes to a
+/// closure; grep_searcher's Bytes sink, plus the offset
+///
+/// Context lines (-A/-B) are kept aside as `(line, after, text)` for
+/// `attach_context` to hand to the matches once the file is done.
+struct Lines<F> {
+    on_line: F,
+    context: Vec<(u64, bool, String)>,
+}
+
+impl<F> Lines<F> {
+    fn new(on_line: F) -> Lines<F> {
+        Lines { on_line, context: Vec::new() }
+    }
+}
+
+impl<F> Sink for Lines<F>
+where
+    F: FnMut(u64, u64, &[u8]) -> Result<bool, std::io::Error>,
+{
+    type Error = std::io::Error;
+
+    fn matched(&mut self, _searcher: &Searcher, mat: &SinkMatch<'_>) -> Result<bool, std::io::Error> {
+        let line_num = mat
+            .line_number()
+            .ok_or_else(|| std::io::Error::other("line numbers must be enabled"))?;
+        (self.on_line)(line_num, mat.absolute_byte_offset(), mat.bytes())
+    }
+
+    fn context(
+        &mut self,
+        _searcher: &Searcher,
+        context: &SinkContext<'_>,
+    ) -> Result<bool, std::io::Error> {
+        if let Some(line_num) = context.line_number() {
+            let after = *context.kind() == SinkContextKind::After;
+            let text = String::from_utf8_lossy(context.bytes());
+            let text = text.trim_end_matches(&['\r', '\n'][..]).to_string();
+            self.context.push((line_num, after, text));
+        }
+        Ok(true)
+    }
+}
+
+/// Give each context line to the match it was shown for: an after-context
+/// line to the match before it, a before-context line to the one after
+///
+/// Lines before a match that max_count stopped short of are dropped.
+fn attach_context(matches: &mut [Match], context: Vec<(u64, bool, String)>) {
+    for (line_num, after, text) in context {
+        let next = matches.partition_point(|m| m.line_number < line_num);
+        let owner = if after { next.checked_sub(1) } else { Some(next) };
+        if let Some(m) = owner.and_then(|at| matches.get_mut(at)) {
+            m.context.push((line_num, text));
+        }
+    }
+}
+
+/// Decode a reported line, replacing invalid UTF-8 with U+FFFD when
+/// `lossy`, else failing with InvalidData so callers can tell an
+/// undecodable file from one they couldn't read
+fn decode_line(bytes: &[u8], lossy: bool) -> Result<std::borrow::Cow<'_, str>, std::io::Error> {
+    if lossy {
+        return Ok(String::from_utf8_lossy(bytes));
+    }
+    std::str::from_utf8(bytes)
+        .map(std::borrow::Cow::Borrowed)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Encoding the searcher transcodes a file from, if it isn't UTF-8
+///
+/// Mirrors the searcher: a byte order mark wins over the -E default.
+fn file_encoding(path: &Path, default: Option<&'static Encoding>) -> Option<&'static Encoding> {
+    let mut head = Vec::with_capacity(3);
+    let _ = std::fs::File::open(path).and_then(|f| f.take(3).read_to_end(&mut head));
+    let encoding = Encoding::for_bom(&head).map_or(default, |(encoding, _)| Some(encoding));
+    encoding.filter(|&e| e != UTF_8)
+}
+
+/// Size of `text` once encoded back into `encoding`, to map a column in
+/// the transcoded line onto the file's own bytes
+fn encoded_len(text: &str, encoding: &'static Encoding) -> usize {
+    // encoding_rs only encodes UTF-16 as UTF-8, so count code units
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        return text.encode_utf16().count() * 2;
+    }
+    encoding.encode(text).0.len()
+}
+
+/// Search a single file and collect matches
+///
+/// Returns the matches and whether max_count cut the file short. With
+/// `lossy` set, lines that aren't valid UTF-8 (Latin-1 and the like)
+/// still match, with the bad bytes shown as U+FFFD; otherwise such a
+/// file fails with InvalidData.
+fn search_file<M: Matcher>(
+    matcher: &M,
+    searcher: &mut Searcher,
+    path: &Path,
+    max_count: Option<u64>,
+    lossy: bool,
+    unzip: Option<&Unzip>,
+) -> Result<(Vec<Match>, bool), std::io::Error> {
+    let mut matches = Vec::new();
+    let mut truncated = false;
+    let path_str = path.to_path_buf();
+    let match_count = AtomicUsize::new(0);
+    let inverted = searcher.invert_match();
+
+    let mut on_line = Lines::new(|line_num, offset, bytes: &[u8]| {
+        // Check max count
+        if let Some(max) = max_count {
+            if match_count.load(Ordering::Relaxed) as u64 >= max {
+                truncated = true;
+                return Ok(false); // Stop searching this file
+            }
+        }
+
+        let line = decode_line(bytes, lossy)?;
+        matches.push(make_match(matcher, &path_str, line_num, offset, &line, inverted));
+
+        match_count.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    });
+    search_source(matcher, searcher, path, unzip, &mut on_line)?;
+    let context = on_line.context;
+    attach_context(&mut matches, context);
+
+    Ok((matches, truncated))
+}
+
+/// Decompression for compressed files, when search_compressed is on
+struct Unzip {
+    /// Decompressed bytes allowed per file, against zip bombs
+    limit: u64,
+}
+
+impl Unzip {
+    fn new(opts: &SearchOptions) -> Option<Unzip> {
+        opts.search_compressed.then(|| Unzip {
+            limit: opts.max_filesize.unwrap_or(DEFAULT_MAX_DECOMPRESSED),
+        })
+    }
+}
+
+/// A compressed format -z can read, decoded in-process
+///
+/// gzip is always built in; bzip2, xz and zstd come with the features of
+/// the same name. Files in any other format are searched as they are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Codec {
+    Gzip,
+    #[cfg(feature = "bzip2")]
+    Bzip2,
+    #[cfg(feature = "xz")]
+    Xz,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Codec {
+    /// The format a path's extension names, if it's built in
+    fn of(path: &Path) -> Option<Codec> {
+        match path.extension()?.to_str()? {
+            "gz" | "tgz" => Some(Codec::Gzip),
+            #[cfg(feature = "bzip2")]
+            "bz2" | "tbz2" => Some(Codec::Bzip2),
+            #[cfg(feature = "xz")]
+            "xz" | "txz" => Some(Codec::Xz),
+            #[cfg(feature = "zstd")]
+            "zst" | "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Decoded contents of `file`; concatenated streams are read through
+    fn reader(self, file: std::fs::File) -> Result<Box<dyn std::io::Read>, std::io::Error> {
+        let file = std::io::BufReader::new(file);
+        Ok(match self {
+            Codec::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(file)),
+            #[cfg(feature = "bzip2")]
+            Codec::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(file)),
+            #[cfg(feature = "xz")]
+            Codec::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(file)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(file)?),
+        })
+    }
+}
+
+/// Whether a path names a compressed file re2 can search with -z
+pub fn is_compressed(path: &Path) -> bool {
+    Codec::of(path).is_some()
+}
+
+/// Feed a file to the searcher, decompressing it first if `unzip` is set
+/// and the file is in a format it decodes
+fn search_source<M: Matcher, S: Sink<Error = std::io::Error>>(
+    matcher: &M,
+    searcher: &mut Searcher,
+    path: &Path,
+    unzip: Option<&Unzip>,
+    sink: S,
+) -> Result<(), std::io::Error> {
+    let (Some(unzip), Some(codec)) = (unzip, Codec::of(path)) else {
+        return searcher.search_path(matcher, path, sink);
+    };
+
+    let limited = SizeLimit {
+        inner: codec.reader(std::fs::File::open(path)?)?,
+        left: unzip.limit,
+        limit: unzip.limit,
+    };
+    searcher.search_reader(matcher, limited, sink)
+}
+
+/// Reader that fails once more than `limit` bytes have come through
+struct SizeLimit<R> {
+    inner: R,
+    left: u64,
+    limit: u64,
+}
+
+impl<R: std::io::Read> std::io::Read for SizeLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.left {
+            return Err(std::io::Error::other(format!(
+                "decompressed size exceeds {} bytes (max_filesize)",
+                self.limit
+            )));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Count matching lines in a single file without collecting their text
+fn count_file<M: Matcher>(
+    matcher: &M,
+    searcher: &mut Searcher,
+    path: &Path,
+    max_count: Option<u64>,
+    unzip: Option<&Unzip>,
+) -> Result<(u64, bool), std::io::Error> {
+    let mut count = 0u64;
+    let mut truncated = false;
+
+    search_source(
+        matcher,
+        searcher,
+        path,
+        unzip,
+        Bytes(|_, _| {
+            if let Some(max) = max_count {
+                if count >= max {
+                    truncated = true;
+                    return Ok(false);
+                }
+            }
+            count += 1;
             Ok(true)
         }),
     )?;
 
-    Ok(matches)
+    Ok((count, truncated))
+}
+
+/// Search in-memory buffer contents, labelling matches with the buffer name
+pub fn search_buffer(
+    pattern: &str,
+    name: &str,
+    contents: &[u8],
+    opts: &SearchOptions,
+) -> Result<SearchResult, String> {
+    search_buffers(pattern, &[(name.to_string(), contents.to_vec())], opts)
+}
+
+/// Search a line range (1-based, inclusive) of in-memory buffer contents
+///
+/// Line numbers in the result refer to the whole buffer, not the slice.
+pub fn search_buffer_lines(
+    pattern: &str,
+    name: &str,
+    contents: &[u8],
+    first_line: u64,
+    last_line: u64,
+    opts: &SearchOptions,
+) -> Result<SearchResult, String> {
+    let (start, end) = line_range_bytes(contents, first_line, last_line);
+    let mut result = search_buffer(pattern, name, &contents[start..end], opts)?;
+    let offset = first_line.saturating_sub(1);
+    for m in &mut result.matches {
+        m.line_number += offset;
+    }
+    Ok(result)
+}
+
+/// Byte range covering lines first..=last (1-based) of contents
+fn line_range_bytes(contents: &[u8], first: u64, last: u64) -> (usize, usize) {
+    let mut start = contents.len();
+    let mut end = contents.len();
+    let mut line = 1u64;
+
+    if first <= 1 {
+        start = 0;
+    }
+    for (i, &b) in contents.iter().enumerate() {
+        if b != b'\n' {
+            continue;
+        }
+        if line == last {
+            end = i + 1;
+            break;
+        }
+        line += 1;
+        if line == first {
+            start = i + 1;
+        }
+    }
+
+    (start.min(end), end)
+}
+
+/// Search several in-memory buffers, labelling matches with each buffer's name
+pub fn search_buffers(
+    pattern: &str,
+    buffers: &[(String, Vec<u8>)],
+    opts: &SearchOptions,
+) -> Result<SearchResult, String> {
+    let engine = build_engine(pattern, opts)?;
+    let pcre2 = engine.is_pcre2();
+    let mut result = with_engine!(engine, m => search_buffers_with(m, buffers, opts));
+    result.stats.pcre2 = pcre2;
+    Ok(result)
+}
+
+fn search_buffers_with<M: Matcher>(
+    matcher: M,
+    buffers: &[(String, Vec<u8>)],
+    opts: &SearchOptions,
+) -> SearchResult {
+    let start = std::time::Instant::now();
+
+    let mut searcher = build_searcher(opts);
+    let inverted = opts.invert_match;
+    let mut matches = Vec::new();
+    let mut errors = Vec::new();
+    let mut truncated_files = Vec::new();
+    let mut files_matched = 0;
+
+    for (name, contents) in buffers {
+        let label = PathBuf::from(name);
+        let mut buffer_matches = Vec::new();
+        let mut truncated = false;
+
+        let mut on_line = Lines::new(|line_num, offset, bytes: &[u8]| {
+            if let Some(max) = opts.max_count {
+                if buffer_matches.len() as u64 >= max {
+                    truncated = true;
+                    return Ok(false);
+                }
+            }
+            let line = decode_line(bytes, opts.lossy_utf8)?;
+            let m = make_match(&matcher, &label, line_num, offset, &line, inverted);
+            buffer_matches.push(m);
+            Ok(true)
+        });
+        let res = searcher.search_slice(&matcher, contents, &mut on_line);
+        if let Err(err) = res {
+            errors.push(format!("{}: {}", name, err));
+        }
+        let context = on_line.context;
+        attach_context(&mut buffer_matches, context);
+
+        if truncated {
+            truncated_files.push(label);
+        }
+        if !buffer_matches.is_empty() {
+            files_matched += 1;
+            matches.extend(buffer_matches);
+        }
+    }
+
+    SearchResult {
+        stats: SearchStats {
+            matches: matches.len(),
+            files_searched: buffers.len(),
+            files_matched,
+            elapsed_ms: start.elapsed().as_millis() as u64,
+            case_mode: CaseMode::of(opts),
+            whole_word: opts.word_boundary,
+            ..SearchStats::default()
+        },
+        matches,
+        errors,
+        truncated_files,
+        counts: Vec::new(),
+        transcoded: Vec::new(),
+    }
 }
 
 /// Perform a parallel search across a directory
+///
+/// Workers check `cancel` before each file, so a cancelled search returns
+/// the partial result with `stats.cancelled` set. A search running past
+/// `opts.timeout_ms` stops the same way, with `stats.timed_out` set.
+///
+/// The walk runs on worker threads while the calling thread collects, so
+/// `on_batch` sees each file's matches, with running totals, on the
+/// caller's thread as soon as the file is done. Batches arrive in
+/// completion order; the returned result is sorted.
+///
+/// While no batch arrives, `on_batch` is still called every
+/// COLLECT_TICK_MS with an empty one.
+///
+/// `files_searched` is reset and then counts files as they finish, so
+/// another thread can report progress between batches.
 pub fn search_parallel(
     pattern: &str,
     path: &str,
     opts: &SearchOptions,
+    cancel: &AtomicBool,
+    files_searched: &AtomicUsize,
+    on_batch: &mut dyn FnMut(&[Match], &Progress),
+) -> Result<SearchResult, String> {
+    let engine = build_engine(pattern, opts)?;
+    let pcre2 = engine.is_pcre2();
+    let mut result = with_engine!(engine, m => {
+        search_parallel_with(m, path, opts, cancel, files_searched, on_batch)
+    })?;
+    result.stats.pcre2 = pcre2;
+    Ok(result)
+}
+
+/// Whether `pattern` matches anywhere under `path`
+///
+/// The walk stops at the first match and no Match records are built, so
+/// this is much cheaper than a search when only yes or no is needed.
+/// Files that can't be read count as not matching. `cancel`,
+/// `files_searched` and `on_tick` work as for count_matches; a cancelled
+/// walk that found nothing yet gives false.
+pub fn search_exists(
+    pattern: &str,
+    path: &str,
+    opts: &SearchOptions,
+    cancel: &AtomicBool,
+    files_searched: &AtomicUsize,
+    on_tick: &mut dyn FnMut(),
+) -> Result<bool, String> {
+    let engine = build_engine(pattern, opts)?;
+    files_searched.store(0, Ordering::Relaxed);
+    with_ticks(
+        || with_engine!(engine, m => exists_with(m, path, opts, cancel, files_searched)),
+        on_tick,
+    )
+}
+
+fn exists_with<M: Matcher + Send + Sync>(
+    matcher: M,
+    path: &str,
+    opts: &SearchOptions,
+    cancel: &AtomicBool,
+    files_searched: &AtomicUsize,
+) -> Result<bool, String> {
+    let unzip = Unzip::new(opts);
+    let unzip = unzip.as_ref();
+    let found = AtomicBool::new(false);
+
+    visit_files(path, opts, || {
+        let mut searcher = build_searcher(opts);
+        let (matcher, found) = (&matcher, &found);
+        move |file: &Path| {
+            if found.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed) {
+                return WalkState::Quit;
+            }
+            files_searched.fetch_add(1, Ordering::Relaxed);
+            let mut hit = false;
+            let sink = Bytes(|_, _| {
+                hit = true;
+                Ok(false)
+            });
+            let _ = search_source(matcher, &mut searcher, file, unzip, sink);
+            if hit {
+                found.store(true, Ordering::Relaxed);
+                return WalkState::Quit;
+            }
+            WalkState::Continue
+        }
+    })?;
+
+    Ok(found.load(Ordering::Relaxed))
+}
+
+/// Number of lines matching `pattern` under `path`
+///
+/// Only the total is kept: no Match records, per-file counts or text, so
+/// counting millions of hits costs no more memory than counting one.
+/// `max_count` still caps each file. Files that can't be read count as
+/// having no matches.
+///
+/// As in search_parallel, setting `cancel` stops the walk (the total so
+/// far is returned), `files_searched` counts files as they finish and
+/// `on_tick` runs on the calling thread every COLLECT_TICK_MS meanwhile.
+pub fn count_matches(
+    pattern: &str,
+    path: &str,
+    opts: &SearchOptions,
+    cancel: &AtomicBool,
+    files_searched: &AtomicUsize,
+    on_tick: &mut dyn FnMut(),
+) -> Result<usize, String> {
+    let engine = build_engine(pattern, opts)?;
+    files_searched.store(0, Ordering::Relaxed);
+    with_ticks(
+        || with_engine!(engine, m => count_with(m, path, opts, cancel, files_searched)),
+        on_tick,
+    )
+}
+
+fn count_with<M: Matcher + Send + Sync>(
+    matcher: M,
+    path: &str,
+    opts: &SearchOptions,
+    cancel: &AtomicBool,
+    files_searched: &AtomicUsize,
+) -> Result<usize, String> {
+    let unzip = Unzip::new(opts);
+    let unzip = unzip.as_ref();
+    let total = AtomicUsize::new(0);
+
+    visit_files(path, opts, || {
+        let mut searcher = build_searcher(opts);
+        let (matcher, total) = (&matcher, &total);
+        move |file: &Path| {
+            if cancel.load(Ordering::Relaxed) {
+                return WalkState::Quit;
+            }
+            files_searched.fetch_add(1, Ordering::Relaxed);
+            if let Ok((count, _)) = count_file(matcher, &mut searcher, file, opts.max_count, unzip) {
+                total.fetch_add(count as usize, Ordering::Relaxed);
+            }
+            WalkState::Continue
+        }
+    })?;
+
+    Ok(total.into_inner())
+}
+
+/// Run `work` on a thread of its own, calling `on_tick` on the calling
+/// thread every COLLECT_TICK_MS until it returns
+fn with_ticks<T: Send>(work: impl FnOnce() -> T + Send, on_tick: &mut dyn FnMut()) -> T {
+    let tick = std::time::Duration::from_millis(COLLECT_TICK_MS);
+    std::thread::scope(|scope| {
+        // Never sent on; the worker hangs up when it ends, panics included
+        let (done, finished) = channel::bounded::<()>(0);
+        let worker = scope.spawn(move || {
+            let _done = done;
+            work()
+        });
+        while let Err(channel::RecvTimeoutError::Timeout) = finished.recv_timeout(tick) {
+            on_tick();
+        }
+        worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Call a visitor, in parallel, on every file a search of `path` looks at
+///
+/// Files come from the filtered walk, or from the list `listed_files`
+/// gives. Files over `max_filesize` and repeat visits
+/// through symlinks are skipped before the visitor sees them.
+fn visit_files<F, V>(path: &str, opts: &SearchOptions, make_visitor: F) -> Result<(), String>
+where
+    F: Fn() -> V + Sync,
+    V: FnMut(&Path) -> WalkState + Send,
+{
+    let search_path = Path::new(path);
+    let walker = build_walker(search_path, opts)?;
+    let listed = listed_files(search_path, opts)?;
+    let visited = Visited::new(opts, listed.is_some());
+
+    let make_filtered = || {
+        let mut visit = make_visitor();
+        let visited = &visited;
+        move |file: &Path| {
+            let too_large = opts
+                .max_filesize
+                .is_some_and(|max| file.metadata().is_ok_and(|meta| meta.len() > max));
+            if too_large || !visited.first(file) {
+                return WalkState::Continue;
+            }
+            visit(file)
+        }
+    };
+
+    if let Some(files) = &listed {
+        visit_list(files, thread_count(opts), make_filtered);
+    } else {
+        let follow = opts.follow_symlinks;
+        walker.build_parallel().run(|| {
+            let mut visit = make_filtered();
+            let visited = &visited;
+            Box::new(move |entry| {
+                let Ok(entry) = entry else {
+                    return WalkState::Continue;
+                };
+                if entry.file_type().is_some_and(|t| t.is_dir()) {
+                    if follow && !visited.first(entry.path()) {
+                        return WalkState::Skip;
+                    }
+                    return WalkState::Continue;
+                }
+                visit(entry.path())
+            })
+        });
+    }
+
+    Ok(())
+}
+
+fn search_parallel_with<M: Matcher + Send + Sync>(
+    matcher: M,
+    path: &str,
+    opts: &SearchOptions,
+    cancel: &AtomicBool,
+    files_searched: &AtomicUsize,
+    on_batch: &mut dyn FnMut(&[Match], &Progress),
 ) -> Result<SearchResult, String> {
     let start = std::time::Instant::now();
     let search_path = Path::new(path);
 
     // Build components
-    let matcher = Arc::new(build_matcher(pattern, opts)?);
+    let matcher = Arc::new(matcher);
     let walker = build_walker(search_path, opts)?;
+    let listed = listed_files(search_path, opts)?;
+    let line_ranges: Option<LineRanges> = opts.path_list.as_ref().and_then(|list| {
+        let lines = list.lines.as_ref()?;
+        Some(lines.iter().map(|(file, ranges)| (search_path.join(file), ranges.clone())).collect())
+    });
+    let line_ranges = line_ranges.as_ref();
+    let outside_lines = &AtomicUsize::new(0);
 
     // Shared state
-    let matches: Arc<Mutex<Vec<Match>>> = Arc::new(Mutex::new(Vec::new()));
+    let mut file_batches: Vec<Vec<Match>> = Vec::new();
     let errors: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-    let files_searched = Arc::new(AtomicUsize::new(0));
+    let truncated_files: Arc<Mutex<Vec<PathBuf>>> = Arc::new(Mutex::new(Vec::new()));
+    let counts: Arc<Mutex<Vec<(PathBuf, u64)>>> = Arc::new(Mutex::new(Vec::new()));
+    let transcoded: Arc<Mutex<Vec<(PathBuf, &'static str)>>> = Arc::new(Mutex::new(Vec::new()));
+    let skipped_encoding = Arc::new(AtomicUsize::new(0));
+    let skipped_large = Arc::new(AtomicUsize::new(0));
+    files_searched.store(0, Ordering::Relaxed);
     let files_matched = Arc::new(AtomicUsize::new(0));
     let quit_flag = Arc::new(AtomicBool::new(false));
+    let visited = Arc::new(Visited::new(opts, listed.is_some()));
 
-    // Channel for sending matches from workers to collector
-    let (tx, rx) = channel::unbounded::<Vec<Match>>();
-
-    // Spawn collector thread
-    let matches_clone = Arc::clone(&matches);
-    let collector = std::thread::spawn(move || {
-        for file_matches in rx {
-            let mut all_matches = matches_clone.lock().unwrap();
-            all_matches.extend(file_matches);
-        }
-    });
+    // Channel for sending matches from workers to the collecting (calling)
+    // thread. Bounded so workers block instead of racing ahead of it
+    let (tx, rx) = channel::bounded::<Vec<Match>>(MATCH_CHANNEL_CAPACITY);
 
     // Run parallel walk
     let max_count = opts.max_count;
     let max_filesize = opts.max_filesize;
+    let count_only = opts.count_only;
+    let lossy_utf8 = opts.lossy_utf8;
+    let encoding = opts.encoding;
+    let unzip = Unzip::new(opts);
+    let unzip = unzip.as_ref();
 
-    walker.build_parallel().run(|| {
+    // Per-thread file visitor shared by the walker and the file list
+    let make_visitor = |tx: &channel::Sender<Vec<Match>>| {
         let matcher = Arc::clone(&matcher);
         let tx = tx.clone();
         let errors = Arc::clone(&errors);
-        let files_searched = Arc::clone(&files_searched);
+        let truncated_files = Arc::clone(&truncated_files);
+        let counts = Arc::clone(&counts);
+        let transcoded = Arc::clone(&transcoded);
+        let skipped_encoding = Arc::clone(&skipped_encoding);
+        let skipped_large = Arc::clone(&skipped_large);
         let files_matched = Arc::clone(&files_matched);
         let quit_flag = Arc::clone(&quit_flag);
+        let visited = Arc::clone(&visited);
         let mut searcher = build_searcher(opts);
 
-        Box::new(move |entry| {
+        move |path: &Path| {
             // Check if we should quit
-            if quit_flag.load(Ordering::Relaxed) {
+            if quit_flag.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed) {
                 return WalkState::Quit;
             }
 
-            let entry = match entry {
-                Ok(e) => e,
-                Err(err) => {
-                    errors.lock().unwrap().push(format!("{}", err));
-                    return WalkState::Continue;
-                }
-            };
-
-            // Skip directories
-            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
-                return WalkState::Continue;
-            }
-
-            let path = entry.path();
-
             // Check file size limit
             if let Some(max_size) = max_filesize {
                 if let Ok(meta) = path.metadata() {
                     if meta.len() > max_size {
+                        skipped_large.fetch_add(1, Ordering::Relaxed);
                         return WalkState::Continue;
                     }
                 }
             }
 
+            // A symlink can lead back to a file the walk already
+            // reached by another path
+            if !visited.first(path) {
+                return WalkState::Continue;
+            }
+
             files_searched.fetch_add(1, Ordering::Relaxed);
 
+            // Count-only mode never builds Match records
+            if count_only {
+                match count_file(&*matcher, &mut searcher, path, max_count, unzip) {
+                    Ok((count, truncated)) => {
+                        if truncated {
+                            crate::lock(&truncated_files).push(path.to_path_buf());
+                        }
+                        if count > 0 {
+                            files_matched.fetch_add(1, Ordering::Relaxed);
+                            crate::lock(&counts).push((path.to_path_buf(), count));
+                        }
+                    }
+                    Err(err) => {
+                        if err.kind() == std::io::ErrorKind::InvalidData {
+                            skipped_encoding.fetch_add(1, Ordering::Relaxed);
+                        } else {
+                            crate::lock(&errors).push(format!("{}: {}", path.display(), err));
+                        }
+                    }
+                }
+                return WalkState::Continue;
+            }
+
             // Search the file
-            match search_file(&matcher, &mut searcher, path, max_count) {
-                Ok(file_matches) => {
+            match search_file(&*matcher, &mut searcher, path, max_count, lossy_utf8, unzip) {
+                Ok((mut file_matches, truncated)) => {
+                    if let Some(ranges) = line_ranges {
+                        let ranges = ranges.get(path).map_or(&[][..], Vec::as_slice);
+                        let before = file_matches.len();
+                        file_matches.retain(|m| in_ranges(ranges, m.line_number));
+                        outside_lines.fetch_add(before - file_matches.len(), Ordering::Relaxed);
+                    }
+                    // Columns come from the transcoded text; map them back
+                    // onto the file's own bytes
+                    let compressed = unzip.is_some() && is_compressed(path);
+                    let source = if file_matches.is_empty() || compressed {
+                        None
+                    } else {
+                        file_encoding(path, encoding)
+                    };
+                    if let Some(source) = source {
+                        for m in file_matches.iter_mut().filter(|m| !m.inverted) {
+                            m.file_column = Some(encoded_len(&m.text[..m.column], source));
+                        }
+                        crate::lock(&transcoded).push((path.to_path_buf(), source.name()));
+                    }
+                    if truncated {
+                        crate::lock(&truncated_files).push(path.to_path_buf());
+                    }
                     if !file_matches.is_empty() {
                         files_matched.fetch_add(1, Ordering::Relaxed);
                         let _ = tx.send(file_matches);
                     }
                 }
                 Err(err) => {
-                    // Silently skip files that can't be read (binary, permission denied, etc.)
-                    if err.kind() != std::io::ErrorKind::InvalidData {
-                        errors.lock().unwrap().push(format!("{}: {}", path.display(), err));
+                    // Invalid UTF-8 only fails a file with lossy_utf8 off;
+                    // count those rather than listing each as an error
+                    if err.kind() == std::io::ErrorKind::InvalidData {
+                        skipped_encoding.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        crate::lock(&errors).push(format!("{}: {}", path.display(), err));
                     }
                 }
             }
 
             WalkState::Continue
-        })
-    });
+        }
+    };
 
-    // Close sender and wait for collector
-    drop(tx);
-    collector.join().unwrap();
+    let max_total = opts.max_results;
+    let timed_out = AtomicBool::new(false);
+    let (capped, found) = std::thread::scope(|scope| {
+        // Watchdog: stops the walk at the deadline unless the search
+        // finishes first and hangs up
+        let (finished, deadline) = channel::bounded::<()>(0);
+        if let Some(limit) = opts.timeout_ms {
+            let (quit_flag, timed_out) = (&quit_flag, &timed_out);
+            scope.spawn(move || {
+                let limit = std::time::Duration::from_millis(limit);
+                if let Err(channel::RecvTimeoutError::Timeout) = deadline.recv_timeout(limit) {
+                    timed_out.store(true, Ordering::Relaxed);
+                    quit_flag.store(true, Ordering::Relaxed);
+                }
+            });
+        }
 
-    let elapsed = start.elapsed();
-    let all_matches = Arc::try_unwrap(matches).unwrap().into_inner().unwrap();
-    let all_errors = Arc::try_unwrap(errors).unwrap().into_inner().unwrap();
+        let walk = scope.spawn(|| {
+            if let Some(files) = &listed {
+                visit_list(files, thread_count(opts), || make_visitor(&tx));
+            } else {
+                let follow = opts.follow_symlinks;
+                walker.build_parallel().run(|| {
+                    let mut visit = make_visitor(&tx);
+                    let errors = Arc::clone(&errors);
+                    let visited = Arc::clone(&visited);
 
-    Ok(SearchResult {
-        stats: SearchStats {
-            matches: all_matches.len(),
-            files_searched: files_searched.load(Ordering::Relaxed),
-            files_matched: files_matched.load(Ordering::Relaxed),
-            elapsed_ms: elapsed.as_millis() as u64,
-        },
-        matches: all_matches,
-        errors: all_errors,
-    })
-}
+                    Box::new(move |entry| {
+                        let entry = match entry {
+                            Ok(e) => e,
+                            // A link back to an ancestor; that tree is
+                            // already being walked
+                            Err(err) if is_loop(&err) => return WalkState::Continue,
+                            Err(err) => {
+                                crate::lock(&errors).push(format!("{}", err));
+                                return WalkState::Continue;
+                            }
+                        };
 
-/// Format elapsed time in human-readable form
+                        // Directories aren't searched, but with links
+                        // followed one reached twice is walked only once
+                        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                            if follow && !visited.first(entry.path()) {
+                                return WalkState::Skip;
+                            }
+                            return WalkState::Continue;
+                        }
+
+                        visit(entry.path())
+                    })
+                });
+            }
+            // Last sender; dropping it ends the collecting loop below
+            drop(tx);
+        });
+
+        let mut total = 0usize;
+        let mut found = 0usize;
+        let mut capped = false;
+        // Each batch is one file's matches; a file arriving twice despite
+        // `visited` is dropped whole
+        let mut seen_files: HashSet<PathBuf> = HashSet::new();
+        // Keep draining after the cap so blocked senders can finish
+        let tick = std::time::Duration::from_millis(COLLECT_TICK_MS);
+        loop {
+            let mut file_matches = match rx.recv_timeout(tick) {
+                Ok(file_matches) => file_matches,
+                Err(channel::RecvTimeoutError::Timeout) => {
+                    let progress = Progress {
+                        files_searched: files_searched.load(Ordering::Relaxed),
+                        matches: total,
+                    };
+                    on_batch(&[], &progress);
+                    continue;
+                }
+                Err(channel::RecvTimeoutError::Disconnected) => break,
+            };
+            if visited.is_kept() && !seen_files.insert(file_matches[0].file.clone()) {
+                continue;
+            }
+            found += file_matches.len();
+            if capped {
+                continue;
+            }
+            if let Some(cap) = max_total {
+                // Reaching the cap stops the walk, but only a match past
+                // it means results were actually cut off
+                if total + file_matches.len() > cap {
+                    file_matches.truncate(cap - total);
+                    capped = true;
+                }
+                if total + file_matches.len() >= cap {
+                    quit_flag.store(true, Ordering::Relaxed);
+                }
+            }
+            total += file_matches.len();
+            if !file_matches.is_empty() {
+                let progress = Progress {
+                    files_searched: files_searched.load(Ordering::Relaxed),
+                    matches: total,
+                };
+                on_batch(&file_matches, &progress);
+                file_batches.push(file_matches);
+            }
+        }
+
+        if let Err(panic) = walk.join() {
+            std::panic::resume_unwind(panic);
+        }
+        drop(finished);
+        (capped, found)
+    });
+
+    let elapsed = start.elapsed();
+    // Workers finish files in arbitrary order; each batch holds one file's
+    // matches in line order, so sorting batches by path makes output stable
+    file_batches.sort_by(|a, b| a[0].file.cmp(&b[0].file));
+    // Taken under the lock: a worker's clone of an Arc may not be dropped
+    // yet, so unwrapping one could panic
+    let mut all_errors = std::mem::take(&mut *crate::lock(&errors));
+    all_errors.sort();
+    let sorted_by_modified = opts.sort == SortOrder::Modified;
+    if sorted_by_modified {
+        sort_batches_by_modified(&mut file_batches, &mut all_errors);
+    }
+    let all_matches: Vec<Match> = file_batches.into_iter().flatten().collect();
+    let mut all_truncated = std::mem::take(&mut *crate::lock(&truncated_files));
+    all_truncated.sort();
+    let mut all_counts = std::mem::take(&mut *crate::lock(&counts));
+    all_counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let total_count: u64 = all_counts.iter().map(|(_, n)| n).sum();
+    let mut all_transcoded = std::mem::take(&mut *crate::lock(&transcoded));
+    all_transcoded.sort();
+
+    Ok(SearchResult {
+        stats: SearchStats {
+            matches: if count_only { total_count as usize } else { all_matches.len() },
+            files_searched: files_searched.load(Ordering::Relaxed),
+            files_matched: files_matched.load(Ordering::Relaxed),
+            elapsed_ms: elapsed.as_millis() as u64,
+            sorted_by_modified,
+            capped,
+            matches_found: if count_only { total_count as usize } else { found },
+            cancelled: cancel.load(Ordering::Relaxed),
+            case_mode: CaseMode::of(opts),
+            whole_word: opts.word_boundary,
+            pcre2: false,
+            no_ignore: opts.no_ignore,
+            hidden: opts.hidden,
+            max_depth: opts.max_depth,
+            skipped_encoding: skipped_encoding.load(Ordering::Relaxed),
+            skipped_large: skipped_large.load(Ordering::Relaxed),
+            timed_out: opts.timeout_ms.filter(|_| timed_out.load(Ordering::Relaxed)),
+            scope: opts.path_list.as_ref().map(|list| list.source.clone()),
+            outside_lines: line_ranges.as_ref().map(|_| outside_lines.load(Ordering::Relaxed)),
+        },
+        matches: all_matches,
+        errors: all_errors,
+        truncated_files: all_truncated,
+        counts: all_counts,
+        transcoded: all_transcoded,
+    })
+}
+
+/// Files, and with links followed directories, a walk has reached
+///
+/// Only kept when one can be reached twice: with symlinks followed, or
+/// when a file list may name a file more than once. Otherwise every
+/// visit is a first one and no lock is taken.
+struct Visited(Option<Mutex<HashSet<FileId>>>);
+
+/// What makes two paths the same file: device and inode on Unix, the
+/// canonical path elsewhere
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+impl Visited {
+    fn new(opts: &SearchOptions, listed: bool) -> Visited {
+        Visited((opts.follow_symlinks || listed).then(|| Mutex::new(HashSet::new())))
+    }
+
+    fn is_kept(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Record a visit, returning false if the file was reached before;
+    /// one that can't be looked up always counts as new
+    fn first(&self, path: &Path) -> bool {
+        let (Some(seen), Some(id)) = (&self.0, file_id(path)) else {
+            return true;
+        };
+        crate::lock(seen).insert(id)
+    }
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> Option<FileId> {
+    path.canonicalize().ok()
+}
+
+/// Whether a walk error is a symlink leading back to one of its ancestors
+fn is_loop(err: &ignore::Error) -> bool {
+    match err {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => is_loop(err),
+        _ => false,
+    }
+}
+
+/// Result of walking a directory without searching file contents
+#[derive(Debug)]
+pub struct FileList {
+    /// Paths relative to the walk root, in sorted order
+    pub files: Vec<PathBuf>,
+    /// Whether the listing stopped at the limit
+    pub truncated: bool,
+    pub elapsed_ms: u64,
+}
+
+/// List files under a directory (like `rg --files`)
+///
+/// A filter containing glob metacharacters is matched as a glob, anything
+/// else as a substring; both compare case-insensitively against the
+/// relative path.
+pub fn list_files(
+    path: &str,
+    filter: Option<&str>,
+    limit: usize,
+    opts: &SearchOptions,
+) -> Result<FileList, String> {
+    let start = std::time::Instant::now();
+    let root = Path::new(path);
+    let mut walker = build_walker(root, opts)?;
+    walker.sort_by_file_name(|a, b| a.cmp(b));
+
+    let glob = match filter {
+        Some(f) if f.contains(['*', '?', '[']) => {
+            let mut builder = OverrideBuilder::new(root);
+            builder
+                .case_insensitive(true)
+                .map_err(|e| format!("Invalid glob '{}': {}", f, e))?;
+            builder
+                .add(f)
+                .map_err(|e| format!("Invalid glob '{}': {}", f, e))?;
+            Some(
+                builder
+                    .build()
+                    .map_err(|e| format!("Failed to build glob matcher: {}", e))?,
+            )
+        }
+        _ => None,
+    };
+    let needle = filter.map(|f| f.to_lowercase());
+
+    let mut files = Vec::new();
+    let mut truncated = false;
+
+    for entry in walker.build() {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+            continue;
+        }
+
+        let rel = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        let keep = if let Some(ref glob) = glob {
+            glob.matched(rel, false).is_whitelist()
+        } else if let Some(ref needle) = needle {
+            rel.to_string_lossy().to_lowercase().contains(needle.as_str())
+        } else {
+            true
+        };
+        if !keep {
+            continue;
+        }
+
+        if files.len() >= limit {
+            truncated = true;
+            break;
+        }
+        files.push(rel.to_path_buf());
+    }
+
+    Ok(FileList {
+        files,
+        truncated,
+        elapsed_ms: start.elapsed().as_millis() as u64,
+    })
+}
+
+/// Format per-file match counts (`rg -c` style) under a totals header
+pub fn format_counts(result: &SearchResult) -> String {
+    let mut output = String::new();
+
+    let time_str = format_duration(result.stats.elapsed_ms);
+    let match_word = if result.stats.matches == 1 { "MATCH" } else { "MATCHES" };
+    let file_word = if result.stats.files_matched == 1 { "FILE" } else { "FILES" };
+    output.push_str(&format!(
+        "{} {} IN {} {}. Counted in {}.{}\n\n",
+        result.stats.matches,
+        match_word,
+        result.stats.files_matched,
+        file_word,
+        time_str,
+        cancelled_note(result)
+    ));
+
+    for (file, count) in &result.counts {
+        output.push_str(&format!("{}: {}\n", file.display(), count));
+    }
+
+    output
+}
+
+/// Format matches as bare `path:line:col:text` lines for other tools
+///
+/// No header or footer, and the text is written as found, cut only at
+/// the end of its first line. Inverted matches have no column. Paths
+/// under `relative_to` are written relative to it.
+pub fn format_grep(result: &SearchResult, relative_to: Option<&Path>) -> String {
+    let mut output = String::new();
+    for m in &result.matches {
+        let path = relative_to
+            .and_then(|root| m.file.strip_prefix(root).ok())
+            .unwrap_or(&m.file);
+        output.push_str(&format!("{}:{}:{}\n", path.display(), location(m), first_line(&m.text)));
+    }
+    output
+}
+
+/// Format a file listing, one relative path per line
+pub fn format_file_list(list: &FileList, root: &str) -> String {
+    let mut output = String::new();
+
+    let file_word = if list.files.len() == 1 { "FILE" } else { "FILES" };
+    output.push_str(&format!(
+        "{} {} IN {}. Listed in {}.\n\n",
+        list.files.len(),
+        file_word,
+        root,
+        format_duration(list.elapsed_ms)
+    ));
+
+    for file in &list.files {
+        output.push_str(&format!("{}\n", file.display()));
+    }
+
+    if list.truncated {
+        output.push_str(&format!("\n(truncated at {} files)\n", list.files.len()));
+    }
+
+    output
+}
+
+/// Order per-file match batches by modification time, newest first
+///
+/// Each file is stat'ed once. Files whose metadata can't be read sort last
+/// (keeping path order) and the failures are recorded in `errors`.
+fn sort_batches_by_modified(batches: &mut Vec<Vec<Match>>, errors: &mut Vec<String>) {
+    let mut keyed: Vec<(Option<std::time::SystemTime>, Vec<Match>)> = batches
+        .drain(..)
+        .map(|batch| {
+            let mtime = match batch[0].file.metadata().and_then(|m| m.modified()) {
+                Ok(t) => Some(t),
+                Err(err) => {
+                    errors.push(format!("{}: {}", batch[0].file.display(), err));
+                    None
+                }
+            };
+            (mtime, batch)
+        })
+        .collect();
+
+    // Stable sort keeps path order among equal or unknown times
+    keyed.sort_by(|a, b| match (a.0, b.0) {
+        (Some(x), Some(y)) => y.cmp(&x),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+
+    batches.extend(keyed.into_iter().map(|(_, batch)| batch));
+}
+
+/// Format elapsed time in human-readable form
 fn format_duration(ms: u64) -> String {
     if ms < 1000 {
         format!("{} ms", ms)
@@ -408,65 +2458,1987 @@ fn format_duration(ms: u64) -> String {
     }
 }
 
-/// Format results with statistics
-pub fn format_results_with_stats(result: &SearchResult) -> String {
-    let mut output = String::new();
+/// Format one match as a result line, with its context lines around it
+fn format_match(m: &Match, layout: &Layout) -> String {
+    with_context(m)
+        .iter()
+        .map(|line| format!("{}\n", match_line(line, layout).0))
+        .collect()
+}
+
+/// A match and its context lines, in line order
+///
+/// Context lines are laid out like inverted results, without a column,
+/// so they can be jumped to but don't read as matches.
+fn with_context(m: &Match) -> Vec<std::borrow::Cow<'_, Match>> {
+    if m.context.is_empty() {
+        return vec![std::borrow::Cow::Borrowed(m)];
+    }
+    let context_line = |(line_number, text): &(u64, String)| {
+        std::borrow::Cow::Owned(Match {
+            file: m.file.clone(),
+            line_number: *line_number,
+            column: 0,
+            end_column: 0,
+            text: text.clone(),
+            inverted: true,
+            file_column: None,
+            offset: 0,
+            context: Vec::new(),
+        })
+    };
+    let split = m.context.partition_point(|(line, _)| *line < m.line_number);
+    let mut lines: Vec<_> = m.context[..split].iter().map(context_line).collect();
+    lines.push(std::borrow::Cow::Borrowed(m));
+    lines.extend(m.context[split..].iter().map(context_line));
+    lines
+}
+
+/// Narrow a result to the matches a refine filter keeps
+///
+/// The filter is a regex tried against each match's text, or against its
+/// path when written `path:regex`; a leading `!` drops the matches it
+/// finds instead of keeping them.
+pub fn refine(result: &SearchResult, filter: &str) -> Result<SearchResult, String> {
+    let (exclude, filter) = match filter.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, filter),
+    };
+    let (on_path, pattern) = match filter.strip_prefix("path:") {
+        Some(rest) => (true, rest),
+        None => (false, filter),
+    };
+    if pattern.is_empty() {
+        return Err("Empty refine pattern".to_string());
+    }
+    let matcher = build_matcher(pattern, &SearchOptions::default())?;
+
+    let matches: Vec<Match> = result
+        .matches
+        .iter()
+        .filter(|m| {
+            let haystack = if on_path {
+                m.file.to_string_lossy().into_owned()
+            } else {
+                m.text.clone()
+            };
+            matcher.is_match(haystack.as_bytes()).unwrap_or(false) != exclude
+        })
+        .cloned()
+        .collect();
+
+    let mut refined = result.clone();
+    refined.stats.matches = matches.len();
+    refined.stats.files_matched = matches.chunk_by(|a, b| a.file == b.file).count();
+    refined.matches = matches;
+    Ok(refined)
+}
 
+/// Header line listing the refine filters applied, oldest first
+pub fn format_refined(filters: &[String]) -> String {
+    format!("REFINED BY: {}\n", filters.join(" > "))
+}
+
+/// Format results with statistics, as `layout` lays them out
+///
+/// Flat results are one template line per match. Grouped ones give each
+/// file a heading line holding just its path, followed by its matches as
+/// indented `line:col: text` entries and a blank line.
+pub fn format_results(result: &SearchResult, layout: &Layout) -> String {
+    let mut output = format_header(result);
+
+    match layout.style {
+        Style::Flat(_) => {
+            for m in &result.matches {
+                output.push_str(&format_match(m, layout));
+            }
+        }
+        Style::Grouped => {
+            let groups: Vec<String> = result
+                .matches
+                .chunk_by(|a, b| a.file == b.file)
+                .map(|group| format_group(group, layout))
+                .collect();
+            output.push_str(&groups.join("\n"));
+        }
+    }
+
+    output.push_str(&format_footer(result));
+    output
+}
+
+/// Marker regex re2-todo searches for unless config `todo_markers` says
+/// otherwise
+pub const DEFAULT_TODO_MARKERS: &str = r"\b(TODO|FIXME|HACK|XXX)\b";
+
+/// Order re2-todo lists marker kinds in unless config `todo_order` says
+/// otherwise
+pub const DEFAULT_TODO_ORDER: &str = "TODO,FIXME,HACK,XXX";
+
+/// The kind of marker a match is: the word the matched text starts with,
+/// so `TODO(alice)` counts as a TODO
+pub fn marker_kind(m: &Match) -> &str {
+    let matched = m.text.get(m.column..m.end_column).unwrap_or("");
+    let end = matched
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(matched.len());
+    if end > 0 {
+        &matched[..end]
+    } else {
+        matched
+    }
+}
+
+/// Regroup a marker search's matches by kind
+///
+/// Kinds named in `order` come first, in that order, then any others
+/// alphabetically. Matches keep their order within a kind.
+pub fn group_markers<'a>(matches: &'a [Match], order: &[String]) -> Vec<(&'a str, Vec<&'a Match>)> {
+    let mut groups: Vec<(&str, Vec<&Match>)> = Vec::new();
+    for m in matches {
+        let kind = marker_kind(m);
+        match groups.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, group)) => group.push(m),
+            None => groups.push((kind, vec![m])),
+        }
+    }
+    let rank = |kind: &str| order.iter().position(|o| o == kind).unwrap_or(order.len());
+    groups.sort_by(|(a, _), (b, _)| rank(a).cmp(&rank(b)).then(a.cmp(b)));
+    groups
+}
+
+/// re2-todo listing: a header counting each kind, then each kind under
+/// a `KIND (N)` heading as flat `layout` lines
+pub fn format_todo(result: &SearchResult, groups: &[(&str, Vec<&Match>)], layout: &Layout) -> String {
+    let counts: Vec<String> = groups
+        .iter()
+        .map(|(kind, group)| format!("{} {}", kind, group.len()))
+        .collect();
+    let marker_word = if result.matches.len() == 1 { "MARKER" } else { "MARKERS" };
+    let file_word = if result.stats.files_searched == 1 { "FILE" } else { "FILES" };
+    let mut output = format!(
+        "{} {} ACROSS {} {}. Search completed in {}.{} {}\n",
+        result.matches.len(),
+        marker_word,
+        result.stats.files_searched,
+        file_word,
+        format_duration(result.stats.elapsed_ms),
+        cancelled_note(result),
+        counts.join(", ")
+    );
+
+    for (kind, group) in groups {
+        output.push_str(&format!("\n{} ({})\n", kind, group.len()));
+        for m in group {
+            output.push_str(&format_match(m, layout));
+        }
+    }
+
+    output.push_str(&format_footer(result));
+    output
+}
+
+/// Render a match as one line, without its newline
+///
+/// Also returns the highlight spans up to the match text and the byte
+/// offset where that text starts, so highlighting lines up with what
+/// was written. Matches spanning several lines (multiline mode) show
+/// their first line and how many follow, e.g. `fn f( (+2 lines)`.
+///
+/// A line longer than the layout's `max_line_len` is cut there and ends
+/// in `…`: minified files can put megabytes on one line. Only what's
+/// shown is cut; `Match.text` and the column stay whole.
+fn match_line(m: &Match, layout: &Layout) -> (String, LineHighlight, usize) {
+    let first = first_line(&m.text);
+    let first = match clip_at(first, layout.max_line_len) {
+        Some(cut) => format!("{}\u{2026}", escape_controls(&first[..cut])),
+        None => escape_controls(first),
+    };
+    let text = match m.text.lines().count().saturating_sub(1) {
+        0 => first,
+        1 => format!("{} (+1 line)", first),
+        more => format!("{} (+{} lines)", first, more),
+    };
+    match &layout.style {
+        Style::Flat(template) => template.render(m, &text),
+        Style::Grouped => {
+            let prefix = format!("  {}: ", location(m));
+            let text_start = prefix.len();
+            let spans = vec![(2, Highlight::Plain), (text_start, Highlight::Location)];
+            (prefix + &text, spans, text_start)
+        }
+    }
+}
+
+/// Byte offset `line` is cut at to show `max` characters, if it's longer
+fn clip_at(line: &str, max: Option<usize>) -> Option<usize> {
+    line.char_indices().nth(max?).map(|(cut, _)| cut)
+}
+
+/// The first line of a match's text, without any CR
+fn first_line(text: &str) -> &str {
+    text.split('\n').next().unwrap_or("").trim_end_matches('\r')
+}
+
+/// Make control characters visible before text goes into a buffer
+///
+/// Tabs become a space; other C0 controls and DEL use caret notation
+/// (`\r` shows as `^M`), so a stray byte can't break the line apart.
+/// Only the display changes - `Match.text` keeps the original.
+fn escape_controls(text: &str) -> String {
+    if !text.contains(|c: char| c.is_ascii_control()) {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '\t' => out.push(' '),
+            '\x7f' => out.push_str("^?"),
+            c if c.is_ascii_control() => {
+                out.push('^');
+                out.push((c as u8 + b'@') as char);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `line:col`, or just `line` for an inverted result
+///
+/// Columns are shown 1-based like the editor's own, so a match at the
+/// start of a line is column 1. Transcoded files show the column in the
+/// file's own bytes.
+fn location(m: &Match) -> String {
+    if m.inverted {
+        m.line_number.to_string()
+    } else {
+        format!("{}:{}", m.line_number, m.file_column.unwrap_or(m.column) + 1)
+    }
+}
+
+/// Highlight spans for the lines `format_batch` writes for a batch
+///
+/// Returns each rendered line with its spans, context lines included. A
+/// multi-line match is highlighted up to the end of its first line.
+pub fn highlight_batch(batch: &[Match], layout: &Layout) -> Vec<(String, LineHighlight)> {
+    let mut lines = Vec::new();
+
+    if layout.style == Style::Grouped {
+        if let Some(first) = batch.first() {
+            let heading = first.file.display().to_string();
+            let len = heading.len();
+            lines.push((heading, vec![(len, Highlight::Path)]));
+        }
+    }
+
+    for m in batch.iter().flat_map(with_context) {
+        let m = &*m;
+        let (line, mut spans, text_start) = match_line(m, layout);
+        let len = line.len();
+        // Offsets into the escaped text; a clipped line may end before the
+        // match, which then clamps to where it was cut
+        let first = first_line(&m.text);
+        let first = &first[..clip_at(first, layout.max_line_len).unwrap_or(first.len())];
+        let shown =
+            |col: usize| (text_start + escape_controls(first.get(..col).unwrap_or(first)).len()).min(len);
+        let start = shown(m.column);
+        let end = shown(m.end_column).max(start);
+
+        spans.extend([(start, Highlight::Plain), (end, Highlight::Match), (len, Highlight::Plain)]);
+        // Drop empty spans
+        let mut prev = 0;
+        let spans = spans
+            .into_iter()
+            .filter(|&(at, _)| {
+                let keep = at > prev;
+                prev = prev.max(at);
+                keep
+            })
+            .collect();
+        lines.push((line, spans));
+    }
+
+    lines
+}
+
+/// One file's heading and its indented `line:col: text` entries
+fn format_group(matches: &[Match], layout: &Layout) -> String {
+    let Some(first) = matches.first() else {
+        return String::new();
+    };
+    let mut output = format!("{}\n", first.file.display());
+
+    for m in matches {
+        output.push_str(&format_match(m, layout));
+    }
+
+    output
+}
+
+/// Format one file's batch of matches for streaming into the results
+///
+/// Grouped batches after the first are separated by a blank line, as in
+/// `format_results`.
+pub fn format_batch(batch: &[Match], layout: &Layout, first: bool) -> String {
+    if layout.style != Style::Grouped {
+        return batch.iter().map(|m| format_match(m, layout)).collect();
+    }
+    let group = format_group(batch, layout);
+    if first {
+        group
+    } else {
+        format!("\n{}", group)
+    }
+}
+
+/// Statistics line that heads every results listing
+fn format_header(result: &SearchResult) -> String {
+    let capped = if result.stats.capped {
+        format!(
+            "{} {} MATCHES (pattern matched too broadly). Found {} before stopping.\n",
+            CAPPED_NOTE, result.stats.matches, result.stats.matches_found
+        )
+    } else {
+        String::new()
+    };
     let time_str = format_duration(result.stats.elapsed_ms);
     let result_word = if result.stats.matches == 1 { "RESULT" } else { "RESULTS" };
     let file_word = if result.stats.files_searched == 1 { "FILE" } else { "FILES" };
-    output.push_str(&format!(
-        "{} {} ACROSS {} {}. Search completed in {}.\n\n",
+    let sort_str = if result.stats.sorted_by_modified {
+        " Sorted by modification time."
+    } else {
+        ""
+    };
+    format!(
+        "{}{} {} ACROSS {} {}. Search completed in {}.{}{} {}\n\n",
+        capped,
         result.stats.matches,
         result_word,
         result.stats.files_searched,
         file_word,
-        time_str
-    ));
+        time_str,
+        sort_str,
+        cancelled_note(result),
+        mode_note(&result.stats)
+    )
+}
 
-    for m in &result.matches {
+/// `[case: smart]`, plus `whole word` when -w was on, `pcre2` when
+/// PCRE2 ran, the restrictions lifted (`no ignore` when ignore files were
+/// bypassed, `hidden` when dotfiles were searched), `max depth N`,
+/// where a listed search's files came from and how many matches fell
+/// outside its line ranges
+fn mode_note(stats: &SearchStats) -> String {
+    let word = if stats.whole_word { ", whole word" } else { "" };
+    let engine = if stats.pcre2 { ", pcre2" } else { "" };
+    let ignore = if stats.no_ignore { ", no ignore" } else { "" };
+    let hidden = if stats.hidden { ", hidden" } else { "" };
+    let depth = stats.max_depth.map(|d| format!(", max depth {}", d)).unwrap_or_default();
+    let scope = stats.scope.as_ref().map(|s| format!(", {}", s)).unwrap_or_default();
+    let outside = match stats.outside_lines {
+        Some(1) => ", 1 unchanged-line match skipped".to_string(),
+        Some(n) => format!(", {} unchanged-line matches skipped", n),
+        None => String::new(),
+    };
+    format!(
+        "[case: {}{}{}{}{}{}{}{}]",
+        stats.case_mode.label(),
+        word,
+        engine,
+        ignore,
+        hidden,
+        depth,
+        scope,
+        outside
+    )
+}
+
+/// Header suffix marking partial results from a cancelled or timed-out
+/// search
+fn cancelled_note(result: &SearchResult) -> String {
+    if result.stats.cancelled {
+        " (cancelled)".to_string()
+    } else if let Some(ms) = result.stats.timed_out {
+        format!(" (timed out after {})", format_timeout(ms))
+    } else {
+        String::new()
+    }
+}
+
+/// `5s` for whole seconds, else `1500ms`
+fn format_timeout(ms: u64) -> String {
+    if ms >= 1000 && ms.is_multiple_of(1000) {
+        format!("{}s", ms / 1000)
+    } else {
+        format!("{}ms", ms)
+    }
+}
+
+/// Truncation and error sections that follow the matches
+fn format_footer(result: &SearchResult) -> String {
+    let mut output = String::new();
+
+    if !result.truncated_files.is_empty() {
         output.push_str(&format!(
-            "{}:{}:{}: {}\n",
-            m.file.display(),
-            m.line_number,
-            m.column,
-            m.text
+            "\n{} files hit max_count:\n",
+            result.truncated_files.len()
         ));
+        for file in &result.truncated_files {
+            output.push_str(&format!("  {} (truncated)\n", file.display()));
+        }
     }
 
-    if !result.errors.is_empty() {
-        output.push_str(&format!("\n{} errors encountered:\n", result.errors.len()));
-        for err in &result.errors {
-            output.push_str(&format!("  {}\n", err));
+    if !result.transcoded.is_empty() {
+        output.push_str(&format!("\n{} files transcoded to UTF-8:\n", result.transcoded.len()));
+        for (file, encoding) in &result.transcoded {
+            output.push_str(&format!("  {} ({})\n", file.display(), encoding));
         }
     }
 
+    if result.stats.skipped_large > 0 {
+        output.push_str(&format!(
+            "\n{} files skipped (too large): over max_filesize\n",
+            result.stats.skipped_large
+        ));
+    }
+
+    if result.stats.skipped_encoding > 0 {
+        output.push_str(&format!(
+            "\n{} files skipped (encoding): not UTF-8, set encoding or lossy_utf8\n",
+            result.stats.skipped_encoding
+        ));
+    }
+
     output
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_default_options() {
-        let opts = SearchOptions::default();
-        assert!(!opts.case_insensitive);
-        assert!(opts.smart_case);
-        assert!(opts.git_ignore);
+/// Contents of the errors buffer: a count line, then one error per line
+pub fn format_errors(errors: &[String]) -> String {
+    let mut output = format!("{} errors encountered:\n\n", errors.len());
+    for err in errors {
+        output.push_str(&format!("{}\n", err));
     }
+    output
+}
 
-    #[test]
-    fn test_build_matcher() {
-        let opts = SearchOptions::default();
-        let matcher = build_matcher("test", &opts);
-        assert!(matcher.is_ok());
-    }
+/// A result location recovered from the results buffer text
+#[derive(Debug, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: u64,
+    /// 1-based, as shown in the results buffer
+    pub column: usize,
+}
 
-    #[test]
-    fn test_build_matcher_invalid() {
-        let opts = SearchOptions::default();
-        let matcher = build_matcher("[invalid", &opts);
-        assert!(matcher.is_err());
+/// Resolve the location under line `idx` (1-based) of grouped output
+///
+/// `get_line` fetches buffer lines by number. An indented `line:col:` entry
+/// is combined with the nearest file heading above it; a heading on its
+/// own resolves to line 1 of that file.
+pub fn parse_grouped_location<F>(idx: usize, mut get_line: F) -> Option<Location>
+where
+    F: FnMut(usize) -> Option<String>,
+{
+    let mut entry: Option<(u64, usize)> = None;
+    let mut i = idx;
+
+    while i >= 1 {
+        let text = get_line(i)?;
+        if text.trim().is_empty() {
+            return None;
+        }
+
+        if !text.starts_with(char::is_whitespace) {
+            // Statistics header and footer section titles are not files
+            if text.contains(" ACROSS ") || text.ends_with(':') || text.starts_with(CAPPED_NOTE) {
+                return None;
+            }
+            let (line, column) = entry.unwrap_or((1, 1));
+            return Some(Location { file: text, line, column });
+        }
+
+        if entry.is_none() {
+            entry = parse_grouped_entry(&text);
+        }
+        i -= 1;
+    }
+
+    None
+}
+
+/// Whether a results line is a match (not a header, heading, blank,
+/// footer or continuation line)
+pub fn is_result_line(text: &str, layout: &Layout) -> bool {
+    match &layout.style {
+        Style::Grouped => text.starts_with("  ") && parse_grouped_entry(text).is_some(),
+        Style::Flat(template) => template.parse_line(text).is_some(),
+    }
+}
+
+/// Parse an indented `line:col:` (or inverted `line:`) grouped entry
+fn parse_grouped_entry(text: &str) -> Option<(u64, usize)> {
+    let entry = text.strip_prefix("  ")?;
+    let (line, rest) = entry.split_once(':')?;
+    let line = line.parse().ok()?;
+    match rest.split_once(':') {
+        Some((column, _)) if column.parse::<usize>().is_ok() => Some((line, column.parse().ok()?)),
+        _ if rest.is_empty() || rest.starts_with(' ') => Some((line, 1)),
+        _ => None,
+    }
+}
+
+/// Lines `line - radius ..= line + radius` of `reader`, numbered from 1
+///
+/// Reading stops after the last wanted line, so previewing near the top
+/// of a huge file stays cheap. Invalid UTF-8 is replaced.
+pub fn context_lines<R: BufRead>(reader: R, line: u64, radius: u64) -> std::io::Result<Vec<(u64, String)>> {
+    let first = line.saturating_sub(radius).max(1);
+    let mut lines = Vec::new();
+    for (n, bytes) in (1..=line + radius).zip(reader.split(b'\n')) {
+        let bytes = bytes?;
+        if n >= first {
+            let text = String::from_utf8_lossy(&bytes);
+            lines.push((n, text.trim_end_matches('\r').to_string()));
+        }
+    }
+    Ok(lines)
+}
+
+/// Render context lines around a match for previewing
+///
+/// The match line is marked with `>` and followed by a `^` under its
+/// 1-based column. Tabs before the column are kept so the caret lines up.
+pub fn format_context(lines: &[(u64, String)], line: u64, column: usize) -> String {
+    let width = lines.last().map_or(1, |(n, _)| n.to_string().len());
+    let mut output = String::new();
+    for (n, text) in lines {
+        let mark = if *n == line { '>' } else { ' ' };
+        output.push_str(&format!("{} {:>width$} | {}\n", mark, n, text, width = width));
+        if *n == line {
+            let before = text.get(..column.saturating_sub(1)).unwrap_or(text);
+            let pad: String = before.chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            output.push_str(&format!("  {:>width$} | {}^\n", "", pad, width = width));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::fixture_dir;
+
+    #[test]
+    fn test_default_options() {
+        let opts = SearchOptions::default();
+        assert!(!opts.case_insensitive);
+        assert!(opts.smart_case);
+        assert!(opts.git_ignore);
+    }
+
+    #[test]
+    fn test_ignore_files_outside_git() {
+        let dir = fixture_dir(
+            "ignore_files",
+            &[
+                (".ignore", "generated/\n"),
+                (".rgignore", "*.snap\n"),
+                ("src/main.rs", "hit\n"),
+                ("generated/big.rs", "hit\n"),
+                ("src/out.snap", "hit\n"),
+            ],
+        );
+
+        let result = search_dir("hit", dir.to_str().unwrap(), &SearchOptions::default());
+        let files: Vec<_> = result.matches.iter().map(|m| m.file.strip_prefix(&dir).unwrap()).collect();
+        assert_eq!(files, vec![Path::new("src/main.rs")]);
+        assert!(!format_results(&result, &Layout::default()).contains("no ignore"));
+
+        let mut opts = SearchOptions::default();
+        assert_eq!(apply_flags("--no-ignore hit", &mut opts).unwrap(), "hit");
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 3);
+        assert!(format_results(&result, &Layout::default()).contains(", no ignore]"));
+    }
+
+    #[test]
+    fn test_max_depth_counts_like_ripgrep() {
+        let dir = fixture_dir(
+            "max_depth",
+            &[("top.txt", "hit\n"), ("sub/mid.txt", "hit\n"), ("sub/deep/low.txt", "hit\n")],
+        );
+        let found = |flags: &str| {
+            let mut opts = SearchOptions::default();
+            let pattern = apply_flags(flags, &mut opts).unwrap();
+            let result = search_dir(&pattern, dir.to_str().unwrap(), &opts);
+            let names: Vec<_> = result.matches.iter().map(|m| m.file.file_name().unwrap().to_owned()).collect();
+            (names, mode_note(&result.stats))
+        };
+
+        // Depth 1 is only the files directly in the root
+        assert_eq!(found("--max-depth=1 hit"), (vec!["top.txt".into()], "[case: smart, max depth 1]".into()));
+        assert_eq!(found("--max-depth=2 hit").0.len(), 2);
+        assert_eq!(found("--max-depth=0 hit"), (found("hit").0, "[case: smart]".into()));
+        assert!(apply_flags("--max-depth=deep x", &mut SearchOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_hidden_and_unrestricted_flags() {
+        let dir = fixture_dir(
+            "unrestricted",
+            &[(".ignore", "*.log\n"), (".env", "KEY=hit\n"), ("app.log", "hit\n"), ("main.rs", "hit\n")],
+        );
+        let count = |flags: &str| {
+            let mut opts = SearchOptions::default();
+            let pattern = apply_flags(flags, &mut opts).unwrap();
+            let result = search_dir(&pattern, dir.to_str().unwrap(), &opts);
+            (result.matches.len(), mode_note(&result.stats))
+        };
+
+        assert_eq!(count("hit"), (1, "[case: smart]".to_string()));
+        assert_eq!(count("--hidden hit"), (2, "[case: smart, hidden]".to_string()));
+        assert_eq!(count("-u hit").0, 2);
+        assert_eq!(count("-uu hit"), (3, "[case: smart, no ignore, hidden]".to_string()));
+    }
+
+    #[test]
+    fn test_case_mode_cycles_and_applies() {
+        let mut opts = SearchOptions::default();
+        assert_eq!(CaseMode::of(&opts), CaseMode::Smart);
+        for expected in [CaseMode::Sensitive, CaseMode::Insensitive, CaseMode::Smart] {
+            CaseMode::of(&opts).next().apply(&mut opts);
+            assert_eq!(CaseMode::of(&opts), expected);
+        }
+    }
+
+    #[test]
+    fn test_case_modes_over_mixed_case() {
+        let dir = fixture_dir("case_modes", &[("log.txt", "Error one\nerror two\nERROR three\n")]);
+        let lines = |input: &str| {
+            let mut opts = SearchOptions::default();
+            let pattern = apply_flags(input, &mut opts).unwrap();
+            let result = search_dir(&pattern, dir.to_str().unwrap(), &opts);
+            result.matches.iter().map(|m| m.line_number).collect::<Vec<u64>>()
+        };
+
+        // Smart: lowercase ignores case, any uppercase letter makes it exact
+        assert_eq!(lines("error"), vec![1, 2, 3]);
+        assert_eq!(lines("Error"), vec![1]);
+        assert_eq!(lines(r"^error\S* \w"), vec![1, 2, 3]);
+        assert_eq!(lines("-s error"), vec![2]);
+        assert_eq!(lines("-i Error"), vec![1, 2, 3]);
+        assert_eq!(lines("-i -S Error"), vec![1]);
+
+        assert_eq!(CaseMode::Smart.effective_label("error", false), "smart, insensitive");
+        assert_eq!(CaseMode::Smart.effective_label("[A-Z]rror", false), "smart, sensitive");
+        assert_eq!(CaseMode::Smart.effective_label(r"\Serror", false), "smart, insensitive");
+        assert_eq!(CaseMode::Smart.effective_label(r"\S", true), "smart, sensitive");
+        assert_eq!(CaseMode::Sensitive.effective_label("error", false), "sensitive");
+    }
+
+    #[test]
+    fn test_engine_choice() {
+        let opts = SearchOptions::default();
+        assert!(!build_engine("foo", &opts).unwrap().is_pcre2());
+
+        let mut pcre = SearchOptions::default();
+        assert_eq!(apply_flags("--pcre2 foo(?!bar)", &mut pcre).unwrap(), "foo(?!bar)");
+        assert!(pcre.pcre2);
+
+        // Look-around falls back to PCRE2 only when it is built in
+        let fallback = build_engine("foo(?!bar)", &opts);
+        assert_eq!(fallback.is_ok(), cfg!(feature = "pcre2"));
+        if let Err(e) = validate_pattern(r"(\w+)\s+\1", &opts) {
+            assert!(e.contains("not supported"), "{}", e);
+        }
+    }
+
+    #[cfg(feature = "pcre2")]
+    #[test]
+    fn test_pcre2_fallback_search() {
+        let contents = b"foobar\nfoobaz\nthe the cat\n";
+        let opts = SearchOptions::default();
+
+        let result = search_buffer("foo(?!bar)", "a.txt", contents, &opts).unwrap();
+        assert!(result.stats.pcre2);
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![2]);
+        assert!(format_results(&result, &Layout::default()).contains(", pcre2]"));
+
+        let result = search_buffer(r"(\w+)\s+\1", "a.txt", contents, &opts).unwrap();
+        assert_eq!(result.matches[0].line_number, 3);
+    }
+
+    #[test]
+    fn test_build_matcher() {
+        let opts = SearchOptions::default();
+        let matcher = build_matcher("test", &opts);
+        assert!(matcher.is_ok());
+    }
+
+    #[test]
+    fn test_build_matcher_invalid() {
+        let opts = SearchOptions::default();
+        let matcher = build_matcher("[invalid", &opts);
+        assert!(matcher.is_err());
+    }
+
+    #[test]
+    fn test_validate_pattern_reports_position() {
+        let opts = SearchOptions::default();
+        assert!(validate_pattern("foo(bar)", &opts).is_ok());
+
+        let err = validate_pattern("foo(", &opts).unwrap_err();
+        assert!(err.contains("unclosed group"), "{}", err);
+        assert!(err.contains("at byte 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_search_buffer() {
+        let opts = SearchOptions::default();
+        let contents = b"alpha\nbeta gamma\nunsaved beta";
+        let result = search_buffer("beta", "*scratch*", contents, &opts).unwrap();
+        assert_eq!(result.stats.matches, 2);
+        assert_eq!(result.stats.files_matched, 1);
+        assert_eq!(result.matches[0].file, PathBuf::from("*scratch*"));
+        assert_eq!(result.matches[0].line_number, 2);
+        assert_eq!(result.matches[1].line_number, 3);
+        assert_eq!(result.matches[1].column, 8);
+    }
+
+    #[test]
+    fn test_max_count_truncates() {
+        let opts = SearchOptions {
+            max_count: Some(2),
+            ..SearchOptions::default()
+        };
+        let contents = b"hit\nhit\nhit\n";
+        let result = search_buffer("hit", "gen.rs", contents, &opts).unwrap();
+        assert_eq!(result.stats.matches, 2);
+        assert_eq!(result.truncated_files, vec![PathBuf::from("gen.rs")]);
+
+        let output = format_results(&result, &Layout::default());
+        assert!(output.contains("gen.rs (truncated)"));
+    }
+
+    #[test]
+    fn test_search_buffer_lines() {
+        let opts = SearchOptions::default();
+        let contents = b"err one\nok\nerr two\nerr three\nok\nerr four\n";
+        let result = search_buffer_lines("err", "app.log", contents, 2, 4, &opts).unwrap();
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_line_range_bytes() {
+        let contents = b"a\nbb\nccc";
+        assert_eq!(line_range_bytes(contents, 1, 1), (0, 2));
+        assert_eq!(line_range_bytes(contents, 2, 3), (2, 8));
+        assert_eq!(line_range_bytes(contents, 5, 6), (8, 8));
+    }
+
+    /// Run an uncancelled search without streaming
+    fn search_dir(pattern: &str, dir: &str, opts: &SearchOptions) -> SearchResult {
+        let (cancel, files) = (AtomicBool::new(false), AtomicUsize::new(0));
+        search_parallel(pattern, dir, opts, &cancel, &files, &mut |_, _| {}).unwrap()
+    }
+
+    /// The grouped layout at the default line length
+    fn grouped() -> Layout {
+        Layout {
+            style: Style::Grouped,
+            ..Layout::default()
+        }
+    }
+
+    #[test]
+    fn test_list_files() {
+        let dir = fixture_dir(
+            "list_files",
+            &[("src/Main.rs", ""), ("src/util.rs", ""), ("README.md", "")],
+        );
+        let root = dir.to_str().unwrap();
+        let opts = SearchOptions::default();
+
+        let all = list_files(root, None, 100, &opts).unwrap();
+        assert_eq!(all.files.len(), 3);
+        assert!(!all.truncated);
+
+        let sub = list_files(root, Some("main"), 100, &opts).unwrap();
+        assert_eq!(sub.files, vec![PathBuf::from("src/Main.rs")]);
+
+        let glob = list_files(root, Some("*.RS"), 100, &opts).unwrap();
+        assert_eq!(glob.files.len(), 2);
+
+        let capped = list_files(root, None, 2, &opts).unwrap();
+        assert_eq!(capped.files.len(), 2);
+        assert!(capped.truncated);
+    }
+
+    #[test]
+    fn test_count_only() {
+        let dir = fixture_dir(
+            "count_only",
+            &[("a.txt", "x\nx\nx\n"), ("b.txt", "x\n"), ("c.txt", "y\n")],
+        );
+        let opts = SearchOptions {
+            count_only: true,
+            ..SearchOptions::default()
+        };
+        let result = search_dir("x", dir.to_str().unwrap(), &opts);
+        assert!(result.matches.is_empty());
+        assert_eq!(result.stats.matches, 4);
+        assert_eq!(result.stats.files_matched, 2);
+        assert_eq!(result.counts.len(), 2);
+        assert_eq!(result.counts[0].0, dir.join("a.txt"));
+        assert_eq!(result.counts[0].1, 3);
+
+        let output = format_counts(&result);
+        assert!(output.starts_with("4 MATCHES IN 2 FILES."));
+        assert!(output.contains(&format!("{}: 1\n", dir.join("b.txt").display())));
+    }
+
+    #[test]
+    fn test_format_grep() {
+        let dir = fixture_dir("grep_export", &[("a.rs", "fn main() {\n\tlet x = 1;\n}\n")]);
+        let result = search_dir("let", dir.to_str().unwrap(), &SearchOptions::default());
+
+        assert_eq!(format_grep(&result, Some(&dir)), "a.rs:2:2:\tlet x = 1;\n");
+        let absolute = format_grep(&result, None);
+        assert_eq!(absolute, format!("{}:2:2:\tlet x = 1;\n", dir.join("a.rs").display()));
+
+        let inverted = SearchOptions {
+            invert_match: true,
+            ..SearchOptions::default()
+        };
+        let result = search_dir("let", dir.to_str().unwrap(), &inverted);
+        assert_eq!(format_grep(&result, Some(&dir)), "a.rs:1:fn main() {\na.rs:3:}\n");
+    }
+
+    #[test]
+    fn test_multiline_match() {
+        let opts = SearchOptions {
+            multiline: true,
+            multiline_dotall: true,
+            ..SearchOptions::default()
+        };
+        let contents = b"use std::fmt;\n\nstruct Point {\n    x: i32,\n}\n";
+        let result =
+            search_buffer(r"struct\s+\w+\s*\{[^}]*\}", "point.rs", contents, &opts).unwrap();
+        assert_eq!(result.stats.matches, 1);
+        assert_eq!(result.matches[0].line_number, 3);
+        assert_eq!(result.matches[0].text, "struct Point {\n    x: i32,\n}");
+
+        let output = format_match(&result.matches[0], &Layout::default());
+        assert_eq!(output, "point.rs:3:1: struct Point { (+2 lines)\n");
+        let spans = &highlight_batch(&result.matches, &Layout::default())[0].1;
+        assert_eq!(spans.last(), Some(&(output.len() - 1, Highlight::Plain)));
+    }
+
+    #[test]
+    fn test_multiline_crlf() {
+        let mut opts = SearchOptions::default();
+        let pattern = apply_flags(r"-U unsafe \{\s*free\(", &mut opts).unwrap();
+        assert!(opts.multiline);
+
+        let contents = b"fn a() {\r\n    unsafe {\r\n        free(p);\r\n    }\r\n}\r\n";
+        let result = search_buffer(&pattern, "a.rs", contents, &opts).unwrap();
+        assert_eq!(result.matches.len(), 1);
+        let m = &result.matches[0];
+        assert_eq!((m.line_number, m.column), (2, 4));
+
+        let output = format_match(m, &Layout::default());
+        assert_eq!(output, "a.rs:2:5:     unsafe { (+1 line)\n");
+        assert!(!output.contains('\r'));
+    }
+
+    #[test]
+    fn test_any_pattern() {
+        assert_eq!(any_pattern("foo  bar\tbaz", false).unwrap(), "(?:foo|bar|baz)");
+        assert_eq!(any_pattern("a.b c+", true).unwrap(), r"(?:a\.b|c\+)");
+        assert_eq!(any_pattern(" only ", false).unwrap(), "only");
+        assert!(any_pattern("   ", false).is_err());
+
+        let pattern = any_pattern("unwrap( expect(", true).unwrap();
+        let contents = b"x.unwrap();\nx.expect(\"y\");\nx?;\n";
+        let result = search_buffer(&pattern, "a.rs", contents, &SearchOptions::default()).unwrap();
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_whole_word_pattern() {
+        let search = |word: &str, contents: &[u8]| {
+            let (pattern, word_boundary) = whole_word_pattern(word);
+            let opts = SearchOptions {
+                word_boundary,
+                ..SearchOptions::default()
+            };
+            let result = search_buffer(&pattern, "a.cc", contents, &opts).unwrap();
+            result
+                .matches
+                .iter()
+                .map(|m| m.line_number)
+                .collect::<Vec<u64>>()
+        };
+
+        assert_eq!(whole_word_pattern("id"), ("id".to_string(), true));
+        assert_eq!(
+            search("id", b"void f(int id);\nidentifier\nid\n"),
+            vec![1, 3]
+        );
+
+        assert_eq!(
+            whole_word_pattern("operator<<"),
+            (r"\boperator<<".to_string(), false)
+        );
+        let contents = b"os.operator<<(x);\nmyoperator<<x\nstd::cout << x;\n";
+        assert_eq!(search("operator<<", contents), vec![1]);
+        assert_eq!(search("$x", b"echo $x\necho $xy\n"), vec![1]);
+    }
+
+    #[test]
+    fn test_fixed_strings_match_verbatim() {
+        let opts = SearchOptions { fixed_strings: true, ..SearchOptions::default() };
+        let contents = b"call foo(bar)\nfoobar\nx = a.b[0]\naxb[0]\n";
+
+        let result = search_buffer("foo(bar)", "a.rs", contents, &opts).unwrap();
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![1]);
+
+        let result = search_buffer("a.b[0]", "a.rs", contents, &opts).unwrap();
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![3]);
+    }
+
+    #[test]
+    fn test_long_lines_are_clipped_on_char_boundaries() {
+        let opts = SearchOptions { max_line_len: Some(5), ..SearchOptions::default() };
+        let contents = "h\u{e9}ll\u{f6} w\u{f6}rld needle\nshort needle\n".as_bytes();
+        let result = search_buffer("needle", "min.js", contents, &opts).unwrap();
+
+        // Only the rendered line is cut; the match keeps its text and
+        // the column still points at the real match for jumping
+        let m = &result.matches[0];
+        assert_eq!(m.text, "h\u{e9}ll\u{f6} w\u{f6}rld needle");
+        assert_eq!(m.column, "h\u{e9}ll\u{f6} w\u{f6}rld ".len());
+
+        let layout = Layout::of(&opts);
+        let (line, spans) = &highlight_batch(&result.matches, &layout)[0];
+        assert_eq!(line, "min.js:1:16: h\u{e9}ll\u{f6}\u{2026}");
+        // The match lies past the cut, so nothing is highlighted as one
+        assert!(spans.iter().all(|&(_, h)| h != Highlight::Match));
+        assert_eq!(format_match(&result.matches[1], &layout), "min.js:2:7: short\u{2026}\n");
+
+        let unclipped = SearchOptions { max_line_len: None, ..SearchOptions::default() };
+        let layout = Layout::of(&unclipped);
+        assert_eq!(format_match(&result.matches[1], &layout), "min.js:2:7: short needle\n");
+    }
+
+    #[test]
+    fn test_control_characters_are_escaped_for_display() {
+        let contents = b"a\rb\tneedle\x1b end\n";
+        let result = search_buffer("needle", "a.txt", contents, &SearchOptions::default()).unwrap();
+        let m = &result.matches[0];
+        assert_eq!(m.text, "a\rb\tneedle\x1b end");
+
+        let (line, spans) = &highlight_batch(std::slice::from_ref(m), &Layout::default())[0];
+        assert_eq!(line, "a.txt:1:5: a^Mb needle^[ end");
+        assert!(is_result_line(line, &Layout::default()));
+
+        let text_start = "a.txt:1:5: ".len();
+        let start = text_start + "a^Mb ".len();
+        assert!(spans.contains(&(start, Highlight::Plain)));
+        assert!(spans.contains(&(start + "needle".len(), Highlight::Match)));
+    }
+
+    #[test]
+    fn test_columns_are_one_based() {
+        let contents = b"foo at start\n  foo indented\n";
+        let result = search_buffer("foo", "a.rs", contents, &SearchOptions::default()).unwrap();
+        assert_eq!(result.matches[0].column, 0);
+
+        let output = format_results(&result, &Layout::default());
+        assert!(output.contains("a.rs:1:1: foo at start\n"));
+        assert!(output.contains("a.rs:2:3:   foo indented\n"));
+    }
+
+    #[test]
+    fn test_word_boundary_escaped() {
+        let opts = SearchOptions {
+            word_boundary: true,
+            ..SearchOptions::default()
+        };
+        let contents = b"let len = 1;\nlength + silent\nx.len()\n";
+        let result = search_buffer(&regex_syntax::escape("len"), "a.rs", contents, &opts).unwrap();
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![1, 3]);
+
+        let contents = b"a.b\naxb\n";
+        let result = search_buffer(&regex_syntax::escape("a.b"), "b.rs", contents, &opts).unwrap();
+        assert_eq!(result.stats.matches, 1);
+    }
+
+    #[test]
+    fn test_word_boundary_unicode() {
+        let opts = SearchOptions {
+            word_boundary: true,
+            ..SearchOptions::default()
+        };
+        // Accented letters are word characters on either side of the word
+        let contents = "naïve\nnaïveté\nunnaïve\nle naïve.\nnaïve_x\nénaïve\n";
+        let (pattern, whole) = whole_word_pattern("naïve");
+        assert!(whole);
+        let result = search_buffer(&pattern, "a.txt", contents.as_bytes(), &opts).unwrap();
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![1, 4]);
+        assert_eq!(result.matches[1].column, "le ".len());
+
+        // An accented word's own edges count as word characters too
+        let result = search_buffer("été", "b.txt", "été\nétés\nl'été\n".as_bytes(), &opts).unwrap();
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_cancelled_search_stops() {
+        let dir = fixture_dir("cancel", &[("a.txt", "hit\n")]);
+        let cancel = AtomicBool::new(true);
+
+        let opts = SearchOptions::default();
+        let files = AtomicUsize::new(0);
+        let result =
+            search_parallel("hit", dir.to_str().unwrap(), &opts, &cancel, &files, &mut |_, _| {})
+                .unwrap();
+        assert!(result.stats.cancelled);
+        assert!(result.matches.is_empty());
+        assert!(format_results(&result, &Layout::default()).contains("(cancelled)"));
+    }
+
+    #[test]
+    fn test_search_exists() {
+        let dir = fixture_dir("exists", &[("a.txt", "alpha\n"), ("b.txt", "beta\n")]);
+        let root = dir.to_str().unwrap();
+        let (cancel, files) = (AtomicBool::new(false), AtomicUsize::new(0));
+        let exists = |pattern, opts: &SearchOptions| {
+            search_exists(pattern, root, opts, &cancel, &files, &mut || {})
+        };
+        let opts = SearchOptions::default();
+        assert_eq!(exists("bet", &opts), Ok(true));
+        assert_eq!(exists("gamma", &opts), Ok(false));
+        assert_eq!(files.load(Ordering::Relaxed), 2);
+        assert!(exists("(", &opts).is_err());
+
+        let small = SearchOptions {
+            max_filesize: Some(2),
+            ..SearchOptions::default()
+        };
+        assert_eq!(exists("bet", &small), Ok(false));
+
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!(exists("bet", &opts), Ok(false));
+    }
+
+    /// count_matches, run to the end
+    fn count_all(pattern: &str, path: &str, opts: &SearchOptions) -> Result<usize, String> {
+        let (cancel, files) = (AtomicBool::new(false), AtomicUsize::new(0));
+        count_matches(pattern, path, opts, &cancel, &files, &mut || {})
+    }
+
+    #[test]
+    fn test_count_matches() {
+        let dir = fixture_dir("count_total", &[("a.txt", "x\nx\nx\n"), ("b.txt", "x y\n"), ("c.txt", "y\n")]);
+        let root = dir.to_str().unwrap();
+        let opts = SearchOptions::default();
+        assert_eq!(count_all("x", root, &opts), Ok(4));
+        assert_eq!(count_all("z", root, &opts), Ok(0));
+        assert!(count_all("(", root, &opts).is_err());
+
+        let capped = SearchOptions {
+            max_count: Some(1),
+            ..SearchOptions::default()
+        };
+        assert_eq!(count_all("x", root, &capped), Ok(2));
+
+        let (cancel, files) = (AtomicBool::new(true), AtomicUsize::new(0));
+        assert_eq!(count_matches("x", root, &opts, &cancel, &files, &mut || {}), Ok(0));
+        assert_eq!(files.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_timeout_note() {
+        let dir = fixture_dir("timeout", &[("a.txt", "hit\n")]);
+        let opts = SearchOptions {
+            timeout_ms: Some(60_000),
+            ..SearchOptions::default()
+        };
+        // Finishing first hangs up on the watchdog
+        let mut result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.stats.timed_out, None);
+        assert_eq!(result.stats.matches, 1);
+
+        result.stats.timed_out = Some(5000);
+        assert!(format_results(&result, &Layout::default()).contains(". (timed out after 5s) ["));
+        result.stats.timed_out = Some(1500);
+        assert!(format_results(&result, &Layout::default()).contains("(timed out after 1500ms)"));
+    }
+
+    #[test]
+    fn test_streamed_batches_match_final_result() {
+        let dir = fixture_dir(
+            "stream",
+            &[("a.txt", "hit\nmiss\nhit\n"), ("b.txt", "hit\n"), ("c.txt", "miss\n")],
+        );
+        let opts = SearchOptions::default();
+
+        let mut streamed: Vec<Vec<Match>> = Vec::new();
+        let files = AtomicUsize::new(7);
+        let result = search_parallel(
+            "hit",
+            dir.to_str().unwrap(),
+            &opts,
+            &AtomicBool::new(false),
+            &files,
+            &mut |batch, _| streamed.push(batch.to_vec()),
+        )
+        .unwrap();
+        assert_eq!(files.load(Ordering::Relaxed), 3);
+
+        // Completion order varies; once sorted, the streamed text is the body
+        streamed.sort_by(|a, b| a[0].file.cmp(&b[0].file));
+        let body: String = streamed
+            .iter()
+            .enumerate()
+            .map(|(i, batch)| format_batch(batch, &grouped(), i == 0))
+            .collect();
+        let full = format_results(&result, &grouped());
+        assert_eq!(streamed.iter().map(Vec::len).sum::<usize>(), 3);
+        assert!(full.ends_with(&body));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_loop_back_searched_once() {
+        let dir = fixture_dir("symlink", &[("src/a.txt", "hit\nmiss\nhit\n"), ("b.txt", "hit\n")]);
+        std::os::unix::fs::symlink(dir.join("src"), dir.join("src_link")).unwrap();
+        let opts = SearchOptions {
+            follow_symlinks: true,
+            ..SearchOptions::default()
+        };
+
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 3);
+        assert_eq!(result.stats.files_searched, 2);
+        assert_eq!(result.stats.files_matched, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_listed_file_named_twice_searched_once() {
+        let dir = fixture_dir("listed_twice", &[("a.rs", "hit\n")]);
+        std::os::unix::fs::symlink(dir.join("a.rs"), dir.join("link.rs")).unwrap();
+        let opts = SearchOptions {
+            path_list: Some(PathList {
+                paths: vec!["a.rs".into(), dir.join("a.rs"), "link.rs".into()],
+                source: "list".to_string(),
+                lines: None,
+            }),
+            ..SearchOptions::default()
+        };
+
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 1);
+        assert_eq!(result.stats.files_searched, 1);
+        assert_eq!(count_all("hit", dir.to_str().unwrap(), &opts).unwrap(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_survives_cycles_and_keeps_link_paths() {
+        let outside = fixture_dir("follow_target", &[("gen.rs", "hit\n")]);
+        let dir = fixture_dir("follow_cycle", &[("src/a.rs", "hit\n")]);
+        std::os::unix::fs::symlink(&dir, dir.join("src/back")).unwrap();
+        std::os::unix::fs::symlink(dir.join("src"), dir.join("again")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("build")).unwrap();
+
+        // Links are skipped unless followed
+        let result = search_dir("hit", dir.to_str().unwrap(), &SearchOptions::default());
+        assert_eq!(result.matches.len(), 1);
+
+        let mut opts = SearchOptions::default();
+        assert_eq!(apply_flags("--follow hit", &mut opts).unwrap(), "hit");
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 2, "{:?}", result.matches);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        // Found through the link, so reported under the search root
+        assert!(result.matches.iter().any(|m| m.file == dir.join("build/gen.rs")));
+    }
+
+    #[test]
+    fn test_git_tracked_only_skips_untracked() {
+        let dir = fixture_dir("tracked", &[("tracked.txt", "hit\n"), ("build.out", "hit\n")]);
+        let git = |args: &[&str]| {
+            std::process::Command::new("git")
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .map(|o| o.status.success())
+                .unwrap_or(false)
+        };
+        if !git(&["init", "-q"]) {
+            return; // git not installed
+        }
+        assert!(git(&["add", "tracked.txt"]));
+
+        let opts = SearchOptions {
+            git_tracked_only: true,
+            ..SearchOptions::default()
+        };
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 1);
+        assert!(result.matches[0].file.ends_with("tracked.txt"));
+
+        let all = search_dir("hit", dir.to_str().unwrap(), &SearchOptions::default());
+        assert_eq!(all.matches.len(), 2);
+    }
+
+    #[test]
+    fn test_path_list_searches_only_listed_files() {
+        let dir = fixture_dir(
+            "path_list",
+            &[("a.rs", "hit\n"), ("b.rs", "hit\n"), ("c.txt", "hit\n"), ("d.rs", "hit\n")],
+        );
+        let opts = SearchOptions {
+            path_list: Some(PathList {
+                paths: vec!["a.rs".into(), dir.join("c.txt"), "gone.rs".into(), "d.rs".into()],
+                source: "git status vs HEAD".to_string(),
+                lines: None,
+            }),
+            globs: vec![(false, "d.rs".to_string())],
+            ..SearchOptions::default()
+        };
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        let files: Vec<_> = result.matches.iter().map(|m| m.file.clone()).collect();
+        assert_eq!(files, vec![dir.join("a.rs"), dir.join("c.txt")]);
+        assert_eq!(result.stats.files_searched, 2);
+        assert!(format_header(&result).contains(", git status vs HEAD]"));
+        assert_eq!(count_all("hit", dir.to_str().unwrap(), &opts).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_line_ranges_drop_matches_outside() {
+        let dir = fixture_dir(
+            "line_ranges",
+            &[("a.rs", "hit\nhit\nhit\nhit\nhit\n"), ("b.rs", "hit\n"), ("c.rs", "hit\n")],
+        );
+        let lines: LineRanges = [
+            (PathBuf::from("a.rs"), vec![(2, 3), (5, 5)]),
+            (dir.join("b.rs"), vec![(1, 1)]),
+        ]
+        .into_iter()
+        .collect();
+        let opts = SearchOptions {
+            path_list: Some(PathList {
+                paths: vec!["a.rs".into(), "b.rs".into(), "c.rs".into()],
+                source: "git diff -U0 main".to_string(),
+                lines: Some(lines),
+            }),
+            ..SearchOptions::default()
+        };
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        let found: Vec<(String, u64)> = result
+            .matches
+            .iter()
+            .map(|m| (m.file.file_name().unwrap().to_string_lossy().into_owned(), m.line_number))
+            .collect();
+        let expected = [("a.rs", 2), ("a.rs", 3), ("a.rs", 5), ("b.rs", 1)];
+        assert_eq!(found, expected.map(|(f, n)| (f.to_string(), n)));
+        // Two unchanged lines of a.rs, and all of c.rs, which has no ranges
+        assert_eq!(result.stats.outside_lines, Some(3));
+        assert!(format_header(&result).contains(", git diff -U0 main, 3 unchanged-line matches skipped]"));
+
+        assert!(!in_ranges(&[], 1));
+        assert!(!in_ranges(&[(2, 3)], 4));
+    }
+
+    #[test]
+    fn test_globs_apply_in_the_order_given() {
+        let dir = fixture_dir(
+            "glob_order",
+            &[("main.rs", "hit\n"), ("test_main.rs", "hit\n"), ("notes.txt", "hit\n")],
+        );
+        let root = dir.to_str().unwrap();
+        let searched = |flags: &str| {
+            let mut opts = SearchOptions::default();
+            let pattern = apply_flags(flags, &mut opts).unwrap();
+            let result = search_dir(&pattern, root, &opts);
+            let mut names: Vec<String> = result
+                .matches
+                .iter()
+                .map(|m| m.file.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+
+        // Include then exclude: the exclude wins for test_main.rs
+        assert_eq!(searched("-g *.rs -g !test_* hit"), vec!["main.rs"]);
+        // Exclude then include: the later include takes it back, as in rg
+        assert_eq!(searched("-g !test_* --glob=*.rs hit"), vec!["main.rs", "test_main.rs"]);
+        assert_eq!(searched("-g !*.rs hit"), vec!["notes.txt"]);
+        assert!(apply_flags("-g", &mut SearchOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_lossy_utf8_finds_latin1_lines() {
+        let dir = fixture_dir("latin1", &[]);
+        std::fs::write(dir.join("latin1.txt"), b"caf\xe9 hit\nmiss\n").unwrap();
+
+        let result = search_dir("hit", dir.to_str().unwrap(), &SearchOptions::default());
+        assert_eq!(result.matches.len(), 1);
+        assert_eq!(result.matches[0].text, "caf\u{fffd} hit");
+
+        let strict = SearchOptions {
+            lossy_utf8: false,
+            ..SearchOptions::default()
+        };
+        let skipped = search_dir("hit", dir.to_str().unwrap(), &strict);
+        assert!(skipped.matches.is_empty());
+        assert_eq!(skipped.stats.skipped_encoding, 1);
+        assert!(skipped.errors.is_empty());
+    }
+
+    #[test]
+    fn test_transcodes_utf16_and_default_encoding() {
+        let dir = fixture_dir("encodings", &[]);
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend("h\u{e9}llo\nw\u{f6}rld hit\n".encode_utf16().flat_map(u16::to_le_bytes));
+        std::fs::write(dir.join("doc.txt"), utf16).unwrap();
+        std::fs::write(dir.join("legacy.txt"), b"caf\xe9 hit\n").unwrap();
+
+        // The BOM is honoured without -E; the Latin-1 file is read lossily
+        let result = search_dir("hit", dir.to_str().unwrap(), &SearchOptions::default());
+        let doc = &result.matches[0];
+        assert!(doc.file.ends_with("doc.txt"));
+        assert_eq!((doc.line_number, doc.text.as_str()), (2, "w\u{f6}rld hit"));
+        assert_eq!((doc.column, doc.file_column), (7, Some(12)));
+        assert_eq!(location(doc), "2:13");
+        assert_eq!(result.transcoded, vec![(dir.join("doc.txt"), "UTF-16LE")]);
+
+        let mut opts = SearchOptions::default();
+        assert_eq!(apply_flags("-E latin1 hit", &mut opts).unwrap(), "hit");
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        let legacy = &result.matches[1];
+        assert_eq!(legacy.text, "caf\u{e9} hit");
+        assert_eq!(location(legacy), "1:6");
+        assert_eq!(result.transcoded.len(), 2);
+        assert!(format_results(&result, &Layout::default()).contains("legacy.txt (windows-1252)\n"));
+
+        assert!(apply_flags("--encoding=klingon x", &mut opts).is_err());
+        assert_eq!(parse_encoding("auto"), Ok(None));
+        assert_eq!(parse_encoding("utf8"), Ok(None));
+    }
+
+    #[test]
+    fn test_last_line_without_newline() {
+        let dir = fixture_dir(
+            "no_newline",
+            &[("multi.txt", "alpha\nbeta\nkey = foo"), ("single.conf", "x=1;foo")],
+        );
+
+        // Both the buffered reader and the mmap path must see the final line
+        for mmap in [false, true] {
+            let opts = SearchOptions { mmap, ..SearchOptions::default() };
+            let result = search_dir("foo", dir.to_str().unwrap(), &opts);
+            assert_eq!(result.matches.len(), 2, "mmap = {}", mmap);
+
+            let multi = &result.matches[0];
+            assert!(multi.file.ends_with("multi.txt"));
+            assert_eq!((multi.line_number, multi.column, multi.end_column), (3, 6, 9));
+            assert_eq!(multi.text, "key = foo");
+
+            let single = &result.matches[1];
+            assert!(single.file.ends_with("single.conf"));
+            assert_eq!((single.line_number, single.column), (1, 4));
+            assert_eq!(single.text, "x=1;foo");
+        }
+
+        let buffers = vec![("*scratch*".to_string(), b"one\ntwo foo".to_vec())];
+        let result = search_buffers("foo", &buffers, &SearchOptions::default()).unwrap();
+        assert_eq!((result.matches[0].line_number, result.matches[0].column), (2, 4));
+    }
+
+    #[test]
+    fn test_search_compressed_files() {
+        use std::io::Write;
+
+        let dir = fixture_dir("compressed", &[("plain.log", "needle plain\n")]);
+        let gz = dir.join("app.log.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&gz).unwrap(),
+            flate2::Compression::default(),
+        );
+        let contents = format!("one\nneedle zipped\nneedle again\n{}\n", "a".repeat(20_000));
+        encoder.write_all(contents.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        // Off by default: the compressed bytes don't match
+        let result = search_dir("needle", dir.to_str().unwrap(), &SearchOptions::default());
+        assert_eq!(result.matches.len(), 1);
+
+        let mut opts = SearchOptions::default();
+        apply_flags("-z x", &mut opts).unwrap();
+        let result = search_dir("needle", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 3, "{:?}", result.errors);
+        assert_eq!(result.matches[0].file, gz);
+        assert_eq!(result.matches[0].line_number, 2);
+        assert!(is_compressed(&gz));
+        // Other formats only with their feature
+        assert_eq!(is_compressed(Path::new("app.log.xz")), cfg!(feature = "xz"));
+        assert!(!is_compressed(Path::new("app.log.lz4")));
+
+        // Stopping early (max_count) is no error
+        let first = SearchOptions { max_count: Some(1), ..opts.clone() };
+        let result = search_dir("needle", dir.to_str().unwrap(), &first);
+        assert_eq!(result.matches.len(), 2);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+
+        // The size limit counts decompressed bytes, not the small .gz
+        assert!(std::fs::metadata(&gz).unwrap().len() < 1000);
+        let small = SearchOptions { max_filesize: Some(1000), ..opts.clone() };
+        let result = search_dir("needle", dir.to_str().unwrap(), &small);
+        assert!(result.matches.iter().all(|m| m.file != gz));
+        assert!(result.errors.iter().any(|e| e.contains("decompressed size")), "{:?}", result.errors);
+    }
+
+    #[test]
+    fn test_max_results_caps_results() {
+        let dir = fixture_dir(
+            "total_cap",
+            // No set of whole files sums to the cap of 4, so it is always
+            // passed, whatever order the files finish in
+            &[
+                ("a.txt", "hit\nhit\nhit\n"),
+                ("b.txt", "hit\nhit\nhit\n"),
+                ("c.txt", "hit\nhit\nhit\n"),
+            ],
+        );
+        let opts = SearchOptions {
+            max_results: Some(4),
+            ..SearchOptions::default()
+        };
+
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 4);
+        assert!(result.stats.capped);
+        assert!(result.stats.matches_found >= 4);
+        let output = format_results(&result, &Layout::default());
+        assert!(output.starts_with("RESULTS TRUNCATED AT 4 MATCHES (pattern matched too broadly)"));
+
+        let opts = SearchOptions {
+            max_results: None,
+            ..SearchOptions::default()
+        };
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 9);
+        assert!(!result.stats.capped);
+
+        // Exactly as many matches as the cap allows isn't a truncation
+        let opts = SearchOptions {
+            max_results: Some(9),
+            ..SearchOptions::default()
+        };
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 9);
+        assert!(!result.stats.capped);
+        let output = format_results(&result, &Layout::default());
+        assert!(output.starts_with("9 RESULTS ACROSS 3 FILES."), "{}", output);
+    }
+
+    #[test]
+    fn test_grouped_parse_scans_up_to_the_heading() {
+        let m = |line_number, column, text: &str| Match {
+            file: PathBuf::from("/p/point.rs"),
+            line_number,
+            column,
+            end_column: column + 1,
+            text: text.into(),
+            inverted: false,
+            file_column: None,
+            offset: 0,
+            context: Vec::new(),
+        };
+        let result = SearchResult {
+            matches: vec![m(3, 0, "struct Point {\n    x: i32,\n}"), m(8, 3, "impl Point")],
+            stats: SearchStats {
+                matches: 2,
+                files_searched: 1,
+                files_matched: 1,
+                capped: true,
+                matches_found: 9,
+                ..SearchStats::default()
+            },
+            errors: Vec::new(),
+            truncated_files: Vec::new(),
+            counts: Vec::new(),
+            transcoded: Vec::new(),
+        };
+        let output = format_results(&result, &grouped());
+        let lines: Vec<&str> = output.lines().collect();
+        let at = |n: usize| parse_grouped_location(n, |i| lines.get(i - 1).map(|l| l.to_string()));
+
+        // A multi-line match is one entry summing up the lines it spans
+        let heading = lines.iter().position(|&l| l == "/p/point.rs").unwrap() + 1;
+        assert_eq!(lines[heading], "  3:1: struct Point { (+2 lines)");
+        let location = |line, column| Some(Location { file: "/p/point.rs".into(), line, column });
+        assert_eq!(at(heading), location(1, 1));
+        assert_eq!(at(heading + 1), location(3, 1));
+        assert_eq!(at(heading + 2), location(8, 4));
+        // The truncation note and statistics header aren't files
+        assert!(lines[0].starts_with(CAPPED_NOTE));
+        assert_eq!(at(1), None);
+        assert_eq!(at(2), None);
+    }
+
+    #[test]
+    fn test_grouped_format_and_parse() {
+        let result = SearchResult {
+            matches: vec![
+                Match { file: PathBuf::from("/p/a.rs"), line_number: 3, column: 4, end_column: 5, text: "let x".into(), inverted: false, file_column: None, offset: 0, context: Vec::new() },
+                Match { file: PathBuf::from("/p/a.rs"), line_number: 9, column: 0, end_column: 1, text: "x()".into(), inverted: false, file_column: None, offset: 0, context: Vec::new() },
+                Match { file: PathBuf::from("/p/b.rs"), line_number: 1, column: 2, end_column: 3, text: "  x".into(), inverted: false, file_column: None, offset: 0, context: Vec::new() },
+            ],
+            stats: SearchStats {
+                matches: 3,
+                files_searched: 5,
+                files_matched: 2,
+                elapsed_ms: 1,
+                ..SearchStats::default()
+            },
+            errors: Vec::new(),
+            truncated_files: Vec::new(),
+            counts: Vec::new(),
+            transcoded: Vec::new(),
+        };
+
+        let output = format_results(&result, &grouped());
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            &lines[2..],
+            &["/p/a.rs", "  3:5: let x", "  9:1: x()", "", "/p/b.rs", "  1:3:   x"]
+        );
+
+        let get = |n: usize| lines.get(n - 1).map(|l| l.to_string());
+        let at = |n: usize| parse_grouped_location(n, get);
+        assert_eq!(at(5), Some(Location { file: "/p/a.rs".into(), line: 9, column: 1 }));
+        assert_eq!(at(3), Some(Location { file: "/p/a.rs".into(), line: 1, column: 1 }));
+        assert_eq!(at(8), Some(Location { file: "/p/b.rs".into(), line: 1, column: 3 }));
+        assert_eq!(at(1), None);
+        assert_eq!(at(6), None);
+    }
+
+    #[test]
+    fn test_context_preview() {
+        let loc = Template::default().parse_line("src/a.rs:7:4: let main").unwrap();
+        assert_eq!((loc.file.as_str(), loc.line, loc.column), ("src/a.rs", 7, 4));
+        assert_eq!(Template::default().parse_line("src/a.rs:7: inverted").unwrap().column, 1);
+        assert_eq!(Template::default().parse_line("no location here"), None);
+
+        let text = (1..=20).map(|n| format!("line {}", n)).collect::<Vec<_>>().join("\n");
+        let lines = context_lines(text.as_bytes(), 3, 5).unwrap();
+        assert_eq!(lines.first().map(|(n, _)| *n), Some(1));
+        assert_eq!(lines.last(), Some(&(8, "line 8".to_string())));
+
+        let lines = context_lines("a\r\n\tbx\r\nc".as_bytes(), 2, 1).unwrap();
+        assert_eq!(format_context(&lines, 2, 3), "  1 | a\n> 2 | \tbx\n    | \t ^\n  3 | c\n");
+
+        // Past the end of a file that has since shrunk
+        assert!(context_lines("short".as_bytes(), 40, 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_result_line() {
+        assert!(is_result_line("src/a.rs:7:4: let main", &Layout::default()));
+        assert!(!is_result_line("3 MATCHES IN 2 FILES. Search completed in 1 ms.", &Layout::default()));
+        assert!(!is_result_line("    continued line", &Layout::default()));
+        assert!(!is_result_line("", &Layout::default()));
+
+        assert!(is_result_line("  7:4: let main", &grouped()));
+        assert!(!is_result_line("src/a.rs", &grouped()));
+        assert!(!is_result_line("      let x = 1;", &grouped()));
+
+        // Inverted results carry no column
+        assert!(is_result_line("src/a.rs:7: let main", &Layout::default()));
+        assert!(is_result_line("  7: let main", &grouped()));
+        assert!(!is_result_line("      12: continued", &grouped()));
+    }
+
+    #[test]
+    fn test_invert_with_context_lists_each_line_once() {
+        let dir = fixture_dir("invert", &[("a.txt", "keep 1\nskip\nkeep 2\nskip\nkeep 3\n")]);
+        let opts = SearchOptions {
+            invert_match: true,
+            context_before: 1,
+            context_after: 1,
+            ..SearchOptions::default()
+        };
+
+        let result = search_dir("skip", dir.to_str().unwrap(), &opts);
+        let lines: Vec<u64> = result.matches.iter().map(|m| m.line_number).collect();
+        assert_eq!(lines, vec![1, 3, 5]);
+        assert!(result.matches.iter().all(|m| m.inverted && m.column == 0));
+
+        let output = format_results(&result, &Layout::default());
+        assert!(output.contains("a.txt:3: keep 2\n"));
+        for line in 1..=5 {
+            assert_eq!(output.matches(&format!("a.txt:{}: ", line)).count(), 1, "{}", output);
+        }
+        let grouped = format_results(&result, &grouped());
+        assert!(grouped.contains("\n  2: skip\n  3: keep 2\n"));
+    }
+
+    #[test]
+    fn test_context_lines_surround_matches() {
+        let text = "one\ntwo\nmatch a\nthree\nfour\nfive\nsix\nmatch b\n";
+        let dir = fixture_dir("context", &[("a.txt", text)]);
+        let opts = SearchOptions {
+            context_before: 1,
+            context_after: 2,
+            ..SearchOptions::default()
+        };
+
+        let result = search_dir("match", dir.to_str().unwrap(), &opts);
+        assert_eq!(result.stats.matches, 2);
+        let context = vec![(2, "two".into()), (4, "three".into()), (5, "four".into())];
+        assert_eq!(result.matches[0].context, context);
+        assert_eq!(result.matches[1].context, vec![(7, "six".into())]);
+
+        let output = format_results(&result, &Layout::default());
+        let body: Vec<&str> = output.lines().filter(|l| l.contains("a.txt:")).collect();
+        let file = dir.join("a.txt").display().to_string();
+        let lines: Vec<String> = ["2: two", "3:1: match a", "4: three", "5: four", "7: six"]
+            .iter()
+            .chain(&["8:1: match b"])
+            .map(|l| format!("{}:{}", file, l))
+            .collect();
+        assert_eq!(body, lines);
+        // Context lines jump like results but aren't highlighted as matches
+        let spans = highlight_batch(&result.matches[..1], &Layout::default());
+        assert_eq!(spans.len(), 4);
+        assert!(spans[0].1.iter().all(|&(_, kind)| kind != Highlight::Match));
+        assert!(Template::default().parse_line(&spans[0].0).is_some());
+    }
+
+    #[test]
+    fn test_refine_keeps_excludes_and_matches_paths() {
+        let m = |file: &str, text: &str| Match {
+            file: PathBuf::from(file),
+            line_number: 1,
+            column: 0,
+            end_column: 0,
+            text: text.into(),
+            inverted: false,
+            file_column: None,
+            offset: 0,
+            context: Vec::new(),
+        };
+        let result = SearchResult {
+            matches: vec![m("src/a.rs", "fn main"), m("src/a.rs", "// TODO"), m("tests/b.rs", "fn test")],
+            stats: SearchStats { matches: 3, files_matched: 2, ..SearchStats::default() },
+            errors: Vec::new(),
+            truncated_files: Vec::new(),
+            counts: Vec::new(),
+            transcoded: Vec::new(),
+        };
+
+        let kept = refine(&result, "fn ").unwrap();
+        assert_eq!(kept.stats.matches, 2);
+        assert_eq!(kept.stats.files_matched, 2);
+
+        let dropped = refine(&result, "!TODO").unwrap();
+        assert_eq!(dropped.matches.len(), 2);
+
+        let by_path = refine(&kept, "path:^src/").unwrap();
+        assert_eq!(by_path.matches.len(), 1);
+        assert_eq!(by_path.stats.files_matched, 1);
+
+        assert!(refine(&result, "!").is_err());
+    }
+
+    #[test]
+    fn test_highlight_batch_spans() {
+        let m = Match {
+            file: PathBuf::from("a.rs"),
+            line_number: 7,
+            column: 4,
+            end_column: 8,
+            text: "let main = 1;".into(),
+            inverted: false,
+            file_column: None,
+            offset: 0,
+            context: Vec::new(),
+        };
+
+        let flat = highlight_batch(std::slice::from_ref(&m), &Layout::default());
+        assert_eq!(flat[0].0, "a.rs:7:5: let main = 1;");
+        assert_eq!(&flat[0].0[14..18], "main");
+        assert_eq!(
+            flat[0].1,
+            vec![
+                (4, Highlight::Path),
+                (10, Highlight::Location),
+                (14, Highlight::Plain),
+                (18, Highlight::Match),
+                (23, Highlight::Plain),
+            ]
+        );
+
+        let grouped = highlight_batch(std::slice::from_ref(&m), &grouped());
+        assert_eq!(grouped[0], ("a.rs".to_string(), vec![(4, Highlight::Path)]));
+        assert_eq!(grouped[1].0, "  7:5: let main = 1;");
+        assert_eq!(grouped[1].1[0], (2, Highlight::Plain));
+        assert_eq!(grouped[1].1[3], (15, Highlight::Match));
+    }
+
+    #[test]
+    fn test_definition_patterns() {
+        let opts = SearchOptions::default();
+        let defined_on = |file_type: &str, word: &str, text: &str| {
+            let pattern = definition_pattern(file_type, word, None).unwrap();
+            let result = search_buffer(&pattern, "f", text.as_bytes(), &opts).unwrap();
+            result.matches.iter().map(|m| m.line_number).collect::<Vec<u64>>()
+        };
+
+        let rust = "use a::parse;\nfn parse() {}\nlet x = parse();\npub struct Parser;\n";
+        assert_eq!(defined_on("rust", "parse", rust), vec![2]);
+        assert_eq!(defined_on("rust", "Parser", rust), vec![4]);
+
+        let py = "class Store:\n    def load(self):\n        return load()\nload = 1\n";
+        assert_eq!(defined_on("py", "load", py), vec![2, 4]);
+
+        // Definitions, not calls or prototypes
+        let c = "int count(int n);\nint count(int n) {\n    return count(n - 1);\n}\nstatic char *\ncount2(void)\n";
+        assert_eq!(defined_on("c", "count", c), vec![2]);
+        assert_eq!(defined_on("c", "count2", c), vec![6]);
+
+        // The symbol is taken literally, and config can supply a pattern
+        assert_eq!(definition_pattern("rust", "a.b", None).unwrap().matches(r"a\.b").count(), 1);
+        assert_eq!(definition_pattern("elixir", "go", None), None);
+        assert_eq!(definition_pattern("elixir", "go", Some(r"def\s+WORD")).unwrap(), r"def\s+go");
+    }
+
+    #[test]
+    fn test_todo_markers_group_by_kind() {
+        let text = "// FIXME: leak\n// TODO(ann): docs\n// XXX\n// TODO: tests\n// NOTE: x\n";
+        let opts = SearchOptions::default();
+        let pattern = r"\b(TODO|FIXME|HACK|XXX|NOTE)\b(\(\w+\))?";
+        let result = search_buffer(pattern, "/p/a.rs", text.as_bytes(), &opts).unwrap();
+
+        let order: Vec<String> = DEFAULT_TODO_ORDER.split(',').map(str::to_string).collect();
+        let groups = group_markers(&result.matches, &order);
+        let kinds: Vec<(&str, usize)> = groups.iter().map(|(k, g)| (*k, g.len())).collect();
+        // Configured kinds first in their order, then the rest
+        assert_eq!(kinds, vec![("TODO", 2), ("FIXME", 1), ("XXX", 1), ("NOTE", 1)]);
+        assert_eq!(groups[0].1[0].line_number, 2);
+
+        let output = format_todo(&result, &groups, &Layout::default());
+        assert!(output.starts_with("5 MARKERS ACROSS 1 FILE."), "{}", output);
+        assert!(output.lines().next().unwrap().ends_with("TODO 2, FIXME 1, XXX 1, NOTE 1"));
+        assert!(output.contains("\nTODO (2)\n/p/a.rs:2:4: // TODO(ann): docs\n/p/a.rs:4:4:"));
+        // Headings aren't result lines
+        let layout = Layout::default();
+        let listed = output.lines().filter(|l| is_result_line(l, &layout)).count();
+        assert_eq!(listed, 5);
+    }
+
+    #[test]
+    fn test_format_template() {
+        let m = Match {
+            file: PathBuf::from("a.rs"),
+            line_number: 7,
+            column: 4,
+            end_column: 8,
+            text: "let main = 1;".into(),
+            inverted: false,
+            file_column: None,
+            offset: 0,
+            context: Vec::new(),
+        };
+        let inverted = Match {
+            inverted: true,
+            ..m.clone()
+        };
+
+        let tabs = Layout::flat(Template::parse("{path}\t{line}\t{col}\t{text}").unwrap());
+        let (line, spans) = &highlight_batch(std::slice::from_ref(&m), &tabs)[0];
+        assert_eq!(line, "a.rs\t7\t5\tlet main = 1;");
+        assert_eq!(&spans[..3], &[(4, Highlight::Path), (9, Highlight::Location), (13, Highlight::Plain)]);
+        assert_eq!(format_match(&inverted, &tabs), "a.rs\t7\tlet main = 1;\n");
+
+        // Lines are read back with the template's own separators
+        let Style::Flat(template) = &tabs.style else { unreachable!() };
+        let loc = template.parse_line("dir:x/a.rs\t7\t5\tlet a: u8").unwrap();
+        assert_eq!((loc.file.as_str(), loc.line, loc.column), ("dir:x/a.rs", 7, 5));
+        assert_eq!(template.parse_line("a.rs\t7\tlet main").unwrap().column, 1);
+        assert!(is_result_line("a.rs\t7\t5\tx", &tabs));
+        assert!(!is_result_line("3 RESULTS ACROSS 2 FILES. Search completed in 1 ms.", &tabs));
+
+        let grep = Layout::flat(Template::parse("{path}:{line}: {text}").unwrap());
+        assert_eq!(format_match(&m, &grep), "a.rs:7: let main = 1;\n");
+
+        assert!(Template::parse("{line}: {text}").is_err());
+        assert!(Template::parse("{path}{line}").is_err());
+        assert!(Template::parse("{path}:{line}:{name}").is_err());
+        assert!(Template::parse("{text} {path}:{line}").is_err());
+        assert!(Template::parse("{path}:{line").is_err());
+    }
+
+    #[test]
+    fn test_parallel_order_is_deterministic() {
+        let files: Vec<(String, String)> = (0..40)
+            .map(|i| (format!("d{}/f{:02}.txt", i % 4, i), "hit\nmiss\nhit again\n".to_string()))
+            .collect();
+        let refs: Vec<(&str, &str)> = files.iter().map(|(p, c)| (p.as_str(), c.as_str())).collect();
+        let dir = fixture_dir("deterministic", &refs);
+        let root = dir.to_str().unwrap();
+        let opts = SearchOptions {
+            threads: 4,
+            ..SearchOptions::default()
+        };
+
+        let body = |r: &SearchResult| {
+            let out = format_results(r, &Layout::default());
+            out.lines().skip(1).map(String::from).collect::<Vec<_>>()
+        };
+        let first = search_dir("hit", root, &opts);
+        let second = search_dir("hit", root, &opts);
+        assert_eq!(first.stats.matches, 80);
+        assert_eq!(body(&first), body(&second));
+
+        let keys: Vec<(PathBuf, u64)> =
+            first.matches.iter().map(|m| (m.file.clone(), m.line_number)).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn test_unknown_file_types() {
+        let types = vec!["rust".to_string(), "toml".to_string(), "rsut".to_string()];
+        assert_eq!(unknown_file_types(&types), vec!["rsut".to_string()]);
+        assert!(unknown_file_types(&["python".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn test_apply_flags() {
+        let mut opts = SearchOptions::default();
+        let pattern = apply_flags("--sort=modified fn  main", &mut opts).unwrap();
+        assert_eq!(pattern, "fn  main");
+        assert_eq!(opts.sort, SortOrder::Modified);
+
+        let mut opts = SearchOptions::default();
+        assert_eq!(apply_flags("-- -foo", &mut opts).unwrap(), "-foo");
+        assert_eq!(opts.sort, SortOrder::Path);
+
+        let mut opts = SearchOptions::default();
+        assert_eq!(apply_flags("-m 20 foo", &mut opts).unwrap(), "foo");
+        assert_eq!(opts.max_results, Some(20));
+        assert_eq!(apply_flags("-m0 foo", &mut opts).unwrap(), "foo");
+        assert_eq!(opts.max_results, None);
+        assert_eq!(apply_flags("--max-results=7 -- -x", &mut opts).unwrap(), "-x");
+        assert_eq!(opts.max_results, Some(7));
+        assert!(apply_flags("-m lots foo", &mut opts).is_err());
+
+        let mut opts = SearchOptions::default();
+        assert_eq!(apply_flags("-F foo(bar)", &mut opts).unwrap(), "foo(bar)");
+        assert!(opts.fixed_strings);
+
+        assert!(apply_flags("--sort=size x", &mut opts).is_err());
+        assert!(apply_flags("-foo", &mut opts).is_err());
+
+        assert_eq!(apply_flags("--max-filesize=2M x", &mut opts).unwrap(), "x");
+        assert_eq!(opts.max_filesize, Some(2 << 20));
+        assert!(apply_flags("--max-filesize=5Q x", &mut opts).is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500K"), Ok(Some(500 << 10)));
+        assert_eq!(parse_size("1g"), Ok(Some(1 << 30)));
+        assert_eq!(parse_size("2MB"), Ok(Some(2 << 20)));
+        assert_eq!(parse_size("4096"), Ok(Some(4096)));
+        assert_eq!(parse_size("0"), Ok(None));
+        assert!(parse_size("5Q").unwrap_err().contains("5Q"));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1K").is_err());
+        assert!(parse_size("99999999999G").is_err());
+
+        assert_eq!(format_size(2 << 20), "2M");
+        assert_eq!(format_size(1536), "1536");
+    }
+
+    #[test]
+    fn test_max_filesize_skips_are_counted() {
+        let big = format!("hit\n{}", "x".repeat(2000));
+        let dir = fixture_dir("max_filesize", &[("dump.txt", big.as_str()), ("small.txt", "hit\n")]);
+
+        let mut opts = SearchOptions::default();
+        let pattern = apply_flags("--max-filesize=1K hit", &mut opts).unwrap();
+        let result = search_dir(&pattern, dir.to_str().unwrap(), &opts);
+        assert_eq!(result.matches.len(), 1);
+        assert_eq!(result.stats.skipped_large, 1);
+        assert!(result.errors.is_empty());
+        assert!(format_results(&result, &Layout::default()).contains("1 files skipped (too large)"));
+    }
+
+    #[test]
+    fn test_sort_by_modified() {
+        let dir = fixture_dir("sort_modified", &[("a.txt", "hit\n"), ("b.txt", "hit\nhit\n")]);
+        // Make a.txt clearly older than b.txt
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("a.txt"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let opts = SearchOptions {
+            sort: SortOrder::Modified,
+            ..SearchOptions::default()
+        };
+        let result = search_dir("hit", dir.to_str().unwrap(), &opts);
+        let files: Vec<PathBuf> = result.matches.iter().map(|m| m.file.clone()).collect();
+        assert_eq!(files, vec![dir.join("b.txt"), dir.join("b.txt"), dir.join("a.txt")]);
+        assert_eq!(result.matches[1].line_number, 2);
+        assert!(format_results(&result, &Layout::default()).contains("Sorted by modification time."));
+    }
+
+    #[test]
+    fn test_clamp_threads() {
+        let max = num_cpus::get() * 4;
+        assert_eq!(clamp_threads(0), (0, false));
+        assert_eq!(clamp_threads(1), (1, false));
+        assert_eq!(clamp_threads(max), (max, false));
+        assert_eq!(clamp_threads(max + 1), (max, true));
+    }
+
+    #[test]
+    fn test_search_buffers() {
+        let opts = SearchOptions::default();
+        let buffers = vec![
+            ("main.rs".to_string(), b"fn main() {}\n".to_vec()),
+            ("notes.txt".to_string(), b"nothing here\n".to_vec()),
+            ("lib.rs".to_string(), b"// main entry\nfn main_loop() {}\n".to_vec()),
+        ];
+        let result = search_buffers("main", &buffers, &opts).unwrap();
+        assert_eq!(result.stats.files_searched, 3);
+        assert_eq!(result.stats.files_matched, 2);
+        assert_eq!(result.stats.matches, 3);
+        assert_eq!(result.matches[0].file, PathBuf::from("main.rs"));
+        assert_eq!(result.matches[2].file, PathBuf::from("lib.rs"));
+        assert_eq!(result.matches[2].line_number, 2);
     }
 }
@@ -0,0 +1,257 @@
+//! Persistent search state
+//!
+//! Pattern history and named saved searches live in one plain-text file,
+//! `~/.config/muemacs/rust_re2_state`, one record per line:
+//!
+//! ```text
+//! history<TAB>pattern
+//! saved<TAB>name<TAB>options<TAB>pattern
+//! ```
+//!
+//! History lines are newest first. Options are `key=value` pairs joined
+//! by `;`, and tabs, newlines and backslashes in fields are escaped.
+//! Unknown record kinds and option keys are ignored so older builds can
+//! read newer files.
+
+use std::path::PathBuf;
+
+use crate::search::{self, SearchOptions, SortOrder};
+
+/// A search stored under a name by re2-save-search
+#[derive(Clone)]
+pub struct SavedSearch {
+    pub name: String,
+    pub pattern: String,
+    pub opts: SearchOptions,
+}
+
+/// Everything persisted between sessions
+#[derive(Default)]
+pub struct State {
+    /// Patterns, newest first
+    pub history: Vec<String>,
+    pub saved: Vec<SavedSearch>,
+}
+
+impl State {
+    pub fn find_saved(&self, name: &str) -> Option<&SavedSearch> {
+        self.saved.iter().find(|s| s.name == name)
+    }
+
+    /// Add a saved search, replacing any with the same name
+    pub fn put_saved(&mut self, search: SavedSearch) {
+        match self.saved.iter_mut().find(|s| s.name == search.name) {
+            Some(existing) => *existing = search,
+            None => self.saved.push(search),
+        }
+    }
+}
+
+/// Location of the state file
+pub fn state_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(
+        PathBuf::from(home)
+            .join(".config")
+            .join("muemacs")
+            .join("rust_re2_state"),
+    )
+}
+
+/// Read the state file; a missing file is an empty state
+///
+/// Saved searches are decoded on top of `base`, so options a record
+/// doesn't mention keep the caller's current values.
+pub fn load(base: &SearchOptions) -> State {
+    state_path()
+        .and_then(|p| std::fs::read_to_string(p).ok())
+        .map(|text| parse(&text, base))
+        .unwrap_or_default()
+}
+
+/// Write the state file, creating its directory if needed
+pub fn save(state: &State) -> Result<(), String> {
+    let path = state_path().ok_or("HOME is not set")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    std::fs::write(&path, serialize(state)).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn parse(text: &str, base: &SearchOptions) -> State {
+    let mut state = State::default();
+
+    for line in text.lines() {
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        match fields.as_slice() {
+            [kind, pattern] if kind == "history" => state.history.push(pattern.clone()),
+            [kind, name, opts, pattern] if kind == "saved" => state.put_saved(SavedSearch {
+                name: name.clone(),
+                pattern: pattern.clone(),
+                opts: decode_options(opts, base),
+            }),
+            _ => {}
+        }
+    }
+
+    state
+}
+
+pub fn serialize(state: &State) -> String {
+    let mut out = String::new();
+    for pattern in &state.history {
+        out.push_str(&format!("history\t{}\n", escape(pattern)));
+    }
+    for saved in &state.saved {
+        out.push_str(&format!(
+            "saved\t{}\t{}\t{}\n",
+            escape(&saved.name),
+            escape(&encode_options(&saved.opts)),
+            escape(&saved.pattern)
+        ));
+    }
+    out
+}
+
+/// Options that shape which lines match; display and performance
+/// settings always come from the live config
+fn encode_options(opts: &SearchOptions) -> String {
+    let flag = |b: bool| if b { "1" } else { "0" };
+    let globs: Vec<String> = opts.globs.iter().map(search::format_glob).collect();
+    let sort = match opts.sort {
+        SortOrder::Path => "path",
+        SortOrder::Modified => "modified",
+    };
+    [
+        format!("case={}", flag(opts.case_insensitive)),
+        format!("smart={}", flag(opts.smart_case)),
+        format!("word={}", flag(opts.word_boundary)),
+        format!("invert={}", flag(opts.invert_match)),
+        format!("hidden={}", flag(opts.hidden)),
+        format!("gitignore={}", flag(opts.git_ignore)),
+        format!("noignore={}", flag(opts.no_ignore)),
+        format!("tracked={}", flag(opts.git_tracked_only)),
+        format!("fixed={}", flag(opts.fixed_strings)),
+        format!("pcre2={}", flag(opts.pcre2)),
+        format!("zip={}", flag(opts.search_compressed)),
+        format!("multiline={}", flag(opts.multiline)),
+        format!("dotall={}", flag(opts.multiline_dotall)),
+        format!("sort={}", sort),
+        format!("types={}", opts.file_types.join(",")),
+        format!("globs={}", globs.join(",")),
+    ]
+    .join(";")
+}
+
+fn decode_options(s: &str, base: &SearchOptions) -> SearchOptions {
+    let mut opts = base.clone();
+    let list = |v: &str| -> Vec<String> {
+        v.split(',').filter(|x| !x.is_empty()).map(str::to_string).collect()
+    };
+
+    for (key, value) in s.split(';').filter_map(|kv| kv.split_once('=')) {
+        let on = value == "1";
+        match key {
+            "case" => opts.case_insensitive = on,
+            "smart" => opts.smart_case = on,
+            "word" => opts.word_boundary = on,
+            "invert" => opts.invert_match = on,
+            "hidden" => opts.hidden = on,
+            "gitignore" => opts.git_ignore = on,
+            "noignore" => opts.no_ignore = on,
+            "tracked" => opts.git_tracked_only = on,
+            "fixed" => opts.fixed_strings = on,
+            "pcre2" => opts.pcre2 = on,
+            "zip" => opts.search_compressed = on,
+            "multiline" => opts.multiline = on,
+            "dotall" => opts.multiline_dotall = on,
+            "sort" => opts.sort = SortOrder::parse(value).unwrap_or(opts.sort),
+            "types" => opts.file_types = list(value),
+            "globs" => opts.globs = list(value).iter().map(|g| search::parse_glob(g)).collect(),
+            // Older files kept includes and excludes apart
+            "glob" => {
+                let exclude = search::globs_of(&opts.globs, false);
+                opts.globs = search::ordered_globs(&list(value), &exclude);
+            }
+            "exclude" => {
+                let include = search::globs_of(&opts.globs, true);
+                opts.globs = search::ordered_globs(&include, &list(value));
+            }
+            _ => {}
+        }
+    }
+
+    opts
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut state = State {
+            history: vec!["newest".into(), "tab\there".into()],
+            saved: Vec::new(),
+        };
+        state.put_saved(SavedSearch {
+            name: "unwraps".into(),
+            pattern: r"\.unwrap\(\)".into(),
+            opts: SearchOptions {
+                word_boundary: true,
+                file_types: vec!["rust".into()],
+                globs: vec![(false, "test_*".into()), (true, "*.rs".into())],
+                sort: SortOrder::Modified,
+                ..SearchOptions::default()
+            },
+        });
+
+        let parsed = parse(&serialize(&state), &SearchOptions::default());
+        assert_eq!(parsed.history, state.history);
+
+        let saved = parsed.find_saved("unwraps").unwrap();
+        assert_eq!(saved.pattern, r"\.unwrap\(\)");
+        assert!(saved.opts.word_boundary);
+        assert_eq!(saved.opts.file_types, vec!["rust".to_string()]);
+        assert_eq!(saved.opts.sort, SortOrder::Modified);
+        assert_eq!(saved.opts.globs, vec![(false, "test_*".into()), (true, "*.rs".into())]);
+    }
+
+    #[test]
+    fn test_put_saved_overwrites_by_name() {
+        let mut state = State::default();
+        for pattern in ["TODO", "TODO\\(security\\)"] {
+            state.put_saved(SavedSearch {
+                name: "todo".into(),
+                pattern: pattern.into(),
+                opts: SearchOptions::default(),
+            });
+        }
+        assert_eq!(state.saved.len(), 1);
+        assert_eq!(state.saved[0].pattern, "TODO\\(security\\)");
+    }
+}